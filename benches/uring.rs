@@ -0,0 +1,88 @@
+// compares reading and writing a pack file through io_uring with tokio::fs,
+// run with `cargo bench --features io-uring`; set TMPDIR to a directory on
+// the disk to measure
+
+use std::fs;
+use std::path::Path;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures_util::TryStreamExt;
+use rustic_server::uring::{Ring, Writer};
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+// SIZE is the size of the pack file, larger than typical packs so the page
+// cache of a single file doesn't dominate
+const SIZE: usize = 64 << 20;
+
+// BLOCK is the size of the writes, as done by tokio::io::copy for uploads
+const BLOCK: usize = 8 << 10;
+
+async fn read_tokio(path: &Path) -> u64 {
+    let file = tokio::fs::File::open(path).await.unwrap();
+    ReaderStream::new(file)
+        .try_fold(0, |len, chunk| async move { Ok(len + chunk.len() as u64) })
+        .await
+        .unwrap()
+}
+
+async fn read_uring(ring: &'static Ring, path: &Path) -> u64 {
+    let file = fs::File::open(path).unwrap();
+    ring.read(file, 0, SIZE as u64)
+        .try_fold(0, |len, chunk| async move { Ok(len + chunk.len() as u64) })
+        .await
+        .unwrap()
+}
+
+async fn write_tokio(path: &Path, data: &[u8]) {
+    let mut file = tokio::fs::File::create(path).await.unwrap();
+    for block in data.chunks(BLOCK) {
+        file.write_all(block).await.unwrap();
+    }
+    file.flush().await.unwrap();
+    file.sync_all().await.unwrap();
+}
+
+async fn write_uring(ring: &'static Ring, path: &Path, data: &[u8]) {
+    let mut writer = Writer::new(ring, fs::File::create(path).unwrap());
+    for block in data.chunks(BLOCK) {
+        writer.write_all(block).await.unwrap();
+    }
+    writer.flush().await.unwrap();
+    writer.sync_all().await.unwrap();
+}
+
+fn pack_files(c: &mut Criterion) {
+    let Some(ring) = Ring::get() else {
+        eprintln!("io_uring is unavailable");
+        return;
+    };
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("pack");
+    let data: Vec<u8> = (0..SIZE).map(|i| (i % 251) as u8).collect();
+    fs::write(&path, &data).unwrap();
+
+    let mut group = c.benchmark_group("pack");
+    group
+        .sample_size(10)
+        .throughput(Throughput::Bytes(SIZE as u64));
+    group.bench_function(BenchmarkId::new("read", "tokio::fs"), |b| {
+        b.to_async(&runtime).iter(|| read_tokio(&path));
+    });
+    group.bench_function(BenchmarkId::new("read", "io_uring"), |b| {
+        b.to_async(&runtime).iter(|| read_uring(ring, &path));
+    });
+    let written = dir.path().join("written");
+    group.bench_function(BenchmarkId::new("write", "tokio::fs"), |b| {
+        b.to_async(&runtime).iter(|| write_tokio(&written, &data));
+    });
+    group.bench_function(BenchmarkId::new("write", "io_uring"), |b| {
+        b.to_async(&runtime)
+            .iter(|| write_uring(ring, &written, &data));
+    });
+    group.finish();
+}
+
+criterion_group!(benches, pack_files);
+criterion_main!(benches);
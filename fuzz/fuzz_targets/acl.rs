@@ -0,0 +1,22 @@
+// parses an ACL file and checks an access with it
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rustic_server::acl::{AccessType, Acl, AclChecker};
+
+fuzz_target!(|input: (bool, bool, &str, &str, &str, &str, u8)| {
+    let (append_only, private_repo, content, user, path, tpe, access) = input;
+    let Ok(acl) = Acl::from_toml(append_only, private_repo, content) else {
+        return;
+    };
+    let access = match access % 5 {
+        0 => AccessType::Nothing,
+        1 => AccessType::Read,
+        2 => AccessType::Append,
+        3 => AccessType::Create,
+        _ => AccessType::Modify,
+    };
+    let _ = acl.allowed(user, path, tpe, access);
+    let _ = acl.is_admin(user);
+    let _ = acl.users().count();
+});
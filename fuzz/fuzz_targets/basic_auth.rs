@@ -0,0 +1,33 @@
+// parses an authorization header like AuthFromRequest and verifies the
+// credentials against users with each supported hash format; the password of
+// all users is "secret"
+#![no_main]
+
+use std::sync::OnceLock;
+
+use axum::http::header::AUTHORIZATION;
+use axum::http::{HeaderMap, HeaderValue};
+use libfuzzer_sys::fuzz_target;
+use rustic_server::auth::{Auth, AuthChecker};
+use rustic_server::web::basic_auth;
+
+const HTPASSWD: &str = "\
+bcrypt:$2b$04$PrUTT3eRdwAue7scDZGanOWfLF5./lilc.aR7XPD5KyhQb8Yb00ei
+apr1:$apr1$Fq7yEPHx$VM.CORQ44QdexreEa90CS0
+sha:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=
+";
+
+fuzz_target!(|data: &[u8]| {
+    static AUTH: OnceLock<Auth> = OnceLock::new();
+    let auth = AUTH.get_or_init(|| Auth::from_htpasswd(false, HTPASSWD));
+
+    let Ok(value) = HeaderValue::from_bytes(data) else {
+        return;
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(AUTHORIZATION, value);
+    let (user, passwd) = basic_auth(&headers).unwrap_or_default();
+    if auth.verify(&user, &passwd) {
+        assert_eq!(passwd, "secret", "{data:?}");
+    }
+});
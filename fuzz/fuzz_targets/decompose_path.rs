@@ -0,0 +1,24 @@
+// decompose_path splits the request path, so it must neither panic nor
+// return a repository path escaping the storage
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rustic_server::web::{decompose_path, TYPES};
+
+fuzz_target!(|path: &str| {
+    let Ok(parts) = decompose_path(path) else {
+        return;
+    };
+    if !parts.repo.is_empty() {
+        for part in parts.repo.split('/') {
+            assert!(!part.is_empty() && part != "." && part != "..", "{path:?}");
+            assert!(!TYPES.contains(&part), "{path:?}");
+        }
+    }
+    if let Some(tpe) = &parts.tpe {
+        assert!(tpe == "config" || TYPES.contains(&tpe.as_str()), "{path:?}");
+    }
+    if let Some(name) = &parts.name {
+        assert!(!name.contains('/'), "{path:?}");
+    }
+});
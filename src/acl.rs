@@ -1,21 +1,25 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
 
 use anyhow::Result;
 
-// Access Types
-#[derive(Debug, Clone, PartialEq, PartialOrd, serde::Deserialize)]
+// Access Types; Create allows appending and creating the repository
+#[derive(Debug, Clone, PartialEq, PartialOrd, Deserialize, Serialize)]
 pub enum AccessType {
     Nothing,
     Read,
     Append,
+    Create,
     Modify,
 }
 
 pub trait AclChecker: Send + Sync + 'static {
     fn allowed(&self, user: &str, path: &str, tpe: &str, access: AccessType) -> bool;
+    // is_admin yields whether user may use the admin endpoints and delete
+    // whole repositories, which no per-repo access type grants
+    fn is_admin(&self, user: &str) -> bool;
 }
 
 // ACL for a repo
@@ -27,6 +31,8 @@ pub struct Acl {
     repos: HashMap<String, RepoAcl>,
     append_only: bool,
     private_repo: bool,
+    #[serde(default)]
+    admins: Vec<String>,
 }
 
 impl Default for Acl {
@@ -35,24 +41,35 @@ impl Default for Acl {
             repos: HashMap::new(),
             append_only: true,
             private_repo: true,
+            admins: Vec::new(),
         }
     }
 }
 
+// AclFile is the content of an ACL file: the key "admins" lists the users
+// with the admin capability, all other keys are repos
+#[derive(Deserialize)]
+struct AclFile {
+    #[serde(default)]
+    admins: Vec<String>,
+    #[serde(flatten)]
+    repos: HashMap<String, RepoAcl>,
+}
+
 // read_toml is a helper func that reads the given file in toml
-// into a Hashmap mapping each user to the whole passwd line
-fn read_toml(file_path: &PathBuf) -> Result<HashMap<String, RepoAcl>> {
-    let s = fs::read_to_string(file_path)?;
-    // make the contents static in memory
-    let s = Box::leak(s.into_boxed_str());
+// into a Hashmap mapping each repo to its ACL
+fn read_toml(file_path: &PathBuf) -> Result<AclFile> {
+    parse_toml(&fs::read_to_string(file_path)?)
+}
 
-    let mut repos: HashMap<String, RepoAcl> = toml::from_str(s)?;
+fn parse_toml(s: &str) -> Result<AclFile> {
+    let mut file: AclFile = toml::from_str(s)?;
     // copy key "default" into ""
-    if let Some(default) = repos.get("default") {
+    if let Some(default) = file.repos.get("default") {
         let default = default.clone();
-        repos.insert("".to_owned(), default);
+        file.repos.insert("".to_owned(), default);
     }
-    Ok(repos)
+    Ok(file)
 }
 
 impl Acl {
@@ -61,19 +78,77 @@ impl Acl {
         private_repo: bool,
         file_path: Option<PathBuf>,
     ) -> Result<Self> {
-        let repos = match file_path {
+        let file = match file_path {
             Some(file_path) => read_toml(&file_path)?,
-            None => HashMap::new(),
+            None => AclFile {
+                admins: Vec::new(),
+                repos: HashMap::new(),
+            },
         };
         Ok(Self {
             append_only,
             private_repo,
-            repos,
+            repos: file.repos,
+            admins: file.admins,
+        })
+    }
+
+    // from_toml uses the ACLs of content in the format of an ACL file
+    pub fn from_toml(append_only: bool, private_repo: bool, content: &str) -> Result<Self> {
+        let file = parse_toml(content)?;
+        Ok(Self {
+            append_only,
+            private_repo,
+            repos: file.repos,
+            admins: file.admins,
         })
     }
+
+    // with_admins adds users which may use the admin endpoints to those
+    // given in the ACL file
+    pub fn with_admins(mut self, admins: Vec<String>) -> Self {
+        for admin in admins {
+            if !self.admins.contains(&admin) {
+                self.admins.push(admin);
+            }
+        }
+        self
+    }
+
+    // diff describes which repo ACLs and standard flags differ in other
+    pub fn diff(&self, other: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        if self.append_only != other.append_only {
+            changes.push(format!("append_only set to {}", other.append_only));
+        }
+        if self.private_repo != other.private_repo {
+            changes.push(format!("private_repo set to {}", other.private_repo));
+        }
+        if self.admins != other.admins {
+            changes.push(format!("admins set to {:?}", other.admins));
+        }
+        changes.extend(
+            crate::helpers::diff_maps(&self.repos, &other.repos)
+                .into_iter()
+                .map(|(repo, change)| format!("ACL for repo {repo:?} {change}")),
+        );
+        changes
+    }
+
+    // users yields all users which are mentioned in the ACLs
+    pub fn users(&self) -> impl Iterator<Item = &str> {
+        self.repos
+            .values()
+            .flat_map(|repo_acl| repo_acl.keys().map(String::as_str))
+            .chain(self.admins.iter().map(String::as_str))
+    }
 }
 
 impl AclChecker for Acl {
+    fn is_admin(&self, user: &str) -> bool {
+        !user.is_empty() && self.admins.iter().any(|admin| admin == user)
+    }
+
     // allowed yields whether these access to {path,tpe, access} is allowed by user
     fn allowed(&self, user: &str, path: &str, tpe: &str, access: AccessType) -> bool {
         // Access to locks is always treated as Read
@@ -109,6 +184,7 @@ mod tests {
             repos: HashMap::new(),
             append_only: true,
             private_repo: true,
+            admins: Vec::new(),
         };
         assert!(!acl.allowed("bob", "sam", "keys", Read));
         assert!(!acl.allowed("bob", "sam", "data", Read));
@@ -118,6 +194,7 @@ mod tests {
         assert!(acl.allowed("bob", "bob", "locks", Modify));
         assert!(acl.allowed("bob", "bob", "keys", Append));
         assert!(acl.allowed("bob", "bob", "data", Append));
+        assert!(acl.allowed("bob", "bob", "", Create));
         assert!(acl.allowed("", "", "data", Append));
         assert!(!acl.allowed("bob", "", "data", Read));
 
@@ -154,6 +231,8 @@ mod tests {
         assert!(acl.allowed("bob", "all", "keys", Modify));
         assert!(!acl.allowed("sam", "all", "keys", Modify));
         assert!(acl.allowed("sam", "all", "keys", Append));
+        assert!(!acl.allowed("sam", "all", "", Create));
+        assert!(acl.allowed("bob", "all", "", Create));
         assert!(acl.allowed("sam", "all", "locks", Modify));
         assert!(!acl.allowed("paul", "all", "data", Append));
         assert!(acl.allowed("paul", "all", "data", Read));
@@ -177,4 +256,32 @@ mod tests {
         assert!(acl.allowed("paul", "paul", "data", Append));
         assert!(!acl.allowed("sam", "paul", "data", Read));
     }
+
+    #[test]
+    fn admins() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("acl.toml");
+        fs::write(&file, "admins = [\"root\"]\n[bob]\nbob = \"Modify\"\n").unwrap();
+        let acl = Acl::from_file(true, true, Some(file))
+            .unwrap()
+            .with_admins(vec!["alice".to_string(), "root".to_string()]);
+
+        assert!(acl.is_admin("root"));
+        assert!(acl.is_admin("alice"));
+        assert!(!acl.is_admin("bob"));
+        assert!(!acl.is_admin(""));
+        // the admin capability doesn't grant access to repos
+        assert!(!acl.allowed("root", "bob", "data", Read));
+        assert!(acl.allowed("bob", "bob", "data", Modify));
+        assert_eq!(acl.users().filter(|user| *user == "root").count(), 1);
+    }
+
+    #[test]
+    fn from_toml() {
+        let acl = Acl::from_toml(true, true, "[default]\nbob = \"Read\"\n").unwrap();
+        assert!(acl.allowed("bob", "", "data", Read));
+        assert!(!acl.allowed("bob", "", "data", Append));
+        assert!(Acl::from_toml(true, true, "[bob]\nbob = \"Everything\"\n").is_err());
+        assert!(Acl::from_toml(true, true, "bob = ").is_err());
+    }
 }
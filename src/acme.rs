@@ -0,0 +1,381 @@
+// mod acme
+//
+// obtains and renews TLS certificates from an ACME CA like Let's Encrypt
+// using the HTTP-01 challenge, see RFC 8555.
+//
+// The challenges are answered by the REST server itself, so one of the listen
+// addresses must be reachable on port 80 for the configured domains.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail, Context, Result};
+use axum_server::tls_rustls::RustlsConfig;
+use base64::prelude::*;
+use ring::digest::{digest, SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::config::{AcmeConfig, TlsConfig};
+use crate::helpers::write_private;
+use crate::tls;
+
+// certificates are renewed if they expire within RENEW_BEFORE
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+// interval to check whether the certificate needs to be renewed
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+// interval to retry after a failed attempt
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Challenges maps the tokens of pending HTTP-01 challenges to their key authorization
+#[derive(Clone, Default)]
+pub struct Challenges(Arc<RwLock<HashMap<String, String>>>);
+
+impl Challenges {
+    pub fn get(&self, token: &str) -> Option<String> {
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(token)
+            .cloned()
+    }
+
+    fn insert(&self, token: &str, key_authorization: String) {
+        _ = self
+            .0
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(token.to_string(), key_authorization);
+    }
+
+    fn remove(&self, token: &str) {
+        _ = self
+            .0
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(token);
+    }
+}
+
+fn cert_path(cache_dir: &Path) -> PathBuf {
+    cache_dir.join("cert.pem")
+}
+
+fn key_path(cache_dir: &Path) -> PathBuf {
+    cache_dir.join("key.pem")
+}
+
+// tls_config returns a TLS config using the certificate cached in cache_dir
+// and the TLS policy of tls. If there is no certificate yet, a self-signed
+// one is used until the CA issued one.
+pub fn tls_config(config: &AcmeConfig, tls: &TlsConfig, cache_dir: &Path) -> Result<RustlsConfig> {
+    let (cert, key) = (cert_path(cache_dir), key_path(cache_dir));
+    if cert.exists() && key.exists() {
+        return Ok(RustlsConfig::from_config(tls::load(tls, &cert, &key)?));
+    }
+    let self_signed = tls::generate_self_signed(&config.domains, 1)?;
+    Ok(RustlsConfig::from_config(tls::server_config(
+        tls,
+        self_signed.cert.as_bytes(),
+        self_signed.key.as_bytes(),
+    )?))
+}
+
+// needs_renewal returns whether there is no cached certificate or it expires soon
+fn needs_renewal(cache_dir: &Path) -> bool {
+    match tls::cert_not_after(&cert_path(cache_dir)) {
+        Ok(not_after) => not_after < SystemTime::now() + RENEW_BEFORE,
+        Err(_) => true,
+    }
+}
+
+// run obtains a certificate if needed and renews it before it expires.
+// New certificates are stored in cache_dir and loaded into tls_config.
+pub async fn run(
+    config: AcmeConfig,
+    tls: TlsConfig,
+    cache_dir: PathBuf,
+    tls_config: RustlsConfig,
+    challenges: Challenges,
+) {
+    loop {
+        let mut interval = CHECK_INTERVAL;
+        if needs_renewal(&cache_dir) {
+            tracing::info!(domains = ?config.domains, "requesting certificate from ACME CA");
+            let res = async {
+                let (cert, key) = obtain(&config, &cache_dir, &challenges).await?;
+                fs::create_dir_all(&cache_dir)?;
+                write_private(&key_path(&cache_dir), &key)?;
+                write_private(&cert_path(&cache_dir), &cert)?;
+                tls_config.reload_from_config(tls::load(
+                    &tls,
+                    &cert_path(&cache_dir),
+                    &key_path(&cache_dir),
+                )?);
+                anyhow::Ok(())
+            }
+            .await;
+            match res {
+                Ok(()) => tracing::info!("obtained new certificate from ACME CA"),
+                Err(err) => {
+                    tracing::error!("cannot obtain certificate from ACME CA: {err:#}");
+                    interval = RETRY_INTERVAL;
+                }
+            }
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+// account_key loads the ACME account key from cache_dir or creates a new one
+fn account_key(cache_dir: &Path) -> Result<EcdsaKeyPair> {
+    let file = cache_dir.join("account.pk8");
+    let rng = SystemRandom::new();
+    let pkcs8 = match fs::read(&file) {
+        Ok(pkcs8) => pkcs8,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                .map_err(|_| anyhow!("cannot generate account key"))?;
+            fs::create_dir_all(cache_dir)?;
+            fs::write(&file, pkcs8.as_ref())?;
+            pkcs8.as_ref().to_vec()
+        }
+        Err(err) => return Err(err).context("cannot read ACME account key"),
+    };
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+        .map_err(|_| anyhow!("invalid ACME account key {}", file.display()))
+}
+
+fn b64(data: impl AsRef<[u8]>) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(data)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    tpe: String,
+    url: String,
+    token: String,
+}
+
+// Client is a minimal ACME client signing its requests with the account key
+struct Client {
+    http: reqwest::Client,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    directory: Directory,
+    nonce: Option<String>,
+    kid: Option<String>,
+}
+
+impl Client {
+    async fn new(directory: &str, key: EcdsaKeyPair) -> Result<Self> {
+        let http = reqwest::Client::new();
+        let directory = http
+            .get(directory)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .context("invalid ACME directory")?;
+        Ok(Self {
+            http,
+            key,
+            rng: SystemRandom::new(),
+            directory,
+            nonce: None,
+            kid: None,
+        })
+    }
+
+    fn jwk(&self) -> Value {
+        // uncompressed P-256 public key: 0x04 || x || y
+        let public = self.key.public_key().as_ref();
+        json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": b64(&public[1..33]),
+            "y": b64(&public[33..65]),
+        })
+    }
+
+    // thumbprint is the JWK thumbprint of the account key, see RFC 7638
+    fn thumbprint(&self) -> String {
+        // serde_json sorts the keys, which is the canonical form needed here
+        b64(digest(&SHA256, self.jwk().to_string().as_bytes()))
+    }
+
+    async fn nonce(&mut self) -> Result<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let res = self.http.head(&self.directory.new_nonce).send().await?;
+        replay_nonce(&res).ok_or_else(|| anyhow!("ACME CA did not return a nonce"))
+    }
+
+    // post sends a signed request; payload None is a POST-as-GET request
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<reqwest::Response> {
+        for _ in 0..3 {
+            let nonce = self.nonce().await?;
+            let mut protected = json!({"alg": "ES256", "nonce": nonce, "url": url});
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.jwk(),
+            }
+            let protected = b64(protected.to_string());
+            let payload = payload.map(|p| b64(p.to_string())).unwrap_or_default();
+            let signature = self
+                .key
+                .sign(&self.rng, format!("{protected}.{payload}").as_bytes())
+                .map_err(|_| anyhow!("cannot sign ACME request"))?;
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": b64(signature),
+            });
+
+            let res = self
+                .http
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/jose+json")
+                .body(body.to_string())
+                .send()
+                .await?;
+            self.nonce = replay_nonce(&res);
+            if res.status().is_success() {
+                return Ok(res);
+            }
+            let problem: Value = res.json().await.unwrap_or_default();
+            if problem["type"] != "urn:ietf:params:acme:error:badNonce" {
+                bail!("ACME request to {url} failed: {problem}");
+            }
+        }
+        bail!("ACME request to {url} failed: too many bad nonces")
+    }
+
+    // poll requests url until its status is "valid"
+    async fn poll<T: serde::de::DeserializeOwned>(
+        &mut self,
+        url: &str,
+        status: impl Fn(&T) -> &str,
+    ) -> Result<T> {
+        for _ in 0..30 {
+            let res: T = self.post(url, None).await?.json().await?;
+            match status(&res) {
+                "valid" => return Ok(res),
+                "invalid" => bail!("ACME CA reported {url} as invalid"),
+                _ => tokio::time::sleep(Duration::from_secs(2)).await,
+            }
+        }
+        bail!("timeout waiting for {url}")
+    }
+}
+
+fn replay_nonce(res: &reqwest::Response) -> Option<String> {
+    res.headers()
+        .get("Replay-Nonce")?
+        .to_str()
+        .ok()
+        .map(str::to_string)
+}
+
+fn location(res: &reqwest::Response) -> Result<String> {
+    res.headers()
+        .get(reqwest::header::LOCATION)
+        .and_then(|l| l.to_str().ok())
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("ACME CA did not return a location"))
+}
+
+// obtain runs through the ACME protocol and returns certificate chain and key as PEM
+async fn obtain(
+    config: &AcmeConfig,
+    cache_dir: &Path,
+    challenges: &Challenges,
+) -> Result<(String, String)> {
+    let mut client = Client::new(&config.directory, account_key(cache_dir)?).await?;
+
+    let new_account = client.directory.new_account.clone();
+    let account = json!({"termsOfServiceAgreed": true, "contact": config.contact});
+    let res = client.post(&new_account, Some(&account)).await?;
+    client.kid = Some(location(&res)?);
+
+    let new_order = client.directory.new_order.clone();
+    let identifiers: Vec<_> = config
+        .domains
+        .iter()
+        .map(|domain| json!({"type": "dns", "value": domain}))
+        .collect();
+    let res = client
+        .post(&new_order, Some(&json!({"identifiers": identifiers})))
+        .await?;
+    let order_url = location(&res)?;
+    let order: Order = res.json().await?;
+
+    for authz_url in &order.authorizations {
+        let authz: Authorization = client.post(authz_url, None).await?.json().await?;
+        if authz.status == "valid" {
+            continue;
+        }
+        let challenge = authz
+            .challenges
+            .iter()
+            .find(|c| c.tpe == "http-01")
+            .ok_or_else(|| anyhow!("ACME CA offers no HTTP-01 challenge"))?;
+        let key_authorization = format!("{}.{}", challenge.token, client.thumbprint());
+        challenges.insert(&challenge.token, key_authorization);
+        let res = async {
+            client.post(&challenge.url, Some(&json!({}))).await?;
+            client
+                .poll(authz_url, |a: &Authorization| a.status.as_str())
+                .await
+        }
+        .await;
+        challenges.remove(&challenge.token);
+        _ = res?;
+    }
+
+    let key_pair = rcgen::KeyPair::generate()?;
+    let csr =
+        rcgen::CertificateParams::new(config.domains.clone())?.serialize_request(&key_pair)?;
+    client
+        .post(&order.finalize, Some(&json!({"csr": b64(csr.der())})))
+        .await?;
+    let order: Order = client
+        .poll(&order_url, |o: &Order| o.status.as_str())
+        .await?;
+    let cert_url = order
+        .certificate
+        .ok_or_else(|| anyhow!("ACME CA did not return a certificate"))?;
+    let cert = client.post(&cert_url, None).await?.text().await?;
+    Ok((cert, key_pair.serialize_pem()))
+}
@@ -0,0 +1,205 @@
+// mod activity
+//
+// records when repositories were last read and written, so repositories of
+// hosts whose backups silently stopped running can be found. The timestamps
+// are stored in a marker file of each repository, but at most once per
+// PERSIST_INTERVAL and kind, so busy repositories don't get a write per request.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::stats::format_time;
+use crate::storage::Storage;
+
+// ACTIVITY_MARKER holds the RepoActivity of a repository as JSON
+pub const ACTIVITY_MARKER: &str = ".activity";
+
+// PERSIST_INTERVAL is how often a kind of activity is stored per repository
+const PERSIST_INTERVAL: Duration = Duration::from_secs(300);
+
+// Kind distinguishes reads and writes of a repository
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Kind {
+    Read,
+    Write,
+}
+
+// RepoActivity holds the RFC 3339 timestamps of the last read and write
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RepoActivity {
+    pub last_read: Option<String>,
+    pub last_write: Option<String>,
+}
+
+impl RepoActivity {
+    // read returns the stored activity of the repository at path
+    pub fn read(storage: &dyn Storage, path: &Path) -> Self {
+        storage
+            .marker(path, ACTIVITY_MARKER)
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    // last returns the time of the last activity of the given kinds
+    fn last(&self, writes_only: bool) -> Option<OffsetDateTime> {
+        let parse = |time: &Option<String>| {
+            time.as_deref()
+                .and_then(|time| OffsetDateTime::parse(time, &Rfc3339).ok())
+        };
+        match writes_only {
+            true => parse(&self.last_write),
+            false => parse(&self.last_write).max(parse(&self.last_read)),
+        }
+    }
+}
+
+// Activity remembers when the activity of repositories was last stored
+#[derive(Clone, Default)]
+pub struct Activity(Arc<Mutex<HashMap<(String, Kind), Instant>>>);
+
+impl Activity {
+    // record notes a read or write of repo; errors are only logged, as they
+    // must not fail the request
+    pub fn record(&self, storage: &dyn Storage, repo: &str, kind: Kind) {
+        {
+            let mut persisted = self.lock();
+            let key = (repo.to_string(), kind);
+            if persisted
+                .get(&key)
+                .is_some_and(|last| last.elapsed() < PERSIST_INTERVAL)
+            {
+                return;
+            }
+            _ = persisted.insert(key, Instant::now());
+        }
+        let path = Path::new(repo);
+        let mut activity = RepoActivity::read(storage, path);
+        let now = Some(format_time(SystemTime::now()));
+        match kind {
+            Kind::Read => activity.last_read = now,
+            Kind::Write => activity.last_write = now,
+        }
+        let stored = serde_json::to_string(&activity)
+            .map_err(std::io::Error::from)
+            .and_then(|json| storage.set_marker(path, ACTIVITY_MARKER, Some(&json)));
+        if let Err(err) = stored {
+            tracing::error!(repo, "cannot store activity: {err}");
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<(String, Kind), Instant>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// IdleRepo is a repository without activity for at least idle_days
+#[derive(Debug, Serialize)]
+pub struct IdleRepo {
+    pub repo: String,
+    pub last_read: Option<String>,
+    pub last_write: Option<String>,
+    // whole days since the last activity, None if none was ever recorded
+    pub idle_days: Option<u64>,
+}
+
+// idle_repos returns the repositories which weren't read or written within
+// max_idle; with writes_only, reads don't count, so repositories which are
+// only checked or restored from are reported as well
+pub fn idle_repos(
+    storage: &dyn Storage,
+    repos: &[String],
+    max_idle: Duration,
+    writes_only: bool,
+) -> Vec<IdleRepo> {
+    let now = OffsetDateTime::now_utc();
+    repos
+        .iter()
+        .filter_map(|repo| {
+            let activity = RepoActivity::read(storage, Path::new(repo));
+            let idle = activity.last(writes_only).map(|last| now - last);
+            if idle.is_some_and(|idle| idle < max_idle) {
+                return None;
+            }
+            Some(IdleRepo {
+                repo: repo.clone(),
+                idle_days: idle.map(|idle| idle.whole_days().max(0).unsigned_abs()),
+                last_read: activity.last_read,
+                last_write: activity.last_write,
+            })
+        })
+        .collect()
+}
+
+// format_table renders idle repositories as a table with one line per repository
+pub fn format_table(idle: &[IdleRepo]) -> String {
+    let width = idle
+        .iter()
+        .map(|i| i.repo.len())
+        .chain([4])
+        .max()
+        .unwrap_or_default();
+    let mut out = format!(
+        "{:width$}  {:>9}  {:20}  {}\n",
+        "REPO", "IDLE DAYS", "LAST WRITE", "LAST READ"
+    );
+    for i in idle {
+        let days = i.idle_days.map_or("-".to_string(), |days| days.to_string());
+        _ = writeln!(
+            out,
+            "{:width$}  {:>9}  {:20}  {}",
+            i.repo,
+            days,
+            i.last_write.as_deref().unwrap_or("-"),
+            i.last_read.as_deref().unwrap_or("-")
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LocalStorage;
+    use std::fs;
+
+    #[test]
+    fn activity() {
+        let dir = tempfile::tempdir().unwrap();
+        for repo in ["active", "read", "never"] {
+            fs::create_dir(dir.path().join(repo)).unwrap();
+        }
+        let storage = LocalStorage::try_new(dir.path()).unwrap();
+        let activity = Activity::default();
+        activity.record(&storage, "active", Kind::Write);
+        activity.record(&storage, "read", Kind::Read);
+        let stored = RepoActivity::read(&storage, Path::new("active"));
+        assert!(stored.last_write.is_some() && stored.last_read.is_none());
+
+        // a second record within the interval doesn't write the marker
+        fs::remove_file(dir.path().join("active").join(ACTIVITY_MARKER)).unwrap();
+        activity.record(&storage, "active", Kind::Write);
+        assert!(!dir.path().join("active").join(ACTIVITY_MARKER).exists());
+        Activity::default().record(&storage, "active", Kind::Write);
+
+        let repos = ["active", "never", "read"].map(String::from);
+        let hour = Duration::from_secs(3600);
+        let idle: Vec<_> = idle_repos(&storage, &repos, hour, false)
+            .into_iter()
+            .map(|i| i.repo)
+            .collect();
+        assert_eq!(idle, ["never"]);
+        let idle: Vec<_> = idle_repos(&storage, &repos, hour, true)
+            .into_iter()
+            .map(|i| i.repo)
+            .collect();
+        assert_eq!(idle, ["never", "read"]);
+        assert_eq!(idle_repos(&storage, &repos, Duration::ZERO, false).len(), 3);
+    }
+}
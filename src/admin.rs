@@ -0,0 +1,546 @@
+// mod admin
+//
+// administrative endpoints below /admin, which are only accessible by the
+// users given in acl.admins
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use axum::body::{to_bytes, Body};
+use axum::extract::{self, FromRequestParts};
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use tokio_util::io::{ReaderStream, StreamReader, SyncIoBridge};
+
+use crate::activity::{idle_repos, IdleRepo};
+use crate::archive::{export_repo, import_repo};
+use crate::immutable::{Immutability, IMMUTABLE_MARKER};
+use crate::locks::remove_stale_locks;
+use crate::rename::rename_repo;
+use crate::stats::{repo_stats, RepoStats};
+use crate::storage::{APPEND_ONLY_MARKER, FROZEN_MARKER};
+use crate::tenant::{self, Tenant};
+use crate::versions;
+use crate::web::{
+    blocking, decompose_path, AuthFromRequest, Error, State, CONFIG_NAME, CONFIG_TYPE,
+};
+
+// MAX_LIMIT is the maximum number of entries per page
+const MAX_LIMIT: usize = 1000;
+
+// EXPORT_BUFFER is the size of the buffer between the tar writer and the response
+const EXPORT_BUFFER: usize = 64 * 1024;
+
+// MAX_TEXT is the maximum size of request bodies containing a reason or path
+const MAX_TEXT: usize = 4096;
+
+pub fn router() -> Router<State> {
+    Router::new()
+        .route("/admin/repos", get(list_repos))
+        .route("/admin/idle", get(list_idle))
+        .route(
+            "/admin/repos/*path",
+            post(post_repo).get(get_repo).delete(delete_repo),
+        )
+        .route("/admin/tenants", post(create_tenant))
+        .route("/admin/tenants/:user", delete(delete_tenant))
+}
+
+// AdminFromRequest extracts an authenticated admin; other users get 403
+pub struct AdminFromRequest {
+    pub user: String,
+}
+
+#[async_trait::async_trait]
+impl FromRequestParts<State> for AdminFromRequest {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &State) -> Result<Self, Response> {
+        let AuthFromRequest { user } = AuthFromRequest::from_request_parts(parts, state).await?;
+        match state.is_admin(&user) {
+            true => Ok(Self { user }),
+            false => {
+                tracing::debug!(user, "admin access denied");
+                Err(Error::new(StatusCode::FORBIDDEN, "admin access required").into_response())
+            }
+        }
+    }
+}
+
+// Page selects a part of a list by offset and limit
+#[derive(Deserialize)]
+struct Page {
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+fn default_limit() -> usize {
+    100
+}
+
+#[derive(Serialize)]
+struct RepoList {
+    // number of all repositories
+    total: usize,
+    offset: usize,
+    repos: Vec<RepoStats>,
+}
+
+// list_repos returns the statistics of all repositories in the storage
+async fn list_repos(
+    extract::State(state): extract::State<State>,
+    admin: AdminFromRequest,
+    extract::Query(page): extract::Query<Page>,
+) -> Result<Json<RepoList>, Error> {
+    tracing::debug!(admin = admin.user, page.offset, page.limit, "list_repos");
+
+    blocking(move || {
+        let storage = state.storage();
+        let repos = storage.repos();
+        let stats = repos
+            .iter()
+            .skip(page.offset)
+            .take(page.limit.min(MAX_LIMIT))
+            .map(|repo| repo_stats(storage, repo))
+            .collect();
+        Ok(Json(RepoList {
+            total: repos.len(),
+            offset: page.offset,
+            repos: stats,
+        }))
+    })
+    .await
+}
+
+#[derive(Deserialize)]
+struct Idle {
+    #[serde(default = "default_idle_days")]
+    days: u64,
+    // only count uploads and deletions as activity
+    #[serde(default)]
+    writes_only: bool,
+}
+
+fn default_idle_days() -> u64 {
+    7
+}
+
+// list_idle returns the repositories without activity for the given number of days
+async fn list_idle(
+    extract::State(state): extract::State<State>,
+    admin: AdminFromRequest,
+    extract::Query(idle): extract::Query<Idle>,
+) -> Result<Json<Vec<IdleRepo>>, Error> {
+    tracing::debug!(admin = admin.user, idle.days, idle.writes_only, "list_idle");
+
+    let max_idle = Duration::from_secs(idle.days.saturating_mul(24 * 60 * 60));
+    blocking(move || {
+        let storage = state.storage();
+        Ok(Json(idle_repos(
+            storage,
+            &storage.repos(),
+            max_idle,
+            idle.writes_only,
+        )))
+    })
+    .await
+}
+
+// repo_action splits a path like "<repo>/<action>" and checks that the
+// repository exists
+fn repo_action<'a>(state: &State, path: &'a str) -> Result<(String, &'a str), Error> {
+    let not_found = || Error::new(StatusCode::NOT_FOUND, "not found");
+    let (repo, action) = path.rsplit_once('/').ok_or_else(not_found)?;
+    let parts = decompose_path(repo)?;
+    if parts.tpe.is_some() || parts.repo.is_empty() {
+        return Err(not_found());
+    }
+    let config_file = state
+        .storage()
+        .filename(Path::new(&parts.repo), CONFIG_TYPE, CONFIG_NAME);
+    let exists = match action {
+        // the config file is missing after a client deleted it to re-init
+        "config-versions" => config_file.parent().is_some_and(Path::is_dir),
+        _ => config_file.exists(),
+    };
+    if !exists {
+        return Err(Error::new(StatusCode::NOT_FOUND, "repository not found"));
+    }
+    Ok((parts.repo, action))
+}
+
+async fn post_repo(
+    extract::State(state): extract::State<State>,
+    admin: AdminFromRequest,
+    extract::Path(path): extract::Path<String>,
+    body: Body,
+) -> Result<Response, Error> {
+    // the repository is created by the import
+    if let Some(repo) = path.strip_suffix("/import") {
+        return import(&state, &admin, repo, body).await;
+    }
+    // the other actions have a short or no request body
+    let text = text(body).await?;
+    if path.ends_with("/rename") {
+        return rename(&state, &admin, path, text.trim()).await;
+    }
+    blocking(move || match repo_action(&state, &path)? {
+        (repo, "freeze") => freeze(&state, &admin, &repo, text.trim()),
+        (repo, "append-only") => set_append_only(&state, &admin, &repo, Some(text.trim())),
+        (repo, "verify") => start_verify(&state, &admin, &repo),
+        (repo, "config-versions") => restore_config(&state, &admin, &repo, text.trim()),
+        (repo, "immutable") => make_immutable(&state, &admin, &repo, &text),
+        _ => Err(Error::new(StatusCode::NOT_FOUND, "not found")),
+    })
+    .await
+}
+
+async fn get_repo(
+    extract::State(state): extract::State<State>,
+    admin: AdminFromRequest,
+    extract::Path(path): extract::Path<String>,
+) -> Result<Response, Error> {
+    blocking(move || match repo_action(&state, &path)? {
+        (repo, "verify") => get_verify(&state, &admin, &repo),
+        (repo, "export") => export(&state, &admin, &repo),
+        (repo, "config-versions") => list_configs(&state, &admin, &repo),
+        (repo, "immutable") => get_immutable(&state, &admin, &repo),
+        _ => Err(Error::new(StatusCode::NOT_FOUND, "not found")),
+    })
+    .await
+}
+
+#[derive(Deserialize)]
+struct LockAge {
+    // defaults to locks.max_age_hours
+    max_age_hours: Option<u64>,
+}
+
+async fn delete_repo(
+    extract::State(state): extract::State<State>,
+    admin: AdminFromRequest,
+    extract::Path(path): extract::Path<String>,
+    extract::Query(age): extract::Query<LockAge>,
+) -> Result<Response, Error> {
+    blocking(move || match repo_action(&state, &path)? {
+        (repo, "freeze") => unfreeze(&state, &admin, &repo),
+        (repo, "append-only") => set_append_only(&state, &admin, &repo, None),
+        (repo, "locks") => remove_locks(&state, &admin, &repo, age.max_age_hours),
+        _ => Err(Error::new(StatusCode::NOT_FOUND, "not found")),
+    })
+    .await
+}
+
+// text reads a short request body
+async fn text(body: Body) -> Result<String, Error> {
+    let bytes = to_bytes(body, MAX_TEXT)
+        .await
+        .map_err(|_| Error::new(StatusCode::PAYLOAD_TOO_LARGE, "request body too large"))?;
+    String::from_utf8(bytes.to_vec())
+        .map_err(|_| Error::new(StatusCode::BAD_REQUEST, "request body is no UTF-8 text"))
+}
+
+// freeze rejects all further writes to a repository; the request body is the
+// reason given to clients
+fn freeze(
+    state: &State,
+    admin: &AdminFromRequest,
+    repo: &str,
+    reason: &str,
+) -> Result<Response, Error> {
+    tracing::info!(admin = admin.user, repo, reason, "freeze repository");
+
+    state
+        .storage()
+        .set_marker(Path::new(repo), FROZEN_MARKER, Some(reason))?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+// unfreeze allows writes to a frozen repository again
+fn unfreeze(state: &State, admin: &AdminFromRequest, repo: &str) -> Result<Response, Error> {
+    tracing::info!(admin = admin.user, repo, "unfreeze repository");
+
+    state
+        .storage()
+        .set_marker(Path::new(repo), FROZEN_MARKER, None)?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+// set_append_only denies deleting files of a repository to all users, e.g.
+// while a client is suspected to be compromised; None allows it again
+fn set_append_only(
+    state: &State,
+    admin: &AdminFromRequest,
+    repo: &str,
+    reason: Option<&str>,
+) -> Result<Response, Error> {
+    tracing::info!(admin = admin.user, repo, reason, "set append-only");
+
+    state
+        .storage()
+        .set_marker(Path::new(repo), APPEND_ONLY_MARKER, reason)?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+// remove_locks removes the lock files of a repository which weren't modified
+// within max_age_hours; 0 removes all locks
+fn remove_locks(
+    state: &State,
+    admin: &AdminFromRequest,
+    repo: &str,
+    max_age_hours: Option<u64>,
+) -> Result<Response, Error> {
+    let max_age_hours = max_age_hours.unwrap_or(state.config().locks.max_age_hours);
+    tracing::info!(
+        admin = admin.user,
+        repo,
+        max_age_hours,
+        "remove stale locks"
+    );
+
+    let max_age = Duration::from_secs(max_age_hours.saturating_mul(60 * 60));
+    let removed = remove_stale_locks(state.storage(), state.usage(), repo, max_age)?;
+    Ok(Json(removed).into_response())
+}
+
+// make_immutable protects the files of a repository for a number of days
+// after they were written; the protection can be extended, but not reduced
+fn make_immutable(
+    state: &State,
+    admin: &AdminFromRequest,
+    repo: &str,
+    body: &str,
+) -> Result<Response, Error> {
+    let immutability: Immutability = serde_json::from_str(body)
+        .map_err(|err| Error::new(StatusCode::BAD_REQUEST, format!("invalid request: {err}")))?;
+    tracing::info!(
+        admin = admin.user,
+        repo,
+        ?immutability,
+        "make repository immutable"
+    );
+
+    immutability
+        .validate()
+        .map_err(|err| Error::new(StatusCode::BAD_REQUEST, err))?;
+    let path = Path::new(repo);
+    if let Some(old) = Immutability::read(state.storage(), path) {
+        if old.weakens(&immutability) {
+            return Err(Error::new(
+                StatusCode::CONFLICT,
+                "the immutability of a repository can't be reduced",
+            ));
+        }
+    }
+    let json = serde_json::to_string(&immutability).map_err(std::io::Error::from)?;
+    state
+        .storage()
+        .set_marker(path, IMMUTABLE_MARKER, Some(&json))?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+// get_immutable returns the immutability of a repository
+fn get_immutable(state: &State, admin: &AdminFromRequest, repo: &str) -> Result<Response, Error> {
+    tracing::debug!(admin = admin.user, repo, "get_immutable");
+
+    match Immutability::read(state.storage(), Path::new(repo)) {
+        Some(immutability) => Ok(Json(immutability).into_response()),
+        None => Err(Error::new(
+            StatusCode::NOT_FOUND,
+            "repository is not immutable",
+        )),
+    }
+}
+
+// rename moves a repository to the path given as request body; path is
+// "<repo>/rename"
+async fn rename(
+    state: &State,
+    admin: &AdminFromRequest,
+    path: String,
+    to: &str,
+) -> Result<Response, Error> {
+    let (checked, to) = (state.clone(), to.to_string());
+    let (from, to) = blocking(move || {
+        let (repo, _) = repo_action(&checked, &path)?;
+        rename_repo(&checked.config(), &repo, &to)
+    })
+    .await?;
+    tracing::info!(admin = admin.user, repo = from, to, "renamed repository");
+
+    state.usage().rename_repo(&from, &to);
+    reload(state).await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+// repo_dir returns the directory of a repository
+fn repo_dir(state: &State, repo: &str) -> Result<PathBuf, Error> {
+    let config_file = state
+        .storage()
+        .filename(Path::new(repo), CONFIG_TYPE, CONFIG_NAME);
+    config_file
+        .parent()
+        .map(Path::to_path_buf)
+        .ok_or_else(|| Error::new(StatusCode::NOT_FOUND, "repository not found"))
+}
+
+// list_configs returns the kept versions of the config file of a repository
+fn list_configs(state: &State, admin: &AdminFromRequest, repo: &str) -> Result<Response, Error> {
+    tracing::debug!(admin = admin.user, repo, "list_configs");
+
+    Ok(Json(versions::list(&repo_dir(state, repo)?)?).into_response())
+}
+
+// restore_config replaces the config file of a repository by the version
+// given as request body
+fn restore_config(
+    state: &State,
+    admin: &AdminFromRequest,
+    repo: &str,
+    version: &str,
+) -> Result<Response, Error> {
+    tracing::info!(admin = admin.user, repo, version, "restore config");
+
+    let keep = state.config().storage.config_versions;
+    match versions::restore(&repo_dir(state, repo)?, version, keep)? {
+        true => Ok(StatusCode::NO_CONTENT.into_response()),
+        false => Err(Error::new(
+            StatusCode::NOT_FOUND,
+            format!("version {version:?} not found"),
+        )),
+    }
+}
+
+// start_verify re-hashes all files of a repository in the background
+fn start_verify(state: &State, admin: &AdminFromRequest, repo: &str) -> Result<Response, Error> {
+    tracing::info!(admin = admin.user, repo, "start verification");
+
+    match state.verifier().start(repo) {
+        Some(verification) => Ok((StatusCode::ACCEPTED, Json(verification)).into_response()),
+        None => Err(Error::new(
+            StatusCode::CONFLICT,
+            "verification is already running",
+        )),
+    }
+}
+
+// get_verify returns the running or last verification of a repository
+fn get_verify(state: &State, admin: &AdminFromRequest, repo: &str) -> Result<Response, Error> {
+    tracing::debug!(admin = admin.user, repo, "get_verify");
+
+    match state.verifier().status(repo) {
+        Some(verification) => Ok(Json(verification).into_response()),
+        None => Err(Error::new(
+            StatusCode::NOT_FOUND,
+            "repository was never verified",
+        )),
+    }
+}
+
+// export streams a repository as tar archive. The archive is written while
+// sending, so a failure can only abort the response; the missing end of the
+// archive shows the client that it is incomplete.
+fn export(state: &State, admin: &AdminFromRequest, repo: &str) -> Result<Response, Error> {
+    tracing::info!(admin = admin.user, repo, "export repository");
+
+    let dir = repo_dir(state, repo)?;
+    let (writer, reader) = tokio::io::duplex(EXPORT_BUFFER);
+    let repo_name = repo.to_string();
+    _ = tokio::task::spawn_blocking(move || {
+        if let Err(err) = export_repo(&dir, SyncIoBridge::new(writer)) {
+            tracing::error!(repo = repo_name, "export failed: {err}");
+        }
+    });
+
+    let name = repo.rsplit('/').next().unwrap_or(repo);
+    let disposition = format!("attachment; filename=\"{name}.tar\"");
+    Ok((
+        [
+            (CONTENT_TYPE, "application/x-tar".to_string()),
+            (CONTENT_DISPOSITION, disposition),
+        ],
+        Body::from_stream(ReaderStream::new(reader)),
+    )
+        .into_response())
+}
+
+// import creates a repository from a tar archive as returned by export
+async fn import(
+    state: &State,
+    admin: &AdminFromRequest,
+    repo: &str,
+    body: Body,
+) -> Result<Response, Error> {
+    tracing::info!(admin = admin.user, repo, "import repository");
+
+    let reader = StreamReader::new(body.into_data_stream().map_err(std::io::Error::other));
+    let reader = SyncIoBridge::new(reader);
+    let data = state.config().storage.path.clone();
+    let repo_name = repo.to_string();
+    let imported = blocking(move || import_repo(&data, &repo_name, reader)).await?;
+    state.usage().add_repo(&imported.repo);
+    Ok((StatusCode::CREATED, Json(imported)).into_response())
+}
+
+// create_tenant creates a user with its ACL grant, quota and repository
+async fn create_tenant(
+    extract::State(state): extract::State<State>,
+    admin: AdminFromRequest,
+    Json(tenant): Json<Tenant>,
+) -> Result<Response, Error> {
+    tracing::info!(admin = admin.user, user = tenant.user, "create tenant");
+
+    let config = state.config();
+    let provisioned = blocking(move || tenant::provision(&config, &tenant)).await?;
+    reload(&state).await?;
+    Ok((StatusCode::CREATED, Json(provisioned)).into_response())
+}
+
+#[derive(Deserialize)]
+struct Teardown {
+    // repository to delete together with the user
+    repo: Option<String>,
+}
+
+// delete_tenant removes a user with its ACL grants and settings and
+// optionally its repository
+async fn delete_tenant(
+    extract::State(state): extract::State<State>,
+    admin: AdminFromRequest,
+    extract::Path(user): extract::Path<String>,
+    extract::Query(teardown): extract::Query<Teardown>,
+) -> Result<Response, Error> {
+    tracing::info!(
+        admin = admin.user,
+        user,
+        repo = teardown.repo,
+        "delete tenant"
+    );
+
+    let (config, torn_down) = (state.config(), user.clone());
+    let repo = teardown.repo.clone();
+    blocking(move || tenant::teardown(&config, &torn_down, repo.as_deref())).await?;
+    if let Some(repo) = &teardown.repo {
+        state.usage().remove_repo(repo);
+    }
+    reload(&state).await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+// reload applies the changed configuration files
+async fn reload(state: &State) -> Result<(), Error> {
+    state.reload().await.map_err(|err| {
+        Error::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("files changed, but reloading failed: {err:#}"),
+        )
+    })
+}
@@ -0,0 +1,218 @@
+// mod archive
+//
+// exports repositories as tar archives and imports them, e.g. for offsite
+// copies or to move a tenant to another server
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use axum::http::StatusCode;
+use serde::Serialize;
+use tar::{Archive, Builder, EntryType, HeaderMode};
+use walkdir::WalkDir;
+
+use crate::check::{check_repo, expected_name, Report};
+use crate::edit::{check_not_nested, conflict, repo_path};
+use crate::storage::is_repo;
+use crate::web::{Error, TYPES};
+
+// IMPORT_PREFIX starts the names of the directories imports are unpacked to
+pub const IMPORT_PREFIX: &str = ".import-";
+
+// Imported summarizes an imported repository
+#[derive(Debug, Serialize)]
+pub struct Imported {
+    pub repo: String,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+// export_repo writes the repository at dir as tar archive to writer. Paths
+// within the archive are relative to the repository. Nested repositories and
+// hidden files like the markers of the server are left out.
+pub fn export_repo(dir: &Path, writer: impl Write) -> io::Result<()> {
+    let mut builder = Builder::new(writer);
+    builder.mode(HeaderMode::Deterministic);
+    builder.follow_symlinks(false);
+    let walker = WalkDir::new(dir)
+        .sort_by_file_name()
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|e| {
+            !(e.file_name().to_string_lossy().starts_with('.')
+                || e.file_type().is_dir() && is_repo(e.path()))
+        });
+    for entry in walker {
+        let entry = entry?;
+        let Ok(name) = entry.path().strip_prefix(dir) else {
+            continue;
+        };
+        if entry.file_type().is_dir() {
+            builder.append_dir(name, entry.path())?;
+        } else if entry.file_type().is_file() {
+            builder.append_path_with_name(entry.path(), name)?;
+        }
+    }
+    builder.into_inner()?.flush()
+}
+
+// import_repo creates the repository repo within the data directory from a
+// tar archive as written by export_repo. The archive is unpacked into a
+// hidden directory first and only moved into place if it contains nothing but
+// a repository whose files all match their hashes.
+pub fn import_repo(data: &Path, repo: &str, reader: impl Read) -> Result<Imported, Error> {
+    let repo = repo_path(repo)?;
+    let dst = data.join(&repo);
+    if dst.exists() {
+        return Err(conflict(format!("{repo} already exists")));
+    }
+    check_not_nested(data, &repo)?;
+
+    fs::create_dir_all(data)?;
+    let tmp = data.join(format!("{IMPORT_PREFIX}{:016x}", rand::random::<u64>()));
+    let res = unpack(&tmp, reader).and_then(|report| {
+        if dst.exists() {
+            return Err(conflict(format!("{repo} already exists")));
+        }
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&tmp, &dst)?;
+        Ok(report)
+    });
+    match res {
+        Ok(report) => Ok(Imported {
+            repo,
+            files: report.files,
+            bytes: report.bytes,
+        }),
+        Err(err) => {
+            if let Err(err) = fs::remove_dir_all(&tmp) {
+                tracing::error!("cannot remove {}: {err}", tmp.display());
+            }
+            Err(err)
+        }
+    }
+}
+
+// unpack extracts the archive into dir and checks the repository within it
+fn unpack(dir: &Path, reader: impl Read) -> Result<Report, Error> {
+    let invalid = |message: String| Error::new(StatusCode::BAD_REQUEST, message);
+    fs::create_dir(dir)?;
+    let mut archive = Archive::new(reader);
+    let entries = archive
+        .entries()
+        .map_err(|err| invalid(format!("invalid archive: {err}")))?;
+    for entry in entries {
+        let mut entry = entry.map_err(|err| invalid(format!("invalid archive: {err}")))?;
+        let path = entry
+            .path()
+            .map_err(|err| invalid(format!("invalid archive: {err}")))?
+            .into_owned();
+        let allowed = match entry.header().entry_type() {
+            EntryType::Directory => allowed_dir(&path),
+            EntryType::Regular => expected_name(&path).is_some(),
+            _ => false,
+        };
+        if !allowed || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(invalid(format!(
+                "unexpected entry {} in archive",
+                path.display()
+            )));
+        }
+        _ = entry
+            .unpack_in(dir)
+            .map_err(|err| invalid(format!("cannot unpack {}: {err}", path.display())))?;
+    }
+    if !is_repo(dir) {
+        return Err(invalid("archive contains no repository".to_string()));
+    }
+
+    let mut report = Report::default();
+    check_repo(dir, true, &mut report);
+    if !report.problems.is_empty() {
+        let mut problem = report.problems.swap_remove(0);
+        if let Ok(file) = problem.file.strip_prefix(dir) {
+            problem.file = file.to_path_buf();
+        }
+        return Err(invalid(format!("invalid repository: {problem}")));
+    }
+    // the server expects all type directories to exist
+    let mut dirs: Vec<PathBuf> = TYPES.iter().map(|tpe| dir.join(tpe)).collect();
+    dirs.extend((0..256).map(|i| dir.join("data").join(format!("{i:02x}"))));
+    for dir in dirs {
+        fs::create_dir_all(dir)?;
+    }
+    Ok(report)
+}
+
+// allowed_dir returns whether a repository contains the directory at path
+fn allowed_dir(path: &Path) -> bool {
+    let parts: Option<Vec<_>> = path.iter().map(|p| p.to_str()).collect();
+    match parts.as_deref() {
+        Some([tpe]) => TYPES.contains(tpe),
+        Some(["data", shard]) => shard.len() == 2 && shard.bytes().all(|b| b.is_ascii_hexdigit()),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // HASH is the SHA-256 hash of "hello"
+    const HASH: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn export_and_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().join("data/alice");
+        for sub in ["keys", "data/2c", "nested/keys"] {
+            fs::create_dir_all(repo.join(sub)).unwrap();
+        }
+        fs::write(repo.join("config"), "x").unwrap();
+        fs::write(repo.join("keys").join(HASH), "hello").unwrap();
+        fs::write(repo.join("data/2c").join(HASH), "hello").unwrap();
+        fs::write(repo.join(".frozen"), "\n").unwrap();
+        fs::write(repo.join("nested/config"), "x").unwrap();
+
+        let mut tar = Vec::new();
+        export_repo(&repo, &mut tar).unwrap();
+        let mut archive = Archive::new(tar.as_slice());
+        let names: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().into_owned())
+            .collect();
+        let data_file = format!("data/2c/{HASH}");
+        let key_file = format!("keys/{HASH}");
+        assert_eq!(
+            names,
+            ["config", "data", "data/2c", &data_file, "keys", &key_file]
+        );
+
+        let data = dir.path().join("data");
+        let imported = import_repo(&data, "bob/laptop", tar.as_slice()).unwrap();
+        assert_eq!((imported.repo.as_str(), imported.files), ("bob/laptop", 3));
+        assert!(is_repo(&data.join("bob/laptop")));
+        assert!(data.join("bob/laptop/data/ff").is_dir());
+        assert!(!data.join("bob/laptop/.frozen").exists());
+
+        let status = |repo, tar: &[u8]| import_repo(&data, repo, tar).unwrap_err().status();
+        assert_eq!(status("bob/laptop", &tar), StatusCode::CONFLICT);
+        assert_eq!(status("alice/x", &tar), StatusCode::CONFLICT);
+        assert_eq!(status("carol", b"no tar"), StatusCode::BAD_REQUEST);
+
+        // files must match their hashes
+        fs::write(data.join("bob/laptop/keys").join(HASH), "bit rot").unwrap();
+        let mut tar = Vec::new();
+        export_repo(&data.join("bob/laptop"), &mut tar).unwrap();
+        assert_eq!(status("carol", &tar), StatusCode::BAD_REQUEST);
+        assert!(!data.join("carol").exists());
+        let hidden = fs::read_dir(&data)
+            .unwrap()
+            .any(|e| e.unwrap().file_name().to_string_lossy().starts_with('.'));
+        assert!(!hidden);
+    }
+}
@@ -2,28 +2,37 @@ use std::collections::HashMap;
 use std::path::PathBuf;
 use std::{fs, io};
 
+use base64::prelude::*;
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use subtle::ConstantTimeEq;
+
 pub trait AuthChecker: Send + Sync + 'static {
     fn verify(&self, user: &str, passwd: &str) -> bool;
+    // has_user tells failed logins of unknown users from wrong passwords
+    fn has_user(&self, user: &str) -> bool;
 }
 
 // read_htpasswd is a helper func that reads the given file in .httpasswd format
 // into a Hashmap mapping each user to the whole passwd line
-fn read_htpasswd(file_path: &PathBuf) -> io::Result<HashMap<&'static str, &'static str>> {
+fn read_htpasswd(file_path: &PathBuf) -> io::Result<HashMap<String, String>> {
     let s = fs::read_to_string(file_path)?;
-    // make the contents static in memory
-    let s = Box::leak(s.into_boxed_str());
+    Ok(parse_htpasswd(&s))
+}
 
+// parse_htpasswd maps each user of the .htpasswd content s to its line
+fn parse_htpasswd(s: &str) -> HashMap<String, String> {
     let mut user_map = HashMap::new();
     for line in s.lines() {
         let user = line.split(':').collect::<Vec<&str>>()[0];
-        user_map.insert(user, line);
+        user_map.insert(user.to_string(), line.to_string());
     }
-    Ok(user_map)
+    user_map
 }
 
 #[derive(Clone)]
 pub struct Auth {
-    users: Option<HashMap<&'static str, &'static str>>,
+    users: Option<HashMap<String, String>>,
 }
 
 impl Auth {
@@ -35,6 +44,42 @@ impl Auth {
             },
         })
     }
+
+    // from_htpasswd uses the users of content in .htpasswd format, e.g.
+    // fetched from Vault
+    pub fn from_htpasswd(no_auth: bool, content: &str) -> Self {
+        Self {
+            users: (!no_auth).then(|| parse_htpasswd(content)),
+        }
+    }
+
+    // has_user returns whether user is known; always true if authentication is disabled
+    pub fn has_user(&self, user: &str) -> bool {
+        match &self.users {
+            Some(users) => users.contains_key(user),
+            None => true,
+        }
+    }
+
+    // diff describes which users were added, removed or changed their password in other
+    pub fn diff(&self, other: &Self) -> Vec<String> {
+        let empty = HashMap::new();
+        let old = self.users.as_ref().unwrap_or(&empty);
+        let new = other.users.as_ref().unwrap_or(&empty);
+        let mut changes = Vec::new();
+        if self.users.is_some() != other.users.is_some() {
+            changes.push(match other.users {
+                Some(_) => "authentication enabled".to_string(),
+                None => "authentication disabled".to_string(),
+            });
+        }
+        changes.extend(
+            crate::helpers::diff_maps(old, new)
+                .into_iter()
+                .map(|(user, change)| format!("user {user} {change}")),
+        );
+        changes
+    }
 }
 
 impl AuthChecker for Auth {
@@ -43,9 +88,149 @@ impl AuthChecker for Auth {
     fn verify(&self, user: &str, passwd: &str) -> bool {
         match &self.users {
             Some(users) => {
-                matches!(users.get(user), Some(passwd_data) if htpasswd_verify::Htpasswd::from(*passwd_data).check(user, passwd))
+                matches!(users.get(user), Some(passwd_data) if check_htpasswd_line(passwd_data, passwd))
             }
             None => true,
         }
     }
+
+    fn has_user(&self, user: &str) -> bool {
+        Self::has_user(self, user)
+    }
+}
+
+// htpasswd_line creates a line for a .htpasswd file with a bcrypt hashed password
+pub fn htpasswd_line(user: &str, passwd: &str) -> Result<String, bcrypt::BcryptError> {
+    Ok(format!(
+        "{user}:{}\n",
+        bcrypt::hash(passwd, bcrypt::DEFAULT_COST)?
+    ))
+}
+
+// is_supported_hash returns whether the hash of a .htpasswd line can be verified
+pub fn is_supported_hash(hash: &str) -> bool {
+    let hash = hash.trim();
+    hash.starts_with("$2") || hash.starts_with("$apr1$") || hash.starts_with("{SHA}")
+}
+
+// check_htpasswd_line checks passwd against a line of a .htpasswd file.
+// Supported hash formats are bcrypt, apr1 (md5) and {SHA}.
+fn check_htpasswd_line(line: &str, passwd: &str) -> bool {
+    let hash = match line.split_once(':') {
+        Some((_, hash)) => hash.trim(),
+        None => return false,
+    };
+    if hash.starts_with("$2") {
+        bcrypt::verify(passwd, hash).unwrap_or(false)
+    } else if let Some(rest) = hash.strip_prefix("$apr1$") {
+        match rest.split_once('$') {
+            Some((salt, _)) => same(&apr1_crypt(passwd, salt), hash),
+            None => false,
+        }
+    } else if let Some(digest) = hash.strip_prefix("{SHA}") {
+        same(
+            &BASE64_STANDARD.encode(Sha1::digest(passwd.as_bytes())),
+            digest,
+        )
+    } else {
+        false
+    }
+}
+
+// same compares the computed hash a with the stored hash b in constant time,
+// so response times don't tell how much of a guessed hash is right
+fn same(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+// apr1_crypt computes the Apache variant of the md5-crypt password hash
+fn apr1_crypt(passwd: &str, salt: &str) -> String {
+    const MAGIC: &str = "$apr1$";
+    const ITOA64: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+    let pw = passwd.as_bytes();
+    let salt = &salt.as_bytes()[..salt.len().min(8)];
+
+    let alt = Md5::new()
+        .chain_update(pw)
+        .chain_update(salt)
+        .chain_update(pw)
+        .finalize();
+
+    let mut ctx = Md5::new()
+        .chain_update(pw)
+        .chain_update(MAGIC)
+        .chain_update(salt);
+    for chunk in pw.chunks(16) {
+        ctx.update(&alt[..chunk.len()]);
+    }
+    let mut i = pw.len();
+    while i > 0 {
+        match i & 1 {
+            1 => ctx.update([0u8]),
+            _ => ctx.update(&pw[..1]),
+        }
+        i >>= 1;
+    }
+    let mut digest = ctx.finalize();
+
+    for i in 0..1000 {
+        let mut ctx = Md5::new();
+        match i & 1 {
+            1 => ctx.update(pw),
+            _ => ctx.update(digest),
+        }
+        if i % 3 != 0 {
+            ctx.update(salt);
+        }
+        if i % 7 != 0 {
+            ctx.update(pw);
+        }
+        match i & 1 {
+            1 => ctx.update(digest),
+            _ => ctx.update(pw),
+        }
+        digest = ctx.finalize();
+    }
+
+    let mut result = format!("{MAGIC}{}$", String::from_utf8_lossy(salt));
+    let mut to64 = |mut v: u32, n: usize| {
+        for _ in 0..n {
+            result.push(ITOA64[(v & 0x3f) as usize] as char);
+            v >>= 6;
+        }
+    };
+    for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        to64(
+            (u32::from(digest[a]) << 16) | (u32::from(digest[b]) << 8) | u32::from(digest[c]),
+            4,
+        );
+    }
+    to64(u32::from(digest[11]), 2);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn htpasswd_formats() {
+        // generated with `openssl passwd -apr1 -salt 5RPvBa.6 password`
+        let apr1 = "bob:$apr1$5RPvBa.6$MUwUiiudLOJCOzzm3QDgd.";
+        assert!(check_htpasswd_line(apr1, "password"));
+        assert!(!check_htpasswd_line(apr1, "passwort"));
+
+        // generated with `htpasswd -nbs bob password`
+        let sha = "bob:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=";
+        assert!(check_htpasswd_line(sha, "password"));
+        assert!(!check_htpasswd_line(sha, "passwort"));
+
+        let bcrypt = format!("bob:{}", bcrypt::hash("password", 4).unwrap());
+        assert!(check_htpasswd_line(&bcrypt, "password"));
+        assert!(!check_htpasswd_line(&bcrypt, "passwort"));
+
+        assert!(!check_htpasswd_line("bob:password", "password"));
+        assert!(!check_htpasswd_line("bob", ""));
+    }
 }
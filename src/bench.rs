@@ -0,0 +1,310 @@
+// mod bench
+//
+// generates restic-like load against a running server and measures the
+// latency of the requests, so performance regressions can be measured. Each
+// client mixes HEAD requests for existing files, uploads of packs of random
+// size and listings, like restic does during a backup.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use rand::Rng;
+use ring::digest::{digest, SHA256};
+use serde::Serialize;
+
+use crate::check::to_hex;
+use crate::config::secret;
+use crate::BenchOpts;
+
+// the operations and their share of all requests in percent
+const MIX: [(&str, u32); 4] = [("head", 55), ("upload", 20), ("list", 15), ("download", 10)];
+
+// Sample is the outcome of a single request
+struct Sample {
+    op: &'static str,
+    latency: Duration,
+    ok: bool,
+    bytes: u64,
+}
+
+// OpStats summarizes the requests of one operation; latencies in milliseconds
+#[derive(Debug, Serialize)]
+pub struct OpStats {
+    pub requests: usize,
+    pub errors: usize,
+    pub bytes: u64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+// Report is the result of a benchmark run
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub seconds: f64,
+    pub requests_per_second: f64,
+    pub ops: BTreeMap<&'static str, OpStats>,
+}
+
+// Client sends the requests of one simulated restic client
+#[derive(Clone)]
+struct Client {
+    http: reqwest::Client,
+    url: String,
+    user: Option<String>,
+    password: Option<String>,
+    // packs uploaded by all clients, used as targets of HEAD and GET
+    uploaded: Arc<Mutex<Vec<String>>>,
+}
+
+impl Client {
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let req = self.http.request(method, format!("{}/{path}", self.url));
+        match &self.user {
+            Some(user) => req.basic_auth(user, self.password.as_deref()),
+            None => req,
+        }
+    }
+
+    // random_pack returns the path of an uploaded pack, if any
+    fn random_pack(&self) -> Option<String> {
+        let uploaded = self.uploaded.lock().unwrap_or_else(PoisonError::into_inner);
+        match uploaded.len() {
+            0 => None,
+            n => Some(uploaded[rand::rng().random_range(0..n)].clone()),
+        }
+    }
+
+    // run sends one request of the operation op and measures it
+    async fn run(&self, op: &'static str, pack_size: usize) -> Sample {
+        let start = Instant::now();
+        let (res, bytes) = match op {
+            "head" => {
+                let path = self.random_pack().unwrap_or_else(|| "config".to_string());
+                (self.request(reqwest::Method::HEAD, &path).send().await, 0)
+            }
+            "upload" => {
+                let mut pack = vec![0; rand::rng().random_range(pack_size / 4..=pack_size)];
+                rand::rng().fill(&mut pack[..]);
+                let path = format!("data/{}", to_hex(digest(&SHA256, &pack).as_ref()));
+                let bytes = pack.len() as u64;
+                let res = self.request(reqwest::Method::POST, &path);
+                let res = res.body(pack).send().await;
+                if res.as_ref().is_ok_and(|res| res.status().is_success()) {
+                    self.uploaded
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .push(path);
+                }
+                (res, bytes)
+            }
+            "list" => {
+                let tpe = match rand::rng().random_bool(0.5) {
+                    true => "data/",
+                    false => "snapshots/",
+                };
+                (self.request(reqwest::Method::GET, tpe).send().await, 0)
+            }
+            _ => match self.random_pack() {
+                Some(path) => (self.request(reqwest::Method::GET, &path).send().await, 0),
+                None => (self.request(reqwest::Method::GET, "config").send().await, 0),
+            },
+        };
+        // the body is part of the latency of downloads
+        let (ok, bytes) = match res {
+            Ok(res) if res.status().is_success() => match res.bytes().await {
+                Ok(body) => (true, bytes.max(body.len() as u64)),
+                Err(_) => (false, bytes),
+            },
+            _ => (false, bytes),
+        };
+        Sample {
+            op,
+            latency: start.elapsed(),
+            ok,
+            bytes,
+        }
+    }
+}
+
+// pick chooses an operation according to MIX
+fn pick() -> &'static str {
+    let mut n = rand::rng().random_range(0..MIX.iter().map(|(_, share)| share).sum::<u32>());
+    for (op, share) in MIX {
+        if n < share {
+            return op;
+        }
+        n -= share;
+    }
+    MIX[0].0
+}
+
+// run creates the repository at opts.url if it has no config file, runs the clients for
+// opts.duration seconds and removes the uploaded packs unless opts.keep is set
+pub async fn run(opts: &BenchOpts) -> Result<Report> {
+    let http = reqwest::Client::builder()
+        .danger_accept_invalid_certs(opts.insecure)
+        .build()?;
+    let client = Client {
+        http,
+        url: opts.url.trim_end_matches('/').to_string(),
+        user: opts.user.clone(),
+        password: secret(&opts.password, &opts.password_file, &None)?,
+        uploaded: Arc::default(),
+    };
+    let res = client
+        .request(reqwest::Method::GET, "config")
+        .send()
+        .await
+        .with_context(|| format!("cannot connect to {}", opts.url))?;
+    if res.status() == reqwest::StatusCode::NOT_FOUND {
+        let res = client
+            .request(reqwest::Method::POST, "?create=true")
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            bail!("cannot create repository: {}", res.status());
+        }
+    } else if !res.status().is_success() {
+        bail!("cannot access repository: {}", res.status());
+    }
+
+    let start = Instant::now();
+    let end = start + Duration::from_secs(opts.duration);
+    let mut tasks = Vec::new();
+    for _ in 0..opts.concurrency.max(1) {
+        let client = client.clone();
+        let pack_size = opts.pack_size;
+        tasks.push(tokio::spawn(async move {
+            let mut samples = Vec::new();
+            while Instant::now() < end {
+                samples.push(client.run(pick(), pack_size).await);
+            }
+            samples
+        }));
+    }
+    let mut samples = Vec::new();
+    for task in tasks {
+        samples.extend(task.await?);
+    }
+    let report = summarize(samples, start.elapsed());
+
+    if !opts.keep {
+        let uploaded = std::mem::take(
+            &mut *client
+                .uploaded
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        for path in uploaded {
+            _ = client.request(reqwest::Method::DELETE, &path).send().await;
+        }
+    }
+    Ok(report)
+}
+
+fn summarize(samples: Vec<Sample>, elapsed: Duration) -> Report {
+    let mut by_op: BTreeMap<&'static str, Vec<Sample>> = BTreeMap::new();
+    let total = samples.len();
+    for sample in samples {
+        by_op.entry(sample.op).or_default().push(sample);
+    }
+    let ops = by_op
+        .into_iter()
+        .map(|(op, samples)| {
+            let mut latencies: Vec<_> = samples.iter().map(|s| s.latency).collect();
+            latencies.sort();
+            let millis = |p: f64| percentile(&latencies, p).as_secs_f64() * 1000.0;
+            let stats = OpStats {
+                requests: samples.len(),
+                errors: samples.iter().filter(|s| !s.ok).count(),
+                bytes: samples.iter().map(|s| s.bytes).sum(),
+                p50: millis(0.5),
+                p90: millis(0.9),
+                p99: millis(0.99),
+                max: millis(1.0),
+            };
+            (op, stats)
+        })
+        .collect();
+    Report {
+        seconds: elapsed.as_secs_f64(),
+        requests_per_second: total as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+        ops,
+    }
+}
+
+// percentile returns the latency below which the fraction p of the sorted
+// latencies lies (nearest rank)
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+// format_table renders a report with one line per operation
+pub fn format_table(report: &Report) -> String {
+    let mut out = format!(
+        "{:8}  {:>8}  {:>6}  {:>10}  {:>9}  {:>9}  {:>9}  {:>9}\n",
+        "OP", "REQUESTS", "ERRORS", "BYTES", "P50 MS", "P90 MS", "P99 MS", "MAX MS"
+    );
+    for (op, s) in &report.ops {
+        _ = writeln!(
+            out,
+            "{op:8}  {:>8}  {:>6}  {:>10}  {:>9.1}  {:>9.1}  {:>9.1}  {:>9.1}",
+            s.requests,
+            s.errors,
+            crate::stats::format_size(s.bytes),
+            s.p50,
+            s.p90,
+            s.p99,
+            s.max
+        );
+    }
+    _ = writeln!(
+        out,
+        "{:.1} requests/s in {:.1} s",
+        report.requests_per_second, report.seconds
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let sorted: Vec<_> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&sorted, 0.5), Duration::from_millis(50));
+        assert_eq!(percentile(&sorted, 0.99), Duration::from_millis(99));
+        assert_eq!(percentile(&sorted, 1.0), Duration::from_millis(100));
+        assert_eq!(percentile(&sorted, 0.0), Duration::from_millis(1));
+        assert_eq!(percentile(&[], 0.5), Duration::ZERO);
+
+        let samples = vec![
+            Sample {
+                op: "head",
+                latency: Duration::from_millis(2),
+                ok: true,
+                bytes: 0,
+            },
+            Sample {
+                op: "upload",
+                latency: Duration::from_millis(8),
+                ok: false,
+                bytes: 10,
+            },
+        ];
+        let report = summarize(samples, Duration::from_secs(2));
+        assert_eq!(report.requests_per_second, 1.0);
+        assert_eq!(report.ops["upload"].errors, 1);
+        assert_eq!(report.ops["head"].p99, 2.0);
+    }
+}
@@ -1,16 +1,356 @@
-use clap::Parser;
-use rustic_server::{acl::Acl, auth::Auth, storage::LocalStorage, web, web::State, Opts};
+use std::path::Path;
+use std::time::Duration;
 
-#[async_std::main]
-async fn main() -> tide::Result<()> {
+use anyhow::{bail, Context, Result};
+use clap::{CommandFactory, Parser};
+use rand::Rng;
+use rustic_server::{
+    activity, archive, bench, check,
+    config::{secret, Config, ServerConfig, MIN_STACK_SIZE},
+    daemon,
+    helpers::write_private,
+    immutable::{Immutability, IMMUTABLE_MARKER},
+    logging, migrate, rename, stats,
+    storage::{find_repos, is_repo, LocalStorage, Storage, APPEND_ONLY_MARKER, FROZEN_MARKER},
+    tls, web,
+    web::State,
+    BenchOpts, CertCommand, CertGenerateOpts, CheckOpts, Command, ConfigCommand, IdleOpts,
+    InitOpts, MigrateOpts, Opts, RepoCommand, StatsOpts,
+};
+
+fn main() -> Result<()> {
     let opts = Opts::parse();
+    let config = Config::from_opts(&opts)?;
+
+    match opts.command {
+        None => run(config, opts),
+        Some(Command::Config(ConfigCommand::Validate)) => validate(&config),
+        Some(Command::Config(ConfigCommand::Init(init_opts))) => init(&config, init_opts),
+        Some(Command::Cert(CertCommand::Generate(cert_opts))) => generate_cert(cert_opts),
+        Some(Command::Migrate(migrate_opts)) => migrate(migrate_opts),
+        Some(Command::Check(check_opts)) => check(&config, check_opts),
+        Some(Command::Stats(stats_opts)) => show_stats(&config, stats_opts),
+        Some(Command::Idle(idle_opts)) => show_idle(&config, idle_opts),
+        Some(Command::Bench(bench_opts)) => bench(bench_opts),
+        Some(Command::Repo(repo_command)) => repo(&config, repo_command),
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(
+                shell,
+                &mut Opts::command(),
+                "rustic-server",
+                &mut std::io::stdout(),
+            );
+            Ok(())
+        }
+    }
+}
+
+// run daemonizes if requested and runs the server. Forking is only safe as
+// long as there is a single thread, so the async runtime is started afterwards.
+fn run(config: Config, opts: Opts) -> Result<()> {
+    for warning in config.check()? {
+        eprintln!("warning: {warning}");
+    }
+    let pid_file = config.server.pid_file.clone();
+    if let Some(pid_file) = &pid_file {
+        daemon::check_pid_file(pid_file)?;
+    }
+    if config.server.daemonize {
+        daemon::daemonize(config.server.log_file.as_deref())
+            .context("cannot run in the background")?;
+    }
+    if let Some(pid_file) = &pid_file {
+        daemon::write_pid_file(pid_file)
+            .with_context(|| format!("cannot write PID file {}", pid_file.display()))?;
+    }
+
+    let runtime = runtime(&config.server)?;
+    let res = runtime.block_on(serve(config, opts));
+    // don't wait for a running verification of a repository
+    runtime.shutdown_background();
+    if let Some(pid_file) = &pid_file {
+        if let Err(err) = daemon::remove_pid_file(pid_file) {
+            eprintln!("cannot remove PID file {}: {err}", pid_file.display());
+        }
+    }
+    res
+}
+
+// runtime builds the async runtime with the thread settings of [server]
+fn runtime(server: &ServerConfig) -> Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(threads) = server.worker_threads {
+        if threads == 0 {
+            bail!("[server] worker_threads must be at least 1");
+        }
+        builder.worker_threads(threads);
+    }
+    if let Some(threads) = server.max_blocking_threads {
+        if threads == 0 {
+            bail!("[server] max_blocking_threads must be at least 1");
+        }
+        builder.max_blocking_threads(threads);
+    }
+    if let Some(size) = server.thread_stack_size {
+        if size < MIN_STACK_SIZE {
+            bail!("[server] thread_stack_size must be at least {MIN_STACK_SIZE}");
+        }
+        builder.thread_stack_size(size);
+    }
+    builder.build().context("cannot start the async runtime")
+}
+
+async fn serve(config: Config, opts: Opts) -> Result<()> {
+    logging::init(&config.log.filter)?;
+
+    let state = State::from_config(&config)?;
+    web::main(state, &config, move || Config::from_opts(&opts)).await
+}
+
+fn validate(config: &Config) -> Result<()> {
+    let errors = config.validate();
+    for error in &errors {
+        eprintln!("error: {error}");
+    }
+    match errors.len() {
+        0 => {
+            println!("configuration is valid");
+            Ok(())
+        }
+        n => bail!("found {n} problem(s) in the configuration"),
+    }
+}
+
+fn init(config: &Config, opts: InitOpts) -> Result<()> {
+    let admin_password = secret(&opts.admin_password, &opts.admin_password_file, &None)?;
+    let admin = opts.admin_user.map(|user| {
+        let passwd = admin_password.unwrap_or_else(|| {
+            let passwd: String = rand::rng()
+                .sample_iter(rand::distr::Alphanumeric)
+                .take(24)
+                .map(char::from)
+                .collect();
+            println!("generated password for user {user}: {passwd}");
+            passwd
+        });
+        (user, passwd)
+    });
+
+    let files = config.init(
+        &opts.dir,
+        opts.force,
+        admin
+            .as_ref()
+            .map(|(user, passwd)| (user.as_str(), passwd.as_str())),
+    )?;
+    for file in files {
+        println!("written {}", file.display());
+    }
+    Ok(())
+}
+
+fn generate_cert(opts: CertGenerateOpts) -> Result<()> {
+    if !opts.force {
+        if let Some(file) = [&opts.cert, &opts.key].into_iter().find(|f| f.exists()) {
+            bail!(
+                "{} already exists, use --force to overwrite",
+                file.display()
+            );
+        }
+    }
+    let generated = tls::generate_self_signed(&opts.hosts, opts.days)?;
+    write_private(&opts.key, &generated.key)?;
+    std::fs::write(&opts.cert, &generated.cert)?;
+    println!("written {}", opts.cert.display());
+    println!("written {}", opts.key.display());
+    println!("SHA-256 fingerprint: {}", generated.fingerprint);
+    Ok(())
+}
 
-    tide::log::with_level(opts.log);
+fn migrate(opts: MigrateOpts) -> Result<()> {
+    let source = migrate::MigrateSource {
+        data: &opts.data,
+        htpasswd: opts.htpasswd_file.as_deref(),
+        private_repos: opts.private_repos,
+        append_only: opts.append_only,
+        no_auth: opts.no_auth,
+    };
+    let migration = migrate::migrate(&source, &opts.dir, opts.force)?;
+    println!("found {} repositories", migration.repos.len());
+    for warning in &migration.warnings {
+        eprintln!("warning: {warning}");
+    }
+    for file in migration.files {
+        println!("written {}", file.display());
+    }
+    Ok(())
+}
 
-    let storage = LocalStorage::try_new(&opts.path)?;
-    let auth = Auth::from_file(opts.no_auth, &opts.path.join(".htpasswd"))?;
-    let acl = Acl::from_file(opts.append_only, opts.private_repo, opts.acl)?;
+fn check(config: &Config, opts: CheckOpts) -> Result<()> {
+    let data = &config.storage.path;
+    let repos = match opts.repos.is_empty() {
+        true => find_repos(data),
+        false => opts.repos,
+    };
+    let mut report = check::Report::default();
+    for repo in &repos {
+        let dir = data.join(repo);
+        if !is_repo(&dir) {
+            bail!("{} is no repository", dir.display());
+        }
+        check::check_repo(&dir, !opts.quick, &mut report);
+    }
+    for problem in &report.problems {
+        eprintln!("error: {problem}");
+    }
+    println!(
+        "checked {} files ({} bytes) in {} repositories",
+        report.files, report.bytes, report.repos
+    );
+    match report.problems.len() {
+        0 => Ok(()),
+        n => bail!("found {n} problem(s)"),
+    }
+}
+
+fn show_stats(config: &Config, opts: StatsOpts) -> Result<()> {
+    let data = &config.storage.path;
+    let repos = match opts.repos.is_empty() {
+        true => find_repos(data),
+        false => opts.repos,
+    };
+    let storage = LocalStorage::try_new(data)?;
+    let mut all = Vec::new();
+    for repo in &repos {
+        if !is_repo(&data.join(repo)) {
+            bail!("{} is no repository", data.join(repo).display());
+        }
+        all.push(stats::repo_stats(&storage, repo));
+    }
+    match opts.json {
+        true => println!("{}", serde_json::to_string_pretty(&all)?),
+        false => print!("{}", stats::format_table(&all)),
+    }
+    Ok(())
+}
+
+fn show_idle(config: &Config, opts: IdleOpts) -> Result<()> {
+    let data = &config.storage.path;
+    let storage = LocalStorage::try_new(data)?;
+    let max_idle = Duration::from_secs(opts.days.saturating_mul(24 * 60 * 60));
+    let idle = activity::idle_repos(&storage, &find_repos(data), max_idle, opts.writes_only);
+    match opts.json {
+        true => println!("{}", serde_json::to_string_pretty(&idle)?),
+        false => print!("{}", activity::format_table(&idle)),
+    }
+    Ok(())
+}
+
+fn bench(opts: BenchOpts) -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    let report = runtime.block_on(bench::run(&opts))?;
+    match opts.json {
+        true => println!("{}", serde_json::to_string_pretty(&report)?),
+        false => print!("{}", bench::format_table(&report)),
+    }
+    Ok(())
+}
+
+fn repo(config: &Config, command: RepoCommand) -> Result<()> {
+    let data = &config.storage.path;
+    let (repo, marker, reason) = match &command {
+        RepoCommand::Freeze { repo, reason } => (repo, FROZEN_MARKER, Some(reason.trim())),
+        RepoCommand::Unfreeze { repo } => (repo, FROZEN_MARKER, None),
+        RepoCommand::AppendOnly { repo, reason, off } => {
+            (repo, APPEND_ONLY_MARKER, (!off).then(|| reason.trim()))
+        }
+        RepoCommand::Rename { from, to } => return rename(config, from, to),
+        RepoCommand::Export { repo, output } => return export(config, repo, output),
+        RepoCommand::Import { repo, input } => return import(config, repo, input),
+        RepoCommand::Immutable { repo, days, types } => {
+            let immutability = Immutability {
+                days: *days,
+                types: types.clone(),
+            };
+            return immutable(config, repo, &immutability);
+        }
+    };
+    if !is_repo(&data.join(repo)) {
+        bail!("{} is no repository", data.join(repo).display());
+    }
+    let storage = LocalStorage::try_new(data)?;
+    storage.set_marker(Path::new(repo), marker, reason)?;
+    match (marker, reason) {
+        (FROZEN_MARKER, Some(_)) => println!("frozen repository {repo}"),
+        (FROZEN_MARKER, None) => println!("unfrozen repository {repo}"),
+        (_, Some(_)) => println!("repository {repo} is append-only"),
+        (_, None) => println!("repository {repo} allows deletes again"),
+    }
+    Ok(())
+}
+
+fn rename(config: &Config, from: &str, to: &str) -> Result<()> {
+    let (from, to) = rename::rename_repo(config, from, to)?;
+    println!("renamed repository {from} to {to}");
+    println!("a running server applies the changed ACL and config files on SIGHUP");
+    Ok(())
+}
+
+fn export(config: &Config, repo: &str, output: &Path) -> Result<()> {
+    let dir = config.storage.path.join(repo);
+    if !is_repo(&dir) {
+        bail!("{} is no repository", dir.display());
+    }
+    let res = match output == Path::new("-") {
+        true => archive::export_repo(&dir, std::io::stdout().lock()),
+        false => {
+            let file = std::fs::File::create(output)
+                .with_context(|| format!("cannot create {}", output.display()))?;
+            archive::export_repo(&dir, std::io::BufWriter::new(file))
+        }
+    };
+    res.with_context(|| format!("cannot export repository {repo}"))
+}
+
+fn import(config: &Config, repo: &str, input: &Path) -> Result<()> {
+    let data = &config.storage.path;
+    let imported = match input == Path::new("-") {
+        true => archive::import_repo(data, repo, std::io::stdin().lock()),
+        false => {
+            let file = std::fs::File::open(input)
+                .with_context(|| format!("cannot open {}", input.display()))?;
+            archive::import_repo(data, repo, std::io::BufReader::new(file))
+        }
+    };
+    let imported = imported.with_context(|| format!("cannot import repository {repo}"))?;
+    println!(
+        "imported repository {} ({} files, {} bytes)",
+        imported.repo, imported.files, imported.bytes
+    );
+    Ok(())
+}
 
-    let new_state = State::new(auth, acl, storage);
-    web::main(new_state, opts.listen, opts.tls, opts.cert, opts.key).await
+fn immutable(config: &Config, repo: &str, immutability: &Immutability) -> Result<()> {
+    let data = &config.storage.path;
+    if !is_repo(&data.join(repo)) {
+        bail!("{} is no repository", data.join(repo).display());
+    }
+    if let Err(err) = immutability.validate() {
+        bail!("{err}");
+    }
+    let storage = LocalStorage::try_new(data)?;
+    let path = Path::new(repo);
+    if let Some(old) = Immutability::read(&storage, path) {
+        if old.weakens(immutability) {
+            bail!("the immutability of repository {repo} can't be reduced");
+        }
+    }
+    let json = serde_json::to_string(immutability)?;
+    storage.set_marker(path, IMMUTABLE_MARKER, Some(&json))?;
+    println!(
+        "repository {repo} is immutable for {} days",
+        immutability.days
+    );
+    Ok(())
 }
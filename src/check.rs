@@ -0,0 +1,207 @@
+// mod check
+//
+// verifies the files of repositories within the data directory without a
+// client: all files except config are named by the SHA-256 hash of their content
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use ring::digest::{Context, SHA256};
+use walkdir::WalkDir;
+
+use crate::storage::is_repo;
+use crate::throttle::Throttle;
+use crate::web::TYPES;
+
+// Problem is a single finding of check_repo
+#[derive(Debug)]
+pub struct Problem {
+    pub file: PathBuf,
+    pub kind: ProblemKind,
+}
+
+#[derive(Debug)]
+pub enum ProblemKind {
+    // the content doesn't match the name
+    HashMismatch(String),
+    Empty,
+    // a file which doesn't belong into a repository or is at the wrong place
+    Foreign,
+    Unreadable(io::Error),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let file = self.file.display();
+        match &self.kind {
+            ProblemKind::HashMismatch(hash) => {
+                write!(f, "{file}: content has SHA-256 hash {hash}")
+            }
+            ProblemKind::Empty => write!(f, "{file}: file is empty"),
+            ProblemKind::Foreign => write!(f, "{file}: unexpected file"),
+            ProblemKind::Unreadable(err) => write!(f, "{file}: cannot read: {err}"),
+        }
+    }
+}
+
+// Report summarizes the check of one or more repositories
+#[derive(Debug, Default)]
+pub struct Report {
+    pub repos: usize,
+    pub files: usize,
+    pub bytes: u64,
+    pub problems: Vec<Problem>,
+}
+
+// check_repo checks all files of the repository at dir and adds the results
+// to report. If read_data is false, only names and sizes are checked.
+// Nested repositories and hidden files are skipped.
+pub fn check_repo(dir: &Path, read_data: bool, report: &mut Report) {
+    check_repo_throttled(dir, read_data, None, report);
+}
+
+// check_repo_throttled is check_repo reading the file contents no faster
+// than throttle allows
+pub fn check_repo_throttled(
+    dir: &Path,
+    read_data: bool,
+    throttle: Option<&Throttle>,
+    report: &mut Report,
+) {
+    report.repos += 1;
+    let walker = WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| {
+            e.depth() == 0
+                || !(e.file_name().to_string_lossy().starts_with('.')
+                    || e.file_type().is_dir() && is_repo(e.path()))
+        });
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                report.problems.push(Problem {
+                    file: err.path().unwrap_or(dir).to_path_buf(),
+                    kind: ProblemKind::Unreadable(err.into()),
+                });
+                continue;
+            }
+        };
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let file = entry.path();
+        report.files += 1;
+        let size = entry.metadata().map(|m| m.len()).unwrap_or_default();
+        report.bytes += size;
+
+        let rel = file.strip_prefix(dir).unwrap_or(file);
+        let Some(name) = expected_name(rel) else {
+            report.problems.push(Problem {
+                file: file.to_path_buf(),
+                kind: ProblemKind::Foreign,
+            });
+            continue;
+        };
+        if size == 0 {
+            report.problems.push(Problem {
+                file: file.to_path_buf(),
+                kind: ProblemKind::Empty,
+            });
+            continue;
+        }
+        if read_data && !name.is_empty() {
+            match sha256(file, throttle) {
+                Ok(hash) if hash == name => {}
+                Ok(hash) => report.problems.push(Problem {
+                    file: file.to_path_buf(),
+                    kind: ProblemKind::HashMismatch(hash),
+                }),
+                Err(err) => report.problems.push(Problem {
+                    file: file.to_path_buf(),
+                    kind: ProblemKind::Unreadable(err),
+                }),
+            }
+        }
+    }
+}
+
+// expected_name returns the hash a file at path (relative to the repository)
+// must have, "" for the config file and None if the file doesn't belong there
+pub(crate) fn expected_name(path: &Path) -> Option<&str> {
+    let parts: Vec<_> = path.iter().map(|p| p.to_str()).collect::<Option<_>>()?;
+    let name = match parts[..] {
+        ["config"] => return Some(""),
+        ["data", shard, name] if name.starts_with(shard) && shard.len() == 2 => name,
+        [tpe, name] if tpe != "data" && TYPES.contains(&tpe) => name,
+        _ => return None,
+    };
+    let is_hash = name.len() == 64 && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+    is_hash.then_some(name)
+}
+
+fn sha256(file: &Path, throttle: Option<&Throttle>) -> io::Result<String> {
+    let mut file = File::open(file)?;
+    let mut context = Context::new(&SHA256);
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            n => {
+                context.update(&buf[..n]);
+                if let Some(throttle) = throttle {
+                    throttle.consume_blocking(n);
+                }
+            }
+        }
+    }
+    Ok(to_hex(context.finish().as_ref()))
+}
+
+// to_hex formats a hash as lowercase hex string, like restic names files
+pub(crate) fn to_hex(hash: &[u8]) -> String {
+    hash.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn check() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path();
+        let content = b"hello";
+        let hash = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        for sub in ["data/2c", "keys", "snapshots", "sub/keys"] {
+            fs::create_dir_all(repo.join(sub)).unwrap();
+        }
+        fs::write(repo.join("config"), "x").unwrap();
+        fs::write(repo.join("data/2c").join(hash), content).unwrap();
+        fs::write(repo.join("keys").join(hash), "bit rot").unwrap();
+        fs::write(repo.join("snapshots").join(hash), "").unwrap();
+        fs::write(repo.join("snapshots/foo.tmp"), "x").unwrap();
+        // nested repository and hidden files are skipped
+        fs::write(repo.join("sub/config"), "x").unwrap();
+        fs::write(repo.join(".htpasswd"), "x").unwrap();
+
+        let mut report = Report::default();
+        check_repo(repo, true, &mut report);
+        assert_eq!(report.files, 5);
+        assert_eq!(report.problems.len(), 3);
+        assert!(matches!(
+            report.problems[0].kind,
+            ProblemKind::HashMismatch(_)
+        ));
+        assert!(matches!(report.problems[1].kind, ProblemKind::Empty));
+        assert!(matches!(report.problems[2].kind, ProblemKind::Foreign));
+
+        let mut report = Report::default();
+        check_repo(repo, false, &mut report);
+        assert_eq!(report.problems.len(), 2);
+    }
+}
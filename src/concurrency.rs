@@ -0,0 +1,56 @@
+// mod concurrency
+//
+// limits the number of requests processed at once, e.g. per repository, so
+// a restore storm can't starve running backups
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// Limit is the maximum number of permits and the semaphore handing them out
+type Limit = (usize, Arc<Semaphore>);
+
+// ConcurrencyLimits hands out permits per key; a request holds its permits
+// until its response is sent completely
+#[derive(Clone, Debug, Default)]
+pub struct ConcurrencyLimits(Arc<Mutex<HashMap<String, Limit>>>);
+
+impl ConcurrencyLimits {
+    // try_acquire returns a permit for key if less than max permits are held.
+    // If max changed, permits held for the old value are no longer counted.
+    pub fn try_acquire(&self, key: &str, max: usize) -> Option<OwnedSemaphorePermit> {
+        let semaphore = {
+            let mut limits = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+            match limits.get(key) {
+                Some((limit, semaphore)) if *limit == max => semaphore.clone(),
+                _ => {
+                    let semaphore = Arc::new(Semaphore::new(max));
+                    _ = limits.insert(key.to_string(), (max, semaphore.clone()));
+                    semaphore
+                }
+            }
+        };
+        semaphore.try_acquire_owned().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn try_acquire() {
+        let limits = ConcurrencyLimits::default();
+        let first = limits.try_acquire("repo", 2);
+        let second = limits.try_acquire("repo", 2);
+        assert!(first.is_some() && second.is_some());
+        assert!(limits.try_acquire("repo", 2).is_none());
+        assert!(limits.try_acquire("other", 2).is_some());
+
+        drop(first);
+        assert!(limits.try_acquire("repo", 2).is_some());
+        // a new limit starts from scratch
+        assert!(limits.try_acquire("repo", 1).is_some());
+    }
+}
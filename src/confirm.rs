@@ -0,0 +1,64 @@
+// mod confirm
+//
+// hands out one-time tokens confirming a destructive request, so a mistyped
+// path doesn't delete a large repository right away
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+// TOKEN_VALIDITY is how long a token can be used
+pub const TOKEN_VALIDITY: Duration = Duration::from_secs(300);
+
+// Confirmations holds the pending token per key, e.g. per repository
+#[derive(Clone, Debug, Default)]
+pub struct Confirmations(Arc<Mutex<HashMap<String, (String, Instant)>>>);
+
+impl Confirmations {
+    // issue returns a new token for key, replacing a pending one
+    pub fn issue(&self, key: &str) -> String {
+        let token = format!("{:032x}", rand::random::<u128>());
+        let mut pending = self.lock();
+        pending.retain(|_, (_, issued)| issued.elapsed() < TOKEN_VALIDITY);
+        _ = pending.insert(key.to_string(), (token.clone(), Instant::now()));
+        token
+    }
+
+    // confirm returns whether token is the valid token for key; it can only be used once
+    pub fn confirm(&self, key: &str, token: &str) -> bool {
+        let mut pending = self.lock();
+        match pending.get(key) {
+            Some((expected, issued)) if expected == token => {
+                let valid = issued.elapsed() < TOKEN_VALIDITY;
+                _ = pending.remove(key);
+                valid
+            }
+            _ => false,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, (String, Instant)>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn confirm() {
+        let confirmations = Confirmations::default();
+        let token = confirmations.issue("alice");
+        assert!(!confirmations.confirm("bob", &token));
+        assert!(!confirmations.confirm("alice", "guess"));
+        assert!(confirmations.confirm("alice", &token));
+        assert!(!confirmations.confirm("alice", &token));
+
+        // only the last token is valid
+        let old = confirmations.issue("alice");
+        let new = confirmations.issue("alice");
+        assert!(!confirmations.confirm("alice", &old));
+        assert!(confirmations.confirm("alice", &new));
+    }
+}
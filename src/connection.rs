@@ -0,0 +1,131 @@
+// mod connection
+//
+// applies the connection settings of [server] to the listeners: socket
+// options of accepted TCP connections and the HTTP/1 and HTTP/2 limits of
+// hyper. Settings not given keep the defaults of hyper.
+
+use std::future::{ready, Ready};
+use std::io;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use axum_server::accept::Accept;
+use hyper_util::rt::TokioTimer;
+use hyper_util::server::conn::auto::Builder;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::TcpStream;
+
+use crate::config::ServerConfig;
+
+// MIN_HEADER_BYTES is the smallest buffer hyper accepts for HTTP/1 headers
+pub const MIN_HEADER_BYTES: usize = 8192;
+
+// TcpAcceptor sets the socket options of accepted connections
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TcpAcceptor {
+    nodelay: bool,
+    keepalive: Option<Duration>,
+}
+
+impl TcpAcceptor {
+    pub fn new(config: &ServerConfig) -> Self {
+        Self {
+            nodelay: config.tcp_nodelay,
+            keepalive: config.tcp_keepalive.map(Duration::from_secs),
+        }
+    }
+
+    fn configure(self, stream: &TcpStream) -> io::Result<()> {
+        if self.nodelay {
+            stream.set_nodelay(true)?;
+        }
+        if let Some(time) = self.keepalive {
+            let keepalive = TcpKeepalive::new().with_time(time);
+            SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S> Accept<TcpStream, S> for TcpAcceptor {
+    type Stream = TcpStream;
+    type Service = S;
+    type Future = Ready<io::Result<(TcpStream, S)>>;
+
+    fn accept(&self, stream: TcpStream, service: S) -> Self::Future {
+        ready(self.configure(&stream).map(|()| (stream, service)))
+    }
+}
+
+// configure_http applies the HTTP settings of config to builder; hyper
+// panics on some invalid values, so they are refused here
+pub fn configure_http<E>(builder: &mut Builder<E>, config: &ServerConfig) -> Result<()> {
+    if config
+        .max_header_bytes
+        .is_some_and(|size| size < MIN_HEADER_BYTES)
+    {
+        bail!("[server] max_header_bytes must be at least {MIN_HEADER_BYTES}");
+    }
+    // keep-alive pings and header timeouts need a timer
+    if config.http2_keep_alive_interval.is_some() || config.header_read_timeout.is_some() {
+        builder.http1().timer(TokioTimer::new());
+        builder.http2().timer(TokioTimer::new());
+    }
+    let mut http1 = builder.http1();
+    http1.keep_alive(config.keep_alive);
+    if let Some(max) = config.max_header_bytes {
+        http1.max_buf_size(max);
+    }
+    if let Some(timeout) = config.header_read_timeout {
+        http1.header_read_timeout(Duration::from_secs(timeout));
+    }
+    let mut http2 = builder.http2();
+    if let Some(max) = config.max_header_bytes {
+        http2.max_header_list_size(u32::try_from(max).unwrap_or(u32::MAX));
+    }
+    if let Some(max) = config.http2_max_concurrent_streams {
+        http2.max_concurrent_streams(max);
+    }
+    if let Some(interval) = config.http2_keep_alive_interval {
+        http2.keep_alive_interval(Duration::from_secs(interval));
+    }
+    Ok(())
+}
+
+// changed returns whether the connection settings of old and new differ
+pub fn changed(old: &ServerConfig, new: &ServerConfig) -> bool {
+    let settings = |c: &ServerConfig| {
+        (
+            c.max_header_bytes,
+            c.header_read_timeout,
+            c.keep_alive,
+            c.http2_max_concurrent_streams,
+            c.http2_keep_alive_interval,
+            c.tcp_nodelay,
+            c.tcp_keepalive,
+        )
+    };
+    settings(old) != settings(new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn socket_options() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let config = ServerConfig {
+            tcp_nodelay: true,
+            tcp_keepalive: Some(60),
+            ..ServerConfig::default()
+        };
+        let (stream, ()) = TcpAcceptor::new(&config).accept(stream, ()).await.unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+        drop(client);
+    }
+}
@@ -0,0 +1,146 @@
+// mod daemon
+//
+// detaches the server from the terminal for init systems other than systemd
+// and manages its PID file, see daemon(7)
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// check_pid_file fails if pid_file contains the PID of a running process.
+// Stale PID files, e.g. after a crash, are ignored as they are overwritten.
+pub fn check_pid_file(pid_file: &Path) -> io::Result<()> {
+    let content = match fs::read_to_string(pid_file) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    match content.trim().parse() {
+        Ok(pid) if is_running(pid) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "already running with PID {pid} according to {}",
+                pid_file.display()
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+// write_pid_file writes the PID of the current process to pid_file
+pub fn write_pid_file(pid_file: &Path) -> io::Result<()> {
+    fs::write(pid_file, format!("{}\n", std::process::id()))
+}
+
+// remove_pid_file removes pid_file if it still contains our PID
+pub fn remove_pid_file(pid_file: &Path) -> io::Result<()> {
+    match fs::read_to_string(pid_file) {
+        Ok(content) if content.trim() == std::process::id().to_string() => {
+            fs::remove_file(pid_file)
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn is_running(pid: libc::pid_t) -> bool {
+    if pid <= 0 {
+        return false;
+    }
+    // SAFETY: signal 0 only checks whether the process exists
+    match unsafe { libc::kill(pid, 0) } {
+        0 => true,
+        // the process exists, but belongs to another user
+        _ => io::Error::last_os_error().raw_os_error() == Some(libc::EPERM),
+    }
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    false
+}
+
+// daemonize detaches from the controlling terminal by forking twice and
+// starting a new session. stdin is redirected to /dev/null, stdout and stderr
+// are appended to log_file (or /dev/null). Must be called before any threads
+// are started, i.e. before the async runtime is created.
+// The working directory is kept, as paths in the config may be relative.
+#[cfg(unix)]
+pub fn daemonize(log_file: Option<&Path>) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // open the files before forking, so errors are reported to the terminal
+    let null = fs::File::open("/dev/null")?;
+    let log = match log_file {
+        Some(log_file) => fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_file)?,
+        None => fs::OpenOptions::new().write(true).open("/dev/null")?,
+    };
+
+    fork_and_exit_parent()?;
+    // SAFETY: plain system call without pointers
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // fork again, so the daemon is no session leader and can't acquire a terminal
+    fork_and_exit_parent()?;
+
+    for (from, to) in [
+        (null.as_raw_fd(), libc::STDIN_FILENO),
+        (log.as_raw_fd(), libc::STDOUT_FILENO),
+        (log.as_raw_fd(), libc::STDERR_FILENO),
+    ] {
+        // SAFETY: both file descriptors are valid, dup2 closes `to` first
+        if unsafe { libc::dup2(from, to) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn fork_and_exit_parent() -> io::Result<()> {
+    // SAFETY: the process is still single-threaded, so forking is safe
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        // SAFETY: _exit terminates the parent without running destructors
+        // which could flush buffers shared with the child
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+#[cfg(not(unix))]
+pub fn daemonize(_log_file: Option<&Path>) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "daemonizing is only supported on unix",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pid_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("rustic-server.pid");
+        assert!(check_pid_file(&pid_file).is_ok());
+
+        write_pid_file(&pid_file).unwrap();
+        assert!(check_pid_file(&pid_file).is_err());
+
+        // stale PID file
+        fs::write(&pid_file, "999999999\n").unwrap();
+        assert!(check_pid_file(&pid_file).is_ok());
+        remove_pid_file(&pid_file).unwrap();
+        assert!(pid_file.exists());
+
+        write_pid_file(&pid_file).unwrap();
+        remove_pid_file(&pid_file).unwrap();
+        assert!(!pid_file.exists());
+    }
+}
@@ -0,0 +1,265 @@
+// mod discovery
+//
+// registers the server in Consul and announces it via multicast DNS
+// (DNS-SD service _restic-rest._tcp), so clients on the LAN find the backup
+// target without hard-coded addresses. Both advertise the address, whether
+// TLS is used with the fingerprint of the certificate and the supported
+// protocol versions. The Consul registration has a TTL check which is passed
+// while the server runs, so Consul removes the service a minute after the
+// server stopped. The mDNS announcement is made by the mdns-sd daemon, which
+// covers IPv4 and IPv6 and resolves name conflicts.
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use serde_json::json;
+
+use crate::config::DiscoveryConfig;
+use crate::info::PROTOCOLS;
+
+// RETRY is the time to wait before registering again after a failure
+const RETRY: Duration = Duration::from_secs(30);
+
+// CHECK_INTERVAL is the interval of passing the Consul TTL check
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+// SERVICE is the DNS-SD service type
+const SERVICE: &str = "_restic-rest._tcp.local.";
+
+// Service describes how clients reach the server
+#[derive(Clone, Debug)]
+pub struct Service {
+    // address of the listener, unspecified if listening on all interfaces
+    pub addr: SocketAddr,
+    pub tls: bool,
+    // SHA-256 fingerprint of the TLS certificate, if it is known at startup
+    pub fingerprint: Option<String>,
+    pub base_path: String,
+}
+
+impl Service {
+    // properties are the TXT entries and Consul service metadata
+    fn properties(&self) -> Vec<(&'static str, String)> {
+        let mut properties = vec![
+            ("version", env!("CARGO_PKG_VERSION").to_string()),
+            ("protocols", PROTOCOLS.join(",")),
+            ("tls", u8::from(self.tls).to_string()),
+        ];
+        if !self.base_path.is_empty() {
+            properties.push(("path", self.base_path.clone()));
+        }
+        if let Some(fingerprint) = &self.fingerprint {
+            properties.push(("fingerprint", fingerprint.clone()));
+        }
+        properties
+    }
+
+    // address returns the address to advertise, if any is known
+    fn address(&self, config: &DiscoveryConfig) -> Option<String> {
+        match &config.address {
+            Some(address) => Some(address.clone()),
+            None => (!self.addr.ip().is_unspecified()).then(|| self.addr.ip().to_string()),
+        }
+    }
+}
+
+// run registers and announces service as given by config until the server
+// stops
+pub async fn run(config: DiscoveryConfig, service: Service) {
+    if let Some(consul) = config.consul.clone() {
+        tokio::spawn(register(consul, config.clone(), service.clone()));
+    }
+    if config.mdns {
+        match announce(&config, &service) {
+            // keeps the daemon running while the server runs
+            Ok(_daemon) => std::future::pending().await,
+            Err(err) => tracing::warn!("cannot announce via mDNS: {err:#}"),
+        }
+    }
+}
+
+// register keeps service registered in the Consul agent at url
+async fn register(url: String, config: DiscoveryConfig, service: Service) {
+    let url = url.trim_end_matches('/');
+    let id = format!("{}-{}", config.name, service.addr.port());
+    let client = reqwest::Client::new();
+    let token = match config.read_consul_token() {
+        Ok(token) => token,
+        Err(err) => {
+            tracing::error!("cannot read the Consul token: {err:#}");
+            return;
+        }
+    };
+    let consul = |method, path: &str| {
+        let req = client.request(method, format!("{url}{path}"));
+        match &token {
+            Some(token) => req.header("X-Consul-Token", token),
+            None => req,
+        }
+    };
+    let registration = registration(&id, &config, &service);
+    let mut registered = false;
+    loop {
+        let res = match registered {
+            false => consul(reqwest::Method::PUT, "/v1/agent/service/register")
+                .json(&registration)
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status),
+            true => consul(
+                reqwest::Method::PUT,
+                &format!("/v1/agent/check/pass/service:{id}"),
+            )
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status),
+        };
+        match res {
+            Ok(_) if !registered => {
+                tracing::info!(url, id, "registered in Consul");
+                registered = true;
+            }
+            Ok(_) => {}
+            // e.g. the agent was restarted and lost the registration
+            Err(err) => {
+                tracing::warn!(url, "Consul agent not usable: {err}");
+                registered = false;
+            }
+        }
+        let wait = match registered {
+            true => CHECK_INTERVAL,
+            false => RETRY,
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+// registration is the service definition for the Consul agent API
+fn registration(id: &str, config: &DiscoveryConfig, service: &Service) -> serde_json::Value {
+    let tags: Vec<_> = ["restic"]
+        .into_iter()
+        .map(str::to_string)
+        .chain(PROTOCOLS.iter().map(|protocol| format!("rest-{protocol}")))
+        .collect();
+    let meta: serde_json::Map<_, _> = service
+        .properties()
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.into()))
+        .collect();
+    json!({
+        "ID": id,
+        "Name": config.name,
+        "Tags": tags,
+        "Address": service.address(config).unwrap_or_default(),
+        "Port": service.addr.port(),
+        "Meta": meta,
+        "Check": {
+            "CheckID": format!("service:{id}"),
+            "TTL": format!("{}s", 3 * CHECK_INTERVAL.as_secs()),
+            "DeregisterCriticalServiceAfter": "1m",
+        },
+    })
+}
+
+// announce registers service in an mDNS daemon, which answers queries on all
+// interfaces in a thread of its own until it is dropped
+fn announce(config: &DiscoveryConfig, service: &Service) -> Result<ServiceDaemon> {
+    let info = service_info(config, service)?;
+    let daemon = ServiceDaemon::new().context("cannot start the mDNS daemon")?;
+    daemon.register(info)?;
+    tracing::info!(name = config.name, "announcing via mDNS");
+    Ok(daemon)
+}
+
+// service_info describes service as DNS-SD instance; the host name is the
+// instance name reduced to letters, digits and hyphens
+fn service_info(config: &DiscoveryConfig, service: &Service) -> Result<ServiceInfo> {
+    let host: String = config
+        .name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_lowercase(),
+            false => '-',
+        })
+        .collect();
+    let host = format!("{}.local.", host.trim_matches('-'));
+    let properties: Vec<_> = std::iter::once(("txtvers", "1".to_string()))
+        .chain(service.properties())
+        .collect();
+    let ip: Option<IpAddr> = service.address(config).and_then(|a| a.parse().ok());
+    let info = ServiceInfo::new(
+        SERVICE,
+        &config.name,
+        &host,
+        ip.map(|ip| ip.to_string()).unwrap_or_default(),
+        service.addr.port(),
+        &properties[..],
+    )?;
+    // without a usable address the daemon advertises those of all interfaces
+    Ok(match ip {
+        Some(_) => info,
+        None => info.enable_addr_auto(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service() -> Service {
+        Service {
+            addr: "0.0.0.0:8000".parse().unwrap(),
+            tls: true,
+            fingerprint: Some("AB:CD".to_string()),
+            base_path: String::new(),
+        }
+    }
+
+    #[test]
+    fn mdns() {
+        let config = DiscoveryConfig {
+            name: "Backup NAS".to_string(),
+            ..DiscoveryConfig::default()
+        };
+        let info = service_info(&config, &service()).unwrap();
+        assert_eq!(info.get_fullname(), "Backup NAS._restic-rest._tcp.local.");
+        assert_eq!(info.get_hostname(), "backup-nas.local.");
+        assert_eq!(info.get_port(), 8000);
+        assert!(info.is_addr_auto());
+        assert_eq!(info.get_property_val_str("txtvers"), Some("1"));
+        assert_eq!(info.get_property_val_str("fingerprint"), Some("AB:CD"));
+
+        let config = DiscoveryConfig {
+            address: Some("192.0.2.1".to_string()),
+            ..config
+        };
+        let info = service_info(&config, &service()).unwrap();
+        assert!(!info.is_addr_auto());
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(info.get_addresses().contains(&ip));
+    }
+
+    #[test]
+    fn consul() {
+        let config = DiscoveryConfig {
+            consul: Some("http://127.0.0.1:8500".to_string()),
+            ..DiscoveryConfig::default()
+        };
+        let registration = registration("rustic-server-8000", &config, &service());
+        assert_eq!(registration["Name"], "rustic-server");
+        assert_eq!(registration["Address"], "");
+        assert_eq!(registration["Port"], 8000);
+        assert_eq!(
+            registration["Tags"],
+            json!(["restic", "rest-v1", "rest-v2"])
+        );
+        assert_eq!(registration["Meta"]["tls"], "1");
+        assert_eq!(registration["Meta"]["fingerprint"], "AB:CD");
+        assert_eq!(
+            registration["Check"]["CheckID"],
+            "service:rustic-server-8000"
+        );
+    }
+}
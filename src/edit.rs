@@ -0,0 +1,132 @@
+// mod edit
+//
+// changes the configuration files in place, keeping their comments; all
+// changes are serialized and can be rolled back
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use axum::http::StatusCode;
+use toml_edit::{Document, Item, Table, TableLike};
+
+use crate::helpers::{replace_file, write_private};
+use crate::storage::is_repo;
+use crate::web::{decompose_path, Error};
+
+// FILES serializes all changes of the configuration files
+static FILES: Mutex<()> = Mutex::new(());
+
+// lock must be held while reading and changing configuration files
+pub(crate) fn lock() -> MutexGuard<'static, ()> {
+    FILES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Transaction replaces files and restores their old content on rollback
+#[derive(Default)]
+pub(crate) struct Transaction {
+    replaced: Vec<(PathBuf, Option<String>)>,
+    // directory of the created repository
+    pub created: Option<PathBuf>,
+}
+
+impl Transaction {
+    pub fn replace(&mut self, file: &Path, content: &str, private: bool) -> io::Result<()> {
+        let old = match file.exists() {
+            true => Some(fs::read_to_string(file)?),
+            false => None,
+        };
+        match private {
+            true => write_private(file, content)?,
+            false => replace_file(file, content)?,
+        }
+        self.replaced.push((file.to_path_buf(), old));
+        Ok(())
+    }
+
+    pub fn rollback(self) {
+        if let Some(dir) = self.created {
+            if let Err(err) = fs::remove_dir_all(&dir) {
+                tracing::error!("cannot remove {}: {err}", dir.display());
+            }
+        }
+        for (file, old) in self.replaced.into_iter().rev() {
+            let res = match old {
+                Some(old) => replace_file(&file, &old),
+                None => fs::remove_file(&file),
+            };
+            if let Err(err) = res {
+                tracing::error!("cannot restore {}: {err}", file.display());
+            }
+        }
+    }
+}
+
+// repo_path checks and normalizes a repository path
+pub(crate) fn repo_path(repo: &str) -> Result<String, Error> {
+    let parts = decompose_path(repo)?;
+    match parts.tpe.is_none() && !parts.repo.is_empty() {
+        true => Ok(parts.repo),
+        false => Err(Error::new(
+            StatusCode::BAD_REQUEST,
+            format!("invalid repository path {repo:?}"),
+        )),
+    }
+}
+
+// check_not_nested fails if repo would lie within another repository of the
+// data directory, which restic couldn't tell apart from its files
+pub(crate) fn check_not_nested(data: &Path, repo: &str) -> Result<(), Error> {
+    match Path::new(repo)
+        .ancestors()
+        .skip(1)
+        .find(|p| is_repo(&data.join(p)))
+    {
+        Some(parent) => Err(conflict(format!(
+            "{repo} is within repository {}",
+            parent.display()
+        ))),
+        None => Ok(()),
+    }
+}
+
+// read_optional returns the content of file, which is empty if it doesn't exist
+pub(crate) fn read_optional(file: &Path) -> io::Result<String> {
+    match fs::read_to_string(file) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        res => res,
+    }
+}
+
+pub(crate) fn parse(file: &Path) -> Result<Document, Error> {
+    read_optional(file)?
+        .parse()
+        .map_err(|err| internal(format!("invalid file {}: {err}", file.display())))
+}
+
+// table returns the table key of parent, which is created if missing.
+// Implicit tables only show up as part of the headers of their subtables.
+pub(crate) fn table<'a>(
+    parent: &'a mut dyn TableLike,
+    key: &str,
+    implicit: bool,
+) -> Result<&'a mut dyn TableLike, Error> {
+    parent
+        .entry(key)
+        .or_insert_with(|| {
+            let mut table = Table::new();
+            table.set_implicit(implicit);
+            Item::Table(table)
+        })
+        .as_table_like_mut()
+        .ok_or_else(|| internal(format!("{key} is no table")))
+}
+
+pub(crate) fn conflict(message: impl Into<String>) -> Error {
+    Error::new(StatusCode::CONFLICT, message)
+}
+
+pub(crate) fn internal(err: impl ToString) -> Error {
+    Error::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+}
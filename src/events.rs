@@ -0,0 +1,163 @@
+// mod events
+//
+// streams structured server events to admins as Server-Sent Events at
+// /admin/events, so dashboards and scripts can react without polling. Only
+// the last RECENT events are kept in memory; subscribers which can't keep up
+// miss events and get a "lagged" event telling how many.
+
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use axum::extract;
+use axum::response::sse::{self, KeepAlive, Sse};
+use axum::routing::get;
+use axum::Router;
+use futures_util::Stream;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::admin::AdminFromRequest;
+use crate::stats::format_time;
+use crate::web::State;
+
+// CAPACITY is the number of events buffered for each subscriber
+const CAPACITY: usize = 1024;
+
+// RECENT is the number of events kept for the status page
+const RECENT: usize = 50;
+
+// KEEP_ALIVE is the interval of comments keeping idle connections open
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+pub fn router() -> Router<State> {
+    Router::new().route("/admin/events", get(events))
+}
+
+// ServerEvent describes something which happened on the server; empty
+// fields are left out
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ServerEvent {
+    pub event: &'static str,
+    // RFC 3339 timestamp
+    pub time: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub repo: String,
+    #[serde(rename = "type", skip_serializing_if = "String::is_empty")]
+    pub tpe: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub user: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub message: String,
+}
+
+impl ServerEvent {
+    pub fn new(event: &'static str) -> Self {
+        Self {
+            event,
+            time: format_time(SystemTime::now()),
+            ..Self::default()
+        }
+    }
+}
+
+// Events distributes the events to all subscribers and keeps the recent ones
+#[derive(Clone)]
+pub struct Events {
+    sender: broadcast::Sender<ServerEvent>,
+    recent: Arc<Mutex<VecDeque<ServerEvent>>>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+            recent: Arc::default(),
+        }
+    }
+}
+
+impl Events {
+    // publish sends event to the current subscribers, if any
+    pub fn publish(&self, event: ServerEvent) {
+        {
+            let mut recent = self.lock();
+            if recent.len() == RECENT {
+                _ = recent.pop_back();
+            }
+            recent.push_front(event.clone());
+        }
+        _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.sender.subscribe()
+    }
+
+    // recent returns the last published events, newest first
+    pub fn recent(&self) -> Vec<ServerEvent> {
+        self.lock().iter().cloned().collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, VecDeque<ServerEvent>> {
+        self.recent.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// events streams the events until the client disconnects
+async fn events(
+    extract::State(state): extract::State<State>,
+    admin: AdminFromRequest,
+) -> Sse<impl Stream<Item = Result<sse::Event, Infallible>>> {
+    tracing::debug!(admin = admin.user, "subscribed to events");
+    let receiver = state.events().subscribe();
+    let stream = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => ServerEvent {
+                message: format!("{missed} events were dropped"),
+                ..ServerEvent::new("lagged")
+            },
+            Err(RecvError::Closed) => return None,
+        };
+        let sse = sse::Event::default().event(event.event);
+        let sse = sse
+            .json_data(&event)
+            .unwrap_or_else(|_| sse::Event::default());
+        Some((Ok(sse), receiver))
+    });
+    Sse::new(stream).keep_alive(KeepAlive::new().interval(KEEP_ALIVE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn events() {
+        let events = Events::default();
+        // no subscribers
+        events.publish(ServerEvent::new("upload"));
+
+        let mut receiver = events.subscribe();
+        events.publish(ServerEvent {
+            repo: "alice".to_string(),
+            ..ServerEvent::new("delete")
+        });
+        let event = receiver.recv().await.unwrap();
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["event"], "delete");
+        assert_eq!(json["repo"], "alice");
+        assert!(json.get("user").is_none());
+
+        for _ in 0..RECENT {
+            events.publish(ServerEvent::new("upload"));
+        }
+        let recent = events.recent();
+        assert_eq!(recent.len(), RECENT);
+        assert!(recent.iter().all(|event| event.event == "upload"));
+    }
+}
@@ -0,0 +1,150 @@
+// mod geoip
+//
+// allows or denies requests by the country of the client IP, looked up in a
+// MaxMind DB file like GeoLite2-Country. Operators expecting backups only
+// from a few countries can cut off scanners elsewhere before they reach the
+// authentication. The whole file is kept in memory.
+
+use std::net::IpAddr;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use maxminddb::{geoip2, Reader};
+
+use crate::config::GeoIpConfig;
+
+// Database is a MaxMind DB file
+pub struct Database(Reader<Vec<u8>>);
+
+impl Database {
+    pub fn open(path: &Path) -> Result<Self> {
+        let reader = Reader::open_readfile(path)
+            .with_context(|| format!("invalid MaxMind DB {}", path.display()))?;
+        Ok(Self(reader))
+    }
+
+    // country returns the ISO code of the country of ip, if it's known
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        // fails for unknown networks and IPv6 addresses in IPv4 databases
+        let record = self.0.lookup::<geoip2::Country>(ip).ok()?;
+        [record.country, record.registered_country]
+            .into_iter()
+            .find_map(|country| country?.iso_code)
+            .map(str::to_string)
+    }
+}
+
+// Filter decides by the country of the client IP whether to serve a request
+pub struct Filter {
+    db: Database,
+    config: GeoIpConfig,
+}
+
+impl Filter {
+    // new returns None if no database is configured
+    pub fn new(config: &GeoIpConfig) -> Result<Option<Self>> {
+        let Some(path) = &config.database else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            db: Database::open(path)?,
+            config: config.clone(),
+        }))
+    }
+
+    // allows returns whether requests from ip are served and its country
+    pub fn allows(&self, ip: IpAddr) -> (bool, Option<String>) {
+        let country = self.db.country(ip);
+        let allowed = match &country {
+            Some(country) if self.config.deny_countries.contains(country) => false,
+            Some(country) => {
+                self.config.allow_countries.is_empty()
+                    || self.config.allow_countries.contains(country)
+            }
+            None => self.config.allow_unknown,
+        };
+        (allowed, country)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    // database returns an IPv4 database mapping 1.0.0.0/8 to DE
+    fn database() -> Vec<u8> {
+        let node_count = 8;
+        let mut data = Vec::new();
+        // the path of 00000001: left to the next node, the last bit right
+        for node in 0..node_count {
+            let (left, right) = match node {
+                7 => (node_count, node_count + 16),
+                _ => (node + 1, node_count),
+            };
+            data.extend_from_slice(&u32::to_be_bytes(left)[1..]);
+            data.extend_from_slice(&u32::to_be_bytes(right)[1..]);
+        }
+        data.extend_from_slice(&[0; 16]);
+        // {"country": {"iso_code": "DE"}}
+        data.extend_from_slice(b"\xe1\x47country\xe1\x48iso_code\x42DE");
+        data.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+        // the metadata map with string keys, unsigned integers, an empty
+        // description map and an empty languages array
+        data.push(0xe9);
+        let fields: [(&str, &[u8]); 9] = [
+            ("binary_format_major_version", b"\xa1\x02"),
+            ("binary_format_minor_version", b"\xa0"),
+            ("build_epoch", b"\x00\x02"),
+            ("database_type", b"\x50GeoLite2-Country"),
+            ("description", b"\xe0"),
+            ("ip_version", b"\xa1\x04"),
+            ("languages", b"\x00\x04"),
+            ("node_count", b"\xc1\x08"),
+            ("record_size", b"\xa1\x18"),
+        ];
+        for (key, value) in fields {
+            data.push(0x40 | u8::try_from(key.len()).unwrap());
+            data.extend_from_slice(key.as_bytes());
+            data.extend_from_slice(value);
+        }
+        data
+    }
+
+    #[test]
+    fn country() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("country.mmdb");
+        fs::write(&path, database()).unwrap();
+        let db = Database::open(&path).unwrap();
+        assert_eq!(
+            db.country("1.2.3.4".parse().unwrap()).as_deref(),
+            Some("DE")
+        );
+        assert_eq!(db.country("2.2.3.4".parse().unwrap()), None);
+        assert_eq!(db.country("::1".parse().unwrap()), None);
+        let garbage = dir.path().join("garbage.mmdb");
+        fs::write(&garbage, b"garbage").unwrap();
+        assert!(Database::open(&garbage).is_err());
+
+        let mut config = GeoIpConfig {
+            database: Some(path),
+            allow_countries: vec!["DE".to_string()],
+            ..GeoIpConfig::default()
+        };
+        let filter = Filter::new(&config).unwrap().unwrap();
+        assert_eq!(
+            filter.allows("1.0.0.1".parse().unwrap()),
+            (true, Some("DE".to_string()))
+        );
+        // not in the database
+        assert!(filter.allows("10.0.0.1".parse().unwrap()).0);
+        config.allow_countries.clear();
+        config.deny_countries = vec!["DE".to_string()];
+        config.allow_unknown = false;
+        let filter = Filter::new(&config).unwrap().unwrap();
+        assert!(!filter.allows("1.0.0.1".parse().unwrap()).0);
+        assert!(!filter.allows("10.0.0.1".parse().unwrap()).0);
+    }
+}
@@ -0,0 +1,136 @@
+// mod ha
+//
+// coordinates active-passive instances sharing the storage by a lease file:
+// the leader renews its lease every third of ha.lease_seconds, a standby
+// takes over once the lease expired. Only the leader accepts writes and runs
+// the background cleanups; standbys serve reads. A lease file instead of a
+// file lock works on network filesystems without lock support, but the
+// clocks of the instances must be in sync.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::HaConfig;
+use crate::events::ServerEvent;
+use crate::web::State;
+
+// CONFIRM is the time to wait after taking over before checking that no other
+// standby took over at the same time
+const CONFIRM: Duration = Duration::from_secs(1);
+
+// Lease is the content of the lease file
+#[derive(Debug, PartialEq)]
+struct Lease {
+    node: String,
+    // seconds since the epoch
+    expires: u64,
+}
+
+impl Lease {
+    fn read(path: &Path) -> Option<Self> {
+        let content = fs::read_to_string(path).ok()?;
+        let (node, expires) = content.trim().rsplit_once(' ')?;
+        Some(Self {
+            node: node.to_string(),
+            expires: expires.parse().ok()?,
+        })
+    }
+
+    // write replaces the lease file atomically
+    fn write(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension(format!("tmp-{}", std::process::id()));
+        fs::write(&tmp, format!("{} {}\n", self.node, self.expires))?;
+        fs::rename(&tmp, path).inspect_err(|_| _ = fs::remove_file(&tmp))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// node returns the name of this instance in the lease file
+fn node(config: &HaConfig) -> String {
+    match &config.node {
+        Some(node) => node.clone(),
+        None => format!("{:016x}", rand::random::<u64>()),
+    }
+}
+
+// run keeps or acquires the lease until the server stops
+pub async fn run(state: State, config: HaConfig) {
+    let Some(path) = config.lease_file.clone() else {
+        return;
+    };
+    let node = node(&config);
+    let renew = Duration::from_secs(config.lease_seconds / 3).max(CONFIRM);
+    tracing::info!(node, "starting as standby");
+    loop {
+        let leader = acquire(&path, &node, config.lease_seconds).await;
+        if leader != state.is_leader() {
+            let event = match leader {
+                true => "leader",
+                false => "standby",
+            };
+            tracing::warn!(node, "this instance is now the {event}");
+            state.events().publish(ServerEvent {
+                message: format!("node {node}"),
+                ..ServerEvent::new(event)
+            });
+            state.set_leader(leader);
+        }
+        tokio::time::sleep(renew).await;
+    }
+}
+
+// acquire renews the lease of node or takes over an expired one and returns
+// whether node holds the lease
+async fn acquire(path: &Path, node: &str, lease_seconds: u64) -> bool {
+    let current = Lease::read(path);
+    let ours = current.as_ref().is_some_and(|lease| lease.node == node);
+    if !ours && current.is_some_and(|lease| lease.expires > now()) {
+        return false;
+    }
+    let lease = Lease {
+        node: node.to_string(),
+        expires: now() + lease_seconds,
+    };
+    if let Err(err) = lease.write(path) {
+        tracing::error!(?path, "cannot write lease file: {err}");
+        return false;
+    }
+    if ours {
+        return true;
+    }
+    tokio::time::sleep(CONFIRM).await;
+    Lease::read(path).is_some_and(|lease| lease.node == node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lease() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".leader");
+        assert!(acquire(&path, "a", 30).await);
+        assert_eq!(Lease::read(&path).unwrap().node, "a");
+        // the lease of a is still valid
+        assert!(!acquire(&path, "b", 30).await);
+        assert!(acquire(&path, "a", 30).await);
+
+        Lease {
+            node: "a".to_string(),
+            expires: now() - 1,
+        }
+        .write(&path)
+        .unwrap();
+        assert!(acquire(&path, "b", 30).await);
+        assert!(!acquire(&path, "a", 30).await);
+    }
+}
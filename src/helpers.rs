@@ -2,68 +2,195 @@
 use std::fs;
 use std::path::PathBuf;
 
-use async_std::fs::{File, OpenOptions};
-use async_std::io::{self, Write};
+use std::io;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
+use super::listing::ListingCache;
 use super::web::Finalizer;
 
-// helper struct which is like a async_std::fs::File but removes the file
+// helper struct which is like a tokio::fs::File but removes the file
 // if finalize() was not called.
 pub struct WriteOrDeleteFile {
-    file: File,
+    file: Writer,
     path: PathBuf,
     finalized: bool,
+    // listing of the directory to invalidate once the file is complete or removed
+    listing: Option<(ListingCache, PathBuf)>,
+}
+
+// Writer is the file written to
+enum Writer {
+    File(File),
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    Uring(crate::uring::Writer),
 }
 
 impl WriteOrDeleteFile {
     pub async fn new(file_path: PathBuf) -> io::Result<Self> {
-        Ok(Self {
-            file: OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .open(&file_path)
-                .await?,
-            path: file_path,
+        let file = Self::create(&file_path).await?;
+        Ok(Self::with(Writer::File(file), file_path))
+    }
+
+    // uring is like new, but the file is written through io_uring if it's
+    // available
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    pub async fn uring(file_path: PathBuf) -> io::Result<Self> {
+        let Some(ring) = crate::uring::Ring::get() else {
+            return Self::new(file_path).await;
+        };
+        let file = Self::create(&file_path).await?.into_std().await;
+        let writer = crate::uring::Writer::new(ring, file);
+        Ok(Self::with(Writer::Uring(writer), file_path))
+    }
+
+    async fn create(file_path: &std::path::Path) -> io::Result<File> {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(file_path)
+            .await
+    }
+
+    fn with(file: Writer, path: PathBuf) -> Self {
+        Self {
+            file,
+            path,
             finalized: false,
-        })
+            listing: None,
+        }
+    }
+
+    // invalidating makes the file invalidate the cached listing of dir when
+    // it is finalized or removed
+    pub fn invalidating(mut self, listings: ListingCache, dir: PathBuf) -> Self {
+        self.listing = Some((listings, dir));
+        self
+    }
+
+    fn invalidate(&self) {
+        if let Some((listings, dir)) = &self.listing {
+            listings.invalidate(dir);
+        }
     }
 }
 
 #[async_trait::async_trait]
 impl Finalizer for WriteOrDeleteFile {
     async fn finalize(&mut self) -> io::Result<()> {
-        self.file.sync_all().await?;
+        match &mut self.file {
+            Writer::File(file) => {
+                file.flush().await?;
+                file.sync_all().await?;
+            }
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Writer::Uring(writer) => {
+                writer.flush().await?;
+                writer.sync_all().await?;
+            }
+        }
         self.finalized = true;
+        self.invalidate();
         Ok(())
     }
 }
 
-impl Write for WriteOrDeleteFile {
+impl AsyncWrite for WriteOrDeleteFile {
     fn poll_write(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
-        Pin::new(&mut self.get_mut().file).poll_write(cx, buf)
+        match &mut self.get_mut().file {
+            Writer::File(file) => Pin::new(file).poll_write(cx, buf),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Writer::Uring(writer) => Pin::new(writer).poll_write(cx, buf),
+        }
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        Pin::new(&mut self.get_mut().file).poll_flush(cx)
+        match &mut self.get_mut().file {
+            Writer::File(file) => Pin::new(file).poll_flush(cx),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Writer::Uring(writer) => Pin::new(writer).poll_flush(cx),
+        }
     }
 
-    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        Pin::new(&mut self.get_mut().file).poll_close(cx)
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().file {
+            Writer::File(file) => Pin::new(file).poll_shutdown(cx),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Writer::Uring(writer) => Pin::new(writer).poll_shutdown(cx),
+        }
     }
 }
 
 impl Drop for WriteOrDeleteFile {
     fn drop(&mut self) {
         if !self.finalized {
-            // ignore errors
-            fs::remove_file(&self.path).unwrap_or(());
+            // ignore errors; within the runtime, don't block it
+            let path = std::mem::take(&mut self.path);
+            let listing = self.listing.take();
+            let remove = move || {
+                fs::remove_file(path).unwrap_or(());
+                if let Some((listings, dir)) = listing {
+                    listings.invalidate(&dir);
+                }
+            };
+            match tokio::runtime::Handle::try_current() {
+                Ok(handle) => _ = handle.spawn_blocking(remove),
+                Err(_) => remove(),
+            }
+        }
+    }
+}
+
+// used by HashingWriter
+use ring::digest::{Context as HashContext, Digest, SHA256};
+
+// helper struct passing writes to the inner writer and computing the SHA-256
+// hash of the written bytes on the way, if enabled
+pub struct HashingWriter<W> {
+    inner: W,
+    hash: Option<HashContext>,
+}
+
+impl<W> HashingWriter<W> {
+    pub fn new(inner: W, enabled: bool) -> Self {
+        Self {
+            inner,
+            hash: enabled.then(|| HashContext::new(&SHA256)),
+        }
+    }
+
+    // finish returns the inner writer and the hash, if enabled
+    pub fn finish(self) -> (W, Option<Digest>) {
+        (self.inner, self.hash.map(HashContext::finish))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for HashingWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(n)), Some(hash)) = (&res, &mut this.hash) {
+            hash.update(&buf[..*n]);
         }
+        res
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
     }
 }
 
@@ -92,3 +219,132 @@ where
         serializer.collect_seq(self.0.borrow_mut().by_ref())
     }
 }
+
+// used by diff_maps
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+
+// Change describes how an entry differs between two maps
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Added => "added",
+            Self::Removed => "removed",
+            Self::Changed => "changed",
+        })
+    }
+}
+
+// diff_maps returns the keys which differ between old and new, sorted by key
+pub fn diff_maps<'a, K, V>(old: &'a HashMap<K, V>, new: &'a HashMap<K, V>) -> Vec<(&'a K, Change)>
+where
+    K: Eq + Hash + Ord,
+    V: PartialEq,
+{
+    let mut changes: Vec<_> = old
+        .iter()
+        .filter_map(|(k, v)| match new.get(k) {
+            None => Some((k, Change::Removed)),
+            Some(new_v) if new_v != v => Some((k, Change::Changed)),
+            Some(_) => None,
+        })
+        .chain(
+            new.keys()
+                .filter(|k| !old.contains_key(*k))
+                .map(|k| (k, Change::Added)),
+        )
+        .collect();
+    changes.sort_by_key(|(k, _)| *k);
+    changes
+}
+
+// tmp_path returns the temporary file a new version of file is written to.
+// The whole name is kept, so key.pem and key.crt don't share one.
+fn tmp_path(file: &std::path::Path) -> PathBuf {
+    let mut name = file.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    file.with_file_name(name)
+}
+
+// replace_file atomically replaces file by content, keeping its permissions
+pub fn replace_file(file: &std::path::Path, content: &str) -> io::Result<()> {
+    let tmp = tmp_path(file);
+    fs::write(&tmp, content)?;
+    if let Ok(metadata) = fs::metadata(file) {
+        fs::set_permissions(&tmp, metadata.permissions())?;
+    }
+    fs::rename(tmp, file)
+}
+
+// write_private atomically replaces file by content, which is only readable by the owner.
+// The temporary file is created with these permissions, so the content is never
+// readable by others, and removed if anything fails.
+pub fn write_private(file: &std::path::Path, content: &str) -> io::Result<()> {
+    use std::io::Write;
+
+    let tmp = tmp_path(file);
+    // a file left over might be readable by others, only a new file gets the mode
+    match fs::remove_file(&tmp) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut f = options.open(&tmp)?;
+    let written = f
+        .write_all(content.as_bytes())
+        .and_then(|()| f.sync_all())
+        .and_then(|()| fs::rename(&tmp, file));
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("key.pem");
+        fs::write(dir.path().join("key.pem.tmp"), "stale").unwrap();
+        write_private(&file, "secret").unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "secret");
+        assert!(!dir.path().join("key.pem.tmp").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&file).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // a failed rename doesn't leave the temporary file behind
+        let dir_file = dir.path().join("dir.pem");
+        fs::create_dir_all(dir_file.join("x")).unwrap();
+        assert!(write_private(&dir_file, "secret").is_err());
+        assert!(!dir.path().join("dir.pem.tmp").exists());
+    }
+
+    #[test]
+    fn tmp_paths() {
+        let dir = std::path::Path::new("/etc/tls");
+        assert_eq!(tmp_path(&dir.join("key.pem")), dir.join("key.pem.tmp"));
+        assert_eq!(tmp_path(&dir.join("key.crt")), dir.join("key.crt.tmp"));
+        assert_eq!(tmp_path(&dir.join(".htpasswd")), dir.join(".htpasswd.tmp"));
+    }
+}
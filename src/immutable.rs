@@ -0,0 +1,117 @@
+// mod immutable
+//
+// makes the files of a repository immutable for a retention period after
+// they were written (WORM), so a compromised client can't destroy backups.
+// Unlike retention_days of the config, it is set at runtime and can't be
+// shortened by the server afterwards.
+
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::storage::Storage;
+use crate::web::TYPES;
+
+// IMMUTABLE_MARKER holds the Immutability of a repository as JSON
+pub const IMMUTABLE_MARKER: &str = ".immutable";
+
+// Immutability protects the files of the given types for days after they
+// were written; no types means all types. Lock files are never protected.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Immutability {
+    pub days: u64,
+    #[serde(default)]
+    pub types: Vec<String>,
+}
+
+impl Immutability {
+    // read returns the immutability of the repository at path, if any
+    pub fn read(storage: &dyn Storage, path: &Path) -> Option<Self> {
+        let json = storage.marker(path, IMMUTABLE_MARKER)?;
+        match serde_json::from_str(&json) {
+            Ok(immutability) => Some(immutability),
+            Err(err) => {
+                tracing::error!(repo = ?path, "invalid {IMMUTABLE_MARKER}: {err}");
+                // fail safe: protect everything
+                Some(Self {
+                    days: u64::MAX,
+                    types: Vec::new(),
+                })
+            }
+        }
+    }
+
+    // validate checks days and types
+    pub fn validate(&self) -> Result<(), String> {
+        if self.days == 0 {
+            return Err("days must be at least 1".to_string());
+        }
+        match self
+            .types
+            .iter()
+            .find(|tpe| *tpe == "locks" || !(TYPES.contains(&tpe.as_str()) || *tpe == "config"))
+        {
+            Some(tpe) => Err(format!("type {tpe:?} can't be made immutable")),
+            None => Ok(()),
+        }
+    }
+
+    // covers returns whether files of type tpe are protected
+    pub fn covers(&self, tpe: &str) -> bool {
+        tpe != "locks" && (self.types.is_empty() || self.types.iter().any(|t| t == tpe))
+    }
+
+    // protects returns whether a file of type tpe written at modified may
+    // not be deleted
+    pub fn protects(&self, tpe: &str, modified: SystemTime) -> bool {
+        let days = Duration::from_secs(self.days.saturating_mul(24 * 60 * 60));
+        self.covers(tpe) && modified.elapsed().unwrap_or_default() < days
+    }
+
+    // weakens returns whether replacing self by new would protect less
+    pub fn weakens(&self, new: &Self) -> bool {
+        new.days < self.days
+            || (!new.types.is_empty()
+                && (self.types.is_empty() || self.types.iter().any(|t| !new.types.contains(t))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn immutability() {
+        let data = Immutability {
+            days: 30,
+            types: vec!["data".to_string(), "snapshots".to_string()],
+        };
+        assert!(data.validate().is_ok());
+        assert!(data.covers("data") && !data.covers("keys") && !data.covers("locks"));
+        let all = Immutability {
+            days: 30,
+            types: Vec::new(),
+        };
+        assert!(all.covers("keys") && !all.covers("locks"));
+        let locks = Immutability {
+            days: 1,
+            types: vec!["locks".to_string()],
+        };
+        assert!(locks.validate().is_err());
+
+        let day = Duration::from_secs(24 * 60 * 60);
+        assert!(data.protects("data", SystemTime::now() - 29 * day));
+        assert!(!data.protects("data", SystemTime::now() - 31 * day));
+        assert!(!data.protects("keys", SystemTime::now()));
+
+        assert!(!data.weakens(&all));
+        assert!(all.weakens(&data));
+        assert!(data.weakens(&Immutability {
+            days: 30,
+            types: vec!["data".to_string()]
+        }));
+        assert!(data.weakens(&Immutability { days: 7, ..all }));
+    }
+}
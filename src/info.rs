@@ -0,0 +1,75 @@
+// mod info
+//
+// describes the server below /api/info, so clients and monitoring can adapt
+// to the deployment
+
+use axum::extract;
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::Serialize;
+
+use crate::web::State;
+
+// PROTOCOLS are the supported versions of the REST protocol
+pub const PROTOCOLS: [&str; 2] = ["v1", "v2"];
+
+pub fn router() -> Router<State> {
+    Router::new().route("/api/info", get(info))
+}
+
+#[derive(Serialize)]
+struct Info {
+    version: &'static str,
+    protocols: [&'static str; 2],
+    features: Features,
+    limits: Limits,
+}
+
+#[derive(Serialize)]
+struct Features {
+    authentication: bool,
+    tls: bool,
+    // standard ACL of repositories without explicit ACL
+    append_only: bool,
+    private_repos: bool,
+    // any quota is configured
+    quotas: bool,
+    verification: bool,
+}
+
+#[derive(Serialize)]
+struct Limits {
+    requests_per_second: Option<f64>,
+    burst: u32,
+    upload_bandwidth: Option<u64>,
+    download_bandwidth: Option<u64>,
+    max_requests: Option<usize>,
+    storage_quota: Option<u64>,
+}
+
+// info is available without authentication
+async fn info(extract::State(state): extract::State<State>) -> Json<Info> {
+    let config = state.config();
+    Json(Info {
+        version: env!("CARGO_PKG_VERSION"),
+        protocols: PROTOCOLS,
+        features: Features {
+            authentication: !config.auth.disable,
+            tls: config.uses_tls(),
+            append_only: config.acl.append_only,
+            private_repos: config.acl.private_repo,
+            quotas: config.storage.quota.is_some()
+                || config.repos.values().any(|repo| repo.quota.is_some())
+                || config.users.values().any(|user| user.quota.is_some()),
+            verification: config.verify.interval_days.is_some(),
+        },
+        limits: Limits {
+            requests_per_second: config.limits.requests_per_second,
+            burst: config.limits.burst,
+            upload_bandwidth: config.limits.upload_bandwidth,
+            download_bandwidth: config.limits.download_bandwidth,
+            max_requests: config.limits.max_requests,
+            storage_quota: config.storage.quota,
+        },
+    })
+}
@@ -0,0 +1,174 @@
+// mod janitor
+//
+// removes leftovers in the background: the stale lock files of all
+// repositories if locks.auto_remove is set, and temporary files older than
+// janitor.temp_max_age_hours, i.e. the directories of imports and the partial
+// downloads of the upstream cache a crash left behind. With janitor.dry_run
+// the files are only logged, to check the rules before enforcing them.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use walkdir::WalkDir;
+
+use crate::archive::IMPORT_PREFIX;
+use crate::config::Config;
+use crate::locks::{remove_stale_locks, stale_locks};
+use crate::web::State;
+
+// INTERVAL is how often the janitor runs
+const INTERVAL: Duration = Duration::from_secs(600);
+
+fn hours(hours: u64) -> Duration {
+    Duration::from_secs(hours * 60 * 60)
+}
+
+// run cleans up periodically until the server stops
+pub async fn run(state: State) {
+    let mut interval = tokio::time::interval(INTERVAL);
+    loop {
+        _ = interval.tick().await;
+        if !state.is_primary() {
+            continue;
+        }
+        let state = state.clone();
+        _ = tokio::task::spawn_blocking(move || {
+            let config = state.config();
+            if config.locks.auto_remove {
+                clean_locks(&state, &config);
+            }
+            if let Some(max_age) = config.janitor.temp_max_age_hours {
+                clean_temp_files(&config, hours(max_age));
+            }
+        })
+        .await;
+    }
+}
+
+fn clean_locks(state: &State, config: &Config) {
+    let max_age = hours(config.locks.max_age_hours);
+    for repo in state.storage().repos() {
+        if config.janitor.dry_run {
+            match stale_locks(state.storage(), &repo, max_age) {
+                Ok(locks) if locks.is_empty() => {}
+                Ok(locks) => tracing::info!(repo, ?locks, "dry run: would remove stale locks"),
+                Err(err) => tracing::error!(repo, "cannot list stale locks: {err}"),
+            }
+            continue;
+        }
+        match remove_stale_locks(state.storage(), state.usage(), &repo, max_age) {
+            Ok(removed) if removed.locks.is_empty() => {}
+            Ok(removed) => tracing::info!(repo, locks = ?removed.locks, "removed stale locks"),
+            Err(err) => tracing::error!(repo, "cannot remove stale locks: {err}"),
+        }
+    }
+}
+
+fn clean_temp_files(config: &Config, max_age: Duration) {
+    for path in temp_files(config, max_age) {
+        if config.janitor.dry_run {
+            tracing::info!(?path, "dry run: would remove temporary file");
+            continue;
+        }
+        let res = match path.is_dir() {
+            true => fs::remove_dir_all(&path),
+            false => fs::remove_file(&path),
+        };
+        match res {
+            Ok(()) => tracing::info!(?path, "removed temporary file"),
+            Err(err) => tracing::warn!(?path, "cannot remove temporary file: {err}"),
+        }
+    }
+}
+
+// temp_files returns the temporary files and directories which weren't
+// modified within max_age
+fn temp_files(config: &Config, max_age: Duration) -> Vec<PathBuf> {
+    let imports = fs::read_dir(&config.storage.path)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(IMPORT_PREFIX)
+        })
+        .map(|entry| entry.path());
+    let downloads = config
+        .upstream
+        .cache_dir
+        .iter()
+        .flat_map(|dir| WalkDir::new(dir).into_iter().flatten())
+        .filter(|entry| {
+            entry.file_type().is_file() && entry.path().extension() == Some("part".as_ref())
+        })
+        .map(walkdir::DirEntry::into_path);
+    imports
+        .chain(downloads)
+        .filter(|path| older(path, max_age).unwrap_or(false))
+        .collect()
+}
+
+fn older(path: &Path, max_age: Duration) -> io::Result<bool> {
+    Ok(fs::metadata(path)?
+        .modified()?
+        .elapsed()
+        .unwrap_or_default()
+        >= max_age)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::time::SystemTime;
+
+    #[test]
+    fn temp() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("data");
+        let cache = dir.path().join("cache");
+        fs::create_dir_all(data.join(".import-0123/keys")).unwrap();
+        fs::create_dir_all(data.join(".import-4567")).unwrap();
+        fs::create_dir_all(data.join("repo/keys")).unwrap();
+        fs::create_dir_all(cache.join("repo/data/00")).unwrap();
+        fs::write(cache.join("repo/data/00/00ab.part"), "x").unwrap();
+        fs::write(cache.join("repo/data/00/00cd"), "x").unwrap();
+        let day_ago = SystemTime::now() - hours(24);
+        for old in [".import-0123", "repo"] {
+            File::open(data.join(old))
+                .unwrap()
+                .set_modified(day_ago)
+                .unwrap();
+        }
+        for old in ["00ab.part", "00cd"] {
+            File::options()
+                .write(true)
+                .open(cache.join("repo/data/00").join(old))
+                .unwrap()
+                .set_modified(day_ago)
+                .unwrap();
+        }
+
+        let mut config = Config::default();
+        config.storage.path = data.clone();
+        config.upstream.cache_dir = Some(cache.clone());
+        config.janitor.dry_run = true;
+        let expected = [
+            data.join(".import-0123"),
+            cache.join("repo/data/00/00ab.part"),
+        ];
+        assert_eq!(temp_files(&config, hours(1)), expected);
+        clean_temp_files(&config, hours(1));
+        assert!(expected.iter().all(|path| path.exists()));
+
+        config.janitor.dry_run = false;
+        clean_temp_files(&config, hours(1));
+        assert!(!expected.iter().any(|path| path.exists()));
+        assert!(data.join(".import-4567").exists());
+        assert!(data.join("repo").exists());
+    }
+}
@@ -1,23 +1,131 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 
 pub mod acl;
+pub mod acme;
+pub mod activity;
+pub mod admin;
+pub mod archive;
 pub mod auth;
+pub mod bench;
+pub mod check;
+pub mod concurrency;
+pub mod config;
+pub mod confirm;
+pub mod connection;
+pub mod daemon;
+pub mod discovery;
+pub mod edit;
+pub mod events;
+pub mod geoip;
+pub mod ha;
 pub mod helpers;
+pub mod immutable;
+pub mod info;
+pub mod janitor;
+pub mod listing;
+pub mod locks;
+pub mod logging;
+pub mod mail;
+pub mod metrics;
+pub mod migrate;
+pub mod mqtt;
+pub mod privileges;
+pub mod profile;
+pub mod progress;
+pub mod proxy;
+pub mod quota;
+pub mod ratelimit;
+pub mod redis;
+pub mod rename;
+pub mod runtime;
+pub mod schedule;
+pub mod stats;
+pub mod status;
 pub mod storage;
+pub mod systemd;
+pub mod tenant;
+pub mod throttle;
+pub mod tls;
+pub mod uploads;
+pub mod upstream;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
+pub mod values;
+pub mod vault;
+pub mod verify;
+pub mod versions;
 pub mod web;
+pub mod webhook;
+
+pub use config::Config;
+pub use web::State;
+
+/// Builds the router serving the REST API, to embed the server into another
+/// axum application or to run it with custom middleware.
+///
+/// The repositories are stored below `config.storage.path`, users and ACLs are
+/// read from the files referenced by `config`. Unlike running `rustic-server`
+/// without a subcommand, no listeners, TLS, signal handlers or background
+/// tasks (verification, lock cleanup, email reports, MQTT) are started; use
+/// [`State::from_config`] and [`web::router`] to keep the state at hand for
+/// these. Per-IP rate limits need the peer address, so serve the router with
+/// `into_make_service_with_connect_info::<SocketAddr>()`.
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// use std::net::SocketAddr;
+///
+/// let config = rustic_server::Config::from_file("rustic_server.toml".as_ref())?;
+/// let app = axum::Router::new().nest("/backup", rustic_server::router(&config)?);
+/// let listener = tokio::net::TcpListener::bind("127.0.0.1:8000").await?;
+/// axum::serve(
+///     listener,
+///     app.into_make_service_with_connect_info::<SocketAddr>(),
+/// )
+/// .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn router(config: &Config) -> anyhow::Result<axum::Router> {
+    Ok(web::router(State::from_config(config)?))
+}
 
 /// A REST server build in rust for use with restic
 #[derive(Parser)]
 #[command(name = "rustic-server")]
 #[command(bin_name = "rustic-server")]
 pub struct Opts {
-    /// listen address
-    #[arg(short, long, default_value = "localhost:8000")]
-    pub listen: String,
-    /// data directory
-    #[arg(short, long, default_value = "/tmp/restic")]
-    pub path: PathBuf,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// config file (rustic_server.toml); options given on the command line take precedence
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
+    /// listen address, can be given multiple times; prefix with http:// or https:// to choose
+    /// plaintext or TLS per address [default: localhost:8000]
+    #[arg(short, long)]
+    pub listen: Vec<String>,
+    /// seconds running requests may take to finish on shutdown [default: 30]
+    #[arg(long)]
+    pub shutdown_timeout: Option<u64>,
+    /// user to switch to after binding the listen addresses and reading the TLS certificate
+    #[arg(long)]
+    pub user: Option<String>,
+    /// group to switch to, defaults to the primary group of --user
+    #[arg(long)]
+    pub group: Option<String>,
+    /// run in the background, detached from the terminal
+    #[arg(long)]
+    pub daemonize: bool,
+    /// file to write the process id to; refuses to start if the process in it is still running
+    #[arg(long)]
+    pub pid_file: Option<PathBuf>,
+    /// file to append output to when running with --daemonize [default: /dev/null]
+    #[arg(long)]
+    pub log_file: Option<PathBuf>,
+    /// data directory [default: /tmp/restic]
+    #[arg(short, long)]
+    pub path: Option<PathBuf>,
     /// disable .htpasswd authentication
     #[arg(long)]
     pub no_auth: bool,
@@ -35,11 +143,241 @@ pub struct Opts {
     pub tls: bool,
     /// TLS certificate path
     #[arg(long)]
-    pub cert: Option<String>,
+    pub cert: Option<PathBuf>,
     /// TLS key path
     #[arg(long)]
-    pub key: Option<String>,
-    /// logging level (Off/Error/Warn/Info/Debug/Trace)
-    #[arg(long, default_value = "Info")]
-    pub log: tide::log::LevelFilter,
+    pub key: Option<PathBuf>,
+    /// logging filter, e.g. "info" or "warn,rustic_server=debug" (see tracing_subscriber::EnvFilter) [default: info]
+    #[arg(long)]
+    pub log_filter: Option<String>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Manage the server configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Manage TLS certificates
+    #[command(subcommand)]
+    Cert(CertCommand),
+    /// Generate configuration files for the data directory of a restic rest-server
+    Migrate(MigrateOpts),
+    /// Verify the files of repositories in the data directory against their hashes
+    Check(CheckOpts),
+    /// Print size, file counts and last modification of repositories
+    Stats(StatsOpts),
+    /// List repositories which weren't read or written for a number of days
+    Idle(IdleOpts),
+    /// Send restic-like requests to a running server and report their latency
+    Bench(BenchOpts),
+    /// Manage repositories in the data directory
+    #[command(subcommand)]
+    Repo(RepoCommand),
+    /// Print shell completions to stdout
+    Completions {
+        /// shell to generate completions for
+        shell: clap_complete::Shell,
+    },
+}
+
+#[derive(clap::Args)]
+pub struct CheckOpts {
+    /// repositories to check, relative to the data directory [default: all]
+    pub repos: Vec<String>,
+    /// only check names and sizes, don't read the file contents
+    #[arg(long)]
+    pub quick: bool,
+}
+
+#[derive(clap::Args)]
+pub struct StatsOpts {
+    /// repositories to show, relative to the data directory [default: all]
+    pub repos: Vec<String>,
+    /// print JSON instead of a table
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(clap::Args)]
+pub struct IdleOpts {
+    /// number of days without activity
+    #[arg(long, default_value_t = 7)]
+    pub days: u64,
+    /// only count uploads and deletions as activity, not reads
+    #[arg(long)]
+    pub writes_only: bool,
+    /// print JSON instead of a table
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(clap::Args)]
+pub struct BenchOpts {
+    /// URL of the repository to use, e.g. http://localhost:8000/bench; it is created if missing
+    pub url: String,
+    /// user to authenticate as
+    #[arg(long)]
+    pub user: Option<String>,
+    /// password of the user
+    #[arg(long, env = "RUSTIC_SERVER_PASSWORD", hide_env_values = true)]
+    pub password: Option<String>,
+    /// file containing the password of the user
+    #[arg(long, conflicts_with = "password")]
+    pub password_file: Option<PathBuf>,
+    /// number of clients sending requests at the same time
+    #[arg(long, default_value_t = 8)]
+    pub concurrency: usize,
+    /// seconds to send requests for
+    #[arg(long, default_value_t = 10)]
+    pub duration: u64,
+    /// maximum size of uploaded packs in bytes; sizes are random between a quarter and this
+    #[arg(long, default_value_t = 16 << 20)]
+    pub pack_size: usize,
+    /// keep the uploaded packs instead of deleting them afterwards
+    #[arg(long)]
+    pub keep: bool,
+    /// accept invalid TLS certificates, e.g. self-signed ones
+    #[arg(long)]
+    pub insecure: bool,
+    /// print JSON instead of a table
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(clap::Args)]
+pub struct MigrateOpts {
+    /// data directory of the rest-server (its --path); the data stays in place
+    pub data: PathBuf,
+    /// htpasswd file of the rest-server [default: <DATA>/.htpasswd]
+    #[arg(long)]
+    pub htpasswd_file: Option<PathBuf>,
+    /// the rest-server runs with --private-repos
+    #[arg(long)]
+    pub private_repos: bool,
+    /// the rest-server runs with --append-only
+    #[arg(long)]
+    pub append_only: bool,
+    /// the rest-server runs with --no-auth
+    #[arg(long)]
+    pub no_auth: bool,
+    /// directory to write rustic_server.toml, acl.toml and .htpasswd to
+    #[arg(long, default_value = ".")]
+    pub dir: PathBuf,
+    /// overwrite existing files
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Check config file, ACL and htpasswd file for errors and inconsistencies
+    Validate,
+    /// Generate a commented rustic_server.toml, an empty acl.toml and optionally a htpasswd file
+    Init(InitOpts),
+}
+
+#[derive(Subcommand)]
+pub enum RepoCommand {
+    /// Reject all writes to a repository, e.g. during an investigation; takes effect immediately
+    Freeze {
+        /// repository, relative to the data directory
+        repo: String,
+        /// reason sent to clients trying to write
+        #[arg(long, default_value = "")]
+        reason: String,
+    },
+    /// Allow writes to a frozen repository again
+    Unfreeze {
+        /// repository, relative to the data directory
+        repo: String,
+    },
+    /// Deny deleting files of a repository, e.g. while a client is suspected to be compromised; takes effect immediately
+    AppendOnly {
+        /// repository, relative to the data directory
+        repo: String,
+        /// reason sent to clients trying to delete
+        #[arg(long, default_value = "")]
+        reason: String,
+        /// allow deleting files again
+        #[arg(long, conflicts_with = "reason")]
+        off: bool,
+    },
+    /// Write a repository as tar archive, leaving out nested repositories
+    Export {
+        /// repository, relative to the data directory
+        repo: String,
+        /// file to write the archive to; "-" writes to stdout
+        #[arg(short, long, default_value = "-")]
+        output: PathBuf,
+    },
+    /// Create a repository from a tar archive written by export; all files are checked first
+    Import {
+        /// repository to create, relative to the data directory
+        repo: String,
+        /// file to read the archive from; "-" reads from stdin
+        #[arg(short, long, default_value = "-")]
+        input: PathBuf,
+    },
+    /// Protect the files of a repository from deletion for a number of days after they were written
+    Immutable {
+        /// repository, relative to the data directory
+        repo: String,
+        /// number of days; can be extended later, but not reduced
+        #[arg(long)]
+        days: u64,
+        /// types of files to protect, e.g. data or snapshots [default: all]
+        #[arg(long = "type")]
+        types: Vec<String>,
+    },
+    /// Rename or move a repository and update the ACL file and the config file accordingly
+    Rename {
+        /// repository, relative to the data directory
+        from: String,
+        /// new path, relative to the data directory
+        to: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CertCommand {
+    /// Generate a self-signed certificate and key and print its fingerprint
+    Generate(CertGenerateOpts),
+}
+
+#[derive(clap::Args)]
+pub struct CertGenerateOpts {
+    /// hostnames and IP addresses the certificate is valid for
+    #[arg(default_value = "localhost")]
+    pub hosts: Vec<String>,
+    /// file to write the certificate to
+    #[arg(long, default_value = "cert.pem")]
+    pub cert: PathBuf,
+    /// file to write the key to
+    #[arg(long, default_value = "key.pem")]
+    pub key: PathBuf,
+    /// number of days the certificate is valid
+    #[arg(long, default_value_t = 365)]
+    pub days: u32,
+    /// overwrite existing files
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(clap::Args)]
+pub struct InitOpts {
+    /// directory to write the files to
+    #[arg(long, default_value = ".")]
+    pub dir: PathBuf,
+    /// overwrite existing files
+    #[arg(long)]
+    pub force: bool,
+    /// create a htpasswd file containing this user
+    #[arg(long)]
+    pub admin_user: Option<String>,
+    /// password for the admin user; a random password is generated and printed if not given
+    #[arg(long, env = "RUSTIC_SERVER_ADMIN_PASSWORD", hide_env_values = true)]
+    pub admin_password: Option<String>,
+    /// file containing the password for the admin user
+    #[arg(long, requires = "admin_user", conflicts_with = "admin_password")]
+    pub admin_password_file: Option<PathBuf>,
 }
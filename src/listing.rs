@@ -0,0 +1,180 @@
+// mod listing
+//
+// caches the file listings of type directories. restic lists the index and
+// snapshots several times during one operation, which re-scans directories
+// holding up to hundreds of thousands of files. A cached listing is used as
+// long as the modification times of the scanned directories are unchanged;
+// changes made through the storage invalidate it right away, as the
+// modification time of a directory doesn't change while a file in it is written.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::SystemTime;
+
+use serde::Serialize;
+use walkdir::WalkDir;
+
+// MAX_FILES is the maximum number of files of all cached listings; the cache
+// is cleared when it would be exceeded
+const MAX_FILES: usize = 1_000_000;
+
+// FileEntry is a file within a type directory
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FileEntry {
+    pub name: String,
+    pub size: u64,
+}
+
+struct Listing {
+    // the scanned directories with their modification times
+    dirs: Vec<(PathBuf, SystemTime)>,
+    files: Arc<Vec<FileEntry>>,
+}
+
+impl Listing {
+    fn is_current(&self) -> bool {
+        self.dirs.iter().all(|(dir, modified)| {
+            fs::metadata(dir).and_then(|m| m.modified()).ok() == Some(*modified)
+        })
+    }
+}
+
+#[derive(Default)]
+struct Cache {
+    listings: HashMap<PathBuf, Listing>,
+    files: usize,
+}
+
+// ListingCache holds the listings per type directory
+#[derive(Clone, Default)]
+pub struct ListingCache(Arc<Mutex<Cache>>);
+
+impl ListingCache {
+    // list returns the files below dir sorted by name; files removed while
+    // scanning and names which aren't UTF-8 are left out. A missing dir has no files.
+    pub fn list(&self, dir: &Path) -> io::Result<Arc<Vec<FileEntry>>> {
+        if let Some(listing) = self.lock().listings.get(dir) {
+            if listing.is_current() {
+                return Ok(listing.files.clone());
+            }
+        }
+        // scan without holding the lock
+        let listing = match scan(dir) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Arc::default()),
+            res => res?,
+        };
+        let files = listing.files.clone();
+        let mut cache = self.lock();
+        if let Some(old) = cache.listings.remove(dir) {
+            cache.files -= old.files.len();
+        }
+        if cache.files + files.len() > MAX_FILES {
+            *cache = Cache::default();
+        }
+        cache.files += files.len();
+        _ = cache.listings.insert(dir.to_path_buf(), listing);
+        Ok(files)
+    }
+
+    // invalidate forgets the listing of dir after a file in it was changed
+    pub fn invalidate(&self, dir: &Path) {
+        let mut cache = self.lock();
+        if let Some(old) = cache.listings.remove(dir) {
+            cache.files -= old.files.len();
+        }
+    }
+
+    // invalidate_below forgets the listings of all directories below dir,
+    // e.g. of a removed repository
+    pub fn invalidate_below(&self, dir: &Path) {
+        let mut cache = self.lock();
+        let cache = &mut *cache;
+        cache.listings.retain(|listed, listing| {
+            let keep = !listed.starts_with(dir);
+            if !keep {
+                cache.files -= listing.files.len();
+            }
+            keep
+        });
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Cache> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// scan lists the files below dir; the modification times of the directories
+// are taken before reading them, so changes during the scan aren't missed
+fn scan(dir: &Path) -> io::Result<Listing> {
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) if err.depth() == 0 => return Err(err.into()),
+            // removed while scanning
+            Err(_) => continue,
+        };
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            dirs.push((entry.path().to_path_buf(), metadata.modified()?));
+        } else if metadata.is_file() {
+            if let Some(name) = entry.file_name().to_str() {
+                files.push(FileEntry {
+                    name: name.to_string(),
+                    size: metadata.len(),
+                });
+            }
+        }
+    }
+    Ok(Listing {
+        dirs,
+        files: Arc::new(files),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn listing() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("data");
+        let cache = ListingCache::default();
+        assert!(cache.list(&data).unwrap().is_empty());
+
+        fs::create_dir_all(data.join("ab")).unwrap();
+        fs::write(data.join("ab/abcd"), "1234").unwrap();
+        let files = cache.list(&data).unwrap();
+        assert_eq!(
+            *files,
+            [FileEntry {
+                name: "abcd".to_string(),
+                size: 4
+            }]
+        );
+        assert!(Arc::ptr_eq(&files, &cache.list(&data).unwrap()));
+
+        // changes of the size are only seen after invalidating
+        fs::write(data.join("ab/abcd"), "12").unwrap();
+        assert_eq!(cache.list(&data).unwrap()[0].size, 4);
+        cache.invalidate(&data);
+        assert_eq!(cache.list(&data).unwrap()[0].size, 2);
+
+        // new files change the modification time of their directory; wait
+        // for the coarse timestamps of some filesystems
+        std::thread::sleep(Duration::from_millis(20));
+        fs::write(data.join("ab/abef"), "").unwrap();
+        assert_eq!(cache.list(&data).unwrap().len(), 2);
+
+        cache.invalidate_below(dir.path());
+        assert_eq!(cache.lock().files, 0);
+    }
+}
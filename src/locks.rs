@@ -0,0 +1,97 @@
+// mod locks
+//
+// removes stale lock files: clients which crashed or lost their connection
+// leave their locks behind, which block the next prune or check of the
+// repository. Running clients refresh their locks every few minutes, so a
+// lock which wasn't modified for long is stale. With locks.auto_remove, mod
+// janitor removes them periodically.
+
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::quota::Usage;
+use crate::storage::Storage;
+
+// RemovedLocks lists the lock files removed from a repository
+#[derive(Debug, Serialize)]
+pub struct RemovedLocks {
+    pub repo: String,
+    pub locks: Vec<String>,
+}
+
+// stale_locks returns the names of the lock files of repo which weren't
+// modified within max_age
+pub fn stale_locks(
+    storage: &dyn Storage,
+    repo: &str,
+    max_age: Duration,
+) -> io::Result<Vec<String>> {
+    let mut locks = Vec::new();
+    for entry in storage.read_dir(Path::new(repo), "locks") {
+        if entry.metadata()?.modified()?.elapsed().unwrap_or_default() >= max_age {
+            locks.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    locks.sort();
+    Ok(locks)
+}
+
+// remove_stale_locks removes the lock files of repo which weren't modified
+// within max_age
+pub fn remove_stale_locks(
+    storage: &dyn Storage,
+    usage: &Usage,
+    repo: &str,
+    max_age: Duration,
+) -> io::Result<RemovedLocks> {
+    let path = Path::new(repo);
+    let locks = stale_locks(storage, repo, max_age)?;
+    for name in &locks {
+        let len = storage.filename(path, "locks", name).metadata()?.len();
+        storage.remove_file(path, "locks", name)?;
+        usage.add(repo, -i64::try_from(len).unwrap_or(i64::MAX));
+    }
+    Ok(RemovedLocks {
+        repo: repo.to_string(),
+        locks,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LocalStorage;
+    use std::fs::{self, File};
+    use std::time::SystemTime;
+
+    #[test]
+    fn stale_locks() {
+        let dir = tempfile::tempdir().unwrap();
+        let locks = dir.path().join("repo/locks");
+        fs::create_dir_all(&locks).unwrap();
+        fs::write(locks.join("fresh"), "lock").unwrap();
+        fs::write(locks.join("stale"), "lock").unwrap();
+        let day_ago = SystemTime::now() - Duration::from_secs(24 * 60 * 60);
+        File::options()
+            .write(true)
+            .open(locks.join("stale"))
+            .unwrap()
+            .set_modified(day_ago)
+            .unwrap();
+        let storage = LocalStorage::try_new(dir.path()).unwrap();
+
+        let removed = remove_stale_locks(
+            &storage,
+            &Usage::default(),
+            "repo",
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+        assert_eq!(removed.locks, ["stale"]);
+        assert!(locks.join("fresh").exists());
+        assert!(!locks.join("stale").exists());
+    }
+}
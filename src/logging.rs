@@ -0,0 +1,35 @@
+// mod logging
+//
+// sets up tracing and allows to change the log filter at runtime
+
+use std::io::IsTerminal;
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, Result};
+use tracing_subscriber::{
+    fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry,
+};
+
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+// init installs the global tracing subscriber using the given filter
+pub fn init(filter: &str) -> Result<()> {
+    let (filter, handle) = reload::Layer::new(EnvFilter::try_new(filter)?);
+    tracing_subscriber::registry()
+        .with(filter)
+        // no colors if writing to a file, e.g. when daemonized
+        .with(fmt::layer().with_ansi(std::io::stdout().is_terminal()))
+        .try_init()?;
+    FILTER
+        .set(handle)
+        .map_err(|_| anyhow!("logging is already initialized"))
+}
+
+// set_filter replaces the log filter of the subscriber installed by init
+pub fn set_filter(filter: &str) -> Result<()> {
+    let filter = EnvFilter::try_new(filter)?;
+    match FILTER.get() {
+        Some(handle) => Ok(handle.reload(filter)?),
+        None => Err(anyhow!("logging is not initialized")),
+    }
+}
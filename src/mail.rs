@@ -0,0 +1,279 @@
+// mod mail
+//
+// sends email notifications about critical events via SMTP, for setups
+// without a metrics stack: deleted repositories, exceeded quotas, problems
+// found by verifications and repositories without backups. Each event is
+// mailed at most once per MIN_INTERVAL and repository, so a client retrying
+// a failing upload doesn't flood the inbox.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::extension::ClientId;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+use crate::activity::{format_table, idle_repos};
+use crate::config::MailConfig;
+use crate::web::State;
+
+// MIN_INTERVAL is the minimum time between mails about the same event and repository
+const MIN_INTERVAL: Duration = Duration::from_secs(3600);
+
+// TIMEOUT is the maximum time sending a mail may take
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// REPORT_INTERVAL is how often repositories without backups are reported
+const REPORT_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Mailer sends the notifications with the current mail configuration
+#[derive(Clone, Default)]
+pub struct Mailer {
+    config: Arc<RwLock<MailConfig>>,
+    sent: Arc<Mutex<HashMap<(&'static str, String), Instant>>>,
+}
+
+impl Mailer {
+    pub fn set_config(&self, config: MailConfig) {
+        *self.config.write().unwrap_or_else(PoisonError::into_inner) = config;
+    }
+
+    fn config(&self) -> MailConfig {
+        self.config
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    // send mails about event of repo in the background; failures are only logged
+    pub fn send(&self, event: &'static str, repo: &str, subject: &str, body: &str) {
+        let config = self.config();
+        if config.smtp_server.is_none() || !self.due(event, repo) {
+            return;
+        }
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let (subject, body) = (subject.to_string(), body.to_string());
+        let repo = repo.to_string();
+        _ = handle.spawn(async move {
+            let sent = tokio::time::timeout(TIMEOUT, deliver(&config, &subject, &body)).await;
+            match sent {
+                Ok(Ok(())) => tracing::debug!(event, repo, "notification mailed"),
+                Ok(Err(err)) => tracing::warn!(event, repo, "cannot send mail: {err:#}"),
+                Err(_) => tracing::warn!(event, repo, "cannot send mail: timeout"),
+            }
+        });
+    }
+
+    // due returns whether event of repo wasn't mailed within MIN_INTERVAL;
+    // suppressed mails don't postpone the next one
+    fn due(&self, event: &'static str, repo: &str) -> bool {
+        let mut sent = self.sent.lock().unwrap_or_else(PoisonError::into_inner);
+        sent.retain(|_, last| last.elapsed() < MIN_INTERVAL);
+        match sent.entry((event, repo.to_string())) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                _ = entry.insert(Instant::now());
+                true
+            }
+        }
+    }
+}
+
+// schedule reports the repositories without writes within mail.idle_days
+// once a day, starting an hour after the start
+pub async fn schedule(state: State) {
+    let start = tokio::time::Instant::now() + Duration::from_secs(3600);
+    let mut interval = tokio::time::interval_at(start, REPORT_INTERVAL);
+    loop {
+        _ = interval.tick().await;
+        let Some(days) = state.config().mail.idle_days.filter(|_| state.is_primary()) else {
+            continue;
+        };
+        let max_idle = Duration::from_secs(days * 24 * 60 * 60);
+        let storage = state.clone();
+        let idle = tokio::task::spawn_blocking(move || {
+            let storage = storage.storage();
+            idle_repos(storage, &storage.repos(), max_idle, true)
+        })
+        .await
+        .unwrap_or_default();
+        if !idle.is_empty() {
+            state.mailer().send(
+                "idle",
+                "",
+                &format!("{} repositories without backup for {days} days", idle.len()),
+                &format!(
+                    "These repositories weren't written within {days} days:\n\n{}",
+                    format_table(&idle)
+                ),
+            );
+        }
+    }
+}
+
+// deliver sends a mail to all recipients
+async fn deliver(config: &MailConfig, subject: &str, body: &str) -> Result<()> {
+    let server = config.smtp_server.as_deref().context("no SMTP server")?;
+    let (host, port) = server
+        .rsplit_once(':')
+        .with_context(|| format!("SMTP server {server:?} has no port"))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = port.parse().context("invalid SMTP port")?;
+    let builder = match config.smtp_tls.as_str() {
+        "tls" => AsyncSmtpTransport::<Tokio1Executor>::relay(host)?,
+        "starttls" => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?,
+        _ => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+    };
+    let mut builder = builder
+        .port(port)
+        .hello_name(ClientId::Domain(helo_name(config).to_string()))
+        .timeout(Some(TIMEOUT));
+    if let (Some(user), Some(password)) = (&config.smtp_user, config.read_smtp_password()?) {
+        builder = builder.credentials(Credentials::new(user.clone(), password));
+    }
+    _ = builder
+        .build()
+        .send(message(config, subject, body)?)
+        .await
+        .with_context(|| format!("cannot send mail via {server}"))?;
+    Ok(())
+}
+
+// helo_name returns the domain of the sender to introduce the client
+fn helo_name(config: &MailConfig) -> &str {
+    config
+        .from
+        .rsplit_once('@')
+        .map_or("localhost", |(_, domain)| domain)
+}
+
+// message returns the mail; non-ASCII subjects are encoded as RFC 2047
+// encoded words and bodies as quoted-printable or base64 as needed
+fn message(config: &MailConfig, subject: &str, body: &str) -> Result<Message> {
+    let from = config.from.parse().context("invalid sender address")?;
+    let subject = subject.replace(|c: char| c.is_control(), " ");
+    let mut builder = Message::builder()
+        .from(Mailbox::new(Some("rustic-server".to_string()), from))
+        .subject(format!("[rustic-server] {subject}"))
+        .header(ContentType::TEXT_PLAIN);
+    for to in &config.to {
+        let to = to
+            .parse()
+            .with_context(|| format!("invalid recipient address {to:?}"))?;
+        builder = builder.to(Mailbox::new(None, to));
+    }
+    Ok(builder.body(body.to_string())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::prelude::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn due() {
+        let mailer = Mailer::default();
+        assert!(mailer.due("quota", "alice"));
+        assert!(!mailer.due("quota", "alice"));
+        assert!(mailer.due("quota", "bob"));
+        assert!(mailer.due("deleted", "alice"));
+    }
+
+    // an event recurring more often than MIN_INTERVAL is mailed again once
+    // MIN_INTERVAL passed since the last mail
+    #[test]
+    fn recurring() {
+        let mailer = Mailer::default();
+        let key = ("quota", "alice".to_string());
+        assert!(mailer.due("quota", "alice"));
+        let first = Instant::now() - MIN_INTERVAL + Duration::from_secs(60);
+        _ = mailer.sent.lock().unwrap().insert(key.clone(), first);
+        assert!(!mailer.due("quota", "alice"));
+        assert_eq!(mailer.sent.lock().unwrap()[&key], first);
+        let expired = Instant::now() - MIN_INTERVAL - Duration::from_secs(1);
+        _ = mailer.sent.lock().unwrap().insert(key, expired);
+        assert!(mailer.due("quota", "alice"));
+    }
+
+    #[tokio::test]
+    async fn smtp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = MailConfig {
+            smtp_server: Some(listener.local_addr().unwrap().to_string()),
+            smtp_tls: "none".to_string(),
+            smtp_user: Some("user".to_string()),
+            smtp_password: Some("pw".to_string()),
+            to: vec![
+                "admin@example.com".to_string(),
+                "ops@example.com".to_string(),
+            ],
+            ..MailConfig::default()
+        };
+
+        // a minimal SMTP server recording the commands
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut commands = Vec::new();
+            stream.get_mut().write_all(b"220 ready\r\n").await.unwrap();
+            let mut data = false;
+            loop {
+                let mut line = String::new();
+                if stream.read_line(&mut line).await.unwrap() == 0 {
+                    break;
+                }
+                let reply: &[u8] = match line.as_str() {
+                    ".\r\n" if data => {
+                        data = false;
+                        b"250 queued\r\n"
+                    }
+                    _ if data => b"",
+                    line if line.starts_with("EHLO") => b"250-hello\r\n250 AUTH PLAIN\r\n",
+                    line if line.starts_with("AUTH") => b"235 ok\r\n",
+                    "DATA\r\n" => {
+                        data = true;
+                        b"354 go ahead\r\n"
+                    }
+                    "QUIT\r\n" => b"221 bye\r\n",
+                    _ => b"250 ok\r\n",
+                };
+                commands.push(line);
+                stream.get_mut().write_all(reply).await.unwrap();
+            }
+            commands
+        });
+
+        deliver(&config, "repository café deleted", "line\n.dot")
+            .await
+            .unwrap();
+        let commands = server.await.unwrap();
+        assert_eq!(commands[0], "EHLO localhost\r\n");
+        assert_eq!(
+            commands[1],
+            format!("AUTH PLAIN {}\r\n", BASE64_STANDARD.encode("\0user\0pw"))
+        );
+        assert_eq!(commands[2], "MAIL FROM:<rustic-server@localhost>\r\n");
+        assert_eq!(commands[4], "RCPT TO:<ops@example.com>\r\n");
+        // the subject is sent as RFC 2047 encoded word
+        let subject = commands
+            .iter()
+            .find(|line| line.starts_with("Subject: "))
+            .unwrap();
+        assert!(
+            subject.is_ascii() && subject.contains("=?utf-8?"),
+            "{subject}"
+        );
+        assert!(commands.contains(&"..dot\r\n".to_string()));
+        assert_eq!(commands[commands.len() - 1], "QUIT\r\n");
+    }
+}
@@ -0,0 +1,107 @@
+// mod metrics
+//
+// counts failed authentication attempts and exposes the counters in the
+// Prometheus text format at /admin/metrics, so brute-force attempts show up
+// in monitoring. Each failure is also logged as warning in a fixed form
+// fail2ban can match, see config/fail2ban.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use axum::extract;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+
+use crate::admin::AdminFromRequest;
+use crate::web::State;
+
+pub fn router() -> Router<State> {
+    Router::new().route("/admin/metrics", get(metrics))
+}
+
+// AuthFailure is the reason authentication failed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthFailure {
+    // no or malformed credentials
+    MissingCredentials,
+    UnknownUser,
+    WrongPassword,
+}
+
+impl AuthFailure {
+    const ALL: [Self; 3] = [
+        Self::MissingCredentials,
+        Self::UnknownUser,
+        Self::WrongPassword,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::MissingCredentials => "missing_credentials",
+            Self::UnknownUser => "unknown_user",
+            Self::WrongPassword => "wrong_password",
+        }
+    }
+}
+
+// Metrics holds the counters of the server
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    auth_failures: Arc<[AtomicU64; 3]>,
+}
+
+impl Metrics {
+    pub fn auth_failed(&self, reason: AuthFailure) {
+        self.auth_failures[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // render returns the counters in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut text = String::from(
+            "# HELP rustic_server_auth_failures_total Failed authentication attempts.\n\
+             # TYPE rustic_server_auth_failures_total counter\n",
+        );
+        for reason in AuthFailure::ALL {
+            let count = self.auth_failures[reason as usize].load(Ordering::Relaxed);
+            _ = writeln!(
+                text,
+                "rustic_server_auth_failures_total{{reason=\"{}\"}} {count}",
+                reason.as_str()
+            );
+        }
+        text
+    }
+}
+
+async fn metrics(
+    extract::State(state): extract::State<State>,
+    admin: AdminFromRequest,
+) -> impl IntoResponse {
+    tracing::debug!(admin = admin.user, "metrics");
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics().render(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+        metrics.auth_failed(AuthFailure::WrongPassword);
+        metrics.clone().auth_failed(AuthFailure::WrongPassword);
+        metrics.auth_failed(AuthFailure::UnknownUser);
+        let text = metrics.render();
+        assert!(text.contains("rustic_server_auth_failures_total{reason=\"wrong_password\"} 2\n"));
+        assert!(text.contains("rustic_server_auth_failures_total{reason=\"unknown_user\"} 1\n"));
+        assert!(
+            text.contains("rustic_server_auth_failures_total{reason=\"missing_credentials\"} 0\n")
+        );
+    }
+}
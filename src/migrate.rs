@@ -0,0 +1,197 @@
+// mod migrate
+//
+// imports the data directory of a restic rest-server. The repository layout is
+// the same, so the data stays in place; htpasswd file and the semantics of
+// --private-repos are translated into rustic-server configuration files.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::storage::find_repos;
+use anyhow::{bail, Context, Result};
+
+// MigrateSource describes the rest-server installation to import
+pub struct MigrateSource<'a> {
+    pub data: &'a Path,
+    pub htpasswd: Option<&'a Path>,
+    pub private_repos: bool,
+    pub append_only: bool,
+    pub no_auth: bool,
+}
+
+// Migration is the result of a migration
+#[derive(Debug)]
+pub struct Migration {
+    pub files: Vec<PathBuf>,
+    pub repos: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+// convert_htpasswd keeps all entries with a hash format supported by
+// rustic-server. Returns the new content, the kept users and a warning for
+// each dropped entry
+pub fn convert_htpasswd(content: &str) -> (String, Vec<String>, Vec<String>) {
+    let mut out = String::new();
+    let mut users = Vec::new();
+    let mut warnings = Vec::new();
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(':') {
+            Some((user, hash)) if crate::auth::is_supported_hash(hash) => {
+                out.push_str(line);
+                out.push('\n');
+                users.push(user.to_string());
+            }
+            Some((user, _)) => warnings.push(format!(
+                "user {user} dropped: unsupported password hash, set a new password with `htpasswd -B`"
+            )),
+            None => warnings.push(format!("invalid htpasswd line {line:?} dropped")),
+        }
+    }
+    (out, users, warnings)
+}
+
+// private_repos_acl translates rest-server's --private-repos: each user may
+// access the repository named like the user and all repositories below it
+pub fn private_repos_acl(
+    repos: &[String],
+    users: &[String],
+    append_only: bool,
+) -> BTreeMap<String, BTreeMap<String, &'static str>> {
+    // rest-server lets users create their repositories even in append-only mode
+    let access = if append_only { "Create" } else { "Modify" };
+    let mut acl = BTreeMap::new();
+    for user in users {
+        for repo in repos {
+            if repo == user || repo.starts_with(&format!("{user}/")) {
+                _ = acl
+                    .entry(repo.clone())
+                    .or_insert_with(BTreeMap::new)
+                    .insert(user.clone(), access);
+            }
+        }
+    }
+    acl
+}
+
+// migrate writes rustic_server.toml, acl.toml and .htpasswd for the rest-server
+// installation source into dir. Existing files are only overwritten if force is set.
+pub fn migrate(source: &MigrateSource<'_>, dir: &Path, force: bool) -> Result<Migration> {
+    if !source.data.is_dir() {
+        bail!("{} is not a directory", source.data.display());
+    }
+    let data = source
+        .data
+        .canonicalize()
+        .with_context(|| format!("cannot access {}", source.data.display()))?;
+    let repos = find_repos(&data);
+    let mut warnings = Vec::new();
+
+    let config_file = dir.join("rustic_server.toml");
+    let acl_file = dir.join("acl.toml");
+    let htpasswd_file = dir.join(".htpasswd");
+
+    let mut config = Config::default();
+    config.storage.path.clone_from(&data);
+    config.acl.append_only = source.append_only;
+    config.acl.private_repo = source.private_repos;
+    config.acl.path = Some(acl_file.clone());
+    config.auth.disable = source.no_auth;
+
+    let mut files = Vec::new();
+    let mut users = Vec::new();
+    if !source.no_auth {
+        let htpasswd = source
+            .htpasswd
+            .map_or_else(|| data.join(".htpasswd"), Path::to_path_buf);
+        let content = fs::read_to_string(&htpasswd)
+            .with_context(|| format!("cannot read htpasswd file {}", htpasswd.display()))?;
+        let (content, found, dropped) = convert_htpasswd(&content);
+        warnings.extend(dropped);
+        users = found;
+        config.auth.htpasswd = Some(htpasswd_file.clone());
+        files.push((htpasswd_file, content));
+    }
+
+    let mut acl = String::from("# generated by `rustic-server migrate`\n");
+    if source.private_repos {
+        acl.push_str(&toml::to_string(&private_repos_acl(
+            &repos,
+            &users,
+            source.append_only,
+        ))?);
+        if repos.iter().any(|repo| repo.contains('/')) {
+            warnings.push(
+                "repositories below a user's repository which are created later need an entry in acl.toml".to_string(),
+            );
+        }
+    }
+    files.insert(0, (acl_file, acl));
+    files.insert(0, (config_file, config.to_commented_toml()));
+
+    if !force {
+        if let Some((file, _)) = files.iter().find(|(file, _)| file.exists()) {
+            bail!(
+                "{} already exists, use --force to overwrite",
+                file.display()
+            );
+        }
+    }
+    fs::create_dir_all(dir)
+        .with_context(|| format!("cannot create directory {}", dir.display()))?;
+    for (file, content) in &files {
+        fs::write(file, content).with_context(|| format!("cannot write {}", file.display()))?;
+    }
+    Ok(Migration {
+        files: files.into_iter().map(|(file, _)| file).collect(),
+        repos,
+        warnings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::{AccessType, Acl, AclChecker};
+
+    #[test]
+    fn migrate_private_repos() {
+        let data = tempfile::tempdir().unwrap();
+        for repo in ["alice", "alice/laptop", "bob", "shared"] {
+            fs::create_dir_all(data.path().join(repo).join("keys")).unwrap();
+            fs::write(data.path().join(repo).join("config"), "").unwrap();
+        }
+        fs::write(
+            data.path().join(".htpasswd"),
+            "alice:{SHA}xxx\nbob:$2y$05$xxx\ncarol:plaintext\n",
+        )
+        .unwrap();
+
+        let out = tempfile::tempdir().unwrap();
+        let source = MigrateSource {
+            data: data.path(),
+            htpasswd: None,
+            private_repos: true,
+            append_only: true,
+            no_auth: false,
+        };
+        let migration = migrate(&source, out.path(), false).unwrap();
+        assert_eq!(migration.repos, ["alice", "alice/laptop", "bob", "shared"]);
+        assert_eq!(migration.files.len(), 3);
+        assert_eq!(migration.warnings.len(), 2);
+
+        let config = Config::from_file(&migration.files[0]).unwrap();
+        let (auth, acl): (_, Acl) = config.load_access().unwrap();
+        assert!(auth.has_user("bob") && !auth.has_user("carol"));
+        assert!(acl.allowed("alice", "alice/laptop", "data", AccessType::Append));
+        assert!(!acl.allowed("alice", "alice/laptop", "data", AccessType::Modify));
+        assert!(!acl.allowed("bob", "alice/laptop", "data", AccessType::Read));
+        assert!(!acl.allowed("bob", "shared", "data", AccessType::Read));
+
+        assert!(migrate(&source, out.path(), false).is_err());
+    }
+}
@@ -0,0 +1,206 @@
+// mod mqtt
+//
+// publishes the server events to an MQTT broker, one topic per repository,
+// so home automation like Home Assistant can react to backups. Events are
+// published with QoS 0 via MQTT 3.1.1, optionally over TLS; while the broker
+// is unreachable, up to CAPACITY events are queued, later ones are dropped.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rumqttc::tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS, Transport};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::config::MqttConfig;
+use crate::events::ServerEvent;
+use crate::web::State;
+
+// RETRY is the time to wait before reconnecting to the broker
+const RETRY: Duration = Duration::from_secs(30);
+
+// KEEP_ALIVE is the keep alive interval announced to the broker
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+// CAPACITY is the number of events queued for the broker
+const CAPACITY: usize = 64;
+
+// run publishes the events to the broker of config until the server stops,
+// reconnecting after failures
+pub async fn run(state: State, config: MqttConfig) {
+    let Some(broker) = config.broker.clone() else {
+        return;
+    };
+    let options = match options(&broker, &config) {
+        Ok(options) => options,
+        Err(err) => {
+            tracing::error!(broker, "[mqtt] {err:#}");
+            return;
+        }
+    };
+    let (client, eventloop) = AsyncClient::new(options, CAPACITY);
+    let connection = tokio::spawn(connect(broker, eventloop));
+    forward(&client, &config, state.events().subscribe()).await;
+    connection.abort();
+}
+
+// options returns the connection settings of config for broker
+fn options(broker: &str, config: &MqttConfig) -> Result<MqttOptions> {
+    let (host, port) = broker
+        .rsplit_once(':')
+        .with_context(|| format!("broker {broker:?} has no port"))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = port.parse().context("invalid broker port")?;
+    let mut options = MqttOptions::new(&config.client_id, host, port);
+    _ = options.set_keep_alive(KEEP_ALIVE).set_clean_session(true);
+    if let Some(username) = &config.username {
+        let password = config.read_password()?.unwrap_or_default();
+        _ = options.set_credentials(username, password);
+    }
+    if config.tls {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let tls_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        _ = options.set_transport(Transport::tls_with_config(tls_config.into()));
+    }
+    Ok(options)
+}
+
+// connect drives the connection to the broker: it sends the queued events
+// and pings, and reconnects after failures
+async fn connect(broker: String, mut eventloop: EventLoop) {
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                tracing::info!(broker, "connected to MQTT broker");
+            }
+            Ok(_) => {}
+            Err(err) => {
+                tracing::warn!(broker, "MQTT broker not usable: {err:#}");
+                tokio::time::sleep(RETRY).await;
+            }
+        }
+    }
+}
+
+// forward queues the received events for the broker until the server stops
+async fn forward(
+    client: &AsyncClient,
+    config: &MqttConfig,
+    mut events: broadcast::Receiver<ServerEvent>,
+) {
+    loop {
+        match events.recv().await {
+            Ok(event) => {
+                let Ok(payload) = serde_json::to_vec(&event) else {
+                    continue;
+                };
+                let topic = topic(&config.topic_prefix, &event);
+                if client
+                    .try_publish(topic, QoS::AtMostOnce, false, payload)
+                    .is_err()
+                {
+                    tracing::debug!(event = event.event, "MQTT queue full, event dropped");
+                }
+            }
+            Err(RecvError::Lagged(missed)) => {
+                tracing::warn!(missed, "MQTT publishing is too slow, events were dropped");
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+// topic returns the topic of event; events not about a repository are
+// published below "server". Wildcards aren't allowed in topic names.
+fn topic(prefix: &str, event: &ServerEvent) -> String {
+    let repo = match event.repo.as_str() {
+        "" => "server",
+        repo => repo,
+    };
+    format!("{prefix}/{repo}/{}", event.event).replace(['+', '#'], "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn topics() {
+        let event = ServerEvent {
+            repo: "alice/laptop".to_string(),
+            ..ServerEvent::new("upload")
+        };
+        assert_eq!(topic("backup", &event), "backup/alice/laptop/upload");
+        assert_eq!(
+            topic("backup", &ServerEvent::new("auth_failed")),
+            "backup/server/auth_failed"
+        );
+    }
+
+    #[tokio::test]
+    async fn publish() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let broker = listener.local_addr().unwrap().to_string();
+        let config = MqttConfig {
+            broker: Some(broker.clone()),
+            username: Some("user".to_string()),
+            password: Some("pw".to_string()),
+            ..MqttConfig::default()
+        };
+        let (sender, receiver) = broadcast::channel(16);
+        let (client, eventloop) = AsyncClient::new(options(&broker, &config).unwrap(), CAPACITY);
+        let connection = tokio::spawn(connect(broker, eventloop));
+        let forwarded = tokio::spawn(async move { forward(&client, &config, receiver).await });
+
+        // a minimal broker checking the packets
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut connect = vec![0; 2];
+        stream.read_exact(&mut connect).await.unwrap();
+        assert_eq!(connect[0], 0x10);
+        connect.resize(2 + usize::from(connect[1]), 0);
+        stream.read_exact(&mut connect[2..]).await.unwrap();
+        // username, password and clean session
+        assert_eq!(connect[9], 0xc2);
+        assert!(connect.ends_with(b"\0\x04user\0\x02pw"));
+        stream.write_all(&[0x20, 2, 0, 0]).await.unwrap();
+
+        sender
+            .send(ServerEvent {
+                repo: "alice".to_string(),
+                ..ServerEvent::new("delete")
+            })
+            .unwrap();
+        let mut publish = vec![0; 2];
+        stream.read_exact(&mut publish).await.unwrap();
+        assert_eq!(publish[0], 0x30);
+        publish.resize(2 + usize::from(publish[1]), 0);
+        stream.read_exact(&mut publish[2..]).await.unwrap();
+        let topic = b"rustic-server/alice/delete";
+        assert_eq!(publish[4..4 + topic.len()], topic[..]);
+        let event: serde_json::Value = serde_json::from_slice(&publish[4 + topic.len()..]).unwrap();
+        assert_eq!(event["repo"], "alice");
+
+        drop(sender);
+        forwarded.await.unwrap();
+        connection.abort();
+    }
+
+    #[test]
+    fn tls() {
+        let config = MqttConfig {
+            tls: true,
+            ..MqttConfig::default()
+        };
+        let tls = options("mqtt.example:8883", &config).unwrap();
+        assert!(matches!(tls.transport(), Transport::Tls(_)));
+        assert_eq!(tls.broker_address(), ("mqtt.example".to_string(), 8883));
+        assert!(options("mqtt.example", &config).is_err());
+    }
+}
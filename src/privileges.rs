@@ -0,0 +1,189 @@
+// mod privileges
+//
+// drops root privileges after binding the listen addresses and reading the
+// TLS certificate, see setuid(2) and setgid(2)
+
+use std::io;
+
+// Ids are the resolved user and group to switch to
+#[derive(Debug, Default, PartialEq)]
+pub struct Ids {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+// resolve looks up user and group by name or numeric id. If only a user is
+// given, the group is its primary group; a numeric user without an entry in
+// the user database needs a group, as root's groups would be kept otherwise.
+#[cfg(unix)]
+pub fn resolve(user: Option<&str>, group: Option<&str>) -> io::Result<Ids> {
+    let mut ids = Ids::default();
+    if let Some(user) = user {
+        let (uid, gid) = match user.parse() {
+            Ok(uid) => (uid, lookup_uid(uid)?),
+            Err(_) => {
+                let (uid, gid) = lookup_user(user)?;
+                (uid, Some(gid))
+            }
+        };
+        ids.uid = Some(uid);
+        ids.gid = gid;
+    }
+    if let Some(group) = group {
+        ids.gid = Some(match group.parse() {
+            Ok(gid) => gid,
+            Err(_) => lookup_group(group)?,
+        });
+    }
+    if let (Some(user), None) = (user, ids.gid) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("user {user} has no primary group, give a group"),
+        ));
+    }
+    Ok(ids)
+}
+
+#[cfg(not(unix))]
+pub fn resolve(user: Option<&str>, group: Option<&str>) -> io::Result<Ids> {
+    match (user, group) {
+        (None, None) => Ok(Ids::default()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "user and group are only supported on unix",
+        )),
+    }
+}
+
+// drop switches to the given user and group. The group is set first, as
+// this is no longer allowed once the user is switched.
+#[cfg(unix)]
+pub fn drop(ids: &Ids) -> io::Result<()> {
+    if ids.uid.is_some() && ids.gid.is_none() {
+        return Err(io::Error::other("no group to switch to"));
+    }
+    if let Some(gid) = ids.gid {
+        // SAFETY: setgroups reads exactly one gid from the given pointer
+        check(unsafe { libc::setgroups(1, &gid) })?;
+        // SAFETY: plain system call without pointers
+        check(unsafe { libc::setgid(gid) })?;
+    }
+    if let Some(uid) = ids.uid {
+        // SAFETY: plain system call without pointers
+        check(unsafe { libc::setuid(uid) })?;
+        // SAFETY: plain system call without pointers
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(io::Error::other("root privileges could be regained"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop(_ids: &Ids) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn check(ret: libc::c_int) -> io::Result<()> {
+    match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+// BUF_SIZE is the buffer size for the strings returned by getpwnam_r,
+// getpwuid_r and getgrnam_r
+#[cfg(unix)]
+const BUF_SIZE: usize = 16 * 1024;
+
+// lookup_user returns the uid and the primary gid of the user name
+#[cfg(unix)]
+fn lookup_user(name: &str) -> io::Result<(u32, u32)> {
+    let cname = std::ffi::CString::new(name)?;
+    // SAFETY: all pointers are valid for the duration of the call and
+    // buf.len() is the size of buf
+    let found = lookup_passwd(|pwd, buf, result| unsafe {
+        libc::getpwnam_r(cname.as_ptr(), pwd, buf.as_mut_ptr(), buf.len(), result)
+    })?;
+    found.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("unknown user {name}")))
+}
+
+// lookup_uid returns the primary gid of uid, if the user database knows it
+#[cfg(unix)]
+fn lookup_uid(uid: u32) -> io::Result<Option<u32>> {
+    // SAFETY: all pointers are valid for the duration of the call and
+    // buf.len() is the size of buf
+    let found = lookup_passwd(|pwd, buf, result| unsafe {
+        libc::getpwuid_r(uid, pwd, buf.as_mut_ptr(), buf.len(), result)
+    })?;
+    Ok(found.map(|(_, gid)| gid))
+}
+
+// lookup_passwd calls get, getpwnam_r or getpwuid_r, and returns the uid and
+// the primary gid of the entry found
+#[cfg(unix)]
+fn lookup_passwd(
+    get: impl FnOnce(&mut libc::passwd, &mut [libc::c_char], &mut *mut libc::passwd) -> libc::c_int,
+) -> io::Result<Option<(u32, u32)>> {
+    let mut buf = vec![0; BUF_SIZE];
+    // SAFETY: passwd is a plain C struct for which all zeroes is a valid value
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    let ret = get(&mut pwd, &mut buf, &mut result);
+    match (ret, result.is_null()) {
+        (0, false) => Ok(Some((pwd.pw_uid, pwd.pw_gid))),
+        (0, true) => Ok(None),
+        (err, _) => Err(io::Error::from_raw_os_error(err)),
+    }
+}
+
+#[cfg(unix)]
+fn lookup_group(name: &str) -> io::Result<u32> {
+    let cname = std::ffi::CString::new(name)?;
+    let mut buf = vec![0; BUF_SIZE];
+    // SAFETY: group is a plain C struct for which all zeroes is a valid value
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    // SAFETY: all pointers are valid for the duration of the call and
+    // buf.len() is the size of buf
+    let ret = unsafe {
+        libc::getgrnam_r(
+            cname.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    match (ret, result.is_null()) {
+        (0, false) => Ok(grp.gr_gid),
+        (0, true) => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown group {name}"),
+        )),
+        (err, _) => Err(io::Error::from_raw_os_error(err)),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_ids() {
+        assert_eq!(resolve(None, None).unwrap(), Ids::default());
+        let root = resolve(Some("root"), None).unwrap();
+        assert_eq!(root.uid, Some(0));
+        assert_eq!(root.gid, Some(0));
+        let ids = resolve(Some("1234"), Some("5678")).unwrap();
+        assert_eq!((ids.uid, ids.gid), (Some(1234), Some(5678)));
+        assert!(resolve(Some("no-such-user-here"), None).is_err());
+        // a numeric user gets its primary group, or needs a group
+        assert_eq!(resolve(Some("0"), None).unwrap(), root);
+        assert!(resolve(Some("4000000000"), None).is_err());
+        let ids = resolve(Some("4000000000"), Some("5678")).unwrap();
+        assert_eq!((ids.uid, ids.gid), (Some(4000000000), Some(5678)));
+        assert!(resolve(None, Some("no-such-group-here")).is_err());
+    }
+}
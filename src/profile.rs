@@ -0,0 +1,125 @@
+// mod profile
+//
+// captures CPU profiles of the running server at /admin/profile, to diagnose
+// performance issues on production servers without a rebuild. The process is
+// sampled for the requested number of seconds and the stacks are returned as
+// a flamegraph (SVG) or as a pprof protobuf for `go tool pprof`. Only active
+// with server.profiling, and only one profile is taken at a time.
+
+use std::time::Duration;
+
+use axum::extract;
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use serde::Deserialize;
+use tokio::sync::Mutex;
+
+use crate::admin::AdminFromRequest;
+use crate::web::{Error, State};
+
+// MAX_SECONDS is the longest duration of a profile
+const MAX_SECONDS: u64 = 120;
+
+// FREQUENCY is the number of samples per second
+#[cfg(unix)]
+const FREQUENCY: i32 = 99;
+
+pub fn router() -> Router<State> {
+    Router::new().route("/admin/profile", get(profile))
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    #[default]
+    Flamegraph,
+    Pprof,
+}
+
+#[derive(Deserialize)]
+struct Capture {
+    #[serde(default = "default_seconds")]
+    seconds: u64,
+    #[serde(default)]
+    format: Format,
+}
+
+fn default_seconds() -> u64 {
+    10
+}
+
+// RUNNING is held while a profile is taken, as the sampling signal handler
+// is process-wide
+static RUNNING: Mutex<()> = Mutex::const_new(());
+
+// profile samples the server for the requested seconds and returns the profile
+async fn profile(
+    extract::State(state): extract::State<State>,
+    admin: AdminFromRequest,
+    extract::Query(capture): extract::Query<Capture>,
+) -> Result<Response, Error> {
+    tracing::info!(admin = admin.user, capture.seconds, "cpu profile");
+    if !state.config().server.profiling {
+        return Err(Error::new(StatusCode::NOT_FOUND, "profiling is disabled"));
+    }
+    if capture.seconds == 0 || capture.seconds > MAX_SECONDS {
+        return Err(Error::new(
+            StatusCode::BAD_REQUEST,
+            format!("seconds must be between 1 and {MAX_SECONDS}"),
+        ));
+    }
+    let Ok(_running) = RUNNING.try_lock() else {
+        return Err(Error::new(
+            StatusCode::CONFLICT,
+            "a profile is already being taken",
+        ));
+    };
+    let body = sample(Duration::from_secs(capture.seconds), capture.format).await?;
+    let (content_type, file) = match capture.format {
+        Format::Flamegraph => ("image/svg+xml", "flamegraph.svg"),
+        Format::Pprof => ("application/octet-stream", "profile.pb"),
+    };
+    let disposition = format!("attachment; filename=\"{file}\"");
+    Ok((
+        [
+            (CONTENT_TYPE, content_type.to_string()),
+            (CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+#[cfg(unix)]
+async fn sample(duration: Duration, format: Format) -> Result<Vec<u8>, Error> {
+    use pprof::protos::Message;
+
+    let failed = |err: pprof::Error| Error::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string());
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(FREQUENCY)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(failed)?;
+    tokio::time::sleep(duration).await;
+    let report = guard.report().build().map_err(failed)?;
+    drop(guard);
+    match format {
+        Format::Flamegraph => {
+            let mut svg = Vec::new();
+            report.flamegraph(&mut svg).map_err(failed)?;
+            Ok(svg)
+        }
+        Format::Pprof => Ok(report.pprof().map_err(failed)?.encode_to_vec()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn sample(_duration: Duration, _format: Format) -> Result<Vec<u8>, Error> {
+    Err(Error::new(
+        StatusCode::NOT_IMPLEMENTED,
+        "profiling is only supported on unix",
+    ))
+}
@@ -0,0 +1,144 @@
+// mod progress
+//
+// logs the progress of large uploads and downloads every
+// log.progress_interval seconds, so operators can tell a long transfer from a
+// stalled one. A transfer is large if its length is at least
+// log.progress_threshold bytes or, if the length isn't known in advance, once
+// that many bytes were transferred.
+
+use std::time::{Duration, Instant};
+
+use futures_util::{Stream, StreamExt};
+
+use crate::config::LogConfig;
+use crate::web::Direction;
+
+// Progress tracks the bytes transferred of one file
+#[derive(Debug)]
+pub struct Progress {
+    path: String,
+    direction: Direction,
+    // length of the transfer, if known
+    total: Option<u64>,
+    // None if progress logging is disabled
+    threshold: Option<u64>,
+    interval: Duration,
+    transferred: u64,
+    started: Instant,
+    logged: Instant,
+}
+
+impl Progress {
+    pub(crate) fn new(
+        config: &LogConfig,
+        direction: Direction,
+        path: String,
+        total: Option<u64>,
+    ) -> Self {
+        let now = Instant::now();
+        Self {
+            path,
+            direction,
+            total,
+            threshold: (config.progress_threshold > 0 && config.progress_interval > 0)
+                .then_some(config.progress_threshold),
+            interval: Duration::from_secs(config.progress_interval),
+            transferred: 0,
+            started: now,
+            logged: now,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    // advance counts len more bytes and logs the progress if it's due
+    fn advance(&mut self, len: usize, now: Instant) {
+        self.transferred += len as u64;
+        let Some(threshold) = self.threshold else {
+            return;
+        };
+        if self.total.unwrap_or(self.transferred) < threshold
+            || now.duration_since(self.logged) < self.interval
+        {
+            return;
+        }
+        self.logged = now;
+        let (rate, eta) = self.estimate(now);
+        tracing::info!(
+            path = self.path,
+            direction = ?self.direction,
+            bytes = self.transferred,
+            total = self.total,
+            rate = format!("{:.1} MiB/s", rate / 1024.0 / 1024.0),
+            eta = eta.map(|eta| format!("{}s", eta.as_secs())),
+            "transfer in progress"
+        );
+    }
+
+    // estimate returns the mean rate in bytes per second and the time left,
+    // if the length is known
+    fn estimate(&self, now: Instant) -> (f64, Option<Duration>) {
+        let elapsed = now.duration_since(self.started).as_secs_f64();
+        let rate = match elapsed > 0.0 {
+            true => self.transferred as f64 / elapsed,
+            false => 0.0,
+        };
+        let eta = self.total.filter(|_| rate > 0.0).map(|total| {
+            let left = total.saturating_sub(self.transferred) as f64;
+            Duration::from_secs_f64(left / rate)
+        });
+        (rate, eta)
+    }
+}
+
+// with_progress passes on the chunks of stream, logging the progress of the
+// transfer
+pub fn with_progress<S, T, E>(stream: S, mut progress: Progress) -> impl Stream<Item = Result<T, E>>
+where
+    S: Stream<Item = Result<T, E>>,
+    T: AsRef<[u8]>,
+{
+    stream.map(move |chunk| {
+        if let Ok(data) = &chunk {
+            progress.advance(data.as_ref().len(), Instant::now());
+        }
+        chunk
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate() {
+        let config = LogConfig {
+            progress_threshold: 1000,
+            progress_interval: 10,
+            ..LogConfig::default()
+        };
+        let path = "repo/data/0123".to_string();
+        let mut progress = Progress::new(&config, Direction::Download, path, Some(4000));
+        let start = progress.started;
+        progress.advance(1000, start + Duration::from_secs(5));
+        // not logged yet, the interval didn't pass
+        assert_eq!(progress.logged, start);
+        progress.advance(1000, start + Duration::from_secs(10));
+        assert_eq!(progress.logged, start + Duration::from_secs(10));
+        let (rate, eta) = progress.estimate(start + Duration::from_secs(10));
+        assert!((rate - 200.0).abs() < 1e-9);
+        assert_eq!(eta, Some(Duration::from_secs(10)));
+
+        // small transfers of unknown length are only logged once they grow
+        let path = "repo/config".to_string();
+        let mut progress = Progress::new(&config, Direction::Upload, path, None);
+        let start = progress.started;
+        progress.advance(999, start + Duration::from_secs(20));
+        assert_eq!(progress.logged, start);
+        progress.advance(1, start + Duration::from_secs(30));
+        assert_eq!(progress.logged, start + Duration::from_secs(30));
+        assert_eq!(progress.estimate(start).1, None);
+    }
+}
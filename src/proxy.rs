@@ -0,0 +1,250 @@
+// mod proxy
+//
+// determines the IP of the client when the server runs behind reverse
+// proxies. Anyone can send X-Forwarded-For or Forwarded headers, so they are
+// only used for requests from the proxies given in server.trusted_proxies;
+// the forwarded addresses are walked from the nearest hop on and the first
+// one which isn't a trusted proxy is the client.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use axum::http::header::FORWARDED;
+use axum::http::HeaderMap;
+
+// ClientIp is the IP of the client, stored in the request extensions
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClientIp(pub IpAddr);
+
+impl fmt::Display for ClientIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl ClientIp {
+    // network returns the network the client is told apart from others by. An
+    // IPv6 host usually has a whole /64 network, so that is what is limited.
+    pub fn network(&self) -> String {
+        match self.0.to_canonical() {
+            IpAddr::V6(ip) => {
+                let prefix = u128::from(ip) & !u128::from(u64::MAX);
+                format!("{}/64", std::net::Ipv6Addr::from(prefix))
+            }
+            ip => ip.to_string(),
+        }
+    }
+}
+
+// Network is an IP address or a network in CIDR notation like 10.0.0.0/8
+#[derive(Clone, Debug, PartialEq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| format!("invalid IP address {addr:?}"))?
+            .to_canonical();
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= bits)
+                .ok_or_else(|| format!("invalid prefix length in {s:?}"))?,
+            None => bits,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl Network {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                masked(u128::from(u32::from(net)) << 96, self.prefix)
+                    == masked(u128::from(u32::from(ip)) << 96, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                masked(u128::from(net), self.prefix) == masked(u128::from(ip), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+// masked keeps the first prefix bits of addr
+fn masked(addr: u128, prefix: u8) -> u128 {
+    addr.checked_shr(128 - u32::from(prefix))
+        .unwrap_or_default()
+}
+
+// TrustedProxies are the networks whose forwarding headers are believed
+#[derive(Debug, Default)]
+pub struct TrustedProxies(Vec<Network>);
+
+impl TrustedProxies {
+    // new parses the networks of list; invalid ones are reported by
+    // `config validate` and ignored here
+    pub fn new(list: &[String]) -> Self {
+        let networks = list
+            .iter()
+            .filter_map(|network| match network.parse() {
+                Ok(network) => Some(network),
+                Err(err) => {
+                    tracing::warn!("ignoring trusted proxy: {err}");
+                    None
+                }
+            })
+            .collect();
+        Self(networks)
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|network| network.contains(ip))
+    }
+
+    // client_ip returns the IP of the client of a request from peer.
+    // Forwarded takes precedence over X-Forwarded-For if both are given.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let peer = peer.to_canonical();
+        if !self.contains(peer) {
+            return peer;
+        }
+        let mut hops = forwarded(headers);
+        if hops.is_empty() {
+            hops = x_forwarded_for(headers);
+        }
+        let mut client = peer;
+        for hop in hops.iter().rev() {
+            // unknown or obfuscated identifiers can't be followed further
+            let Some(ip) = hop else {
+                break;
+            };
+            client = ip.to_canonical();
+            if !self.contains(client) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+// x_forwarded_for returns the addresses of X-Forwarded-For, the client first
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|hop| hop.trim().parse().ok())
+        .collect()
+}
+
+// forwarded returns the "for" addresses of Forwarded (RFC 7239), the client
+// first. They may be quoted and contain a port, e.g. "[2001:db8::1]:4711".
+fn forwarded(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all(FORWARDED)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("for").then_some(value)
+            })
+        })
+        .map(|node| {
+            let node = node.trim_matches('"');
+            match node.strip_prefix('[') {
+                Some(v6) => v6.split_once(']')?.0.parse().ok(),
+                None => node.split(':').next()?.parse().ok(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn networks() {
+        let net: Network = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("10.1.2.3")));
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!("::1".parse::<Network>().unwrap().contains(ip("::1")));
+        assert!("0.0.0.0/0"
+            .parse::<Network>()
+            .unwrap()
+            .contains(ip("192.0.2.1")));
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("proxy".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn client_networks() {
+        let network = |s| ClientIp(ip(s)).network();
+        assert_eq!(network("192.0.2.1"), "192.0.2.1");
+        assert_eq!(network("::ffff:192.0.2.1"), "192.0.2.1");
+        assert_eq!(network("2001:db8:1:2:a:b:c:d"), "2001:db8:1:2::/64");
+        assert_eq!(network("2001:db8:1:2::1"), network("2001:db8:1:2:ffff::1"));
+    }
+
+    #[test]
+    fn client_ip() {
+        let proxies = TrustedProxies::new(&["127.0.0.1".to_string(), "10.0.0.0/8".to_string()]);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "1.2.3.4, 192.0.2.7, 10.0.0.2".parse().unwrap(),
+        );
+        // headers of untrusted peers are ignored
+        assert_eq!(
+            proxies.client_ip(ip("192.0.2.1"), &headers),
+            ip("192.0.2.1")
+        );
+        // the spoofed 1.2.3.4 is not used
+        assert_eq!(
+            proxies.client_ip(ip("127.0.0.1"), &headers),
+            ip("192.0.2.7")
+        );
+
+        headers.insert(
+            FORWARDED,
+            "for=198.51.100.3, for=\"[2001:db8::1]:4711\";proto=https"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(
+            proxies.client_ip(ip("127.0.0.1"), &headers),
+            ip("2001:db8::1")
+        );
+        headers.insert(
+            FORWARDED,
+            "for=198.51.100.3;by=10.0.0.1, for=unknown".parse().unwrap(),
+        );
+        assert_eq!(proxies.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.1"));
+        assert_eq!(
+            proxies.client_ip(ip("127.0.0.1"), &HeaderMap::new()),
+            ip("127.0.0.1")
+        );
+    }
+}
@@ -0,0 +1,158 @@
+// mod quota
+//
+// tracks the disk usage of repositories, so quotas can be enforced without
+// walking the repository on each upload
+
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crate::storage::Storage;
+
+// Usage caches the size of repositories in bytes and the list of existing
+// repositories. Both are read from the storage on first use and then kept up
+// to date by the handlers.
+#[derive(Clone, Default)]
+pub struct Usage(Arc<Mutex<Cache>>);
+
+#[derive(Default)]
+struct Cache {
+    sizes: HashMap<String, u64>,
+    repos: BTreeSet<String>,
+    // whether repos has been read from the storage
+    scanned: bool,
+}
+
+impl Usage {
+    // get returns the size of repo
+    pub fn get(&self, storage: &dyn Storage, repo: &str) -> io::Result<u64> {
+        if let Some(used) = self.lock().sizes.get(repo) {
+            return Ok(*used);
+        }
+        // compute without holding the lock; a concurrent computation gives the same result
+        let used = storage.size(Path::new(repo))?;
+        Ok(*self.lock().sizes.entry(repo.to_string()).or_insert(used))
+    }
+
+    // add adjusts the size of repo after bytes have been written (positive)
+    // or removed (negative); sizes which are not cached yet are left alone
+    pub fn add(&self, repo: &str, bytes: i64) {
+        if let Some(used) = self.lock().sizes.get_mut(repo) {
+            *used = used.saturating_add_signed(bytes);
+        }
+    }
+
+    // repos returns all repositories within the storage
+    pub fn repos(&self, storage: &dyn Storage) -> Vec<String> {
+        if !self.lock().scanned {
+            let repos = storage.repos();
+            let mut cache = self.lock();
+            cache.repos.extend(repos);
+            cache.scanned = true;
+        }
+        self.lock().repos.iter().cloned().collect()
+    }
+
+    // add_repo records a newly created repository, which is not found by
+    // scanning the storage before its config file is written
+    pub fn add_repo(&self, repo: &str) {
+        _ = self.lock().repos.insert(repo.to_string());
+    }
+
+    // remove_repo forgets a deleted repository
+    pub fn remove_repo(&self, repo: &str) {
+        let mut cache = self.lock();
+        _ = cache.repos.remove(repo);
+        _ = cache.sizes.remove(repo);
+    }
+
+    // rename_repo moves the cached entries of a renamed repository and of the
+    // repositories within it
+    pub fn rename_repo(&self, from: &str, to: &str) {
+        let prefix = format!("{from}/");
+        let renamed = |repo: &str| {
+            (repo == from || repo.starts_with(&prefix))
+                .then(|| format!("{to}{}", &repo[from.len()..]))
+        };
+        let mut cache = self.lock();
+        cache.repos = std::mem::take(&mut cache.repos)
+            .into_iter()
+            .map(|repo| renamed(&repo).unwrap_or(repo))
+            .collect();
+        cache.sizes = std::mem::take(&mut cache.sizes)
+            .into_iter()
+            .map(|(repo, size)| (renamed(&repo).unwrap_or(repo), size))
+            .collect();
+    }
+
+    // total returns the summed size of repos
+    pub fn total<'a>(
+        &self,
+        storage: &dyn Storage,
+        repos: impl IntoIterator<Item = &'a str>,
+    ) -> io::Result<u64> {
+        repos.into_iter().map(|repo| self.get(storage, repo)).sum()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Cache> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LocalStorage;
+
+    #[test]
+    fn usage() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("repo")).unwrap();
+        std::fs::write(dir.path().join("repo/config"), "1234").unwrap();
+        let storage = LocalStorage::try_new(dir.path()).unwrap();
+
+        let usage = Usage::default();
+        usage.add("repo", 100);
+        assert_eq!(usage.get(&storage, "repo").unwrap(), 4);
+        usage.add("repo", 10);
+        usage.add("repo", -4);
+        assert_eq!(usage.get(&storage, "repo").unwrap(), 10);
+        usage.add("repo", -100);
+        assert_eq!(usage.get(&storage, "repo").unwrap(), 0);
+    }
+
+    #[test]
+    fn total() {
+        let dir = tempfile::tempdir().unwrap();
+        for repo in ["alice", "bob"] {
+            std::fs::create_dir_all(dir.path().join(repo).join("keys")).unwrap();
+            std::fs::write(dir.path().join(repo).join("config"), "1234").unwrap();
+        }
+        let storage = LocalStorage::try_new(dir.path()).unwrap();
+
+        let usage = Usage::default();
+        assert_eq!(usage.repos(&storage), ["alice", "bob"]);
+        // new repositories are only found through add_repo once the storage was scanned
+        std::fs::create_dir_all(dir.path().join("carol/keys")).unwrap();
+        std::fs::write(dir.path().join("carol/config"), "12").unwrap();
+        assert_eq!(usage.repos(&storage).len(), 2);
+        usage.add_repo("carol");
+        let repos = usage.repos(&storage);
+        assert_eq!(repos, ["alice", "bob", "carol"]);
+        usage.remove_repo("bob");
+        let repos = usage.repos(&storage);
+        assert_eq!(repos, ["alice", "carol"]);
+        assert_eq!(
+            usage
+                .total(&storage, repos.iter().map(String::as_str))
+                .unwrap(),
+            6
+        );
+        // the cached size moves along with the repository
+        usage.rename_repo("carol", "dave");
+        let repos = usage.repos(&storage);
+        assert_eq!(repos, ["alice", "dave"]);
+        assert_eq!(usage.get(&storage, "dave").unwrap(), 2);
+    }
+}
@@ -0,0 +1,139 @@
+// mod ratelimit
+//
+// limits the request rate of clients with a token bucket per key, e.g. per
+// client IP or user
+
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+// MAX_BUCKETS is the number of buckets kept at most. Once reached, full
+// buckets are dropped and, if that isn't enough, the least recently used ones
+// down to KEPT_BUCKETS, so the next new keys don't have to sweep again.
+const MAX_BUCKETS: usize = 10_000;
+const KEPT_BUCKETS: usize = MAX_BUCKETS * 9 / 10;
+
+// RateLimiter allows rate requests per second and key on average and bursts
+// of up to burst requests
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    // new returns None unless rate is a positive number, as no requests
+    // could be allowed once a burst is used up
+    pub fn new(rate: f64, burst: u32) -> Option<Self> {
+        (rate.is_finite() && rate > 0.0).then(|| Self {
+            rate,
+            burst: f64::from(burst.max(1)),
+            buckets: Mutex::default(),
+        })
+    }
+
+    // acquire takes a token from the bucket of key. If the bucket is empty,
+    // the time until the next token is available is returned as error.
+    pub fn acquire(&self, key: &str) -> Result<(), Duration> {
+        self.acquire_at(key, Instant::now())
+    }
+
+    fn acquire_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(key) {
+            // full buckets behave like new ones, so they can be dropped
+            buckets.retain(|_, bucket| self.tokens(bucket, now) < self.burst);
+            evict(&mut buckets, KEPT_BUCKETS);
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = self.tokens(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    // tokens returns the tokens of bucket at time now
+    fn tokens(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+}
+
+// evict drops the least recently updated buckets until at most kept are left
+fn evict(buckets: &mut HashMap<String, Bucket>, kept: usize) {
+    if buckets.len() <= kept {
+        return;
+    }
+    // the newest of the buckets to drop, ordered from the most recent one
+    let mut updated: Vec<_> = buckets.values().map(|bucket| bucket.updated).collect();
+    let (_, &mut newest_dropped, _) = updated.select_nth_unstable_by(kept, |a, b| b.cmp(a));
+    buckets.retain(|_, bucket| bucket.updated > newest_dropped);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limit() {
+        let limiter = RateLimiter::new(2.0, 3).unwrap();
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.acquire_at("alice", now).is_ok());
+        }
+        assert_eq!(
+            limiter.acquire_at("alice", now),
+            Err(Duration::from_millis(500))
+        );
+        // other keys have their own bucket
+        assert!(limiter.acquire_at("bob", now).is_ok());
+
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.acquire_at("alice", later).is_ok());
+        assert!(limiter.acquire_at("alice", later).is_err());
+        // the bucket holds at most burst tokens
+        let much_later = now + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.acquire_at("alice", much_later).is_ok());
+        }
+        assert!(limiter.acquire_at("alice", much_later).is_err());
+    }
+
+    #[test]
+    fn max_buckets() {
+        let limiter = RateLimiter::new(1.0, 1).unwrap();
+        let now = Instant::now();
+        for i in 0..MAX_BUCKETS {
+            let at = now + Duration::from_micros(i as u64);
+            assert!(limiter.acquire_at(&i.to_string(), at).is_ok());
+        }
+        // none of the buckets has refilled, the oldest are dropped
+        let at = now + Duration::from_millis(100);
+        assert!(limiter.acquire_at("new", at).is_ok());
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), KEPT_BUCKETS + 1);
+        assert!(!buckets.contains_key("0"));
+        assert!(buckets.contains_key(&(MAX_BUCKETS - 1).to_string()));
+    }
+
+    #[test]
+    fn invalid_rate() {
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(RateLimiter::new(rate, 3).is_none(), "{rate}");
+        }
+    }
+}
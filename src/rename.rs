@@ -0,0 +1,155 @@
+// mod rename
+//
+// renames or moves repositories within the storage together with the ACL
+// entries and settings referring to them
+
+use std::fs;
+
+use axum::http::StatusCode;
+use toml_edit::{Item, TableLike};
+
+use crate::config::Config;
+use crate::edit::{self, check_not_nested, conflict, parse, repo_path, Transaction};
+use crate::storage::is_repo;
+use crate::web::Error;
+
+// rename_repo moves the repository from to the path to, including the
+// repositories within it, and renames their entries in the ACL file and in
+// the [repos] section of the config file. It returns the normalized paths.
+pub fn rename_repo(config: &Config, from: &str, to: &str) -> Result<(String, String), Error> {
+    let from = repo_path(from)?;
+    let to = repo_path(to)?;
+    if from == to || to.starts_with(&format!("{from}/")) {
+        return Err(Error::new(
+            StatusCode::BAD_REQUEST,
+            format!("cannot move repository {from} into itself"),
+        ));
+    }
+
+    let _lock = edit::lock();
+    let src = config.storage.path.join(&from);
+    if !is_repo(&src) {
+        return Err(Error::new(
+            StatusCode::NOT_FOUND,
+            format!("repository {from} not found"),
+        ));
+    }
+    let dst = config.storage.path.join(&to);
+    if dst.exists() {
+        return Err(conflict(format!("{to} already exists")));
+    }
+    check_not_nested(&config.storage.path, &to)?;
+
+    let mut files = Vec::new();
+    if let Some(file) = config.acl.path.as_deref() {
+        let mut doc = parse(file)?;
+        if rename_keys(doc.as_table_mut(), &from, &to)? {
+            files.push((file, doc));
+        }
+    }
+    if let Some(file) = config.file.as_deref() {
+        let mut doc = parse(file)?;
+        let repos = doc.get_mut("repos").and_then(Item::as_table_like_mut);
+        if let Some(repos) = repos {
+            if rename_keys(repos, &from, &to)? {
+                files.push((file, doc));
+            }
+        }
+    }
+
+    let mut transaction = Transaction::default();
+    let res = (|| {
+        for (file, doc) in &files {
+            transaction.replace(file, &doc.to_string(), false)?;
+        }
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&src, &dst)
+    })();
+    if let Err(err) = res {
+        transaction.rollback();
+        return Err(err.into());
+    }
+    Ok((from, to))
+}
+
+// rename_keys renames the entries of repo from and of the repos within it;
+// it returns whether any entry was renamed
+fn rename_keys(table: &mut dyn TableLike, from: &str, to: &str) -> Result<bool, Error> {
+    let prefix = format!("{from}/");
+    let keys: Vec<String> = table
+        .iter()
+        .map(|(key, _)| key.to_string())
+        .filter(|key| key == from || key.starts_with(&prefix))
+        .collect();
+    for key in &keys {
+        let new = format!("{to}{}", &key[from.len()..]);
+        if table.contains_key(&new) {
+            return Err(conflict(format!("an entry for {new} already exists")));
+        }
+        if let Some(item) = table.remove(key) {
+            _ = table.insert(&new, item);
+        }
+    }
+    Ok(!keys.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rename() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("data");
+        for repo in ["alice", "alice/nested", "carol"] {
+            fs::create_dir_all(data.join(repo).join("keys")).unwrap();
+            fs::write(data.join(repo).join("config"), "x").unwrap();
+        }
+        let acl_file = dir.path().join("acl.toml");
+        fs::write(
+            &acl_file,
+            "# grants\n[alice]\nalice = \"Modify\"\n\n[\"alice/nested\"]\nbob = \"Read\"\n\n[carol]\ncarol = \"Append\"\n",
+        )
+        .unwrap();
+        let config_file = dir.path().join("rustic_server.toml");
+        fs::write(
+            &config_file,
+            format!(
+                "[storage]\npath = {data:?}\n[acl]\npath = {acl_file:?}\n[repos.alice]\nquota = 1000\n"
+            ),
+        )
+        .unwrap();
+        let config = Config::from_file(&config_file).unwrap();
+
+        assert_eq!(
+            rename_repo(&config, "alice", "alice/nested/x")
+                .unwrap_err()
+                .status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            rename_repo(&config, "alice", "carol").unwrap_err().status(),
+            StatusCode::CONFLICT
+        );
+        // no repository is moved into another one
+        assert_eq!(
+            rename_repo(&config, "alice", "carol/x")
+                .unwrap_err()
+                .status(),
+            StatusCode::CONFLICT
+        );
+        assert!(!data.join("carol/x").exists());
+        let renamed = rename_repo(&config, "/alice/", "users/alice").unwrap();
+        assert_eq!(renamed, ("alice".to_string(), "users/alice".to_string()));
+        assert!(is_repo(&data.join("users/alice/nested")));
+        assert!(!data.join("alice").exists());
+        assert_eq!(
+            fs::read_to_string(&acl_file).unwrap(),
+            "# grants\n[\"users/alice\"]\nalice = \"Modify\"\n\n[\"users/alice/nested\"]\nbob = \"Read\"\n\n[carol]\ncarol = \"Append\"\n"
+        );
+        let config = Config::from_file(&config_file).unwrap();
+        assert_eq!(config.repos["users/alice"].quota, Some(1000));
+    }
+}
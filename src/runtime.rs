@@ -0,0 +1,130 @@
+// mod runtime
+//
+// exposes metrics of the async runtime at /admin/runtime, to debug stalls of
+// the executor under heavy load. A watchdog task measures how late its timer
+// fires: while blocking calls occupy the worker threads, nothing else is
+// polled and the delay grows. Both are only active with server.runtime_metrics.
+
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use axum::extract;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::Serialize;
+
+use crate::admin::AdminFromRequest;
+use crate::web::{Error, State};
+
+// TICK is the interval of the watchdog timer
+const TICK: Duration = Duration::from_millis(100);
+
+// STALL is the delay of the watchdog timer from which on a stall is logged
+const STALL: Duration = Duration::from_millis(250);
+
+pub fn router() -> Router<State> {
+    Router::new().route("/admin/runtime", get(metrics))
+}
+
+#[derive(Default)]
+struct Stalls {
+    count: u64,
+    max: Duration,
+    last: Option<Instant>,
+}
+
+// Watchdog counts the stalls of the runtime
+#[derive(Clone, Default)]
+pub struct Watchdog(Arc<Mutex<Stalls>>);
+
+impl Watchdog {
+    // run measures the delay of a timer until the runtime shuts down
+    pub async fn run(self) {
+        loop {
+            let start = Instant::now();
+            tokio::time::sleep(TICK).await;
+            self.observe(start.elapsed().saturating_sub(TICK));
+        }
+    }
+
+    fn observe(&self, delay: Duration) {
+        if delay < STALL {
+            return;
+        }
+        tracing::warn!("async runtime stalled for {} ms", delay.as_millis());
+        let mut stalls = self.lock();
+        stalls.count += 1;
+        stalls.max = stalls.max.max(delay);
+        stalls.last = Some(Instant::now());
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Stalls> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// Worker holds the metrics of a worker thread since the start
+#[derive(Serialize)]
+struct Worker {
+    busy_seconds: f64,
+    parks: u64,
+}
+
+#[derive(Serialize)]
+struct Metrics {
+    alive_tasks: usize,
+    // tasks waiting to be picked up by any worker
+    global_queue_depth: usize,
+    workers: Vec<Worker>,
+    stalls: u64,
+    max_stall_ms: u128,
+    last_stall_seconds_ago: Option<u64>,
+}
+
+// metrics returns the metrics of the runtime the server runs on
+async fn metrics(
+    extract::State(state): extract::State<State>,
+    admin: AdminFromRequest,
+) -> Result<Json<Metrics>, Error> {
+    tracing::debug!(admin = admin.user, "runtime metrics");
+    if !state.config().server.runtime_metrics {
+        return Err(Error::new(
+            StatusCode::NOT_FOUND,
+            "runtime metrics are disabled",
+        ));
+    }
+    let metrics = tokio::runtime::Handle::current().metrics();
+    let stalls = state.watchdog().lock();
+    Ok(Json(Metrics {
+        alive_tasks: metrics.num_alive_tasks(),
+        global_queue_depth: metrics.global_queue_depth(),
+        workers: (0..metrics.num_workers())
+            .map(|worker| Worker {
+                busy_seconds: metrics.worker_total_busy_duration(worker).as_secs_f64(),
+                parks: metrics.worker_park_count(worker),
+            })
+            .collect(),
+        stalls: stalls.count,
+        max_stall_ms: stalls.max.as_millis(),
+        last_stall_seconds_ago: stalls.last.map(|last| last.elapsed().as_secs()),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stalls() {
+        let watchdog = Watchdog::default();
+        watchdog.observe(Duration::from_millis(3));
+        assert_eq!(watchdog.lock().count, 0);
+        watchdog.observe(Duration::from_secs(2));
+        watchdog.observe(STALL);
+        let stalls = watchdog.lock();
+        assert_eq!(stalls.count, 2);
+        assert_eq!(stalls.max, Duration::from_secs(2));
+        assert!(stalls.last.is_some());
+    }
+}
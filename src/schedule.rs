@@ -0,0 +1,193 @@
+// mod schedule
+//
+// time windows which change the limits of the server, e.g. to throttle
+// backups during business hours or to announce maintenance
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::values;
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const MINUTES_PER_DAY: u32 = 24 * 60;
+const MINUTES_PER_WEEK: u32 = 7 * MINUTES_PER_DAY;
+
+// Schedule is a time window given in local time, e.g. from = "22:00" and
+// to = "06:00". Windows ending before they start span midnight.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Schedule {
+    pub from: String,
+    pub to: String,
+    // days the window starts on, e.g. ["sat", "sun"]; every day if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<String>,
+    // bandwidth limits of the server replacing those of [limits]
+    #[serde(default, deserialize_with = "values::opt_size")]
+    pub upload_bandwidth: Option<u64>,
+    #[serde(default, deserialize_with = "values::opt_size")]
+    pub download_bandwidth: Option<u64>,
+    // answer all requests with 503 until the window ends
+    #[serde(default)]
+    pub maintenance: bool,
+}
+
+// LocalTime is a point in the week
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LocalTime {
+    // 0 is monday
+    pub weekday: u32,
+    pub minute: u32,
+}
+
+impl LocalTime {
+    // now returns the current local time
+    #[cfg(unix)]
+    pub fn now() -> Self {
+        // SAFETY: time with a null pointer only returns the current time,
+        // localtime_r fills tm which is plain data
+        let tm = unsafe {
+            let now = libc::time(std::ptr::null_mut());
+            let mut tm: libc::tm = std::mem::zeroed();
+            libc::localtime_r(&now, &mut tm);
+            tm
+        };
+        Self {
+            weekday: (tm.tm_wday as u32 + 6) % 7,
+            minute: tm.tm_hour as u32 * 60 + tm.tm_min as u32,
+        }
+    }
+
+    // now returns the current time in UTC, local time is not supported
+    #[cfg(not(unix))]
+    pub fn now() -> Self {
+        let now = time::OffsetDateTime::now_utc();
+        Self {
+            weekday: u32::from(now.weekday().number_days_from_monday()),
+            minute: u32::from(now.hour()) * 60 + u32::from(now.minute()),
+        }
+    }
+
+    fn minute_of_week(self) -> u32 {
+        self.weekday * MINUTES_PER_DAY + self.minute
+    }
+}
+
+impl Schedule {
+    // validate checks times and days of the schedule
+    pub fn validate(&self) -> Result<(), String> {
+        parse_time(&self.from)?;
+        parse_time(&self.to)?;
+        match self.days.iter().find(|day| day_number(day).is_none()) {
+            Some(day) => Err(format!("invalid day {day:?}, use one of {DAYS:?}")),
+            None => Ok(()),
+        }
+    }
+
+    // remaining returns how long the window lasts from now on, None if it isn't active
+    pub fn remaining(&self, now: LocalTime) -> Option<Duration> {
+        let from = parse_time(&self.from).ok()?;
+        let to = parse_time(&self.to).ok()?;
+        let length = match (to + MINUTES_PER_DAY - from) % MINUTES_PER_DAY {
+            0 => MINUTES_PER_DAY,
+            length => length,
+        };
+        let days: Vec<_> = match self.days.is_empty() {
+            true => (0..7).collect(),
+            false => self.days.iter().filter_map(|day| day_number(day)).collect(),
+        };
+        days.into_iter()
+            .map(|day| {
+                let start = day * MINUTES_PER_DAY + from;
+                (now.minute_of_week() + MINUTES_PER_WEEK - start) % MINUTES_PER_WEEK
+            })
+            .filter(|elapsed| *elapsed < length)
+            .map(|elapsed| Duration::from_secs(u64::from(length - elapsed) * 60))
+            .min()
+    }
+}
+
+// active returns the first schedule active at now and how long it lasts
+pub fn active(schedules: &[Schedule], now: LocalTime) -> Option<(&Schedule, Duration)> {
+    schedules
+        .iter()
+        .find_map(|schedule| Some((schedule, schedule.remaining(now)?)))
+}
+
+// parse_time parses "HH:MM" into minutes since midnight
+fn parse_time(s: &str) -> Result<u32, String> {
+    let err = || format!("invalid time {s:?}, use HH:MM");
+    let (hour, minute) = s.split_once(':').ok_or_else(err)?;
+    let hour: u32 = hour.parse().map_err(|_| err())?;
+    let minute: u32 = minute.parse().map_err(|_| err())?;
+    match hour < 24 && minute < 60 {
+        true => Ok(hour * 60 + minute),
+        false => Err(err()),
+    }
+}
+
+fn day_number(day: &str) -> Option<u32> {
+    let day = day.to_lowercase();
+    DAYS.iter()
+        .position(|d| day.starts_with(d))
+        .map(|d| d as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(from: &str, to: &str, days: &[&str]) -> Schedule {
+        Schedule {
+            from: from.to_string(),
+            to: to.to_string(),
+            days: days.iter().map(ToString::to_string).collect(),
+            upload_bandwidth: None,
+            download_bandwidth: None,
+            maintenance: false,
+        }
+    }
+
+    fn at(weekday: u32, hour: u32, minute: u32) -> LocalTime {
+        LocalTime {
+            weekday,
+            minute: hour * 60 + minute,
+        }
+    }
+
+    #[test]
+    fn remaining() {
+        let night = schedule("22:00", "06:00", &[]);
+        assert_eq!(
+            night.remaining(at(0, 23, 0)),
+            Some(Duration::from_secs(7 * 3600))
+        );
+        assert_eq!(
+            night.remaining(at(3, 5, 30)),
+            Some(Duration::from_secs(1800))
+        );
+        assert_eq!(night.remaining(at(3, 6, 0)), None);
+        assert_eq!(night.remaining(at(3, 12, 0)), None);
+
+        // the window of sunday night lasts until monday morning
+        let sunday = schedule("22:00", "06:00", &["Sunday"]);
+        assert!(sunday.remaining(at(0, 5, 0)).is_some());
+        assert!(sunday.remaining(at(1, 5, 0)).is_none());
+        assert!(sunday.remaining(at(6, 23, 0)).is_some());
+
+        let whole_day = schedule("00:00", "00:00", &["sat"]);
+        assert!(whole_day.remaining(at(5, 12, 0)).is_some());
+        assert!(whole_day.remaining(at(6, 0, 0)).is_none());
+
+        let schedules = [sunday, night];
+        assert_eq!(active(&schedules, at(6, 23, 0)).unwrap().0, &schedules[0]);
+        assert_eq!(active(&schedules, at(2, 23, 0)).unwrap().0, &schedules[1]);
+        assert!(active(&schedules, at(2, 12, 0)).is_none());
+
+        assert!(schedule("24:00", "06:00", &[]).validate().is_err());
+        assert!(schedule("22:00", "6", &[]).validate().is_err());
+        assert!(schedule("22:00", "06:00", &["someday"]).validate().is_err());
+        assert!(schedule("22:00", "06:00", &["fri"]).validate().is_ok());
+    }
+}
@@ -0,0 +1,136 @@
+// mod stats
+//
+// collects per-repository statistics from the storage for capacity planning
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+use crate::storage::Storage;
+use crate::web::TYPES;
+
+// RepoStats holds size and file counts of a repository
+#[derive(Debug, Serialize)]
+pub struct RepoStats {
+    pub repo: String,
+    // total size of all files in bytes
+    pub size: u64,
+    // number of files per type
+    pub files: BTreeMap<&'static str, usize>,
+    // modification time of the newest file as RFC 3339 timestamp
+    pub last_modified: Option<String>,
+}
+
+// repo_stats collects the statistics of repo from storage
+pub fn repo_stats(storage: &dyn Storage, repo: &str) -> RepoStats {
+    let path = Path::new(repo);
+    let mut size = 0;
+    let mut files = BTreeMap::new();
+    let mut last_modified: Option<SystemTime> = None;
+    let mut add = |metadata: std::fs::Metadata| {
+        size += metadata.len();
+        if let Ok(modified) = metadata.modified() {
+            last_modified = last_modified.max(Some(modified));
+        }
+    };
+
+    if let Ok(metadata) = std::fs::metadata(storage.filename(path, "config", "")) {
+        add(metadata);
+    }
+    for tpe in TYPES {
+        let mut count = 0;
+        for entry in storage.read_dir(path, tpe) {
+            count += 1;
+            if let Ok(metadata) = entry.metadata() {
+                add(metadata);
+            }
+        }
+        _ = files.insert(tpe, count);
+    }
+
+    RepoStats {
+        repo: repo.to_string(),
+        size,
+        files,
+        last_modified: last_modified.map(format_time),
+    }
+}
+
+pub(crate) fn format_time(time: SystemTime) -> String {
+    time::OffsetDateTime::from(time)
+        .replace_nanosecond(0)
+        .ok()
+        .and_then(|t| {
+            t.format(&time::format_description::well_known::Rfc3339)
+                .ok()
+        })
+        .unwrap_or_default()
+}
+
+// format_size formats bytes using binary prefixes, e.g. "1.5 GiB"
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{bytes} B"),
+        _ => format!("{size:.1} {}", UNITS[unit]),
+    }
+}
+
+// format_table renders stats as a table with one line per repository
+pub fn format_table(stats: &[RepoStats]) -> String {
+    let width = stats
+        .iter()
+        .map(|s| s.repo.len())
+        .chain([4])
+        .max()
+        .unwrap_or_default();
+    let mut out = format!("{:width$}  {:>10}", "REPO", "SIZE");
+    for tpe in TYPES {
+        _ = write!(out, "  {:>9}", tpe.to_uppercase());
+    }
+    out.push_str("  LAST MODIFIED\n");
+    for s in stats {
+        _ = write!(out, "{:width$}  {:>10}", s.repo, format_size(s.size));
+        for tpe in TYPES {
+            _ = write!(out, "  {:>9}", s.files.get(tpe).unwrap_or(&0));
+        }
+        _ = writeln!(out, "  {}", s.last_modified.as_deref().unwrap_or("-"));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LocalStorage;
+
+    #[test]
+    fn stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::try_new(dir.path()).unwrap();
+        storage.create_repo(Path::new("repo"), &TYPES).unwrap();
+        std::fs::write(dir.path().join("repo/config"), "12345").unwrap();
+        std::fs::write(dir.path().join("repo/data/ab/abcd"), "123").unwrap();
+        std::fs::write(dir.path().join("repo/keys/abcd"), "12").unwrap();
+
+        let stats = repo_stats(&storage, "repo");
+        assert_eq!(stats.size, 10);
+        assert_eq!(stats.files["data"], 1);
+        assert_eq!(stats.files["keys"], 1);
+        assert_eq!(stats.files["index"], 0);
+        assert!(stats.last_modified.is_some());
+        assert_eq!(format_table(&[stats]).lines().count(), 2);
+
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+    }
+}
@@ -0,0 +1,194 @@
+// mod status
+//
+// renders a read-only HTML status page at /admin/status listing the
+// repositories with their sizes and last backups and the recent events, so
+// operators get basic visibility without deploying a monitoring stack. The
+// page needs no JavaScript and reloads itself every REFRESH seconds.
+
+use std::fmt::Write;
+use std::path::Path;
+
+use axum::extract;
+use axum::response::Html;
+use axum::routing::get;
+use axum::Router;
+
+use crate::activity::RepoActivity;
+use crate::admin::AdminFromRequest;
+use crate::events::ServerEvent;
+use crate::stats::{format_size, format_time};
+use crate::storage::DiskSpace;
+use crate::web::{blocking, Error, State};
+
+// REFRESH is the number of seconds after which browsers reload the page
+const REFRESH: u32 = 60;
+
+pub fn router() -> Router<State> {
+    Router::new().route("/admin/status", get(status))
+}
+
+// RepoStatus is a line of the repository table
+struct RepoStatus {
+    repo: String,
+    size: Option<u64>,
+    snapshots: usize,
+    // RFC 3339 timestamp of the newest snapshot
+    last_backup: Option<String>,
+    last_read: Option<String>,
+}
+
+async fn status(
+    extract::State(state): extract::State<State>,
+    admin: AdminFromRequest,
+) -> Result<Html<String>, Error> {
+    tracing::debug!(admin = admin.user, "status page");
+    let events = state.events().recent();
+    let (repos, space) = blocking(move || {
+        let storage = state.storage();
+        let repos = storage
+            .repos()
+            .into_iter()
+            .map(|repo| repo_status(&state, repo))
+            .collect::<Vec<_>>();
+        Ok((repos, storage.disk_space().ok()))
+    })
+    .await?;
+    Ok(Html(render(&repos, space, &events)))
+}
+
+fn repo_status(state: &State, repo: String) -> RepoStatus {
+    let storage = state.storage();
+    let path = Path::new(&repo);
+    let mut snapshots = 0;
+    let mut newest = None;
+    for entry in storage.read_dir(path, "snapshots") {
+        snapshots += 1;
+        let modified = entry.metadata().ok().and_then(|m| m.modified().ok());
+        newest = newest.max(modified);
+    }
+    RepoStatus {
+        size: state.usage().get(storage, &repo).ok(),
+        snapshots,
+        last_backup: newest.map(format_time),
+        last_read: RepoActivity::read(storage, path).last_read,
+        repo,
+    }
+}
+
+fn render(repos: &[RepoStatus], space: Option<DiskSpace>, events: &[ServerEvent]) -> String {
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta http-equiv=\"refresh\" content=\"{REFRESH}\">\n<title>rustic-server status</title>\n\
+         <style>body{{font-family:sans-serif}}table{{border-collapse:collapse}}\
+         td,th{{padding:2px 8px;text-align:left;border-bottom:1px solid #ddd}}\
+         td.num{{text-align:right}}</style>\n</head>\n<body>\n\
+         <h1>rustic-server {}</h1>\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    let total: u64 = repos.iter().filter_map(|repo| repo.size).sum();
+    _ = write!(
+        page,
+        "<p>{} repositories, {}",
+        repos.len(),
+        format_size(total)
+    );
+    if let Some(space) = space {
+        _ = write!(
+            page,
+            "; {} of {} free on the storage",
+            format_size(space.available),
+            format_size(space.total)
+        );
+    }
+    page.push_str(
+        "</p>\n<h2>Repositories</h2>\n<table>\n<tr><th>Repository</th><th>Size</th>\
+         <th>Snapshots</th><th>Last backup</th><th>Last read</th></tr>\n",
+    );
+    for repo in repos {
+        _ = writeln!(
+            page,
+            "<tr><td>{}</td><td class=\"num\">{}</td><td class=\"num\">{}</td><td>{}</td><td>{}</td></tr>",
+            escape(&repo.repo),
+            repo.size.map_or("-".to_string(), format_size),
+            repo.snapshots,
+            repo.last_backup.as_deref().unwrap_or("-"),
+            repo.last_read.as_deref().unwrap_or("-"),
+        );
+    }
+    page.push_str("</table>\n<h2>Recent events</h2>\n");
+    if events.is_empty() {
+        page.push_str("<p>No events since the start of the server.</p>\n");
+    } else {
+        page.push_str(
+            "<table>\n<tr><th>Time</th><th>Event</th><th>Repository</th><th>File</th>\
+             <th>User</th><th>Message</th></tr>\n",
+        );
+        for event in events {
+            let file = match event.tpe.is_empty() {
+                true => String::new(),
+                false => format!(
+                    "{}/{}",
+                    event.tpe,
+                    event.name.get(..8).unwrap_or(&event.name)
+                ),
+            };
+            _ = writeln!(
+                page,
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                event.time,
+                event.event,
+                escape(&event.repo),
+                escape(&file),
+                escape(&event.user),
+                escape(&event.message),
+            );
+        }
+        page.push_str("</table>\n");
+    }
+    page.push_str("</body>\n</html>\n");
+    page
+}
+
+// escape replaces the characters with a meaning in HTML by entities
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_page() {
+        let repos = [RepoStatus {
+            repo: "alice/<laptop>".to_string(),
+            size: Some(2048),
+            snapshots: 3,
+            last_backup: Some("2024-05-01T02:00:00Z".to_string()),
+            last_read: None,
+        }];
+        let events = [ServerEvent {
+            repo: "alice/<laptop>".to_string(),
+            tpe: "snapshots".to_string(),
+            name: "0123456789abcdef".to_string(),
+            user: "alice".to_string(),
+            ..ServerEvent::new("upload")
+        }];
+        let page = render(&repos, None, &events);
+        assert!(page.contains("<td>alice/&lt;laptop&gt;</td><td class=\"num\">2.0 KiB</td>"));
+        assert!(page.contains("<td>snapshots/01234567</td>"));
+        assert!(!page.contains("<laptop>"));
+        assert!(render(&[], None, &[]).contains("No events"));
+    }
+}
@@ -2,45 +2,192 @@ use std::fs;
 use std::path::{Path, PathBuf};
 
 use crate::helpers::WriteOrDeleteFile;
-use async_std::fs::File;
-use async_std::io::Result;
+use crate::listing::{FileEntry, ListingCache};
+use std::io::{self, Result};
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::fs::File;
 use walkdir::WalkDir;
 
+// find_repos returns the paths of all repositories below data, i.e. all
+// directories containing a config file and a keys directory
+pub fn find_repos(data: &Path) -> Vec<String> {
+    let mut repos: Vec<_> = WalkDir::new(data)
+        .into_iter()
+        .filter_entry(|e| e.depth() == 0 || !e.file_name().to_string_lossy().starts_with('.'))
+        .filter_map(walkdir::Result::ok)
+        .filter(|e| e.file_type().is_dir())
+        .filter(|e| is_repo(e.path()))
+        .filter_map(|e| {
+            let path = e.path().strip_prefix(data).ok()?;
+            Some(path.to_string_lossy().replace('\\', "/"))
+        })
+        .collect();
+    repos.sort();
+    repos
+}
+
+// DiskSpace describes the filesystem containing the storage in bytes
+#[derive(Clone, Copy, Debug)]
+pub struct DiskSpace {
+    pub total: u64,
+    // space available to unprivileged users
+    pub available: u64,
+}
+
+// disk_space returns size and available space of the filesystem containing path
+#[cfg(unix)]
+// the field types of statvfs differ between platforms
+#[allow(clippy::useless_conversion)]
+pub fn disk_space(path: &Path) -> Result<DiskSpace> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: statvfs is plain data which is filled by the call
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: path is a valid C string and stat a valid pointer
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let block_size = u64::from(stat.f_frsize);
+    Ok(DiskSpace {
+        total: u64::from(stat.f_blocks) * block_size,
+        available: u64::from(stat.f_bavail) * block_size,
+    })
+}
+
+#[cfg(not(unix))]
+pub fn disk_space(_path: &Path) -> Result<DiskSpace> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "determining the free disk space is only supported on unix",
+    ))
+}
+
+// OWNER_MARKER holds the name of the user who created a repository
+pub const OWNER_MARKER: &str = ".owner";
+// FROZEN_MARKER marks a repository as frozen and holds the reason
+pub const FROZEN_MARKER: &str = ".frozen";
+// READ_ONLY_MARKER makes a repository read-only and holds the reason; unlike
+// the other markers, it is meant to be created by hand, e.g. with touch
+pub const READ_ONLY_MARKER: &str = ".read-only";
+// APPEND_ONLY_MARKER makes a repository append-only and holds the reason
+pub const APPEND_ONLY_MARKER: &str = ".append-only";
+// TEMPLATE_MARKER holds the name of the template a repository was created with
+pub const TEMPLATE_MARKER: &str = ".template";
+
+// is_repo returns whether dir contains a repository
+pub fn is_repo(dir: &Path) -> bool {
+    dir.join("config").is_file() && dir.join("keys").is_dir()
+}
+
+// CREATE_THREADS is the number of threads creating the directories of a repository
+const CREATE_THREADS: usize = 8;
+
+// create_repo_dirs creates the missing ancestors of repo, then its type
+// directories and the shards of data in parallel; the created directories are
+// added to created
+fn create_repo_dirs(repo: &Path, types: &[&str], created: &Mutex<Vec<PathBuf>>) -> Result<()> {
+    let mut missing: Vec<_> = repo.ancestors().take_while(|dir| !dir.exists()).collect();
+    while let Some(dir) = missing.pop() {
+        create_dir(dir, created)?;
+    }
+    let dirs: Vec<_> = types.iter().map(|tpe| repo.join(tpe)).collect();
+    create_dirs(&dirs, created)?;
+    if types.contains(&"data") {
+        let shards: Vec<_> = (0..256)
+            .map(|i| repo.join("data").join(format!("{i:02x}")))
+            .collect();
+        create_dirs(&shards, created)?;
+    }
+    Ok(())
+}
+
+// create_dirs creates dirs using up to CREATE_THREADS threads
+fn create_dirs(dirs: &[PathBuf], created: &Mutex<Vec<PathBuf>>) -> Result<()> {
+    let chunk = dirs.len().div_ceil(CREATE_THREADS).max(1);
+    std::thread::scope(|scope| {
+        let threads: Vec<_> = dirs
+            .chunks(chunk)
+            .map(|dirs| {
+                scope.spawn(move || dirs.iter().try_for_each(|dir| create_dir(dir, created)))
+            })
+            .collect();
+        threads.into_iter().try_for_each(|thread| {
+            thread
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("cannot create directories")))
+        })
+    })
+}
+
+// create_dir creates dir if it doesn't exist yet and notes it in created
+fn create_dir(dir: &Path, created: &Mutex<Vec<PathBuf>>) -> Result<()> {
+    match fs::create_dir(dir) {
+        Ok(()) => {
+            created
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(dir.to_path_buf());
+            Ok(())
+        }
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists && dir.is_dir() => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
 #[async_trait::async_trait]
 pub trait Storage: Send + Sync + 'static {
-    fn create_dir(&self, path: &Path, tpe: &str) -> std::io::Result<()>;
+    // create_repo creates the directories of the given types within the
+    // repository at path; on errors, the directories created so far are removed
+    fn create_repo(&self, path: &Path, types: &[&str]) -> Result<()>;
     fn read_dir(&self, path: &Path, tpe: &str) -> Box<dyn Iterator<Item = walkdir::DirEntry>>;
+    // list returns the files of type tpe as listed by the REST API, sorted by name
+    fn list(&self, path: &Path, tpe: &str) -> Result<Arc<Vec<FileEntry>>>;
     fn filename(&self, path: &Path, tpe: &str, name: &str) -> PathBuf;
     async fn open_file(&self, path: &Path, tpe: &str, name: &str) -> Result<File>;
     async fn create_file(&self, path: &Path, tpe: &str, name: &str) -> Result<WriteOrDeleteFile>;
     fn remove_file(&self, path: &Path, tpe: &str, name: &str) -> Result<()>;
+    // remove_repo deletes the repository at path with all its files
+    fn remove_repo(&self, path: &Path) -> Result<()>;
+    fn size(&self, path: &Path) -> Result<u64>;
+    fn repos(&self) -> Vec<String>;
+    fn disk_space(&self) -> Result<DiskSpace>;
+    // marker returns the content of the marker file name within the
+    // repository at path, None if it doesn't exist
+    fn marker(&self, path: &Path, name: &str) -> Option<String>;
+    // set_marker writes the marker file name, None removes it
+    fn set_marker(&self, path: &Path, name: &str, content: Option<&str>) -> Result<()>;
 }
 
 #[derive(Clone)]
 pub struct LocalStorage {
     path: PathBuf,
+    listings: ListingCache,
 }
 
 impl LocalStorage {
     pub fn try_new(path: &Path) -> Result<Self> {
         Ok(Self {
             path: path.to_path_buf(),
+            listings: ListingCache::default(),
         })
     }
 }
 
 #[async_trait::async_trait]
 impl Storage for LocalStorage {
-    fn create_dir(&self, path: &Path, tpe: &str) -> std::io::Result<()> {
-        match tpe {
-            "data" => {
-                for i in 0..256 {
-                    fs::create_dir_all(self.path.join(path).join(tpe).join(format!("{:02x}", i)))?
+    fn create_repo(&self, path: &Path, types: &[&str]) -> Result<()> {
+        let created = Mutex::new(Vec::new());
+        let res = create_repo_dirs(&self.path.join(path), types, &created);
+        if res.is_err() {
+            let created = created.into_inner().unwrap_or_else(PoisonError::into_inner);
+            for dir in created.iter().rev() {
+                if let Err(err) = fs::remove_dir(dir) {
+                    tracing::warn!(?dir, "cannot remove directory: {err}");
                 }
-                Ok(())
             }
-            _ => fs::create_dir_all(self.path.join(path).join(tpe)),
         }
+        res
     }
 
     fn read_dir(&self, path: &Path, tpe: &str) -> Box<dyn Iterator<Item = walkdir::DirEntry>> {
@@ -51,6 +198,10 @@ impl Storage for LocalStorage {
         Box::new(walker)
     }
 
+    fn list(&self, path: &Path, tpe: &str) -> Result<Arc<Vec<FileEntry>>> {
+        self.listings.list(&self.path.join(path).join(tpe))
+    }
+
     fn filename(&self, path: &Path, tpe: &str, name: &str) -> PathBuf {
         match tpe {
             "config" => self.path.join(path).join("config"),
@@ -61,16 +212,109 @@ impl Storage for LocalStorage {
 
     async fn open_file(&self, path: &Path, tpe: &str, name: &str) -> Result<File> {
         let file_path = self.filename(path, tpe, name);
-        Ok(File::open(file_path).await?)
+        File::open(file_path).await
     }
 
     async fn create_file(&self, path: &Path, tpe: &str, name: &str) -> Result<WriteOrDeleteFile> {
         let file_path = self.filename(path, tpe, name);
-        WriteOrDeleteFile::new(file_path).await
+        let dir = self.path.join(path).join(tpe);
+        // pack files are large enough to be worth writing through io_uring
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        let file = match tpe {
+            "data" => WriteOrDeleteFile::uring(file_path).await?,
+            _ => WriteOrDeleteFile::new(file_path).await?,
+        };
+        #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+        let file = WriteOrDeleteFile::new(file_path).await?;
+        self.listings.invalidate(&dir);
+        Ok(file.invalidating(self.listings.clone(), dir))
     }
 
     fn remove_file(&self, path: &Path, tpe: &str, name: &str) -> Result<()> {
         let file_path = self.filename(path, tpe, name);
-        fs::remove_file(file_path)
+        fs::remove_file(file_path)?;
+        self.listings.invalidate(&self.path.join(path).join(tpe));
+        Ok(())
+    }
+
+    fn remove_repo(&self, path: &Path) -> Result<()> {
+        let res = fs::remove_dir_all(self.path.join(path));
+        self.listings.invalidate_below(&self.path.join(path));
+        res
+    }
+
+    // size returns the total size of all files within the repository at path
+    fn size(&self, path: &Path) -> Result<u64> {
+        let mut size = 0;
+        for entry in WalkDir::new(self.path.join(path)) {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                size += entry.metadata()?.len();
+            }
+        }
+        Ok(size)
+    }
+
+    fn repos(&self) -> Vec<String> {
+        find_repos(&self.path)
+    }
+
+    fn disk_space(&self) -> Result<DiskSpace> {
+        disk_space(&self.path)
+    }
+
+    fn marker(&self, path: &Path, name: &str) -> Option<String> {
+        let content = fs::read_to_string(self.path.join(path).join(name)).ok()?;
+        Some(content.trim().to_string())
+    }
+
+    fn set_marker(&self, path: &Path, name: &str, content: Option<&str>) -> Result<()> {
+        let file = self.path.join(path).join(name);
+        match content {
+            Some(content) => fs::write(file, format!("{content}\n")),
+            None => match fs::remove_file(file) {
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                res => res,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn create_repo() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::try_new(dir.path()).unwrap();
+        storage
+            .create_repo(Path::new("alice/laptop"), &["data", "keys"])
+            .unwrap();
+        assert!(dir.path().join("alice/laptop/keys").is_dir());
+        assert!(dir.path().join("alice/laptop/data/00").is_dir());
+        assert!(dir.path().join("alice/laptop/data/ff").is_dir());
+        // creating it again is fine
+        storage
+            .create_repo(Path::new("alice/laptop"), &["data", "keys"])
+            .unwrap();
+
+        // a failure removes the created directories, but nothing which
+        // existed before
+        let repo = dir.path().join("bob/repo");
+        fs::create_dir_all(&repo).unwrap();
+        fs::write(repo.join("data"), "not a directory").unwrap();
+        assert!(storage
+            .create_repo(Path::new("bob/repo"), &["keys", "locks", "data"])
+            .is_err());
+        let left: Vec<_> = fs::read_dir(&repo)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(left, ["data"]);
+        assert!(storage
+            .create_repo(Path::new("bob/repo/data/sub"), &["keys"])
+            .is_err());
+        assert!(repo.join("data").is_file());
     }
 }
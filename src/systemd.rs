@@ -0,0 +1,92 @@
+// mod systemd
+//
+// support for systemd socket activation and readiness notification, see
+// sd_listen_fds(3) and sd_notify(3)
+
+use std::env;
+use std::io;
+use std::net::TcpListener;
+
+// SD_LISTEN_FDS_START is the first file descriptor passed by systemd
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+// ActivatedSocket is a listening socket passed by systemd together with
+// the name given by FileDescriptorName= in the socket unit
+pub struct ActivatedSocket {
+    pub listener: TcpListener,
+    pub name: String,
+}
+
+// listeners returns the sockets passed by systemd via socket activation.
+// Returns an empty list if the process was not socket activated.
+// The environment variables are removed, so child processes don't inherit them.
+#[cfg(unix)]
+pub fn listeners() -> io::Result<Vec<ActivatedSocket>> {
+    use std::os::unix::io::FromRawFd;
+
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(Vec::new());
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(Vec::new());
+    }
+    let fds: i32 = fds.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid LISTEN_FDS {fds}"),
+        )
+    })?;
+
+    let mut names = names.split(':');
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds)
+        .map(|fd| {
+            // SAFETY: systemd passes ownership of the file descriptors starting
+            // at SD_LISTEN_FDS_START to us, and we take each one exactly once.
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Ok(ActivatedSocket {
+                listener,
+                name: names.next().unwrap_or_default().to_string(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn listeners() -> io::Result<Vec<ActivatedSocket>> {
+    Ok(Vec::new())
+}
+
+// notify sends a state like "READY=1" to systemd if the service runs with Type=notify.
+// Does nothing if NOTIFY_SOCKET is not set.
+#[cfg(unix)]
+pub fn notify(state: &str) -> io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let socket = UnixDatagram::unbound()?;
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.to_str().and_then(|p| p.strip_prefix('@')) {
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+        return Ok(());
+    }
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) -> io::Result<()> {
+    Ok(())
+}
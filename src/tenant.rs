@@ -0,0 +1,340 @@
+// mod tenant
+//
+// provisions and tears down tenants: a user of the htpasswd file, its ACL
+// grant, quota and repository are changed together in one step, so hosting
+// automation doesn't need to edit several files
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use toml_edit::{value, Item, Table};
+
+use crate::acl::{AccessType, Acl, AclChecker};
+use crate::auth::htpasswd_line;
+use crate::config::Config;
+use crate::edit::{self, conflict, internal, parse, read_optional, repo_path, table, Transaction};
+use crate::immutable::IMMUTABLE_MARKER;
+use crate::storage::{is_repo, OWNER_MARKER};
+use crate::web::Error;
+
+// Tenant is a user to provision together with its repository
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tenant {
+    pub user: String,
+    pub password: String,
+    // repository path, defaults to the user name
+    pub repo: Option<String>,
+    // access of the user to the repository
+    #[serde(default = "default_access")]
+    pub access: AccessType,
+    // maximum size in bytes of all repositories the user may write to
+    pub quota: Option<u64>,
+}
+
+// the tenant must be able to create its repository
+fn default_access() -> AccessType {
+    AccessType::Create
+}
+
+#[derive(Debug, Serialize)]
+pub struct Provisioned {
+    pub user: String,
+    pub repo: String,
+}
+
+// provision adds the user to the htpasswd file, grants it access to the
+// repository in the ACL file, sets its quota in the config file and creates
+// the empty repository directory. If any step fails, the files are restored.
+pub fn provision(config: &Config, tenant: &Tenant) -> Result<Provisioned, Error> {
+    check_user(&tenant.user)?;
+    let repo = repo_path(tenant.repo.as_deref().unwrap_or(&tenant.user))?;
+    if config.auth.disable {
+        return Err(conflict("authentication is disabled"));
+    }
+    let acl_file = config
+        .acl
+        .path
+        .as_deref()
+        .ok_or_else(|| conflict("no ACL file is configured"))?;
+    let config_file = match tenant.quota {
+        Some(_) => Some(
+            config
+                .file
+                .as_deref()
+                .ok_or_else(|| conflict("quotas need a config file"))?,
+        ),
+        None => None,
+    };
+
+    let _lock = edit::lock();
+    let htpasswd_file = config.htpasswd_path();
+    let mut htpasswd = read_optional(&htpasswd_file)?;
+    if htpasswd_users(&htpasswd).any(|user| user == tenant.user) {
+        return Err(conflict(format!("user {} already exists", tenant.user)));
+    }
+    let dir = config.storage.path.join(&repo);
+    if dir.exists() {
+        return Err(conflict(format!("repository {repo} already exists")));
+    }
+
+    if !htpasswd.is_empty() && !htpasswd.ends_with('\n') {
+        htpasswd.push('\n');
+    }
+    htpasswd.push_str(&htpasswd_line(&tenant.user, &tenant.password).map_err(internal)?);
+    let mut acl = parse(acl_file)?;
+    table(acl.as_table_mut(), &repo, false)?
+        .insert(&tenant.user, value(format!("{:?}", tenant.access)));
+    let config_doc = match (config_file, tenant.quota) {
+        (Some(file), Some(quota)) => {
+            let mut doc = parse(file)?;
+            let quota = i64::try_from(quota)
+                .map_err(|_| Error::new(StatusCode::BAD_REQUEST, "quota is too large"))?;
+            let users = table(doc.as_table_mut(), "users", true)?;
+            table(users, &tenant.user, false)?.insert("quota", value(quota));
+            Some((file, doc))
+        }
+        _ => None,
+    };
+
+    let mut transaction = Transaction::default();
+    let res = (|| {
+        transaction.replace(&htpasswd_file, &htpasswd, true)?;
+        transaction.replace(acl_file, &acl.to_string(), false)?;
+        if let Some((file, doc)) = &config_doc {
+            transaction.replace(file, &doc.to_string(), false)?;
+        }
+        fs::create_dir_all(&dir)?;
+        transaction.created = Some(dir.clone());
+        fs::write(dir.join(OWNER_MARKER), format!("{}\n", tenant.user))
+    })();
+    if let Err(err) = res {
+        transaction.rollback();
+        return Err(err.into());
+    }
+    Ok(Provisioned {
+        user: tenant.user.clone(),
+        repo,
+    })
+}
+
+// teardown removes the user from the htpasswd file, all its ACL grants and
+// its settings in the config file. If repo is given, the repository is
+// deleted, too.
+pub fn teardown(config: &Config, user: &str, repo: Option<&str>) -> Result<(), Error> {
+    let repo = repo.map(repo_path).transpose()?;
+    let _lock = edit::lock();
+    let htpasswd_file = config.htpasswd_path();
+    let htpasswd = read_optional(&htpasswd_file)?;
+    if !htpasswd_users(&htpasswd).any(|u| u == user) {
+        return Err(Error::new(
+            StatusCode::NOT_FOUND,
+            format!("user {user} not found"),
+        ));
+    }
+    let dir = repo.as_ref().map(|repo| config.storage.path.join(repo));
+    if let Some(dir) = dir.as_ref().filter(|dir| !dir.is_dir()) {
+        return Err(Error::new(
+            StatusCode::NOT_FOUND,
+            format!("repository {} not found", dir.display()),
+        ));
+    }
+    if let (Some(repo), Some(dir)) = (&repo, &dir) {
+        check_owned(config, user, repo, dir)?;
+    }
+    if let Some(dir) = dir
+        .as_ref()
+        .filter(|dir| dir.join(IMMUTABLE_MARKER).exists())
+    {
+        return Err(Error::new(
+            StatusCode::FORBIDDEN,
+            format!("repository {} is immutable", dir.display()),
+        ));
+    }
+
+    let htpasswd: String = htpasswd
+        .lines()
+        .filter(|line| line.split(':').next() != Some(user))
+        .map(|line| format!("{line}\n"))
+        .collect();
+    let acl = match config.acl.path.as_deref() {
+        Some(file) => {
+            let mut doc = parse(file)?;
+            remove_user(doc.as_table_mut(), user);
+            Some((file, doc))
+        }
+        None => None,
+    };
+    let config_doc = match config.file.as_deref() {
+        Some(file) => {
+            let mut doc = parse(file)?;
+            if let Some(users) = doc.get_mut("users").and_then(Item::as_table_like_mut) {
+                _ = users.remove(user);
+            }
+            Some((file, doc))
+        }
+        None => None,
+    };
+
+    let mut transaction = Transaction::default();
+    let res = (|| {
+        transaction.replace(&htpasswd_file, &htpasswd, true)?;
+        for (file, doc) in acl.iter().chain(&config_doc) {
+            transaction.replace(file, &doc.to_string(), false)?;
+        }
+        Ok::<_, io::Error>(())
+    })();
+    if let Err(err) = res {
+        transaction.rollback();
+        return Err(err.into());
+    }
+    // the data can't be restored, so it is deleted last
+    if let Some(dir) = dir {
+        fs::remove_dir_all(dir)?;
+    }
+    Ok(())
+}
+
+// check_owned fails unless dir is a repository of user, so a typo doesn't
+// delete the data of another tenant. It must be a repository or one
+// provisioned for user, which restic hasn't initialized yet, and user must have
+// created it or be allowed to write to it by the ACL file.
+fn check_owned(config: &Config, user: &str, repo: &str, dir: &Path) -> Result<(), Error> {
+    let owner = fs::read_to_string(dir.join(OWNER_MARKER)).ok();
+    let created = owner.as_deref().map(str::trim) == Some(user);
+    let provisioned = created && fs::read_dir(dir)?.count() == 1;
+    if !is_repo(dir) && !provisioned {
+        return Err(conflict(format!("{repo} is not a repository")));
+    }
+    // only explicit grants count, not the access of all users to any repository
+    let granted = || -> Result<bool, Error> {
+        let acl = Acl::from_file(false, true, config.acl.path.clone()).map_err(internal)?;
+        Ok(acl.allowed(user, repo, "data", AccessType::Append))
+    };
+    if !created && !granted()? {
+        return Err(conflict(format!(
+            "repository {repo} doesn't belong to user {user}"
+        )));
+    }
+    Ok(())
+}
+
+fn check_user(user: &str) -> Result<(), Error> {
+    let invalid = user.is_empty() || user.contains([':', '\n', '\r']) || user.trim() != user;
+    match invalid {
+        true => Err(Error::new(
+            StatusCode::BAD_REQUEST,
+            format!("invalid user name {user:?}"),
+        )),
+        false => Ok(()),
+    }
+}
+
+fn htpasswd_users(htpasswd: &str) -> impl Iterator<Item = &str> {
+    htpasswd.lines().filter_map(|line| line.split(':').next())
+}
+
+// remove_user removes the grants and the admin capability of user from an
+// ACL file; repos without any grant left are removed
+fn remove_user(acl: &mut Table, user: &str) {
+    acl.retain(|_, item| match item.as_table_like_mut() {
+        Some(repo) => repo.remove(user).is_none() || !repo.is_empty(),
+        None => true,
+    });
+    if let Some(admins) = acl.get_mut("admins").and_then(Item::as_array_mut) {
+        admins.retain(|admin| admin.as_str() != Some(user));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{Auth, AuthChecker};
+
+    #[test]
+    fn provision_and_teardown() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_file = dir.path().join("rustic_server.toml");
+        let acl_file = dir.path().join("acl.toml");
+        fs::write(&acl_file, "# grants\n[bob]\nbob = \"Modify\"\n").unwrap();
+        fs::write(
+            &config_file,
+            format!(
+                "[storage]\npath = {:?}\n[auth]\nhtpasswd = {:?}\n[acl]\npath = {acl_file:?}\n",
+                dir.path().join("data"),
+                dir.path().join(".htpasswd")
+            ),
+        )
+        .unwrap();
+        let config = Config::from_file(&config_file).unwrap();
+
+        let tenant = Tenant {
+            user: "alice".to_string(),
+            password: "secret".to_string(),
+            repo: Some("alice/laptop".to_string()),
+            access: AccessType::Modify,
+            quota: Some(1000),
+        };
+        let provisioned = provision(&config, &tenant).unwrap();
+        assert_eq!(provisioned.repo, "alice/laptop");
+        assert!(dir.path().join("data/alice/laptop/.owner").is_file());
+        let (auth, acl) = Config::from_file(&config_file)
+            .unwrap()
+            .load_access()
+            .unwrap();
+        assert!(auth.verify("alice", "secret"));
+        assert!(acl.allowed("alice", "alice/laptop", "data", AccessType::Modify));
+        assert_eq!(
+            Config::from_file(&config_file).unwrap().users["alice"].quota,
+            Some(1000)
+        );
+        assert!(fs::read_to_string(&acl_file)
+            .unwrap()
+            .starts_with("# grants\n"));
+
+        // nothing is changed if the tenant exists
+        let err = provision(&config, &tenant).unwrap_err();
+        assert_eq!(err.status(), StatusCode::CONFLICT);
+        assert!(provision(
+            &config,
+            &Tenant {
+                user: "a:b".to_string(),
+                ..tenant
+            }
+        )
+        .is_err());
+
+        // only repositories of the user are deleted
+        let carol = dir.path().join("data/carol");
+        fs::create_dir_all(carol.join("keys")).unwrap();
+        fs::write(carol.join("config"), "x").unwrap();
+        let other = dir.path().join("data/other");
+        fs::create_dir_all(&other).unwrap();
+        for repo in ["carol", "other"] {
+            assert_eq!(
+                teardown(&config, "alice", Some(repo)).unwrap_err().status(),
+                StatusCode::CONFLICT
+            );
+        }
+        assert!(carol.join("config").is_file());
+        let auth = Auth::from_file(false, &config.htpasswd_path()).unwrap();
+        assert!(auth.has_user("alice"));
+
+        teardown(&config, "alice", Some("alice/laptop")).unwrap();
+        assert!(!dir.path().join("data/alice/laptop").exists());
+        let config = Config::from_file(&config_file).unwrap();
+        assert!(config.users.is_empty());
+        let auth = Auth::from_file(false, &config.htpasswd_path()).unwrap();
+        assert!(!auth.has_user("alice"));
+        let acl = Acl::from_file(false, true, Some(acl_file.clone())).unwrap();
+        assert!(!acl.allowed("alice", "alice/laptop", "data", AccessType::Read));
+        assert!(acl.allowed("bob", "bob", "data", AccessType::Modify));
+        assert_eq!(
+            teardown(&config, "alice", None).unwrap_err().status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+}
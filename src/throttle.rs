@@ -0,0 +1,214 @@
+// mod throttle
+//
+// limits the bandwidth of uploads and downloads; all streams sharing a
+// Throttle together don't exceed its rate. A Preemption makes a throttle
+// apply only while high-priority transfers are running.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use futures_util::stream::BoxStream;
+use futures_util::{Stream, StreamExt};
+
+// ACTIVE is how long a high-priority transfer counts as running after its
+// last chunk
+const ACTIVE: Duration = Duration::from_secs(1);
+
+// Throttle is a token bucket holding up to one second worth of bytes. Streams
+// may take more bytes than available and then wait until the debt is paid.
+#[derive(Clone, Debug)]
+pub struct Throttle {
+    bucket: Arc<Mutex<Bucket>>,
+    role: Role,
+}
+
+#[derive(Clone, Debug)]
+enum Role {
+    Always,
+    // the throttle only applies while the preemption is active
+    Preempted(Preemption),
+    // the throttle never delays but activates the preemption
+    Preempting(Preemption),
+}
+
+// Preemption tracks whether high-priority transfers are running
+#[derive(Clone, Debug, Default)]
+pub struct Preemption(Arc<Mutex<Option<Instant>>>);
+
+impl Preemption {
+    fn mark(&self, now: Instant) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = Some(now);
+    }
+
+    fn is_active(&self, now: Instant) -> bool {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_some_and(|last| now.saturating_duration_since(last) < ACTIVE)
+    }
+
+    // preempting returns a throttle for high-priority transfers
+    pub fn preempting(&self) -> Throttle {
+        Throttle {
+            role: Role::Preempting(self.clone()),
+            ..Throttle::new(u64::MAX)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    // bytes per second
+    rate: u64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Throttle {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate: rate.max(1),
+                tokens: 0.0,
+                updated: Instant::now(),
+            })),
+            role: Role::Always,
+        }
+    }
+
+    // preempted_by returns the throttle applying only while preemption is
+    // active, sharing the bucket of self
+    pub fn preempted_by(&self, preemption: &Preemption) -> Self {
+        Self {
+            bucket: self.bucket.clone(),
+            role: Role::Preempted(preemption.clone()),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.bucket
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .rate
+    }
+
+    // consume takes bytes from the bucket and waits until they may be sent
+    pub async fn consume(&self, bytes: usize) {
+        let delay = self.delay(bytes, Instant::now());
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    // consume_blocking is consume for threads outside the async runtime
+    pub fn consume_blocking(&self, bytes: usize) {
+        let delay = self.delay(bytes, Instant::now());
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+    }
+
+    // delay takes bytes from the bucket at time now and returns how long to
+    // wait before they may be sent
+    fn delay(&self, bytes: usize, now: Instant) -> Duration {
+        match &self.role {
+            Role::Always => {}
+            Role::Preempted(preemption) if preemption.is_active(now) => {}
+            Role::Preempted(_) => return Duration::ZERO,
+            Role::Preempting(preemption) => {
+                preemption.mark(now);
+                return Duration::ZERO;
+            }
+        }
+        let mut bucket = self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+        let rate = bucket.rate as f64;
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate) - bytes as f64;
+        bucket.updated = now;
+        match bucket.tokens < 0.0 {
+            true => Duration::from_secs_f64(-bucket.tokens / rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+// Throttles holds the throttles shared by all requests, e.g. per repository
+#[derive(Clone, Debug, Default)]
+pub struct Throttles(Arc<Mutex<HashMap<String, Throttle>>>);
+
+impl Throttles {
+    // get returns the throttle for key; it is replaced if its rate changed
+    pub fn get(&self, key: &str, rate: u64) -> Throttle {
+        let mut throttles = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        match throttles.get(key) {
+            Some(throttle) if throttle.rate() == rate.max(1) => throttle.clone(),
+            _ => {
+                let throttle = Throttle::new(rate);
+                _ = throttles.insert(key.to_string(), throttle.clone());
+                throttle
+            }
+        }
+    }
+}
+
+// throttle limits the bandwidth of stream to the rates of all throttles
+pub fn throttle<S, T, E>(stream: S, throttles: Vec<Throttle>) -> BoxStream<'static, Result<T, E>>
+where
+    S: Stream<Item = Result<T, E>> + Send + 'static,
+    T: AsRef<[u8]> + Send + 'static,
+    E: Send + 'static,
+{
+    let throttles = Arc::new(throttles);
+    stream
+        .then(move |chunk| {
+            let throttles = throttles.clone();
+            async move {
+                let len = chunk.as_ref().map_or(0, |data| data.as_ref().len());
+                for throttle in throttles.iter() {
+                    throttle.consume(len).await;
+                }
+                chunk
+            }
+        })
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay() {
+        let now = Instant::now();
+        let throttle = Throttle::new(1000);
+        assert_eq!(throttle.delay(500, now), Duration::from_millis(500));
+        assert_eq!(throttle.delay(500, now), Duration::from_secs(1));
+        // at most one second worth of bytes is saved up
+        let later = now + Duration::from_secs(10);
+        assert_eq!(throttle.delay(1000, later), Duration::ZERO);
+        assert_eq!(throttle.delay(100, later), Duration::from_millis(100));
+
+        let throttles = Throttles::default();
+        let a = throttles.get("repo", 1000);
+        _ = a.delay(1000, now);
+        assert_eq!(
+            throttles.get("repo", 1000).delay(0, now),
+            Duration::from_secs(1)
+        );
+        assert_eq!(throttles.get("repo", 2000).delay(0, now), Duration::ZERO);
+    }
+
+    #[test]
+    fn preemption() {
+        let now = Instant::now();
+        let preemption = Preemption::default();
+        let low = Throttle::new(1000).preempted_by(&preemption);
+        assert_eq!(low.delay(5000, now), Duration::ZERO);
+        assert_eq!(preemption.preempting().delay(5000, now), Duration::ZERO);
+        assert_eq!(low.delay(500, now), Duration::from_millis(500));
+        // the preemption ends a second after the last high-priority chunk
+        let later = now + ACTIVE;
+        assert_eq!(low.delay(5000, later), Duration::ZERO);
+    }
+}
@@ -0,0 +1,263 @@
+// mod tls
+//
+// builds the rustls config according to the TLS policy, reloads the TLS
+// certificate when the files change and warns before it expires;
+// generates self-signed certificates
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail, Context, Result};
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, SupportedProtocolVersion};
+
+use crate::config::TlsConfig;
+
+// SECONDS_PER_DAY is used to convert expiry_warning_days
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// provider returns the crypto provider restricted to the configured cipher suites
+pub fn provider(config: &TlsConfig) -> Result<CryptoProvider> {
+    let mut provider = rustls::crypto::ring::default_provider();
+    if !config.ciphers.is_empty() {
+        let available: Vec<_> = provider
+            .cipher_suites
+            .iter()
+            .map(|s| format!("{:?}", s.suite()))
+            .collect();
+        if let Some(unknown) = config.ciphers.iter().find(|c| !available.contains(c)) {
+            bail!(
+                "unknown cipher suite {unknown}, available are {}",
+                available.join(", ")
+            );
+        }
+        provider
+            .cipher_suites
+            .retain(|s| config.ciphers.contains(&format!("{:?}", s.suite())));
+    }
+    Ok(provider)
+}
+
+// protocol_versions returns the TLS versions allowed by min_version
+pub fn protocol_versions(
+    config: &TlsConfig,
+) -> Result<&'static [&'static SupportedProtocolVersion]> {
+    static TLS12_AND_13: &[&SupportedProtocolVersion] =
+        &[&rustls::version::TLS13, &rustls::version::TLS12];
+    static TLS13: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13];
+    match config.min_version.as_str() {
+        "1.2" => Ok(TLS12_AND_13),
+        "1.3" => Ok(TLS13),
+        version => bail!("unsupported TLS version {version:?}, use \"1.2\" or \"1.3\""),
+    }
+}
+
+// server_config builds the rustls config for cert and key given in PEM format
+// applying the TLS policy of config. If client_ca is set, clients must present
+// a certificate signed by one of the CAs within this file.
+pub fn server_config(config: &TlsConfig, cert: &[u8], key: &[u8]) -> Result<Arc<ServerConfig>> {
+    let provider = Arc::new(provider(config)?);
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(protocol_versions(config)?)?;
+    let builder = match &config.client_ca {
+        None => builder.with_no_client_auth(),
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for ca in CertificateDer::pem_file_iter(client_ca)
+                .with_context(|| format!("cannot read client CA {}", client_ca.display()))?
+            {
+                roots.add(ca?)?;
+            }
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+    };
+
+    let certs = CertificateDer::pem_slice_iter(cert).collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_slice(key)?;
+    let mut server_config = builder.with_single_cert(certs, key)?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(server_config))
+}
+
+// load reads cert and key and builds the rustls config, see server_config
+pub fn load(config: &TlsConfig, cert: &Path, key: &Path) -> Result<Arc<ServerConfig>> {
+    let cert_pem =
+        fs::read(cert).with_context(|| format!("cannot read certificate {}", cert.display()))?;
+    let key_pem = fs::read(key).with_context(|| format!("cannot read key {}", key.display()))?;
+    server_config(config, &cert_pem, &key_pem)
+}
+
+// cert_not_after returns the end of the validity period of the first
+// certificate within the PEM file cert
+pub fn cert_not_after(cert: &Path) -> Result<SystemTime> {
+    let data = fs::read(cert)?;
+    let (_, pem) = x509_parser::pem::parse_x509_pem(&data)
+        .map_err(|err| anyhow!("invalid PEM data: {err}"))?;
+    let x509 = pem
+        .parse_x509()
+        .map_err(|err| anyhow!("invalid certificate: {err}"))?;
+    let not_after = u64::try_from(x509.validity().not_after.timestamp())
+        .context("certificate expires before 1970")?;
+    Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(not_after))
+}
+
+// check_expiry logs a warning if cert expires within warning_days
+pub fn check_expiry(cert: &Path, warning_days: u64) {
+    match cert_not_after(cert) {
+        Ok(not_after) => {
+            let warning = Duration::from_secs(warning_days * SECONDS_PER_DAY);
+            match not_after.duration_since(SystemTime::now()) {
+                Err(_) => tracing::error!("TLS certificate {} has expired", cert.display()),
+                Ok(remaining) if remaining < warning => tracing::warn!(
+                    "TLS certificate {} expires in {} days",
+                    cert.display(),
+                    remaining.as_secs() / SECONDS_PER_DAY
+                ),
+                Ok(_) => {}
+            }
+        }
+        Err(err) => tracing::warn!(
+            "cannot read expiry of TLS certificate {}: {err:#}",
+            cert.display()
+        ),
+    }
+}
+
+// SelfSigned is a generated certificate and key in PEM format
+pub struct SelfSigned {
+    pub cert: String,
+    pub key: String,
+    // SHA-256 fingerprint of the certificate, e.g. for pinning it in the client
+    pub fingerprint: String,
+}
+
+// generate_self_signed creates a certificate valid for days with hosts as
+// subject alternative names; IP addresses are recognized as such
+pub fn generate_self_signed(hosts: &[String], days: u32) -> Result<SelfSigned> {
+    let mut params = rcgen::CertificateParams::new(hosts)?;
+    if let Some(host) = hosts.first() {
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, host.as_str());
+    }
+    let now = time::OffsetDateTime::now_utc();
+    params.not_before = now;
+    params.not_after = now + time::Duration::days(days.into());
+    let key_pair = rcgen::KeyPair::generate()?;
+    let cert = params.self_signed(&key_pair)?;
+    Ok(SelfSigned {
+        cert: cert.pem(),
+        key: key_pair.serialize_pem(),
+        fingerprint: fingerprint(cert.der()),
+    })
+}
+
+// fingerprint formats the SHA-256 digest of der as colon-separated hex
+pub fn fingerprint(der: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, der)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// cert_fingerprint returns the fingerprint of the first certificate within
+// the PEM file cert
+pub fn cert_fingerprint(cert: &Path) -> Result<String> {
+    let der = CertificateDer::pem_file_iter(cert)?
+        .next()
+        .ok_or_else(|| anyhow!("no certificate in {}", cert.display()))??;
+    Ok(fingerprint(&der))
+}
+
+// modified returns the modification times of the given files
+fn modified(files: &[&Path]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|file| fs::metadata(file).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+// watch checks cert, key and client CA for changes every reload_interval and
+// reloads tls_config if they changed, e.g. after a renewal by certbot. The
+// expiry warning is logged once a day and after each reload.
+pub async fn watch(tls_config: RustlsConfig, config: TlsConfig, cert: PathBuf, key: PathBuf) {
+    let warning_days = config.expiry_warning_days;
+    let mut files = vec![cert.as_path(), key.as_path()];
+    files.extend(config.client_ca.as_deref());
+    let mut last_modified = modified(&files);
+    let mut last_check = SystemTime::now();
+    check_expiry(&cert, warning_days);
+
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.reload_interval));
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let current = modified(&files);
+        if current != last_modified {
+            match load(&config, &cert, &key) {
+                Ok(server_config) => {
+                    tls_config.reload_from_config(server_config);
+                    tracing::info!("reloaded TLS certificate {}", cert.display());
+                    last_modified = current;
+                    last_check = SystemTime::UNIX_EPOCH;
+                }
+                // cert and key may be in the middle of being replaced, retry next time
+                Err(err) => tracing::warn!("cannot reload TLS certificate: {err:#}"),
+            }
+        }
+        if last_check.elapsed().unwrap_or_default() >= Duration::from_secs(SECONDS_PER_DAY) {
+            check_expiry(&cert, warning_days);
+            last_check = SystemTime::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn self_signed() {
+        let dir = tempfile::tempdir().unwrap();
+        let hosts = ["localhost".to_string(), "127.0.0.1".to_string()];
+        let generated = generate_self_signed(&hosts, 10).unwrap();
+        assert_eq!(generated.fingerprint.len(), 32 * 3 - 1);
+
+        let cert = dir.path().join("cert.pem");
+        fs::write(&cert, &generated.cert).unwrap();
+        let remaining = cert_not_after(&cert)
+            .unwrap()
+            .duration_since(SystemTime::now())
+            .unwrap();
+        assert_eq!(remaining.as_secs() / SECONDS_PER_DAY, 9);
+    }
+
+    #[test]
+    fn policy() {
+        let generated = generate_self_signed(&["localhost".to_string()], 10).unwrap();
+        let (cert, key) = (generated.cert.as_bytes(), generated.key.as_bytes());
+        let mut config = TlsConfig::default();
+        assert!(server_config(&config, cert, key).is_ok());
+
+        config.min_version = "1.3".to_string();
+        config.ciphers = vec!["TLS13_AES_256_GCM_SHA384".to_string()];
+        let server = server_config(&config, cert, key).unwrap();
+        assert_eq!(server.crypto_provider().cipher_suites.len(), 1);
+
+        config.min_version = "1.1".to_string();
+        assert!(server_config(&config, cert, key).is_err());
+        config.min_version = "1.2".to_string();
+        config.ciphers = vec!["TLS_NULL_WITH_NULL_NULL".to_string()];
+        assert!(server_config(&config, cert, key).is_err());
+    }
+}
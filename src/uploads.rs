@@ -0,0 +1,120 @@
+// mod uploads
+//
+// coalesces concurrent uploads of the same file: a client retrying a slow
+// upload on a new connection would otherwise race the first upload, which
+// creates the file. Data, index, snapshot and key files are named by the hash
+// of their content, so a duplicate waits for the running upload and shares
+// its result instead of writing the file again. If the running upload fails,
+// the duplicate writes the file itself.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use tokio::sync::watch;
+
+// Uploads holds the running uploads by path; the channel tells whether the
+// upload succeeded once it finished
+#[derive(Clone, Debug, Default)]
+pub struct Uploads(Arc<Mutex<HashMap<String, watch::Receiver<Option<bool>>>>>);
+
+pub enum Upload {
+    // the request writes the file and reports the result with the guard
+    First(UploadGuard),
+    // another request wrote the file
+    Done,
+}
+
+impl Uploads {
+    // start registers the upload of path, or waits for the running upload of
+    // the same path
+    pub async fn start(&self, path: &str) -> Upload {
+        loop {
+            let mut running = {
+                let mut uploads = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+                match uploads.get(path) {
+                    Some(running) => running.clone(),
+                    None => {
+                        let (tx, rx) = watch::channel(None);
+                        _ = uploads.insert(path.to_string(), rx);
+                        return Upload::First(UploadGuard {
+                            uploads: self.clone(),
+                            path: path.to_string(),
+                            tx,
+                            succeeded: false,
+                        });
+                    }
+                }
+            };
+            tracing::debug!(path, "waiting for running upload of the same file");
+            let succeeded = running
+                .wait_for(Option::is_some)
+                .await
+                .is_ok_and(|result| *result == Some(true));
+            if succeeded {
+                return Upload::Done;
+            }
+        }
+    }
+}
+
+// UploadGuard marks an upload as running until it is dropped
+pub struct UploadGuard {
+    uploads: Uploads,
+    path: String,
+    tx: watch::Sender<Option<bool>>,
+    succeeded: bool,
+}
+
+impl UploadGuard {
+    pub fn succeeded(mut self) {
+        self.succeeded = true;
+    }
+}
+
+impl Drop for UploadGuard {
+    fn drop(&mut self) {
+        _ = self
+            .uploads
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.path);
+        _ = self.tx.send(Some(self.succeeded));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn coalesce() {
+        let uploads = Uploads::default();
+        let Upload::First(first) = uploads.start("repo/data/00").await else {
+            panic!("no upload running");
+        };
+        let duplicate = tokio::spawn({
+            let uploads = uploads.clone();
+            async move { matches!(uploads.start("repo/data/00").await, Upload::Done) }
+        });
+        assert!(matches!(
+            uploads.start("repo/data/01").await,
+            Upload::First(_)
+        ));
+        tokio::task::yield_now().await;
+        first.succeeded();
+        assert!(duplicate.await.unwrap());
+
+        // a duplicate of a failed upload writes the file itself
+        let Upload::First(first) = uploads.start("repo/data/00").await else {
+            panic!("upload still running");
+        };
+        let duplicate = tokio::spawn({
+            let uploads = uploads.clone();
+            async move { matches!(uploads.start("repo/data/00").await, Upload::First(_)) }
+        });
+        tokio::task::yield_now().await;
+        drop(first);
+        assert!(duplicate.await.unwrap());
+    }
+}
@@ -0,0 +1,273 @@
+// mod upstream
+//
+// forwards reads of repositories which don't exist locally to an upstream
+// restic REST server, to migrate repositories gradually or to run a caching
+// edge near remote offices. Clients are authenticated and checked against the
+// ACL here and their credentials are passed on. Files other than locks have
+// content-addressed names and never change, so with upstream.cache_dir they
+// are kept after the first complete download and served locally from then on.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::http::header::{
+    ACCEPT, ACCEPT_RANGES, AUTHORIZATION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, RANGE,
+};
+use axum::http::{HeaderMap, Method, StatusCode};
+use axum::response::Response;
+use tokio::io::AsyncWriteExt;
+
+use crate::acl::AccessType;
+use crate::check::to_hex;
+use crate::config::UpstreamConfig;
+use crate::helpers::{HashingWriter, WriteOrDeleteFile};
+use crate::progress::with_progress;
+use crate::storage::{LocalStorage, Storage};
+use crate::throttle::throttle;
+use crate::web::{
+    blocking, check_auth_and_acl, check_name, send_file, throttles, AuthFromRequest, Direction,
+    Error, Finalizer, PathParts, State, CONFIG_NAME, CONFIG_TYPE,
+};
+
+// Upstream is the server reads of missing repositories are forwarded to
+pub struct Upstream {
+    // base URL without trailing slash
+    url: String,
+    client: reqwest::Client,
+    cache: Option<LocalStorage>,
+}
+
+impl Upstream {
+    pub fn new(config: &UpstreamConfig) -> Option<Self> {
+        let url = config.url.as_deref()?.trim_end_matches('/').to_string();
+        let cache = config
+            .cache_dir
+            .as_deref()
+            .and_then(|dir| LocalStorage::try_new(dir).ok());
+        Some(Self {
+            url,
+            client: reqwest::Client::new(),
+            cache,
+        })
+    }
+
+    // forward sends the request for a file or listing to the upstream server
+    // and streams back its response, keeping complete downloads in the cache
+    pub async fn forward(
+        &self,
+        state: &State,
+        auth: &AuthFromRequest,
+        method: Method,
+        parts: &PathParts,
+        headers: &HeaderMap,
+    ) -> Result<Response, Error> {
+        let repo = parts.repo.as_str();
+        let tpe = parts.tpe.as_deref().unwrap_or_default();
+        tracing::debug!(repo, tpe, name = parts.name, "forward to upstream");
+        if let Some(name) = &parts.name {
+            check_name(tpe, name)?;
+        }
+        {
+            let (state, auth) = (state.clone(), auth.clone());
+            let (path, tpe) = (PathBuf::from(repo), tpe.to_string());
+            blocking(move || check_auth_and_acl(&state, &auth, &path, &tpe, AccessType::Read))
+                .await?;
+        }
+
+        let cache = self.cache.as_ref().filter(|_| tpe != "locks");
+        if let (Some(cache), Some(name)) = (cache.filter(|_| method == Method::GET), &parts.name) {
+            if let Ok(file) = cache.open_file(Path::new(repo), tpe, name).await {
+                return send_file(state, auth, repo, tpe, name, file, headers).await;
+            }
+        }
+
+        let mut req = self.client.request(method.clone(), self.url(parts));
+        for header in [AUTHORIZATION, ACCEPT, RANGE] {
+            if let Some(value) = headers.get(&header) {
+                req = req.header(header, value);
+            }
+        }
+        let res = req.send().await.map_err(|err| {
+            tracing::warn!(url = self.url, "upstream server not usable: {err}");
+            Error::new(StatusCode::BAD_GATEWAY, "upstream server not usable")
+        })?;
+
+        let status = res.status();
+        let mut builder = Response::builder().status(status);
+        for header in [CONTENT_TYPE, CONTENT_LENGTH, CONTENT_RANGE, ACCEPT_RANGES] {
+            if let Some(value) = res.headers().get(&header) {
+                builder = builder.header(header, value);
+            }
+        }
+        let content_length = res.content_length();
+        let cache_file = match (cache, &parts.name) {
+            (Some(cache), Some(name)) if method == Method::GET && status == StatusCode::OK => {
+                CacheFile::create(cache, repo, tpe, name, res.content_length()).await
+            }
+            _ => None,
+        };
+        let stream = futures_util::stream::unfold(Some((res, cache_file)), |next| async move {
+            let (mut res, mut cache_file) = next?;
+            match res.chunk().await {
+                Ok(Some(chunk)) => {
+                    // the body may not be polled again after its last byte
+                    if let Some(mut file) = cache_file.take() {
+                        match file.write(&chunk).await {
+                            Ok(true) => file.finish().await,
+                            Ok(false) => cache_file = Some(file),
+                            Err(err) => {
+                                tracing::warn!(path = ?file.path, "cannot write cache file: {err}");
+                            }
+                        }
+                    }
+                    Some((Ok(chunk), Some((res, cache_file))))
+                }
+                Ok(None) => {
+                    if let Some(file) = cache_file {
+                        file.finish().await;
+                    }
+                    None
+                }
+                Err(err) => Some((Err(io::Error::other(err)), None)),
+            }
+        });
+        let throttles = throttles(state, &auth.user, repo, Direction::Download);
+        let path = format!("{repo}/{tpe}/{}", parts.name.as_deref().unwrap_or_default());
+        let progress = state.progress(Direction::Download, path, content_length);
+        builder
+            .body(Body::from_stream(throttle(
+                with_progress(stream, progress),
+                throttles,
+            )))
+            .map_err(|err| Error::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+    }
+
+    // url returns the URL of the file or listing on the upstream server
+    fn url(&self, parts: &PathParts) -> String {
+        let mut url = self.url.clone();
+        if !parts.repo.is_empty() {
+            url.push('/');
+            url.push_str(&parts.repo);
+        }
+        match (parts.tpe.as_deref(), parts.name.as_deref()) {
+            (Some(CONFIG_TYPE), _) => url.push_str("/config"),
+            (Some(tpe), name) => {
+                url.push('/');
+                url.push_str(tpe);
+                url.push('/');
+                url.push_str(name.unwrap_or_default());
+            }
+            (None, _) => url.push('/'),
+        }
+        url
+    }
+}
+
+// remote returns the upstream server to forward a request for a file or
+// listing to, if one is configured and the repository doesn't exist locally
+pub async fn remote(state: &State, parts: &PathParts) -> Option<Arc<Upstream>> {
+    parts.tpe.as_ref()?;
+    let upstream = state.upstream()?;
+    let config = state
+        .storage()
+        .filename(Path::new(&parts.repo), CONFIG_TYPE, CONFIG_NAME);
+    match tokio::fs::metadata(config.parent()?).await {
+        Ok(metadata) if metadata.is_dir() => None,
+        _ => Some(upstream),
+    }
+}
+
+// CacheFile is a file of the cache being downloaded; it is written next to
+// its final path and only moved there once it is complete and its content
+// matches its name
+struct CacheFile {
+    writer: HashingWriter<WriteOrDeleteFile>,
+    part: PathBuf,
+    path: PathBuf,
+    name: String,
+    // bytes still to be downloaded, if the upstream server told the length
+    remaining: Option<u64>,
+}
+
+impl CacheFile {
+    // create returns None if the file can't be cached, e.g. because another
+    // download of it is running
+    async fn create(
+        cache: &LocalStorage,
+        repo: &str,
+        tpe: &str,
+        name: &str,
+        len: Option<u64>,
+    ) -> Option<Self> {
+        let path = cache.filename(Path::new(repo), tpe, name);
+        let part = path.with_extension("part");
+        tokio::fs::create_dir_all(path.parent()?).await.ok()?;
+        let file = WriteOrDeleteFile::new(part.clone()).await.ok()?;
+        Some(Self {
+            writer: HashingWriter::new(file, tpe != CONFIG_TYPE),
+            part,
+            path,
+            name: name.to_string(),
+            remaining: len,
+        })
+    }
+
+    // write appends chunk and returns whether the file is complete
+    async fn write(&mut self, chunk: &[u8]) -> io::Result<bool> {
+        self.writer.write_all(chunk).await?;
+        let len = u64::try_from(chunk.len()).unwrap_or(u64::MAX);
+        self.remaining = self
+            .remaining
+            .map(|remaining| remaining.saturating_sub(len));
+        Ok(self.remaining == Some(0))
+    }
+
+    async fn finish(self) {
+        let (mut file, digest) = self.writer.finish();
+        if digest.is_some_and(|digest| to_hex(digest.as_ref()) != self.name) {
+            tracing::warn!(path = ?self.path, "upstream file doesn't match its name, not cached");
+            return;
+        }
+        let res = match file.finalize().await {
+            Ok(()) => tokio::fs::rename(&self.part, &self.path).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = res {
+            tracing::warn!(path = ?self.path, "cannot cache upstream file: {err}");
+            _ = tokio::fs::remove_file(&self.part).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls() {
+        let upstream = Upstream::new(&UpstreamConfig {
+            url: Some("https://old.example/backup/".to_string()),
+            cache_dir: None,
+        })
+        .unwrap();
+        let parts = |repo: &str, tpe: Option<&str>, name: Option<&str>| PathParts {
+            repo: repo.to_string(),
+            tpe: tpe.map(str::to_string),
+            name: name.map(str::to_string),
+        };
+        assert_eq!(
+            upstream.url(&parts("a/b", Some("config"), Some(""))),
+            "https://old.example/backup/a/b/config"
+        );
+        assert_eq!(
+            upstream.url(&parts("a", Some("keys"), None)),
+            "https://old.example/backup/a/keys/"
+        );
+        assert_eq!(
+            upstream.url(&parts("", Some("data"), Some("0123"))),
+            "https://old.example/backup/data/0123"
+        );
+    }
+}
@@ -0,0 +1,365 @@
+// mod uring
+//
+// reads and writes large pack files through io_uring on Linux, enabled by the
+// cargo feature io-uring. A driver thread owns the ring: tasks send it
+// operations on files and await their completion, so neither the runtime
+// threads nor the blocking pool of tokio::fs wait for the disk, and several
+// chunks of a file are in flight at once. If the kernel refuses to set up a
+// ring (e.g. a seccomp filter of a container), tokio::fs is used as without
+// the feature.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::pin::Pin;
+use std::sync::{mpsc, Arc, OnceLock};
+use std::task::{ready, Context, Poll};
+
+use axum::body::Bytes;
+use futures_util::future::BoxFuture;
+use futures_util::{Stream, StreamExt};
+use io_uring::{opcode, types, IoUring};
+use tokio::io::AsyncWrite;
+use tokio::sync::oneshot;
+
+// MIN_SIZE is the size from which downloads are read through the ring
+pub const MIN_SIZE: u64 = 1 << 20;
+
+// CHUNK is the size of a single read or write
+const CHUNK: usize = 1 << 20;
+
+// READ_AHEAD is the number of chunks of a download read ahead of the client
+const READ_AHEAD: usize = 4;
+
+// DEPTH is the number of operations in flight at most
+const DEPTH: u32 = 64;
+
+enum Kind {
+    Read,
+    Write,
+    Fsync,
+}
+
+// Op reads into or writes buf[pos..] at offset of file; the driver keeps it,
+// and so the file and the buffer, until the kernel is done with it
+struct Op {
+    kind: Kind,
+    file: Arc<fs::File>,
+    buf: Vec<u8>,
+    pos: usize,
+    offset: u64,
+    reply: oneshot::Sender<(io::Result<usize>, Vec<u8>)>,
+}
+
+impl Op {
+    fn entry(&mut self, id: u64) -> io_uring::squeue::Entry {
+        let fd = types::Fd(self.file.as_raw_fd());
+        let len = u32::try_from(self.buf.len() - self.pos).unwrap_or(u32::MAX);
+        let entry = match self.kind {
+            Kind::Read => opcode::Read::new(fd, self.buf[self.pos..].as_mut_ptr(), len)
+                .offset(self.offset)
+                .build(),
+            Kind::Write => opcode::Write::new(fd, self.buf[self.pos..].as_ptr(), len)
+                .offset(self.offset)
+                .build(),
+            Kind::Fsync => opcode::Fsync::new(fd).build(),
+        };
+        entry.user_data(id)
+    }
+}
+
+// Ring submits operations to the driver thread
+pub struct Ring {
+    ops: mpsc::Sender<Op>,
+}
+
+impl Ring {
+    // get returns the ring of the process, None if io_uring is unavailable
+    pub fn get() -> Option<&'static Self> {
+        static RING: OnceLock<Option<Ring>> = OnceLock::new();
+        RING.get_or_init(|| match Self::start() {
+            Ok(ring) => Some(ring),
+            Err(err) => {
+                tracing::warn!("io_uring is unavailable, using tokio::fs: {err}");
+                None
+            }
+        })
+        .as_ref()
+    }
+
+    fn start() -> io::Result<Self> {
+        let ring = IoUring::new(DEPTH)?;
+        let (ops, receiver) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("io-uring".to_string())
+            .spawn(move || drive(ring, &receiver))?;
+        Ok(Self { ops })
+    }
+
+    async fn submit(
+        &self,
+        kind: Kind,
+        file: &Arc<fs::File>,
+        buf: Vec<u8>,
+        pos: usize,
+        offset: u64,
+    ) -> (io::Result<usize>, Vec<u8>) {
+        let (reply, done) = oneshot::channel();
+        let op = Op {
+            kind,
+            file: Arc::clone(file),
+            buf,
+            pos,
+            offset,
+            reply,
+        };
+        if let Err(mpsc::SendError(op)) = self.ops.send(op) {
+            return (Err(io::Error::other("io_uring driver stopped")), op.buf);
+        }
+        done.await
+            .unwrap_or_else(|_| (Err(io::Error::other("io_uring driver stopped")), Vec::new()))
+    }
+
+    // read_exact_at reads len bytes at offset of file
+    async fn read_exact_at(
+        &self,
+        file: Arc<fs::File>,
+        offset: u64,
+        len: usize,
+    ) -> io::Result<Bytes> {
+        let mut buf = vec![0; len];
+        let mut pos = 0;
+        while pos < len {
+            let (res, returned) = self
+                .submit(Kind::Read, &file, buf, pos, offset + pos as u64)
+                .await;
+            buf = returned;
+            match res? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => pos += n,
+            }
+        }
+        Ok(Bytes::from(buf))
+    }
+
+    // write_all_at writes buf at offset of file and returns buf for reuse
+    async fn write_all_at(
+        &self,
+        file: Arc<fs::File>,
+        mut buf: Vec<u8>,
+        offset: u64,
+    ) -> io::Result<Vec<u8>> {
+        let mut pos = 0;
+        while pos < buf.len() {
+            let (res, returned) = self
+                .submit(Kind::Write, &file, buf, pos, offset + pos as u64)
+                .await;
+            buf = returned;
+            match res? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => pos += n,
+            }
+        }
+        Ok(buf)
+    }
+
+    // read returns the len bytes of file from start on in chunks
+    pub fn read(
+        &'static self,
+        file: fs::File,
+        start: u64,
+        len: u64,
+    ) -> impl Stream<Item = io::Result<Bytes>> + Send + 'static {
+        let file = Arc::new(file);
+        let end = start + len;
+        futures_util::stream::iter((start..end).step_by(CHUNK))
+            .map(move |offset| {
+                let len = usize::try_from(end - offset).map_or(CHUNK, |len| len.min(CHUNK));
+                self.read_exact_at(Arc::clone(&file), offset, len)
+            })
+            .buffered(READ_AHEAD)
+    }
+}
+
+// drive submits the operations received from ops and answers their
+// completions until all senders are gone; new operations are picked up
+// whenever one completes
+fn drive(mut ring: IoUring, ops: &mpsc::Receiver<Op>) {
+    let mut in_flight = HashMap::new();
+    let mut next_id = 0_u64;
+    loop {
+        // block for work only if nothing is in flight
+        let first = match in_flight.is_empty() {
+            true => match ops.recv() {
+                Ok(op) => Some(op),
+                Err(_) => return,
+            },
+            false => None,
+        };
+        let capacity = DEPTH as usize - in_flight.len();
+        for mut op in first.into_iter().chain(ops.try_iter()).take(capacity) {
+            let entry = op.entry(next_id);
+            // SAFETY: the file and the buffer of the entry are kept in
+            // in_flight until the operation is completed
+            if unsafe { ring.submission().push(&entry) }.is_err() {
+                _ = op
+                    .reply
+                    .send((Err(io::Error::other("io_uring is full")), op.buf));
+                continue;
+            }
+            _ = in_flight.insert(next_id, op);
+            next_id += 1;
+        }
+        match ring.submit_and_wait(1) {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => {
+                tracing::error!("io_uring failed: {err}");
+                for (_, op) in in_flight.drain() {
+                    _ = op
+                        .reply
+                        .send((Err(io::Error::new(err.kind(), err.to_string())), op.buf));
+                }
+                // the driver stops; waiting operations fail
+                return;
+            }
+        }
+        for cqe in ring.completion() {
+            let Some(op) = in_flight.remove(&cqe.user_data()) else {
+                continue;
+            };
+            let res = match cqe.result() {
+                res if res < 0 => Err(io::Error::from_raw_os_error(-res)),
+                res => Ok(usize::try_from(res).unwrap_or_default()),
+            };
+            // the requesting task may be gone, e.g. if the client disconnected
+            _ = op.reply.send((res, op.buf));
+        }
+    }
+}
+
+// Writer writes a file through the ring in chunks; one chunk is written while
+// the next one is filled
+pub struct Writer {
+    ring: &'static Ring,
+    file: Arc<fs::File>,
+    offset: u64,
+    buf: Vec<u8>,
+    // the write of the previous chunk, which returns its buffer
+    pending: Option<BoxFuture<'static, io::Result<Vec<u8>>>>,
+    spare: Option<Vec<u8>>,
+}
+
+impl Writer {
+    pub fn new(ring: &'static Ring, file: fs::File) -> Self {
+        Self {
+            ring,
+            file: Arc::new(file),
+            offset: 0,
+            buf: Vec::with_capacity(CHUNK),
+            pending: None,
+            spare: None,
+        }
+    }
+
+    // sync_all waits until the written data reached the disk
+    pub async fn sync_all(&mut self) -> io::Result<()> {
+        let (res, _) = self
+            .ring
+            .submit(Kind::Fsync, &self.file, Vec::new(), 0, 0)
+            .await;
+        res.map(|_| ())
+    }
+
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(write) = &mut self.pending {
+            let buf = ready!(write.as_mut().poll(cx));
+            self.pending = None;
+            self.spare = Some(buf?);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_write(&mut self) {
+        let mut next = self.spare.take().unwrap_or_default();
+        next.clear();
+        let buf = std::mem::replace(&mut self.buf, next);
+        let offset = self.offset;
+        self.offset += buf.len() as u64;
+        let (ring, file) = (self.ring, Arc::clone(&self.file));
+        self.pending = Some(Box::pin(ring.write_all_at(file, buf, offset)));
+    }
+}
+
+impl AsyncWrite for Writer {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.buf.len() >= CHUNK {
+            ready!(this.poll_pending(cx))?;
+            this.start_write();
+        }
+        let n = data.len().min(CHUNK - this.buf.len());
+        this.buf.extend_from_slice(&data[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        if !this.buf.is_empty() {
+            this.start_write();
+            ready!(this.poll_pending(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::TryStreamExt;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn read_write() {
+        let Some(ring) = Ring::get() else {
+            eprintln!("skipping, io_uring is unavailable");
+            return;
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pack");
+        let data: Vec<u8> = (0..3 * CHUNK + 100).map(|i| (i % 251) as u8).collect();
+
+        let mut writer = Writer::new(ring, fs::File::create(&path).unwrap());
+        // writes of odd sizes fill the chunks
+        for part in data.chunks(100_000) {
+            writer.write_all(part).await.unwrap();
+        }
+        writer.flush().await.unwrap();
+        writer.sync_all().await.unwrap();
+        assert_eq!(fs::read(&path).unwrap(), data);
+
+        let read = |start, len| {
+            ring.read(fs::File::open(&path).unwrap(), start, len)
+                .try_fold(Vec::new(), |mut read, chunk| async move {
+                    read.extend_from_slice(&chunk);
+                    Ok(read)
+                })
+        };
+        assert_eq!(read(0, data.len() as u64).await.unwrap(), data);
+        let start = CHUNK as u64 - 10;
+        assert_eq!(read(start, 20).await.unwrap(), data[CHUNK - 10..CHUNK + 10]);
+        // beyond the end of the file
+        let err = read(0, data.len() as u64 + 1).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,281 @@
+// mod values
+//
+// parses configuration values given with units or in a fixed format: byte
+// sizes like "500GB" or "4 MiB", durations like "90s", "15m" or "1h30m" and
+// addresses as host:port. Plain numbers are still accepted as bytes and
+// seconds. Values are checked while the config file is read, so errors name
+// the key and line of the offending value.
+
+use std::fmt;
+use std::marker::PhantomData;
+
+use anyhow::{anyhow, bail, Result};
+use serde::de::{self, Deserializer, Visitor};
+
+// SIZE_UNITS are the units of byte sizes, matched case-insensitively
+const SIZE_UNITS: [(&str, u64); 11] = [
+    ("b", 1),
+    ("kb", 1000),
+    ("mb", 1000 * 1000),
+    ("gb", 1000 * 1000 * 1000),
+    ("tb", 1000 * 1000 * 1000 * 1000),
+    ("pb", 1000 * 1000 * 1000 * 1000 * 1000),
+    ("kib", 1 << 10),
+    ("mib", 1 << 20),
+    ("gib", 1 << 30),
+    ("tib", 1 << 40),
+    ("pib", 1 << 50),
+];
+
+// DURATION_UNITS are the units of durations in seconds
+const DURATION_UNITS: [(&str, u64); 9] = [
+    ("s", 1),
+    ("sec", 1),
+    ("m", 60),
+    ("min", 60),
+    ("h", 60 * 60),
+    ("d", 24 * 60 * 60),
+    ("day", 24 * 60 * 60),
+    ("days", 24 * 60 * 60),
+    ("w", 7 * 24 * 60 * 60),
+];
+
+// parse_size parses a number of bytes, optionally followed by a unit like
+// "GB" (10^9) or "GiB" (2^30); fractions like "1.5TB" are allowed with units
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = (&s[..split], s[split..].trim());
+    if number.is_empty() {
+        bail!("invalid size {s:?}, expected a number like \"500GB\"");
+    }
+    let Some((_, factor)) = SIZE_UNITS
+        .iter()
+        .find(|(name, _)| unit.is_empty() || name.eq_ignore_ascii_case(unit))
+    else {
+        bail!("invalid size {s:?}: unknown unit {unit:?}, expected B, kB, MB, GB, TB, PB, KiB, MiB, GiB, TiB or PiB");
+    };
+    let too_large = || anyhow!("invalid size {s:?}: too large");
+    match number.parse::<u64>() {
+        Ok(n) => n.checked_mul(*factor).ok_or_else(too_large),
+        Err(_) => {
+            let n: f64 = number
+                .parse()
+                .map_err(|_| anyhow!("invalid size {s:?}, expected a number like \"500GB\""))?;
+            let bytes = (n * *factor as f64).round();
+            if bytes >= u64::MAX as f64 {
+                return Err(too_large());
+            }
+            Ok(bytes as u64)
+        }
+    }
+}
+
+// parse_duration parses a number of seconds or a sequence of numbers with
+// units like "1h30m" and returns the seconds
+pub fn parse_duration(s: &str) -> Result<u64> {
+    let s = s.trim();
+    if let Ok(seconds) = s.parse() {
+        return Ok(seconds);
+    }
+    let invalid = || anyhow!("invalid duration {s:?}, expected e.g. \"90s\", \"15m\" or \"1h30m\"");
+    let mut rest = s;
+    let mut seconds = 0u64;
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let number: u64 = rest[..split].parse().map_err(|_| invalid())?;
+        rest = rest[split..].trim_start();
+        let end = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let unit = &rest[..end];
+        let Some((_, factor)) = DURATION_UNITS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(unit))
+        else {
+            bail!("invalid duration {s:?}: unknown unit {unit:?}, expected s, m, h, d or w");
+        };
+        seconds = number
+            .checked_mul(*factor)
+            .and_then(|n| n.checked_add(seconds))
+            .ok_or_else(|| anyhow!("invalid duration {s:?}: too large"))?;
+        rest = rest[end..].trim_start();
+    }
+    Ok(seconds)
+}
+
+// check_host_port checks that addr has the form host:port, optionally
+// prefixed with http:// or https://
+pub fn check_host_port(addr: &str) -> Result<()> {
+    let host_port = addr
+        .strip_prefix("http://")
+        .or_else(|| addr.strip_prefix("https://"))
+        .unwrap_or(addr);
+    let valid = match host_port.rsplit_once(':') {
+        Some((host, port)) => {
+            let host = host
+                .strip_prefix('[')
+                .map_or(Some(host), |host| host.strip_suffix(']'));
+            host.is_some_and(|host| !host.is_empty() && !host.contains(['/', ' ']))
+                && port.parse::<u16>().is_ok()
+        }
+        None => false,
+    };
+    match valid {
+        true => Ok(()),
+        false => bail!("invalid address {addr:?}, expected host:port like \"[::]:8000\""),
+    }
+}
+
+// Parsed is a number or string turned into T by parse
+struct Parsed<T> {
+    parse: fn(&str) -> Result<u64>,
+    expecting: &'static str,
+    marker: PhantomData<T>,
+}
+
+impl<'de, T: TryFrom<u64>> Visitor<'de> for Parsed<T> {
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.expecting)
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> std::result::Result<T, E> {
+        T::try_from(v).map_err(|_| E::custom(format!("{v} is too large")))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> std::result::Result<T, E> {
+        let v = u64::try_from(v).map_err(|_| E::custom(format!("{v} is negative")))?;
+        self.visit_u64(v)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<T, E> {
+        let v = (self.parse)(v).map_err(E::custom)?;
+        self.visit_u64(v)
+    }
+}
+
+fn parsed<'de, D, T>(
+    deserializer: D,
+    parse: fn(&str) -> Result<u64>,
+    expecting: &'static str,
+) -> std::result::Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    deserializer.deserialize_any(Parsed {
+        parse,
+        expecting,
+        marker: PhantomData,
+    })
+}
+
+// size deserializes a byte size given as number or string like "500GB"
+pub fn size<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    parsed(
+        deserializer,
+        parse_size,
+        "a number of bytes or a size like \"500GB\"",
+    )
+}
+
+pub fn opt_size<'de, D, T>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    size(deserializer).map(Some)
+}
+
+// duration deserializes seconds given as number or string like "15m"
+pub fn duration<'de, D>(deserializer: D) -> std::result::Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    parsed(
+        deserializer,
+        parse_duration,
+        "a number of seconds or a duration like \"15m\"",
+    )
+}
+
+pub fn opt_duration<'de, D>(deserializer: D) -> std::result::Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    duration(deserializer).map(Some)
+}
+
+// opt_host_port deserializes an address as host:port
+pub fn opt_host_port<'de, D>(deserializer: D) -> std::result::Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let addr: String = serde::Deserialize::deserialize(deserializer)?;
+    check_host_port(&addr).map_err(de::Error::custom)?;
+    Ok(Some(addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("1024").unwrap(), 1024);
+        assert_eq!(parse_size("500GB").unwrap(), 500_000_000_000);
+        assert_eq!(parse_size("4 MiB").unwrap(), 4 << 20);
+        assert_eq!(parse_size("1.5kib").unwrap(), 1536);
+        assert!(parse_size("GB").is_err());
+        assert!(parse_size("5 XB")
+            .unwrap_err()
+            .to_string()
+            .contains("unknown unit"));
+        assert!(parse_size("1.5").is_ok());
+        assert!(parse_size("100000PB").is_err());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30").unwrap(), 30);
+        assert_eq!(parse_duration("90s").unwrap(), 90);
+        assert_eq!(parse_duration("1h30m").unwrap(), 5400);
+        assert_eq!(parse_duration("2 days").unwrap(), 2 * 86400);
+        assert_eq!(parse_duration("1w 1d").unwrap(), 8 * 86400);
+        assert!(parse_duration("15").is_ok());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("5 fortnights").is_err());
+        assert!(parse_duration("-5s").is_err());
+    }
+
+    #[test]
+    fn addresses() {
+        for addr in [
+            "[::]:8000",
+            "localhost:8000",
+            "https://0.0.0.0:8443",
+            "mqtt:1883",
+        ] {
+            assert!(check_host_port(addr).is_ok(), "{addr}");
+        }
+        for addr in [
+            "localhost",
+            ":8000",
+            "[::]:http",
+            "host:70000",
+            "http://a b:80",
+        ] {
+            assert!(check_host_port(addr).is_err(), "{addr}");
+        }
+    }
+}
@@ -0,0 +1,238 @@
+// mod vault
+//
+// fetches the TLS certificate and the htpasswd file from the KV secrets
+// engine of HashiCorp Vault, for deployments where nothing secret may be
+// stored on local disk. Both versions of the KV engine are supported; the API
+// path of a version 2 secret contains "/data/". The secrets are fetched at
+// startup and on reloads and checked for changes every
+// vault.refresh_interval seconds, so renewed certificates and changed users
+// are used without a restart. The token is read again for each request,
+// e.g. from the sink file of a Vault agent renewing it.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use axum_server::tls_rustls::RustlsConfig;
+use serde_json::Value;
+
+use crate::acl::Acl;
+use crate::auth::Auth;
+use crate::config::{Config, TlsConfig, VaultConfig};
+use crate::tls;
+use crate::web::State;
+
+// TIMEOUT is the maximum time fetching a secret may take
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// Vault reads secrets from the server given by vault.address
+pub struct Vault {
+    address: String,
+    config: VaultConfig,
+    client: reqwest::Client,
+}
+
+impl Vault {
+    pub fn new(config: &VaultConfig) -> Option<Self> {
+        Some(Self {
+            address: config.address.as_deref()?.trim_end_matches('/').to_string(),
+            config: config.clone(),
+            client: reqwest::Client::new(),
+        })
+    }
+
+    // field returns the field of the secret at path
+    async fn field(&self, path: &str, field: &str) -> Result<String> {
+        let token = self.config.read_token()?.context("no token is given")?;
+        let url = format!("{}/v1/{}", self.address, path.trim_start_matches('/'));
+        let res = self
+            .client
+            .get(&url)
+            .header("X-Vault-Token", token)
+            .timeout(TIMEOUT)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .with_context(|| format!("cannot read secret {path}"))?;
+        let body: Value = res.json().await?;
+        // KV version 2 nests the fields within data.data
+        let data = match &body["data"]["data"] {
+            Value::Object(_) if body["data"]["metadata"].is_object() => &body["data"]["data"],
+            _ => &body["data"],
+        };
+        match &data[field] {
+            Value::String(value) => Ok(value.clone()),
+            _ => bail!("secret {path} has no field {field}"),
+        }
+    }
+
+    // tls returns the certificate and key in PEM format
+    async fn tls(&self) -> Result<Option<(String, String)>> {
+        let Some(path) = &self.config.tls_secret else {
+            return Ok(None);
+        };
+        let cert = self.field(path, "cert").await?;
+        let key = self.field(path, "key").await?;
+        Ok(Some((cert, key)))
+    }
+
+    async fn htpasswd(&self) -> Result<Option<String>> {
+        match &self.config.htpasswd_secret {
+            Some(path) => self.field(path, "htpasswd").await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // tls_config fetches the certificate and builds the rustls config; None if
+    // the certificate isn't kept in Vault
+    pub async fn tls_config(&self, config: &TlsConfig) -> Result<Option<RustlsConfig>> {
+        match self.tls().await? {
+            Some((cert, key)) => Ok(Some(RustlsConfig::from_config(tls::server_config(
+                config,
+                cert.as_bytes(),
+                key.as_bytes(),
+            )?))),
+            None => Ok(None),
+        }
+    }
+}
+
+// load_access is Config::load_access with the htpasswd file fetched from
+// Vault if it is kept there
+pub async fn load_access(config: &Config) -> Result<(Auth, Acl)> {
+    let htpasswd = match Vault::new(&config.vault) {
+        Some(vault) => vault.htpasswd().await?,
+        None => None,
+    };
+    match htpasswd {
+        Some(htpasswd) => {
+            let auth = Auth::from_htpasswd(config.auth.disable, &htpasswd);
+            let acl = config.load_acl()?;
+            if let Some(user) = acl.users().find(|user| !auth.has_user(user)) {
+                tracing::warn!(
+                    user,
+                    "user of the ACL is not contained in the htpasswd secret"
+                );
+            }
+            Ok((auth, acl))
+        }
+        None => config.load_access(),
+    }
+}
+
+// reload_tls replaces the certificate of tls_config by the one in Vault;
+// false if it isn't kept there
+pub async fn reload_tls(config: &Config, tls_config: &RustlsConfig) -> Result<bool> {
+    let Some(vault) = Vault::new(&config.vault) else {
+        return Ok(false);
+    };
+    match vault.tls().await? {
+        Some((cert, key)) => {
+            let server_config = tls::server_config(&config.tls, cert.as_bytes(), key.as_bytes())?;
+            tls_config.reload_from_config(server_config);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+// run checks the secrets of the current configuration for changes until the
+// server stops
+pub async fn run(state: State, tls_config: Option<RustlsConfig>) {
+    let mut tls = None;
+    let mut htpasswd = None;
+    loop {
+        let interval = state.config().vault.refresh_interval;
+        if interval == 0 {
+            // check again whether a reload enabled the refresh
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            continue;
+        }
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+        let Some(vault) = Vault::new(&state.config().vault) else {
+            continue;
+        };
+        if let Err(err) = refresh(&state, &vault, &tls_config, &mut tls, &mut htpasswd).await {
+            tracing::warn!("cannot refresh the secrets from Vault: {err:#}");
+        }
+    }
+}
+
+// refresh applies the secrets which changed since the last call
+async fn refresh(
+    state: &State,
+    vault: &Vault,
+    tls_config: &Option<RustlsConfig>,
+    tls: &mut Option<(String, String)>,
+    htpasswd: &mut Option<String>,
+) -> Result<()> {
+    let config = state.config();
+    if let (Some(tls_config), Some(new)) = (tls_config, vault.tls().await?) {
+        if tls.as_ref() != Some(&new) {
+            let server_config = tls::server_config(&config.tls, new.0.as_bytes(), new.1.as_bytes())
+                .map_err(|err| anyhow!("invalid certificate in Vault: {err:#}"))?;
+            tls_config.reload_from_config(server_config);
+            if tls.is_some() {
+                tracing::info!("reloaded TLS certificate from Vault");
+            }
+            *tls = Some(new);
+        }
+    }
+    if let Some(new) = vault.htpasswd().await? {
+        if htpasswd.as_ref() != Some(&new) {
+            state.set_auth(Auth::from_htpasswd(config.auth.disable, &new));
+            if htpasswd.is_some() {
+                tracing::info!("reloaded htpasswd from Vault");
+            }
+            *htpasswd = Some(new);
+        }
+    }
+    Ok(())
+}
+
+// is_used returns whether any secret is kept in Vault
+pub fn is_used(config: &VaultConfig) -> bool {
+    config.address.is_some() && (config.tls_secret.is_some() || config.htpasswd_secret.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use axum::{Json, Router};
+
+    #[tokio::test]
+    async fn secrets() {
+        let app = Router::new()
+            .route(
+                "/v1/secret/data/htpasswd",
+                get(|headers: axum::http::HeaderMap| async move {
+                    assert_eq!(headers["x-vault-token"], "s.token");
+                    Json(serde_json::json!({
+                        "data": {"data": {"htpasswd": "alice:{SHA}xxx\n"}, "metadata": {}}
+                    }))
+                }),
+            )
+            .route(
+                "/v1/kv/tls",
+                get(|| async { Json(serde_json::json!({"data": {"cert": "pem"}})) }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let vault = Vault::new(&VaultConfig {
+            address: Some(format!("http://{addr}/")),
+            token: Some("s.token".to_string()),
+            tls_secret: Some("kv/tls".to_string()),
+            htpasswd_secret: Some("secret/data/htpasswd".to_string()),
+            ..VaultConfig::default()
+        })
+        .unwrap();
+        let htpasswd = vault.htpasswd().await.unwrap().unwrap();
+        assert!(Auth::from_htpasswd(false, &htpasswd).has_user("alice"));
+        let err = vault.tls().await.unwrap_err();
+        assert_eq!(err.to_string(), "secret kv/tls has no field key");
+        assert!(vault.field("kv/missing", "cert").await.is_err());
+        server.abort();
+    }
+}
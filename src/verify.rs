@@ -0,0 +1,243 @@
+// mod verify
+//
+// re-hashes the stored files of repositories in the background, triggered by
+// an admin or periodically, to detect silent corruption of the storage early
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::check::{check_repo_throttled, Report};
+use crate::config::VerifyConfig;
+use crate::mail::Mailer;
+use crate::stats::format_time;
+use crate::storage::Storage;
+use crate::throttle::Throttle;
+use crate::web::{CONFIG_NAME, CONFIG_TYPE};
+
+// VERIFY_MARKER holds the result of the last verification of a repository as JSON
+pub const VERIFY_MARKER: &str = ".verified";
+
+// SCHEDULE_INTERVAL is how often repositories due for verification are searched
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(3600);
+
+// Verification is the state of a running or the result of a finished verification
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Verification {
+    pub repo: String,
+    // RFC 3339 timestamps, finished is None while running
+    pub started: String,
+    pub finished: Option<String>,
+    pub files: usize,
+    pub bytes: u64,
+    pub problems: Vec<String>,
+}
+
+// Verifier runs verifications, at most one per repository at a time
+#[derive(Clone)]
+pub struct Verifier {
+    storage: Arc<dyn Storage>,
+    config: Arc<RwLock<VerifyConfig>>,
+    running: Arc<Mutex<HashMap<String, Verification>>>,
+    mailer: Mailer,
+}
+
+impl Verifier {
+    pub fn new(storage: Arc<dyn Storage>, mailer: Mailer) -> Self {
+        Self {
+            storage,
+            config: Arc::default(),
+            running: Arc::default(),
+            mailer,
+        }
+    }
+
+    pub fn set_config(&self, config: VerifyConfig) {
+        *self.config.write().unwrap_or_else(PoisonError::into_inner) = config;
+    }
+
+    fn config(&self) -> VerifyConfig {
+        self.config
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    // start verifies repo in the background and returns its state, None if
+    // a verification of repo is already running
+    pub fn start(&self, repo: &str) -> Option<Verification> {
+        let verification = self.begin(repo)?;
+        let verifier = self.clone();
+        let state = verification.clone();
+        _ = tokio::task::spawn_blocking(move || verifier.run(verification));
+        Some(state)
+    }
+
+    // status returns the running or the last finished verification of repo
+    pub fn status(&self, repo: &str) -> Option<Verification> {
+        match self.lock().get(repo) {
+            Some(verification) => Some(verification.clone()),
+            None => self.last(repo),
+        }
+    }
+
+    // schedule verifies all repositories one after another, each once per
+    // verify.interval_days
+    pub async fn schedule(self) {
+        let mut interval = tokio::time::interval(SCHEDULE_INTERVAL);
+        loop {
+            _ = interval.tick().await;
+            let Some(days) = self.config().interval_days else {
+                continue;
+            };
+            let max_age = Duration::from_secs(days * 24 * 60 * 60);
+            let verifier = self.clone();
+            let due = tokio::task::spawn_blocking(move || {
+                let repos = verifier.storage.repos().into_iter();
+                repos
+                    .filter(|repo| verifier.due(repo, max_age))
+                    .collect::<Vec<_>>()
+            })
+            .await
+            .unwrap_or_default();
+            for repo in due {
+                let Some(verification) = self.begin(&repo) else {
+                    continue;
+                };
+                let verifier = self.clone();
+                _ = tokio::task::spawn_blocking(move || verifier.run(verification)).await;
+            }
+        }
+    }
+
+    // begin marks repo as being verified
+    fn begin(&self, repo: &str) -> Option<Verification> {
+        let mut running = self.lock();
+        if running.contains_key(repo) {
+            return None;
+        }
+        let verification = Verification {
+            repo: repo.to_string(),
+            started: format_time(SystemTime::now()),
+            finished: None,
+            files: 0,
+            bytes: 0,
+            problems: Vec::new(),
+        };
+        _ = running.insert(repo.to_string(), verification.clone());
+        Some(verification)
+    }
+
+    // run verifies the repository and stores the result within it
+    fn run(&self, mut verification: Verification) {
+        let repo = verification.repo.clone();
+        tracing::info!(repo, "verifying repository");
+
+        let path = Path::new(&repo);
+        let config_file = self.storage.filename(path, CONFIG_TYPE, CONFIG_NAME);
+        if let Some(dir) = config_file.parent() {
+            let throttle = Throttle::new(self.config().bandwidth);
+            let mut report = Report::default();
+            check_repo_throttled(dir, true, Some(&throttle), &mut report);
+            verification.files = report.files;
+            verification.bytes = report.bytes;
+            // don't reveal the location of the storage
+            verification.problems = report
+                .problems
+                .into_iter()
+                .map(|mut problem| {
+                    if let Ok(file) = problem.file.strip_prefix(dir) {
+                        problem.file = file.to_path_buf();
+                    }
+                    problem.to_string()
+                })
+                .collect();
+        }
+        verification.finished = Some(format_time(SystemTime::now()));
+
+        match verification.problems.len() {
+            0 => tracing::info!(repo, files = verification.files, "repository verified"),
+            n => {
+                tracing::warn!(repo, problems = n, "verification found problems");
+                self.mailer.send(
+                    "verify",
+                    &repo,
+                    &format!("verification of {repo} found {n} problems"),
+                    &format!(
+                        "The verification of repository {repo} found damaged files:\n\n{}",
+                        verification.problems.join("\n")
+                    ),
+                );
+            }
+        }
+        let stored = serde_json::to_string(&verification)
+            .map_err(std::io::Error::from)
+            .and_then(|json| self.storage.set_marker(path, VERIFY_MARKER, Some(&json)));
+        if let Err(err) = stored {
+            tracing::error!(repo, "cannot store verification result: {err}");
+        }
+        _ = self.lock().remove(&repo);
+    }
+
+    // last returns the last finished verification of repo
+    fn last(&self, repo: &str) -> Option<Verification> {
+        let json = self.storage.marker(Path::new(repo), VERIFY_MARKER)?;
+        serde_json::from_str(&json).ok()
+    }
+
+    // due returns whether repo wasn't verified within max_age
+    fn due(&self, repo: &str, max_age: Duration) -> bool {
+        let finished = self
+            .last(repo)
+            .and_then(|verification| verification.finished)
+            .and_then(|finished| OffsetDateTime::parse(&finished, &Rfc3339).ok());
+        match finished {
+            Some(finished) => OffsetDateTime::now_utc() - finished >= max_age,
+            None => true,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Verification>> {
+        self.running.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LocalStorage;
+
+    #[test]
+    fn verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().join("repo");
+        let hash = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        std::fs::create_dir_all(repo.join("keys")).unwrap();
+        std::fs::write(repo.join("config"), "x").unwrap();
+        std::fs::write(repo.join("keys").join(hash), "bit rot").unwrap();
+        let verifier = Verifier::new(
+            Arc::new(LocalStorage::try_new(dir.path()).unwrap()),
+            Mailer::default(),
+        );
+
+        assert!(verifier.status("repo").is_none());
+        assert!(verifier.due("repo", Duration::from_secs(3600)));
+        let verification = verifier.begin("repo").unwrap();
+        assert!(verifier.begin("repo").is_none());
+        assert!(verifier.status("repo").unwrap().finished.is_none());
+        verifier.run(verification);
+
+        let verification = verifier.status("repo").unwrap();
+        assert!(verification.finished.is_some());
+        assert_eq!(verification.files, 2);
+        assert_eq!(verification.problems.len(), 1);
+        assert!(verification.problems[0].starts_with(&format!("keys/{hash}: ")));
+        assert!(!verifier.due("repo", Duration::from_secs(3600)));
+        assert!(verifier.due("repo", Duration::ZERO));
+    }
+}
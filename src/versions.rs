@@ -0,0 +1,142 @@
+// mod versions
+//
+// keeps former versions of the config file of repositories, so an
+// accidental re-init overwriting it can be undone. The versions are stored in
+// a hidden directory of the repository, which clients can't access.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::stats::format_time;
+
+// VERSIONS_DIR is the directory within a repository holding the versions
+pub const VERSIONS_DIR: &str = ".config-versions";
+
+// PREFIX starts the file name of each version, followed by its timestamp
+const PREFIX: &str = "config.bak.";
+
+// ConfigVersion describes a stored version of a config file
+#[derive(Debug, Serialize)]
+pub struct ConfigVersion {
+    pub version: String,
+    pub size: u64,
+    // RFC 3339 timestamp of when it was replaced
+    pub replaced: String,
+}
+
+// save copies the config file of the repository at dir into a new version
+// and removes all but the newest keep versions; nothing is done if keep is 0
+// or there is no config file
+pub fn save(dir: &Path, keep: usize) -> io::Result<()> {
+    let config = dir.join("config");
+    if keep == 0 || !config.is_file() {
+        return Ok(());
+    }
+    let versions = dir.join(VERSIONS_DIR);
+    fs::create_dir_all(&versions)?;
+    let now = OffsetDateTime::from(SystemTime::now());
+    let stamp = format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}.{:06}Z",
+        now.year(),
+        u8::from(now.month()),
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second(),
+        now.microsecond()
+    );
+    _ = fs::copy(&config, versions.join(format!("{PREFIX}{stamp}")))?;
+
+    let mut names = names(dir)?;
+    while names.len() > keep {
+        fs::remove_file(versions.join(names.remove(0)))?;
+    }
+    Ok(())
+}
+
+// list returns the stored versions of the config file, oldest first
+pub fn list(dir: &Path) -> io::Result<Vec<ConfigVersion>> {
+    names(dir)?
+        .into_iter()
+        .map(|version| {
+            let metadata = fs::metadata(dir.join(VERSIONS_DIR).join(&version))?;
+            Ok(ConfigVersion {
+                version,
+                size: metadata.len(),
+                replaced: format_time(metadata.modified()?),
+            })
+        })
+        .collect()
+}
+
+// restore replaces the config file by the stored version; the current
+// config file is saved as a version first, so the restore can be undone.
+// It returns false if there is no such version.
+pub fn restore(dir: &Path, version: &str, keep: usize) -> io::Result<bool> {
+    if !names(dir)?.iter().any(|name| name == version) {
+        return Ok(false);
+    }
+    let source = dir.join(VERSIONS_DIR).join(version);
+    let content = fs::read(&source)?;
+    save(dir, keep.max(1))?;
+    let tmp = dir.join(format!(".{version}"));
+    fs::write(&tmp, content)?;
+    fs::rename(tmp, dir.join("config"))?;
+    Ok(true)
+}
+
+// names returns the file names of the stored versions, oldest first
+fn names(dir: &Path) -> io::Result<Vec<String>> {
+    let entries = match fs::read_dir(dir.join(VERSIONS_DIR)) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        res => res?,
+    };
+    let mut names = Vec::new();
+    for entry in entries {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with(PREFIX) {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path();
+        save(repo, 2).unwrap();
+        assert!(list(repo).unwrap().is_empty());
+
+        for content in ["first", "second", "third"] {
+            fs::write(repo.join("config"), content).unwrap();
+            save(repo, 2).unwrap();
+        }
+        let versions = list(repo).unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[0].size, "second".len() as u64);
+
+        fs::write(repo.join("config"), "re-init").unwrap();
+        assert!(!restore(repo, "config.bak.x", 2).unwrap());
+        assert!(!restore(repo, "../config", 2).unwrap());
+        assert!(restore(repo, &versions[0].version, 2).unwrap());
+        assert_eq!(fs::read_to_string(repo.join("config")).unwrap(), "second");
+        // the replaced config is kept as newest version
+        let versions = list(repo).unwrap();
+        let newest = &versions[versions.len() - 1].version;
+        assert_eq!(
+            fs::read_to_string(repo.join(VERSIONS_DIR).join(newest)).unwrap(),
+            "re-init"
+        );
+    }
+}
@@ -8,390 +8,4095 @@
 // auth    - for user authentication
 // acl     - for access control
 
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::convert::TryInto;
+use std::io;
 use std::marker::Unpin;
+use std::net::SocketAddr;
 use std::path::Path;
-use std::sync::Arc;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError, RwLock};
+use std::task::{ready, Poll};
+use std::time::Duration;
 
-use async_std::io;
-use async_std::io::SeekFrom::Start;
-use async_std::prelude::*;
-
-use tide::prelude::*;
-use tide::{Body, Request, Response, StatusCode};
-use tide_http_auth::{BasicAuthRequest, BasicAuthScheme};
-use tide_rustls::TlsListener;
+use anyhow::{anyhow, bail, Context};
+use axum::body::{Body, Bytes, HttpBody};
+use axum::extract::{self, ConnectInfo, FromRequestParts, MatchedPath, Request};
+use axum::http::header::{
+    ACCEPT, ACCEPT_RANGES, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE,
+    CONTENT_TYPE, ETAG, HOST, IF_NONE_MATCH, LOCATION, RANGE, REFERRER_POLICY, RETRY_AFTER,
+    STRICT_TRANSPORT_SECURITY, TRAILER, USER_AGENT, X_CONTENT_TYPE_OPTIONS,
+};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode, Uri};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use axum_server::Handle;
+use base64::prelude::*;
+use futures_util::stream::BoxStream;
+use futures_util::{Stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::signal;
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::io::{ReaderStream, StreamReader};
 
 use http_range::HttpRange;
 
-use super::acl::{AccessType, AclChecker};
-use super::auth::AuthChecker;
-use super::helpers::IteratorAdapter;
-use super::storage::Storage;
+use super::acl::{AccessType, Acl, AclChecker};
+use super::acme;
+use super::activity::{self, Activity};
+use super::admin;
+use super::auth::{Auth, AuthChecker};
+use super::check::to_hex;
+use super::concurrency::ConcurrencyLimits;
+use super::config::{
+    Config, LimitsConfig, LogConfig, Priority, RepoConfig, ServerConfig, StorageConfig, UserConfig,
+};
+use super::confirm::{Confirmations, TOKEN_VALIDITY};
+use super::connection::{self, configure_http, TcpAcceptor};
+use super::discovery;
+use super::events::{self, Events, ServerEvent};
+use super::geoip;
+use super::ha;
+use super::helpers::{HashingWriter, IteratorAdapter};
+use super::immutable::Immutability;
+use super::info;
+use super::janitor;
+use super::logging;
+use super::mail::{self, Mailer};
+use super::metrics::{self, AuthFailure, Metrics};
+use super::mqtt;
+use super::privileges;
+use super::profile;
+use super::progress::{with_progress, Progress};
+use super::proxy::{ClientIp, TrustedProxies};
+use super::quota::Usage;
+use super::ratelimit::RateLimiter;
+use super::redis::{Redis, WriteLock};
+use super::runtime::{self, Watchdog};
+use super::schedule::{self, LocalTime};
+use super::status;
+use super::storage::{
+    is_repo, LocalStorage, Storage, APPEND_ONLY_MARKER, FROZEN_MARKER, OWNER_MARKER,
+    READ_ONLY_MARKER, TEMPLATE_MARKER,
+};
+use super::systemd;
+use super::throttle::{throttle, Preemption, Throttle, Throttles};
+use super::tls;
+use super::uploads::{Upload, Uploads};
+use super::upstream::{self, Upstream};
+use super::vault::{self, Vault};
+use super::verify::Verifier;
+use super::versions;
+use super::webhook;
 
 #[derive(Clone)]
 pub struct State {
-    auth: Arc<dyn AuthChecker>,
-    acl: Arc<dyn AclChecker>,
+    access: Arc<RwLock<Access>>,
     storage: Arc<dyn Storage>,
+    challenges: acme::Challenges,
+    repos: Arc<RwLock<BTreeMap<String, RepoConfig>>>,
+    templates: Arc<RwLock<BTreeMap<String, RepoConfig>>>,
+    // the templates the repositories were created with, read from their
+    // template markers on first use
+    repo_templates: Arc<RwLock<HashMap<String, Option<String>>>>,
+    users: Arc<RwLock<BTreeMap<String, UserConfig>>>,
+    storage_config: Arc<RwLock<StorageConfig>>,
+    log_config: Arc<RwLock<LogConfig>>,
+    usage: Usage,
+    limits: Arc<RwLock<LimitsConfig>>,
+    rate_limiter: Arc<RwLock<Option<Arc<RateLimiter>>>>,
+    geoip: Arc<RwLock<Option<Arc<geoip::Filter>>>>,
+    // shares rate limits and write locks with other instances
+    redis: Arc<RwLock<Option<Arc<Redis>>>>,
+    throttles: Throttles,
+    // running uploads, to coalesce duplicates
+    uploads: Uploads,
+    // slows down low-priority transfers while high-priority ones run
+    preemption: Preemption,
+    concurrency: ConcurrencyLimits,
+    verifier: Verifier,
+    // pending confirmations of repository deletions
+    deletions: Confirmations,
+    activity: Activity,
+    events: Events,
+    metrics: Metrics,
+    mailer: Mailer,
+    proxies: Arc<RwLock<Arc<TrustedProxies>>>,
+    upstream: Arc<RwLock<Option<Arc<Upstream>>>>,
+    // whether this instance accepts writes, see mod ha
+    leader: Arc<AtomicBool>,
+    primary: Arc<RwLock<Option<String>>>,
+    vhosts: Arc<RwLock<BTreeMap<String, String>>>,
+    aliases: Arc<RwLock<BTreeMap<String, String>>>,
+    // added to all responses
+    security_headers: Arc<RwLock<HeaderMap>>,
+    watchdog: Watchdog,
+    config: Arc<RwLock<Config>>,
+    reloads: Arc<OnceLock<mpsc::Sender<ReloadRequest>>>,
 }
 
-#[async_trait::async_trait]
-impl tide_http_auth::Storage<String, BasicAuthRequest> for State {
-    async fn get_user(&self, request: BasicAuthRequest) -> tide::Result<Option<String>> {
-        let user = request.username;
-        match self.auth.verify(&user, &request.password) {
-            true => Ok(Some(user)),
-            false => Ok(None),
-        }
-    }
+// ReloadRequest asks the reload task to reload the configuration; the result
+// is sent back through it
+type ReloadRequest = oneshot::Sender<anyhow::Result<()>>;
+
+// Access holds authentication and ACLs, which are replaced together on reload
+#[derive(Clone)]
+struct Access {
+    auth: Arc<dyn AuthChecker>,
+    acl: Arc<dyn AclChecker>,
 }
 
 impl State {
     pub fn new(auth: impl AuthChecker, acl: impl AclChecker, storage: impl Storage) -> Self {
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        let mailer = Mailer::default();
         Self {
-            storage: Arc::new(storage),
+            verifier: Verifier::new(storage.clone(), mailer.clone()),
+            mailer,
+            events: Events::default(),
+            metrics: Metrics::default(),
+            proxies: Arc::default(),
+            upstream: Arc::default(),
+            leader: Arc::new(AtomicBool::new(true)),
+            primary: Arc::default(),
+            vhosts: Arc::default(),
+            aliases: Arc::default(),
+            security_headers: Arc::default(),
+            deletions: Confirmations::default(),
+            activity: Activity::default(),
+            config: Arc::default(),
+            reloads: Arc::default(),
+            watchdog: Watchdog::default(),
+            storage,
+            challenges: acme::Challenges::default(),
+            repos: Arc::default(),
+            templates: Arc::default(),
+            repo_templates: Arc::default(),
+            users: Arc::default(),
+            storage_config: Arc::default(),
+            log_config: Arc::default(),
+            limits: Arc::default(),
+            rate_limiter: Arc::default(),
+            geoip: Arc::default(),
+            redis: Arc::default(),
+            throttles: Throttles::default(),
+            uploads: Uploads::default(),
+            preemption: Preemption::default(),
+            concurrency: ConcurrencyLimits::default(),
+            usage: Usage::default(),
+            access: Arc::new(RwLock::new(Access {
+                auth: Arc::new(auth),
+                acl: Arc::new(acl),
+            })),
+        }
+    }
+
+    // from_config creates the state for the storage path, users and ACLs
+    // of config and applies its other settings
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        let storage = LocalStorage::try_new(&config.storage.path)?;
+        let (auth, acl) = config.load_access()?;
+        let state = Self::new(auth, acl, storage);
+        state.configure(config);
+        Ok(state)
+    }
+
+    // configure applies the settings of config which can change while the
+    // server is running; the users and ACLs are set by set_access
+    pub fn configure(&self, config: &Config) {
+        self.set_repo_configs(config.repos.clone());
+        self.set_templates(config.templates.clone());
+        self.set_user_configs(config.users.clone());
+        self.set_storage_config(config.storage.clone());
+        *self
+            .log_config
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = config.log.clone();
+        self.set_limits(config.limits.clone());
+        self.verifier.set_config(config.verify.clone());
+        self.mailer.set_config(config.mail.clone());
+        *self.proxies.write().unwrap_or_else(PoisonError::into_inner) =
+            Arc::new(TrustedProxies::new(&config.server.trusted_proxies));
+        *self.vhosts.write().unwrap_or_else(PoisonError::into_inner) = config.vhosts.clone();
+        *self.aliases.write().unwrap_or_else(PoisonError::into_inner) = config.aliases.clone();
+        let geoip = geoip::Filter::new(&config.geoip).unwrap_or_else(|err| {
+            tracing::error!("cannot load GeoIP database, not filtering by country: {err:#}");
+            None
+        });
+        *self.geoip.write().unwrap_or_else(PoisonError::into_inner) = geoip.map(Arc::new);
+        *self
+            .security_headers
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = security_headers(config);
+        *self
+            .upstream
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) =
+            Upstream::new(&config.upstream).map(Arc::new);
+        *self.redis.write().unwrap_or_else(PoisonError::into_inner) = Redis::new(&config.redis)
+            .inspect_err(|err| tracing::error!("[redis] {err:#}"))
+            .ok()
+            .flatten()
+            .map(Arc::new);
+        *self.primary.write().unwrap_or_else(PoisonError::into_inner) = config
+            .replica
+            .primary
+            .as_ref()
+            .map(|url| url.trim_end_matches('/').to_string());
+        self.set_config(config.clone());
+    }
+
+    // set_access atomically replaces authentication and ACLs; requests
+    // which are already running keep using the old ones
+    pub fn set_access(&self, auth: impl AuthChecker, acl: impl AclChecker) {
+        let access = Access {
             auth: Arc::new(auth),
             acl: Arc::new(acl),
-        }
+        };
+        *self.access.write().unwrap_or_else(PoisonError::into_inner) = access;
     }
-}
 
-const TYPES: [&str; 5] = ["data", "keys", "locks", "snapshots", "index"];
-const DEFAULT_PATH: &str = "";
-const CONFIG_TYPE: &str = "config";
-const CONFIG_NAME: &str = "";
+    // set_auth replaces authentication and keeps the ACLs
+    pub fn set_auth(&self, auth: impl AuthChecker) {
+        self.access
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .auth = Arc::new(auth);
+    }
 
-fn check_string_sha256(name: &str) -> bool {
-    if name.len() != 64 {
-        return false;
+    // set_repo_configs replaces the per-repository settings
+    pub fn set_repo_configs(&self, repos: BTreeMap<String, RepoConfig>) {
+        *self.repos.write().unwrap_or_else(PoisonError::into_inner) = repos;
     }
-    for c in name.chars() {
-        if !c.is_ascii_digit() && !('a'..='f').contains(&c) {
-            return false;
+
+    // set_templates replaces the templates of repository settings
+    pub fn set_templates(&self, templates: BTreeMap<String, RepoConfig>) {
+        *self
+            .templates
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = templates;
+    }
+
+    // set_user_configs replaces the per-user settings
+    pub fn set_user_configs(&self, users: BTreeMap<String, UserConfig>) {
+        *self.users.write().unwrap_or_else(PoisonError::into_inner) = users;
+    }
+
+    // set_storage_config replaces the settings of the storage; only the
+    // limits are used, changing the path requires a restart
+    pub fn set_storage_config(&self, config: StorageConfig) {
+        *self
+            .storage_config
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = config;
+    }
+
+    fn storage_config(&self) -> StorageConfig {
+        self.storage_config
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    // progress returns the progress tracker of a transfer of the file at path
+    pub(crate) fn progress(
+        &self,
+        direction: Direction,
+        path: String,
+        total: Option<u64>,
+    ) -> Progress {
+        let config = self
+            .log_config
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        Progress::new(&config, direction, path, total)
+    }
+
+    // set_limits replaces rate and bandwidth limits. The request counts are
+    // only reset if the rate limit changed.
+    pub fn set_limits(&self, config: LimitsConfig) {
+        let mut limits = self.limits.write().unwrap_or_else(PoisonError::into_inner);
+        let mut rate_limiter = self
+            .rate_limiter
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        if rate_limiter.is_none()
+            || limits.requests_per_second != config.requests_per_second
+            || limits.burst != config.burst
+        {
+            *rate_limiter = config
+                .requests_per_second
+                .and_then(|rate| RateLimiter::new(rate, config.burst))
+                .map(Arc::new);
         }
+        *limits = config;
     }
-    true
-}
 
-fn check_name(tpe: &str, name: &str) -> Result<(), tide::Error> {
-    match tpe {
-        "config" => Ok(()),
-        _ if check_string_sha256(name) => Ok(()),
-        _ => Err(tide::Error::from_str(
-            StatusCode::Forbidden,
-            format!("filename {} not allowed", name),
-        )),
+    fn limits(&self) -> LimitsConfig {
+        self.limits
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
     }
-}
 
-fn check_auth_and_acl(
-    req: &Request<State>,
-    path: &Path,
-    tpe: &str,
-    append: AccessType,
-) -> Result<(), tide::Error> {
-    let state = req.state();
-
-    // don't allow paths that includes any of the defined types
-    for part in path.iter() {
-        if let Some(part) = part.to_str() {
-            for tpe in TYPES.iter() {
-                if &part == tpe {
-                    return Err(tide::Error::from_str(StatusCode::Forbidden, "not allowed"));
+    fn redis(&self) -> Option<Arc<Redis>> {
+        self.redis
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    // rate_limit takes a token from the bucket of key, if rate limiting is
+    // enabled, and returns 429 if there is none left. With Redis, the buckets
+    // are shared with the other instances.
+    async fn rate_limit(&self, key: &str) -> std::result::Result<(), Box<Response>> {
+        let rate_limiter = self
+            .rate_limiter
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        let acquired = match (rate_limiter, self.redis()) {
+            (Some(_), Some(redis)) => {
+                let (rate, burst) = {
+                    let limits = self.limits.read().unwrap_or_else(PoisonError::into_inner);
+                    (limits.requests_per_second.unwrap_or_default(), limits.burst)
+                };
+                match redis.rate_limit(key, rate, burst).await {
+                    Ok(acquired) => Some(acquired),
+                    Err(err) => {
+                        tracing::warn!("cannot check the rate limit with Redis: {err:#}");
+                        None
+                    }
                 }
             }
+            (rate_limiter, _) => rate_limiter.map(|limiter| limiter.acquire(key)),
+        };
+        match acquired {
+            Some(Err(wait)) => {
+                tracing::debug!(key, "rate limit exceeded");
+                let retry_after = wait.as_secs_f64().ceil().max(1.0).to_string();
+                Err(Box::new(
+                    (
+                        StatusCode::TOO_MANY_REQUESTS,
+                        [(RETRY_AFTER, retry_after)],
+                        "too many requests",
+                    )
+                        .into_response(),
+                ))
+            }
+            _ => Ok(()),
         }
     }
 
-    let empty = String::new();
-    let user: &str = req.ext::<String>().unwrap_or(&empty);
-    let path = path.to_str().ok_or(tide::Error::from_str(
-        StatusCode::Forbidden,
-        "path is non-unicode",
-    ))?;
-    let allowed = state.acl.allowed(user, path, tpe, append);
-    tide::log::debug!("auth",  {
-    user: user,
-    path: path,
-    tpe: tpe,
-    allowed: allowed,
-    });
+    // write_lock takes the lock of path shared with the other instances if
+    // Redis is used and returns 409 if another request holds it
+    async fn write_lock(&self, path: &str) -> Result<Option<WriteLock>> {
+        let Some(redis) = self.redis() else {
+            return Ok(None);
+        };
+        match redis.lock(path).await {
+            Ok(Some(lock)) => Ok(Some(lock)),
+            Ok(None) => Err(Error::new(
+                StatusCode::CONFLICT,
+                "path is being written by another request",
+            )),
+            Err(err) => {
+                tracing::error!(path, "cannot take write lock with Redis: {err:#}");
+                Err(Error::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "write locks not available",
+                ))
+            }
+        }
+    }
 
-    match allowed {
-        true => Ok(()),
-        false => Err(tide::Error::from_str(StatusCode::Forbidden, "not allowed")),
+    // repo_config returns the settings of the repository: its [repos]
+    // section, else the template it was created with
+    fn repo_config(&self, path: &str) -> RepoConfig {
+        if let Some(config) = self.known_repo_config(path) {
+            return config;
+        }
+        let template = self.repo_template(path);
+        self.template_config(template)
+    }
+
+    // load_repo_config is repo_config for async code: the template marker of
+    // a repository not seen before is read on the threads for blocking code
+    async fn load_repo_config(&self, path: &str) -> Result<RepoConfig> {
+        if let Some(config) = self.known_repo_config(path) {
+            return Ok(config);
+        }
+        let (state, path) = (self.clone(), path.to_string());
+        blocking(move || Ok(state.repo_config(&path))).await
+    }
+
+    // known_repo_config returns the settings of the repository at path if
+    // they are known without reading its template marker
+    fn known_repo_config(&self, path: &str) -> Option<RepoConfig> {
+        if let Some(config) = self
+            .repos
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(path)
+        {
+            return Some(config.clone());
+        }
+        if self
+            .templates
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_empty()
+        {
+            return Some(RepoConfig::default());
+        }
+        let template = self
+            .repo_templates
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(path)
+            .cloned()?;
+        Some(self.template_config(template))
+    }
+
+    // template_config returns the settings of the template with the given name
+    fn template_config(&self, template: Option<String>) -> RepoConfig {
+        template
+            .and_then(|template| {
+                self.templates
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .get(&template)
+                    .cloned()
+            })
+            .unwrap_or_default()
+    }
+
+    // repo_template returns the name of the template the repository was
+    // created with; only existing repositories are remembered, as paths of
+    // requests are arbitrary
+    fn repo_template(&self, path: &str) -> Option<String> {
+        let cached = self
+            .repo_templates
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(path)
+            .cloned();
+        if let Some(template) = cached {
+            return template;
+        }
+        let repo = Path::new(path);
+        let template = self.storage.marker(repo, TEMPLATE_MARKER);
+        let dir = self.storage.filename(repo, CONFIG_TYPE, CONFIG_NAME);
+        if template.is_some() || dir.parent().is_some_and(is_repo) {
+            self.repo_templates
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(path.to_string(), template.clone());
+        }
+        template
+    }
+
+    // user_config returns the settings of user
+    fn user_config(&self, user: &str) -> UserConfig {
+        self.users
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(user)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn storage(&self) -> &dyn Storage {
+        self.storage.as_ref()
+    }
+
+    pub(crate) fn events(&self) -> &Events {
+        &self.events
+    }
+
+    pub(crate) fn mailer(&self) -> &Mailer {
+        &self.mailer
+    }
+
+    pub(crate) fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub(crate) fn watchdog(&self) -> &Watchdog {
+        &self.watchdog
+    }
+
+    // is_primary yields whether this instance runs the background cleanups:
+    // it holds the lease of mod ha, if used, and isn't a read replica
+    pub(crate) fn is_primary(&self) -> bool {
+        self.is_leader() && self.primary().is_none()
+    }
+
+    pub(crate) fn is_leader(&self) -> bool {
+        self.leader.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_leader(&self, leader: bool) {
+        self.leader.store(leader, Ordering::Relaxed);
+    }
+
+    pub(crate) fn upstream(&self) -> Option<Arc<Upstream>> {
+        self.upstream
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    // primary returns the base URL of the primary if this is a read replica
+    fn primary(&self) -> Option<String> {
+        self.primary
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    // config returns the configuration currently in use
+    pub(crate) fn config(&self) -> Config {
+        self.config
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn set_config(&self, config: Config) {
+        *self.config.write().unwrap_or_else(PoisonError::into_inner) = config;
+    }
+
+    // reload reloads the configuration like SIGHUP does and waits until it
+    // is applied. Without the reload task of main, e.g. if the server is
+    // embedded with crate::router, the files are applied directly.
+    pub(crate) async fn reload(&self) -> anyhow::Result<()> {
+        let unsupported = || anyhow!("the configuration cannot be reloaded");
+        let Some(reloads) = self.reloads.get() else {
+            return self.reload_files().await;
+        };
+        let (tx, rx) = oneshot::channel();
+        reloads.send(tx).await.map_err(|_| unsupported())?;
+        rx.await.map_err(|_| unsupported())?
+    }
+
+    // reload_files reads the config file the server was configured with, if
+    // any, and the htpasswd and ACL files again and applies them
+    async fn reload_files(&self) -> anyhow::Result<()> {
+        let config = self.config();
+        let (config, access) = tokio::task::spawn_blocking(move || {
+            let config = match &config.file {
+                Some(file) => Config::from_file(file)?,
+                None => config,
+            };
+            for warning in config.check()? {
+                tracing::warn!("configuration: {warning}");
+            }
+            // the users kept in Vault are fetched below
+            let access = match config.vault.htpasswd_secret {
+                Some(_) => None,
+                None => Some(config.load_access()?),
+            };
+            anyhow::Ok((config, access))
+        })
+        .await??;
+        let (auth, acl) = match access {
+            Some(access) => access,
+            None => vault::load_access(&config).await?,
+        };
+        self.set_access(auth, acl);
+        self.configure(&config);
+        Ok(())
+    }
+
+    pub(crate) fn usage(&self) -> &Usage {
+        &self.usage
+    }
+
+    pub(crate) fn verifier(&self) -> &Verifier {
+        &self.verifier
+    }
+
+    pub(crate) fn is_admin(&self, user: &str) -> bool {
+        self.access().acl.is_admin(user)
+    }
+
+    fn access(&self) -> Access {
+        self.access
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
     }
 }
 
-#[derive(Default, Deserialize)]
-#[serde(default)]
-struct Create {
-    create: bool,
+// Error is the error type returned by all handlers; it is sent to the client
+// as status code with a plain text message
+#[derive(Debug)]
+pub struct Error {
+    status: StatusCode,
+    message: String,
 }
 
-async fn create_dirs(path: &str, req: &Request<State>) -> tide::Result {
-    tide::log::debug!("create_dirs", {
-        path: path,
-    });
+impl Error {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
 
-    let path = Path::new(path);
-    check_auth_and_acl(req, path, "", AccessType::Append)?;
-    let c: Create = req.query()?;
-    match c.create {
-        true => {
-            for tpe in TYPES.iter() {
-                req.state().storage.create_dir(path, tpe)?;
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+}
+
+// storage errors get the status which tells restic whether to retry: a full
+// or read-only disk is temporary, a missing file is not
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        let status = match err.kind() {
+            io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => {
+                StatusCode::INSUFFICIENT_STORAGE
             }
-            Ok(format!("Called create_files with path {:?}\n", path).into())
-        }
-        false => Ok(format!("Called create_files with path {:?}, create=false\n", path).into()),
+            io::ErrorKind::ReadOnlyFilesystem => StatusCode::SERVICE_UNAVAILABLE,
+            io::ErrorKind::PermissionDenied => {
+                // wrong ownership of the data directory needs the operator
+                tracing::error!("storage access denied: {err}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        Self::new(status, err.to_string())
     }
 }
 
-const API_V1: &str = "application/vnd.x.restic.rest.v1";
-const API_V2: &str = "application/vnd.x.restic.rest.v2";
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
 
-#[derive(Serialize)]
-struct RepoPathEntry {
-    name: String,
-    size: u64,
+impl std::error::Error for Error {}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        tracing::debug!(status = %self.status, message = self.message, "request failed");
+        (self.status, self.message).into_response()
+    }
+}
+
+type Result<T = Response> = std::result::Result<T, Error>;
+
+// blocking runs f on the threads for blocking code; all filesystem access of
+// the handlers goes through it, so a slow disk doesn't stall other requests
+pub(crate) async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|err| Error::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
 }
 
-async fn list_files(path: &str, tpe: &str, req: &Request<State>) -> tide::Result {
-    tide::log::debug!("list_files", {
-        path: path,
-        tpe: tpe,
+// record_activity notes a read or write of repo in the background
+fn record_activity(state: &State, repo: &str, kind: activity::Kind) {
+    let (state, repo) = (state.clone(), repo.to_string());
+    _ = tokio::task::spawn_blocking(move || {
+        state.activity.record(state.storage.as_ref(), &repo, kind);
     });
+}
 
-    let path = Path::new(path);
-    check_auth_and_acl(req, path, tpe, AccessType::Read)?;
+// AuthFromRequest extracts the user from the basic auth header of a request.
+// Requests without credentials are verified as empty user, which only
+// succeeds if authentication is disabled.
+#[derive(Clone)]
+pub struct AuthFromRequest {
+    pub user: String,
+}
 
-    let read_dir = req.state().storage.read_dir(path, tpe);
-    let mut res = Response::new(StatusCode::Ok);
+#[async_trait::async_trait]
+impl FromRequestParts<State> for AuthFromRequest {
+    type Rejection = Response;
 
-    // TODO: error handling
-    match req.header("Accept") {
-        Some(a) if a.as_str() == API_V2 => {
-            res.set_content_type(API_V2);
-            let read_dir_version = read_dir.map(|e| RepoPathEntry {
-                name: e.file_name().to_str().unwrap().to_string(),
-                size: e.metadata().unwrap().len(),
-            });
-            res.set_body(Body::from_json(&IteratorAdapter::new(read_dir_version))?);
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &State,
+    ) -> std::result::Result<Self, Response> {
+        let credentials = basic_auth(&parts.headers);
+        let given = credentials.is_some();
+        let (user, passwd) = credentials.unwrap_or_default();
+        let auth = state.access().auth;
+        match auth.verify(&user, &passwd) {
+            true => {
+                // limit users only after authentication, so nobody can use up
+                // the requests of others
+                if !user.is_empty() {
+                    state
+                        .rate_limit(&format!("user {user}"))
+                        .await
+                        .map_err(|res| *res)?;
+                }
+                Ok(Self { user })
+            }
+            false => {
+                let ip = parts
+                    .extensions
+                    .get::<ClientIp>()
+                    .map(ToString::to_string)
+                    .unwrap_or_default();
+                let reason = match (given, auth.has_user(&user)) {
+                    (false, _) => AuthFailure::MissingCredentials,
+                    (true, false) => AuthFailure::UnknownUser,
+                    (true, true) => AuthFailure::WrongPassword,
+                };
+                state.metrics.auth_failed(reason);
+                let user_agent = parts
+                    .headers
+                    .get(USER_AGENT)
+                    .and_then(|agent| agent.to_str().ok())
+                    .unwrap_or_default();
+                // clients without credentials are mostly browsers asking for
+                // them, not attacks
+                match reason {
+                    AuthFailure::MissingCredentials => {
+                        tracing::debug!(%ip, user_agent, "no credentials given");
+                    }
+                    // the form is matched by config/fail2ban/rustic-server.conf
+                    _ => tracing::warn!(
+                        user,
+                        %ip,
+                        user_agent,
+                        reason = reason.as_str(),
+                        "authentication failed"
+                    ),
+                }
+                state.events.publish(ServerEvent {
+                    user,
+                    message: match ip.is_empty() {
+                        true => String::new(),
+                        false => format!("from {ip}"),
+                    },
+                    ..ServerEvent::new("auth_failed")
+                });
+                Err((
+                    StatusCode::UNAUTHORIZED,
+                    [("WWW-Authenticate", "Basic realm=\"restic\"")],
+                    "authentication failed",
+                )
+                    .into_response())
+            }
         }
-        _ => {
-            res.set_content_type(API_V1);
-            let read_dir_version = read_dir.map(|e| e.file_name().to_str().unwrap().to_string());
-            res.set_body(Body::from_json(&IteratorAdapter::new(read_dir_version))?);
+    }
+}
+
+// client_ip stores the IP of the client in the request extensions; behind
+// trusted proxies it is taken from the forwarding headers
+async fn client_ip(
+    extract::State(state): extract::State<State>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    if let Some(ConnectInfo(addr)) = req.extensions().get::<ConnectInfo<SocketAddr>>() {
+        let proxies = state
+            .proxies
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        let ip = proxies.client_ip(addr.ip(), req.headers());
+        _ = req.extensions_mut().insert(ClientIp(ip));
+    }
+    next.run(req).await
+}
+
+// geoip_filter rejects requests from client IPs of countries not allowed by
+// [geoip]
+async fn geoip_filter(
+    extract::State(state): extract::State<State>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let filter = state
+        .geoip
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    if let (Some(filter), Some(ClientIp(ip))) = (filter, req.extensions().get::<ClientIp>()) {
+        let (allowed, country) = filter.allows(*ip);
+        if !allowed {
+            tracing::debug!(%ip, country, "request denied by country");
+            return Error::new(StatusCode::FORBIDDEN, "access denied").into_response();
         }
-    };
-    Ok(res)
+    }
+    next.run(req).await
 }
 
-async fn length(path: &str, tpe: &str, name: &str, req: &Request<State>) -> tide::Result {
-    tide::log::debug!("length", {
-        path: path,
-        tpe: tpe,
-        name: name,
+// rate_limit_ip rejects requests from client IPs exceeding the rate limit
+async fn rate_limit_ip(
+    extract::State(state): extract::State<State>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if let Some(ip) = req.extensions().get::<ClientIp>() {
+        if let Err(res) = state.rate_limit(&format!("ip {}", ip.network())).await {
+            return *res;
+        }
+    }
+    next.run(req).await
+}
+
+// limit_concurrency answers requests with 503 if the server or the repository
+// is already processing the maximum number of requests. It runs after routing,
+// so the repository is resolved like in the handlers: from the decoded path,
+// after the base path, the host directory and the aliases are applied.
+async fn limit_concurrency(
+    extract::State(state): extract::State<State>,
+    matched: Option<MatchedPath>,
+    path: Option<extract::Path<String>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let repo = match matched.as_ref().map(MatchedPath::as_str) {
+        Some("/" | "/*path") => path_parts(&state, path).ok().map(|parts| parts.repo),
+        _ => None,
+    };
+    let repo_limit = match &repo {
+        Some(repo) => match state.load_repo_config(repo).await {
+            Ok(config) => config.max_requests,
+            Err(err) => return err.into_response(),
+        },
+        None => None,
+    };
+    let server = state.limits();
+    // the user isn't authenticated yet, so only reads count as high priority
+    let read = matches!(*req.method(), Method::GET | Method::HEAD);
+    let limits = [
+        ("server".to_string(), server.max_requests),
+        match (server.max_requests, server.reserved_requests) {
+            (Some(max), Some(reserved)) if !read => (
+                "server writes".to_string(),
+                Some(max.saturating_sub(reserved)),
+            ),
+            _ => (String::new(), None),
+        },
+        match &repo {
+            Some(repo) => (format!("repo {repo}"), repo_limit),
+            None => (String::new(), None),
+        },
+    ];
+    let mut permits = Vec::new();
+    for (key, max) in limits {
+        let Some(max) = max else { continue };
+        match state.concurrency.try_acquire(&key, max) {
+            Some(permit) => permits.push(permit),
+            None => {
+                tracing::debug!(key, max, "too many concurrent requests");
+                return (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    [(RETRY_AFTER, "1")],
+                    "too many concurrent requests, try again later",
+                )
+                    .into_response();
+            }
+        }
+    }
+
+    let res = next.run(req).await;
+    if permits.is_empty() {
+        return res;
+    }
+    // keep the permits until the body is sent, e.g. for downloads
+    let (parts, body) = res.into_parts();
+    let body = body.into_data_stream().map(move |chunk| {
+        let _ = &permits;
+        chunk
     });
+    Response::from_parts(parts, Body::from_stream(body))
+}
 
-    check_name(tpe, name)?;
-    let path = Path::new(path);
-    check_auth_and_acl(req, path, tpe, AccessType::Read)?;
+// replica redirects all requests but reads to the primary with 307, which
+// makes clients repeat them there with the same method and body
+async fn replica(
+    extract::State(state): extract::State<State>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let primary = match state.primary() {
+        Some(primary) if !matches!(*req.method(), Method::GET | Method::HEAD) => primary,
+        _ => return next.run(req).await,
+    };
+    let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+    (
+        StatusCode::TEMPORARY_REDIRECT,
+        [(LOCATION, format!("{primary}{path}"))],
+        "this instance is a read replica, writes go to the primary",
+    )
+        .into_response()
+}
 
-    let _file = req.state().storage.filename(path, tpe, name);
-    Err(tide::Error::from_str(
-        StatusCode::NotImplemented,
-        "not yet implemented",
-    ))
+// standby answers writes with 503 while another instance holds the lease
+async fn standby(
+    extract::State(state): extract::State<State>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if state.is_leader() || matches!(*req.method(), Method::GET | Method::HEAD) {
+        return next.run(req).await;
+    }
+    Error::new(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "this instance is the standby, writes go to the leader",
+    )
+    .into_response()
 }
 
-async fn get_file(path: &str, tpe: &str, name: &str, req: &Request<State>) -> tide::Result {
-    tide::log::debug!("get_file", {
-        path: path,
-        tpe: tpe,
-        name: name,
-    });
+// maintenance answers all requests with 503 while a maintenance window is active
+async fn maintenance(
+    extract::State(state): extract::State<State>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let limits = state.limits();
+    match schedule::active(&limits.schedule, LocalTime::now()) {
+        Some((schedule, remaining)) if schedule.maintenance => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(RETRY_AFTER, remaining.as_secs().to_string())],
+            format!("server is under maintenance until {}", schedule.to),
+        )
+            .into_response(),
+        _ => next.run(req).await,
+    }
+}
 
-    check_name(tpe, name)?;
-    let path = Path::new(path);
-    check_auth_and_acl(req, path, tpe, AccessType::Read)?;
+// basic_auth parses user and password from a basic authorization header
+pub fn basic_auth(headers: &HeaderMap) -> Option<(String, String)> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let encoded = value.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(BASE64_STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (user, passwd) = decoded.split_once(':')?;
+    Some((user.to_string(), passwd.to_string()))
+}
 
-    let mut file = req.state().storage.open_file(path, tpe, name).await?;
-    let mut len = file.metadata().await?.len();
+pub const TYPES: [&str; 5] = ["data", "keys", "locks", "snapshots", "index"];
+pub(crate) const CONFIG_TYPE: &str = "config";
+pub(crate) const CONFIG_NAME: &str = "";
 
-    let mut res;
-    match req.header("Range") {
-        None => {
-            res = Response::new(StatusCode::Ok);
+// PathParts are the parts a request path is composed of:
+// repository path, file type and file name.
+// tpe is None for requests to the repository itself, name is None for
+// requests to a type directory.
+#[derive(Debug, PartialEq)]
+pub struct PathParts {
+    pub repo: String,
+    pub tpe: Option<String>,
+    pub name: Option<String>,
+}
+
+// decompose_path splits a request path into repository path, type and name.
+// Paths are of the form `<repo>/config`, `<repo>/<tpe>/` or `<repo>/<tpe>/<name>`
+// where `<repo>` may be empty or consist of several components.
+pub fn decompose_path(path: &str) -> Result<PathParts> {
+    let mut parts: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    let n = parts.len();
+
+    let (tpe, name) = if parts[n - 1] == CONFIG_TYPE {
+        parts.pop();
+        (Some(CONFIG_TYPE.to_string()), Some(CONFIG_NAME.to_string()))
+    } else if n >= 2 && TYPES.contains(&parts[n - 2]) {
+        let name = parts.pop().unwrap_or_default();
+        let tpe = parts.pop().unwrap_or_default();
+        let name = (!name.is_empty()).then(|| name.to_string());
+        (Some(tpe.to_string()), name)
+    } else {
+        if parts[n - 1].is_empty() {
+            parts.pop();
         }
-        Some(r) => match HttpRange::parse(r.as_str(), len) {
-            Ok(range) if range.len() == 1 => {
-                file.seek(Start(range[0].start)).await?;
-                len = range[0].length;
-                res = Response::new(StatusCode::PartialContent);
-            }
-            Ok(_) => {
-                return Err(tide::Error::from_str(
-                    StatusCode::NotImplemented,
-                    "multipart range not implemented",
-                ))
-            }
-            Err(_) => {
-                return Err(tide::Error::from_str(
-                    StatusCode::InternalServerError,
-                    "range error",
-                ))
-            }
-        },
+        (None, None)
     };
 
-    let file = io::BufReader::new(file);
-    res.set_body(Body::from_reader(file, Some(len.try_into()?)));
-    Ok(res)
+    // don't allow empty, relative or type components in the repository path
+    if parts
+        .iter()
+        .any(|part| part.is_empty() || *part == "." || *part == ".." || TYPES.contains(part))
+    {
+        return Err(Error::new(StatusCode::FORBIDDEN, "not allowed"));
+    }
+
+    Ok(PathParts {
+        repo: parts.join("/"),
+        tpe,
+        name,
+    })
 }
 
-#[async_trait::async_trait]
-pub trait Finalizer {
-    async fn finalize(&mut self) -> Result<(), io::Error>;
+fn check_string_sha256(name: &str) -> bool {
+    if name.len() != 64 {
+        return false;
+    }
+    for c in name.chars() {
+        if !c.is_ascii_digit() && !('a'..='f').contains(&c) {
+            return false;
+        }
+    }
+    true
 }
 
-async fn save_body(
-    req: &mut Request<State>,
-    mut file: impl io::Write + Unpin + Finalizer,
-) -> tide::Result {
-    let bytes_written = io::copy(req, &mut file).await?;
-    tide::log::debug!("file written", {
-        bytes: bytes_written,
-    });
-    file.finalize().await?;
-    Ok(Response::new(StatusCode::Ok))
+pub(crate) fn check_name(tpe: &str, name: &str) -> Result<()> {
+    match tpe {
+        "config" => Ok(()),
+        _ if check_string_sha256(name) => Ok(()),
+        _ => Err(Error::new(
+            StatusCode::FORBIDDEN,
+            format!("filename {} not allowed", name),
+        )),
+    }
 }
 
-async fn get_save_file(
-    path: &str,
+pub(crate) fn check_auth_and_acl(
+    state: &State,
+    auth: &AuthFromRequest,
+    path: &Path,
     tpe: &str,
-    name: &str,
-    req: &Request<State>,
-) -> Result<impl io::Write + Unpin + Finalizer, tide::Error> {
-    tide::log::debug!("get_save_file", {
-        path: path,
-        tpe: tpe,
-        name: name,
-    });
+    append: AccessType,
+) -> Result<()> {
+    let user = auth.user.as_str();
+    let path = path
+        .to_str()
+        .ok_or_else(|| Error::new(StatusCode::FORBIDDEN, "path is non-unicode"))?;
+    let allowed = state.access().acl.allowed(user, path, tpe, append.clone());
+    tracing::debug!(user, path, tpe, allowed, "auth");
 
-    check_name(tpe, name)?;
-    let path = Path::new(path);
-    check_auth_and_acl(req, path, tpe, AccessType::Append)?;
+    if !allowed {
+        return Err(Error::new(StatusCode::FORBIDDEN, "not allowed"));
+    }
+    check_repo_config(state, path, tpe, append)
+}
 
-    Ok(req.state().storage.create_file(path, tpe, name).await?)
+// check_repo_config applies the per-repository settings; like for the ACL,
+// access to locks is always treated as Read
+fn check_repo_config(state: &State, path: &str, tpe: &str, access: AccessType) -> Result<()> {
+    if tpe == "locks" || access == AccessType::Read {
+        return Ok(());
+    }
+    let repo = state.repo_config(path);
+    check_writable(state, path, &repo)?;
+    if access != AccessType::Modify {
+        return Ok(());
+    }
+    if repo.append_only {
+        return Err(Error::new(
+            StatusCode::FORBIDDEN,
+            "repository is append-only",
+        ));
+    }
+    if let Some(reason) = state.storage.marker(Path::new(path), APPEND_ONLY_MARKER) {
+        return Err(Error::new(
+            StatusCode::FORBIDDEN,
+            with_reason("repository is append-only", &reason),
+        ));
+    }
+    Ok(())
 }
 
-async fn delete_file(path: &str, tpe: &str, name: &str, req: &Request<State>) -> tide::Result {
-    check_name(tpe, name)?;
+// check_writable fails if the repository at path is read-only by its
+// settings or its read-only marker, or frozen
+fn check_writable(state: &State, path: &str, repo: &RepoConfig) -> Result<()> {
+    if repo.read_only {
+        return Err(Error::new(StatusCode::FORBIDDEN, "repository is read-only"));
+    }
     let path = Path::new(path);
-    check_auth_and_acl(req, path, tpe, AccessType::Modify)?;
-    req.state().storage.remove_file(path, tpe, name)?;
-    Ok(Response::new(StatusCode::Ok))
+    if let Some(reason) = state.storage.marker(path, READ_ONLY_MARKER) {
+        return Err(Error::new(
+            StatusCode::FORBIDDEN,
+            with_reason("repository is read-only", &reason),
+        ));
+    }
+    if let Some(reason) = state.storage.marker(path, FROZEN_MARKER) {
+        return Err(Error::new(
+            StatusCode::FORBIDDEN,
+            with_reason("repository is frozen", &reason),
+        ));
+    }
+    Ok(())
 }
 
-pub async fn main(
-    state: State,
-    addr: String,
-    tls: bool,
-    cert: Option<String>,
-    key: Option<String>,
-) -> tide::Result<()> {
-    let mid = tide_http_auth::Authentication::new(BasicAuthScheme);
-    let mut app = tide::with_state(state);
-    app.with(mid);
-
-    app.at("/:path/")
-        .post(|req: Request<State>| async move { create_dirs(req.param("path")?, &req).await });
-    app.at("/")
-        .post(|req| async move { create_dirs(DEFAULT_PATH, &req).await });
-
-    for tpe in TYPES.iter() {
-        let path = &("/".to_string() + tpe + "/");
-        tide::log::debug!("add path: {}", path);
-        app.at(path)
-            .get(move |req| async move { list_files(DEFAULT_PATH, tpe, &req).await });
-
-        let path = &("/".to_string() + tpe + "/:name");
-        tide::log::debug!("add path: {}", path);
-        app.at(path)
-            .head(move |req: Request<State>| async move {
-                length(DEFAULT_PATH, tpe, req.param("name")?, &req).await
-            })
-            .get(move |req: Request<State>| async move {
-                get_file(DEFAULT_PATH, tpe, req.param("name")?, &req).await
-            })
-            .post(move |mut req: Request<State>| async move {
-                let file = get_save_file(DEFAULT_PATH, tpe, req.param("name")?, &req).await?;
-                save_body(&mut req, file).await
-            })
-            .delete(move |req: Request<State>| async move {
-                delete_file(DEFAULT_PATH, tpe, req.param("name")?, &req).await
-            });
-
-        let path = &("/:path/".to_string() + tpe + "/");
-        tide::log::debug!("add path: {}", path);
-        app.at(path).get(move |req: Request<State>| async move {
-            list_files(req.param("path")?, tpe, &req).await
-        });
-
-        let path = &("/:path/".to_string() + tpe + "/:name");
-        tide::log::debug!("add path: {}", path);
-        app.at(path)
-            .head(move |req: Request<State>| async move {
-                length(req.param("path")?, tpe, req.param("name")?, &req).await
-            })
-            .get(move |req: Request<State>| async move {
-                get_file(req.param("path")?, tpe, req.param("name")?, &req).await
-            })
-            .post(move |mut req: Request<State>| async move {
-                let file = get_save_file(req.param("path")?, tpe, req.param("name")?, &req).await?;
-                save_body(&mut req, file).await
-            })
-            .delete(move |req: Request<State>| async move {
-                delete_file(req.param("path")?, tpe, req.param("name")?, &req).await
-            });
+fn with_reason(message: &str, reason: &str) -> String {
+    match reason.is_empty() {
+        true => message.to_string(),
+        false => format!("{message}: {reason}"),
     }
+}
 
-    app.at("config")
-        .get(|req| async move { get_file(DEFAULT_PATH, CONFIG_TYPE, CONFIG_NAME, &req).await })
-        .post(|mut req| async move {
-            let file = get_save_file(DEFAULT_PATH, CONFIG_TYPE, CONFIG_NAME, &req).await?;
-            save_body(&mut req, file).await
-        })
-        .delete(
-            |req| async move { delete_file(DEFAULT_PATH, CONFIG_TYPE, CONFIG_NAME, &req).await },
-        );
+// QuotaKind tells what a quota limits
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum QuotaKind {
+    // all repositories
+    Storage,
+    // the free space on the filesystem
+    Reserve,
+    Repository,
+    // all repositories of a user
+    User,
+}
 
-    app.at("/:path/config")
-        .get(|req: Request<State>| async move {
-            get_file(req.param("path")?, CONFIG_TYPE, CONFIG_NAME, &req).await
+impl QuotaKind {
+    // status returns the status of uploads exceeding the quota
+    fn status(self) -> StatusCode {
+        match self {
+            Self::Storage | Self::Reserve => StatusCode::INSUFFICIENT_STORAGE,
+            Self::Repository | Self::User => StatusCode::PAYLOAD_TOO_LARGE,
+        }
+    }
+
+    // headers returns the headers reporting usage and limit to clients; the
+    // limits of the storage are not disclosed
+    fn headers(self) -> Option<[&'static str; 2]> {
+        match self {
+            Self::Storage | Self::Reserve => None,
+            Self::Repository => Some(["x-quota-used", "x-quota-limit"]),
+            Self::User => Some(["x-user-quota-used", "x-user-quota-limit"]),
+        }
+    }
+}
+
+// Quota is a limit on the bytes stored in a repository, in all repositories
+// of a user or in the whole storage, together with its current usage
+struct Quota {
+    kind: QuotaKind,
+    // what is limited, e.g. `the quota of repository "alice"`
+    scope: String,
+    limit: u64,
+    used: u64,
+}
+
+impl Quota {
+    fn remaining(&self) -> u64 {
+        self.limit.saturating_sub(self.used)
+    }
+
+    // exceeded returns the error for an upload of bytes (if known) exceeding the quota
+    fn exceeded(&self, bytes: Option<u64>) -> Error {
+        let upload = match bytes {
+            Some(bytes) => format!("upload of {bytes} bytes"),
+            None => "upload".to_string(),
+        };
+        Error::new(
+            self.kind.status(),
+            format!(
+                "{upload} exceeds {}: {} bytes left",
+                self.scope,
+                self.remaining()
+            ),
+        )
+    }
+
+    // add_headers reports usage and limit in the response headers
+    fn add_headers(&self, res: &mut Response) {
+        if let Some([used, limit]) = self.kind.headers() {
+            let headers = res.headers_mut();
+            headers.insert(used, self.used.into());
+            headers.insert(limit, self.limit.into());
+        }
+    }
+}
+
+// storage_quotas returns the limits of the whole storage, which protect the
+// filesystem from running full; exceeding them gives 507
+fn storage_quotas(state: &State, path: &str) -> Result<Vec<Quota>> {
+    let storage = state.storage.as_ref();
+    let config = state.storage_config();
+    let mut quotas = Vec::new();
+    if let Some(limit) = config.quota {
+        let repos = state.usage.repos(storage);
+        // path may not be a complete repository yet, but is always counted
+        let repos: BTreeSet<_> = repos.iter().map(String::as_str).chain([path]).collect();
+        quotas.push(Quota {
+            kind: QuotaKind::Storage,
+            scope: "the quota of the storage".to_string(),
+            limit,
+            used: state.usage.total(storage, repos)?,
+        });
+    }
+    if let Some(reserve) = config.reserve {
+        let space = storage.disk_space()?;
+        quotas.push(Quota {
+            kind: QuotaKind::Reserve,
+            scope: format!("the space on the filesystem keeping {reserve} bytes free"),
+            limit: space.total.saturating_sub(reserve),
+            used: space.total.saturating_sub(space.available),
+        });
+    }
+    Ok(quotas)
+}
+
+// quotas returns the quotas which apply when user writes to the repository
+// at path. The quota of a user covers all repositories the user may write to.
+fn quotas(state: &State, user: &str, path: &str) -> Result<Vec<Quota>> {
+    let storage = state.storage.as_ref();
+    let mut quotas = storage_quotas(state, path)?;
+    if let Some(limit) = state.repo_config(path).quota {
+        quotas.push(Quota {
+            kind: QuotaKind::Repository,
+            scope: format!("the quota of repository {path:?}"),
+            limit,
+            used: state.usage.get(storage, path)?,
+        });
+    }
+    if let Some(limit) = state.user_config(user).quota {
+        let acl = state.access().acl;
+        let repos = state.usage.repos(storage);
+        // path may not be a complete repository yet, but is always counted
+        let writable: BTreeSet<_> = repos
+            .iter()
+            .map(String::as_str)
+            .filter(|repo| acl.allowed(user, repo, "data", AccessType::Append))
+            .chain([path])
+            .collect();
+        quotas.push(Quota {
+            kind: QuotaKind::User,
+            scope: format!("the quota of user {user:?}"),
+            limit,
+            used: state.usage.total(storage, writable)?,
+        });
+    }
+    Ok(quotas)
+}
+
+// Direction of a transfer, selecting the bandwidth limits which apply
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Direction {
+    Upload,
+    Download,
+}
+
+// throttles returns the bandwidth limits which apply when user transfers
+// files to or from the repository at path in the given direction. An active
+// schedule replaces the limits of the server. With
+// limits.low_priority_bandwidth, low-priority transfers are slowed down while
+// high-priority ones run.
+pub(crate) fn throttles(
+    state: &State,
+    user: &str,
+    path: &str,
+    direction: Direction,
+) -> Vec<Throttle> {
+    let limits = state.limits();
+    let (upload, download) = match schedule::active(&limits.schedule, LocalTime::now()) {
+        Some((schedule, _)) => (schedule.upload_bandwidth, schedule.download_bandwidth),
+        None => (limits.upload_bandwidth, limits.download_bandwidth),
+    };
+    let repo = state.repo_config(path);
+    let user_config = state.user_config(user);
+    let select = |upload, download| match direction {
+        Direction::Upload => upload,
+        Direction::Download => download,
+    };
+    let mut throttles: Vec<_> = [
+        ("server".to_string(), select(upload, download)),
+        (
+            format!("repo {path}"),
+            select(repo.upload_bandwidth, repo.download_bandwidth),
+        ),
+        (
+            format!("user {user}"),
+            select(user_config.upload_bandwidth, user_config.download_bandwidth)
+                .filter(|_| !user.is_empty()),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(key, rate)| Some(state.throttles.get(&format!("{direction:?} {key}"), rate?)))
+    .collect();
+    if let Some(rate) = limits.low_priority_bandwidth {
+        let priority = user_config.priority.unwrap_or(match direction {
+            Direction::Upload => Priority::Low,
+            Direction::Download => Priority::High,
+        });
+        throttles.push(match priority {
+            Priority::High => state.preemption.preempting(),
+            Priority::Low => state
+                .throttles
+                .get("low priority", rate)
+                .preempted_by(&state.preemption),
+        });
+    }
+    throttles
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers.get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
+// notify publishes event and sends it to the webhook of the repository, if
+// one is configured; written snapshots complete a backup and ping the
+// healthcheck URL. The first snapshot of a repository, which confirms a new
+// host is backed up, and deleted snapshots, which may be a prune or
+// tampering, get an additional event.
+fn notify(
+    state: &State,
+    auth: &AuthFromRequest,
+    event: &'static str,
+    path: &str,
+    tpe: &str,
+    name: &str,
+) {
+    let repo_config = state.repo_config(path);
+    if let Some(url) = &repo_config.healthcheck {
+        if event == "upload" && tpe == "snapshots" {
+            webhook::ping(url, path);
+        }
+    }
+    let snapshot_event = match (event, tpe) {
+        ("upload", "snapshots") if is_first_snapshot(state, path) => Some("first_snapshot"),
+        ("delete", "snapshots") => Some("snapshot_deleted"),
+        _ => None,
+    };
+    for event in std::iter::once(event).chain(snapshot_event) {
+        state.events.publish(ServerEvent {
+            repo: path.to_string(),
+            tpe: tpe.to_string(),
+            name: name.to_string(),
+            user: auth.user.clone(),
+            ..ServerEvent::new(event)
+        });
+        if let Some(url) = &repo_config.webhook {
+            webhook::send(
+                url,
+                webhook::Event {
+                    event,
+                    repo: path.to_string(),
+                    tpe: tpe.to_string(),
+                    name: name.to_string(),
+                    user: auth.user.clone(),
+                },
+            );
+        }
+    }
+}
+
+// is_first_snapshot returns whether the snapshot just written is the only
+// one of the repository
+fn is_first_snapshot(state: &State, path: &str) -> bool {
+    state
+        .storage
+        .read_dir(Path::new(path), "snapshots")
+        .take(2)
+        .count()
+        == 1
+}
+
+// report_quota publishes and mails an upload rejected for exceeding a quota
+fn report_quota(state: &State, repo: &str, err: &Error) {
+    if matches!(
+        err.status,
+        StatusCode::PAYLOAD_TOO_LARGE | StatusCode::INSUFFICIENT_STORAGE
+    ) {
+        state.events.publish(ServerEvent {
+            repo: repo.to_string(),
+            message: err.message.clone(),
+            ..ServerEvent::new("quota_exceeded")
+        });
+        state.mailer.send(
+            "quota",
+            repo,
+            &format!("upload to {repo} exceeds a quota"),
+            &format!("An upload to repository {repo} was rejected: {err}"),
+        );
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct Create {
+    create: bool,
+    // name of the template whose settings the repository gets
+    template: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct Delete {
+    // delete a repository even if it is locked
+    force: bool,
+    // confirms deleting a large repository
+    token: Option<String>,
+}
+
+// DeleteConfirmation describes a repository to delete and the token confirming it
+#[derive(Serialize)]
+struct DeleteConfirmation {
+    repo: String,
+    size: u64,
+    snapshots: usize,
+    token: String,
+    expires_in: u64,
+}
+
+fn create_repository(state: &State, auth: &AuthFromRequest, path: &str, c: Create) -> Result {
+    tracing::debug!(path, "create_repository");
+
+    let repo = path;
+    let path = Path::new(path);
+    let config = state.config();
+    check_auth_and_acl(state, auth, path, "", config.acl.create_access.clone())?;
+    match c.create {
+        true => {
+            let depth = path.components().count();
+            if depth > config.storage.max_depth {
+                return Err(Error::new(
+                    StatusCode::FORBIDDEN,
+                    format!(
+                        "repository paths may have at most {} components",
+                        config.storage.max_depth
+                    ),
+                ));
+            }
+            if let Some(parent) = path.ancestors().skip(1).find(|parent| {
+                state
+                    .storage
+                    .filename(parent, CONFIG_TYPE, CONFIG_NAME)
+                    .exists()
+            }) {
+                return Err(Error::new(
+                    StatusCode::CONFLICT,
+                    format!("cannot create a repository within repository {parent:?}"),
+                ));
+            }
+            let quotas = storage_quotas(state, repo)?;
+            if let Some(quota) = quotas.iter().find(|quota| quota.remaining() == 0) {
+                return Err(quota.exceeded(None));
+            }
+            check_max_repos(state, &auth.user, repo)?;
+            let template = choose_template(state, auth, &config, c.template)?;
+            state.storage.create_repo(path, &TYPES)?;
+            if !auth.user.is_empty() && state.storage.marker(path, OWNER_MARKER).is_none() {
+                state
+                    .storage
+                    .set_marker(path, OWNER_MARKER, Some(&auth.user))?;
+            }
+            // the template of an existing repository is kept
+            if let Some(template) = template {
+                if state.storage.marker(path, TEMPLATE_MARKER).is_none() {
+                    state
+                        .storage
+                        .set_marker(path, TEMPLATE_MARKER, Some(&template))?;
+                }
+            }
+            state
+                .repo_templates
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove(repo);
+            state.usage.add_repo(repo);
+            // restic expects an empty body, also if the repository exists
+            Ok(StatusCode::OK.into_response())
+        }
+        // like rest-server, POST to a repository without create=true is a
+        // bad request
+        false => Err(Error::new(
+            StatusCode::BAD_REQUEST,
+            "repositories are only created with ?create=true",
+        )),
+    }
+}
+
+// choose_template returns the template of a repository the user creates: the
+// requested one or the default of the user or the ACL. Only admins may choose
+// another template than the default, which could lift its restrictions.
+fn choose_template(
+    state: &State,
+    auth: &AuthFromRequest,
+    config: &Config,
+    requested: Option<String>,
+) -> Result<Option<String>> {
+    let default = state
+        .user_config(&auth.user)
+        .template
+        .or_else(|| config.acl.default_template.clone());
+    let Some(requested) = requested else {
+        return Ok(default);
+    };
+    if !config.templates.contains_key(&requested) {
+        return Err(Error::new(
+            StatusCode::BAD_REQUEST,
+            format!("unknown template {requested:?}"),
+        ));
+    }
+    match default {
+        Some(default) if default != requested && !state.is_admin(&auth.user) => Err(Error::new(
+            StatusCode::FORBIDDEN,
+            format!(
+                "repositories of user {:?} use template {default:?}",
+                auth.user
+            ),
+        )),
+        _ => Ok(Some(requested)),
+    }
+}
+
+// check_max_repos fails if user already created the maximum number of
+// repositories and path is not one of them
+fn check_max_repos(state: &State, user: &str, path: &str) -> Result<()> {
+    let Some(max) = state.user_config(user).max_repos else {
+        return Ok(());
+    };
+    let storage = state.storage.as_ref();
+    let owned = state
+        .usage
+        .repos(storage)
+        .iter()
+        .filter(|repo| {
+            *repo != path && storage.marker(Path::new(repo), OWNER_MARKER).as_deref() == Some(user)
         })
-        .post(|mut req: Request<State>| async move {
-            let file = get_save_file(req.param("path")?, CONFIG_TYPE, CONFIG_NAME, &req).await?;
-            save_body(&mut req, file).await
+        .count();
+    match owned < max {
+        true => Ok(()),
+        false => Err(Error::new(
+            StatusCode::FORBIDDEN,
+            format!(
+                "user {user:?} already created {owned} repositories, which is the maximum of {max}"
+            ),
+        )),
+    }
+}
+
+// RepoUsage is the response of GET /<repo>/usage
+#[derive(Serialize)]
+struct RepoUsage {
+    repo: String,
+    // size of the repository in bytes
+    used: u64,
+    quotas: Vec<QuotaUsage>,
+}
+
+#[derive(Serialize)]
+struct QuotaUsage {
+    kind: QuotaKind,
+    limit: u64,
+    used: u64,
+    remaining: u64,
+}
+
+// get_usage reports the size of the repository at path and the quotas
+// applying to uploads of the user, so clients can warn before they run full
+fn get_usage(state: &State, auth: &AuthFromRequest, path: &str) -> Result {
+    tracing::debug!(path, "get_usage");
+
+    check_auth_and_acl(state, auth, Path::new(path), "", AccessType::Read)?;
+    if !state
+        .storage
+        .filename(Path::new(path), CONFIG_TYPE, CONFIG_NAME)
+        .exists()
+    {
+        return Err(Error::new(StatusCode::NOT_FOUND, "repository not found"));
+    }
+    let quotas: Vec<_> = quotas(state, &auth.user, path)?
+        .into_iter()
+        .filter(|quota| quota.kind.headers().is_some())
+        .collect();
+    let usage = RepoUsage {
+        repo: path.to_string(),
+        used: state.usage.get(state.storage.as_ref(), path)?,
+        quotas: quotas
+            .iter()
+            .map(|quota| QuotaUsage {
+                kind: quota.kind,
+                limit: quota.limit,
+                used: quota.used,
+                remaining: quota.remaining(),
+            })
+            .collect(),
+    };
+    let mut res = Json(usage).into_response();
+    for quota in &quotas {
+        quota.add_headers(&mut res);
+    }
+    Ok(res)
+}
+
+const API_V1: &str = "application/vnd.x.restic.rest.v1";
+const API_V2: &str = "application/vnd.x.restic.rest.v2";
+
+fn list_files(
+    state: &State,
+    auth: &AuthFromRequest,
+    path: &str,
+    tpe: &str,
+    headers: &HeaderMap,
+) -> Result {
+    tracing::debug!(path, tpe, "list_files");
+
+    let repo = path;
+    let path = Path::new(path);
+    check_auth_and_acl(state, auth, path, tpe, AccessType::Read)?;
+
+    let files = state.storage.list(path, tpe)?;
+
+    let mut res = match headers.get(ACCEPT) {
+        Some(a) if a == API_V2 => {
+            let mut res = Json(files.as_slice()).into_response();
+            res.headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static(API_V2));
+            res
+        }
+        _ => {
+            let names = files.iter().map(|file| &file.name);
+            let mut res = Json(&IteratorAdapter::new(names)).into_response();
+            res.headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static(API_V1));
+            res
+        }
+    };
+    *res.status_mut() = StatusCode::OK;
+    state
+        .activity
+        .record(state.storage.as_ref(), repo, activity::Kind::Read);
+    // the quotas are only informational here, a listing never fails on them
+    match quotas(state, &auth.user, repo) {
+        Ok(quotas) => quotas.iter().for_each(|quota| quota.add_headers(&mut res)),
+        Err(err) => tracing::warn!(repo, "cannot add the quota headers: {err}"),
+    }
+    Ok(res)
+}
+
+fn length(state: &State, auth: &AuthFromRequest, path: &str, tpe: &str, name: &str) -> Result {
+    tracing::debug!(path, tpe, name, "length");
+
+    check_name(tpe, name)?;
+    let path = Path::new(path);
+    check_auth_and_acl(state, auth, path, tpe, AccessType::Read)?;
+
+    let file = state.storage.filename(path, tpe, name);
+    let len = std::fs::metadata(file)?.len();
+    let mut headers = cache_headers(&state.storage_config(), tpe, name).unwrap_or_default();
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+    Ok(headers.into_response())
+}
+
+async fn get_file(
+    state: &State,
+    auth: &AuthFromRequest,
+    path: &str,
+    tpe: &str,
+    name: &str,
+    headers: &HeaderMap,
+) -> Result {
+    tracing::debug!(path, tpe, name, "get_file");
+
+    check_name(tpe, name)?;
+    let repo = path;
+    let path = Path::new(path);
+    {
+        let (state, auth) = (state.clone(), auth.clone());
+        let (path, tpe) = (path.to_path_buf(), tpe.to_string());
+        blocking(move || check_auth_and_acl(&state, &auth, &path, &tpe, AccessType::Read)).await?;
+    }
+
+    let file = state.storage.open_file(path, tpe, name).await?;
+    record_activity(state, repo, activity::Kind::Read);
+    send_file(state, auth, repo, tpe, name, file, headers).await
+}
+
+// IMMUTABLE_TYPES are the types whose files are named by the hash of their
+// content, so a file of a name never changes
+const IMMUTABLE_TYPES: [&str; 4] = ["data", "keys", "snapshots", "index"];
+
+// cache_headers returns the ETag and Cache-Control headers for downloads of
+// the file, if it may be cached
+fn cache_headers(config: &StorageConfig, tpe: &str, name: &str) -> Option<HeaderMap> {
+    if config.cache_max_age == 0 || !IMMUTABLE_TYPES.contains(&tpe) {
+        return None;
+    }
+    let scope = if config.cache_public {
+        "public"
+    } else {
+        "private"
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(ETAG, HeaderValue::from_str(&format!("\"{name}\"")).ok()?);
+    headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_str(&format!(
+            "{scope}, max-age={}, immutable",
+            config.cache_max_age
+        ))
+        .ok()?,
+    );
+    Some(headers)
+}
+
+// not_modified returns whether the If-None-Match header of the request
+// contains etag
+fn not_modified(headers: &HeaderMap, etag: &HeaderValue) -> bool {
+    let Some(tags) = headers.get(IF_NONE_MATCH).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let etag = etag.to_str().unwrap_or_default();
+    tags.split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+// send_file answers with the content of file, or the range of it requested
+// in headers, limited to the download bandwidth of the user and repo
+pub(crate) async fn send_file(
+    state: &State,
+    auth: &AuthFromRequest,
+    repo: &str,
+    tpe: &str,
+    name: &str,
+    file: File,
+    headers: &HeaderMap,
+) -> Result {
+    let path = format!("{repo}/{tpe}/{name}");
+    let cache = cache_headers(&state.storage_config(), tpe, name);
+    if let Some(cache) = &cache {
+        if not_modified(headers, &cache[ETAG]) {
+            return Ok((StatusCode::NOT_MODIFIED, cache.clone()).into_response());
+        }
+    }
+    let total = file.metadata().await?.len();
+    let (mut start, mut len) = (0, total);
+    let mut res_headers = cache.unwrap_or_default();
+    res_headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    let throttles = throttles(state, &auth.user, repo, Direction::Download);
+
+    let status = match headers.get(RANGE) {
+        None => StatusCode::OK,
+        Some(r) => match HttpRange::parse_bytes(r.as_bytes(), total) {
+            Ok(range) if range.len() == 1 => {
+                (start, len) = (range[0].start, range[0].length);
+                res_headers.insert(CONTENT_RANGE, content_range(&range[0], total));
+                StatusCode::PARTIAL_CONTENT
+            }
+            Ok(ranges) if ranges.len() <= MAX_RANGES => {
+                let file = file.into_std().await;
+                let len = ranges.iter().map(|range| range.length).sum();
+                let progress = state.progress(Direction::Download, path, Some(len));
+                return multipart_ranges(file, &ranges, total, throttles, progress, res_headers);
+            }
+            Ok(_) => {
+                let message = format!("more than {MAX_RANGES} ranges requested");
+                return Ok(range_not_satisfiable(total, message));
+            }
+            Err(_) => return Ok(range_not_satisfiable(total, "invalid range")),
+        },
+    };
+
+    let progress = state.progress(Direction::Download, path, Some(len));
+    let stream = with_progress(file_stream(file, start, len).await?, progress);
+    let body = Body::from_stream(throttle(stream, throttles));
+    let len: usize = len
+        .try_into()
+        .map_err(|_| Error::new(StatusCode::INTERNAL_SERVER_ERROR, "file too large"))?;
+    Ok((status, res_headers, [(CONTENT_LENGTH, len)], body).into_response())
+}
+
+// file_stream returns len bytes of file from start on; large reads go
+// through io_uring if the feature is enabled
+async fn file_stream(
+    mut file: File,
+    start: u64,
+    len: u64,
+) -> io::Result<BoxStream<'static, io::Result<Bytes>>> {
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if len >= crate::uring::MIN_SIZE {
+        if let Some(ring) = crate::uring::Ring::get() {
+            return Ok(ring.read(file.into_std().await, start, len).boxed());
+        }
+    }
+    file.seek(io::SeekFrom::Start(start)).await?;
+    Ok(ReaderStream::new(file.take(len)).boxed())
+}
+
+// MAX_RANGES is the maximum number of ranges of one request, as overlapping
+// ranges could make a small request read a file many times
+const MAX_RANGES: usize = 64;
+
+// range_not_satisfiable answers a malformed or unsatisfiable range with the
+// size of the file, see RFC 9110, section 15.5.17
+fn range_not_satisfiable(total: u64, message: impl Into<String>) -> Response {
+    let message = message.into();
+    tracing::debug!(status = %StatusCode::RANGE_NOT_SATISFIABLE, message, "request failed");
+    (
+        StatusCode::RANGE_NOT_SATISFIABLE,
+        [(CONTENT_RANGE, format!("bytes */{total}"))],
+        message,
+    )
+        .into_response()
+}
+
+fn content_range(range: &HttpRange, total: u64) -> HeaderValue {
+    let end = range.start + range.length - 1;
+    let value = format!("bytes {}-{end}/{total}", range.start);
+    HeaderValue::from_str(&value).unwrap_or_else(|_| HeaderValue::from_static("bytes */0"))
+}
+
+// multipart_ranges answers a request for several ranges of file with a
+// multipart/byteranges body holding one part per range
+fn multipart_ranges(
+    file: std::fs::File,
+    ranges: &[HttpRange],
+    total: u64,
+    throttles: Vec<Throttle>,
+    progress: Progress,
+    mut headers: HeaderMap,
+) -> Result {
+    let boundary = format!("{:032x}", rand::random::<u128>());
+    let parts: Vec<_> = ranges
+        .iter()
+        .map(|range| {
+            let head = format!(
+                "\r\n--{boundary}\r\n{CONTENT_TYPE}: application/octet-stream\r\n\
+                 {CONTENT_RANGE}: {}\r\n\r\n",
+                content_range(range, total).to_str().unwrap_or_default()
+            );
+            (Bytes::from(head), range.start, range.length)
         })
-        .delete(|req: Request<State>| async move {
-            delete_file(req.param("path")?, CONFIG_TYPE, CONFIG_NAME, &req).await
-        });
+        .collect();
+    let tail = Bytes::from(format!("\r\n--{boundary}--\r\n"));
+    let len = parts
+        .iter()
+        .map(|(head, _, length)| head.len() as u64 + length)
+        .sum::<u64>()
+        + tail.len() as u64;
+
+    // the parts are read one after another, so they can share the file offset
+    let stream = futures_util::stream::iter(parts)
+        .then(move |(head, start, length)| {
+            let file = file.try_clone();
+            async move {
+                let mut file = File::from_std(file?);
+                file.seek(io::SeekFrom::Start(start)).await?;
+                let data = ReaderStream::new(file.take(length));
+                Ok::<_, io::Error>(futures_util::stream::once(async { Ok(head) }).chain(data))
+            }
+        })
+        .try_flatten()
+        .chain(futures_util::stream::once(async { Ok(tail) }));
+    let body = Body::from_stream(throttle(with_progress(stream, progress), throttles));
+    let content_type = format!("multipart/byteranges; boundary={boundary}");
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_str(&content_type)
+            .map_err(|err| Error::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?,
+    );
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+    Ok((StatusCode::PARTIAL_CONTENT, headers, body).into_response())
+}
+
+#[async_trait::async_trait]
+pub trait Finalizer {
+    async fn finalize(&mut self) -> io::Result<()>;
+}
+
+// CHECKSUM_HEADER may hold the SHA-256 hash of an upload, given as header or
+// as trailer announced in the Trailer header
+const CHECKSUM_HEADER: &str = "x-content-sha256";
+
+// save_body writes body to file limited to the bandwidth of throttles and
+// returns the number of bytes written; if the upload exceeds quota or its
+// SHA-256 hash differs from hash or the checksum sent by the client, the file
+// is removed and an error is returned
+async fn save_body(
+    progress: Progress,
+    body: Body,
+    file: impl AsyncWrite + Unpin + Finalizer,
+    quota: Option<&Quota>,
+    throttles: Vec<Throttle>,
+    hash: Option<&str>,
+    headers: &HeaderMap,
+) -> Result<u64> {
+    let checksum = headers.get(CHECKSUM_HEADER).cloned();
+    let announced = headers.get_all(TRAILER).iter().any(|value| {
+        value.to_str().is_ok_and(|value| {
+            value
+                .split(',')
+                .any(|name| name.trim().eq_ignore_ascii_case(CHECKSUM_HEADER))
+        })
+    });
+    let trailers = Arc::default();
+    let max_bytes = quota.map(Quota::remaining);
+    let received = Arc::new(AtomicU64::new(0));
+    let counter = Arc::clone(&received);
+    let path = progress.path().to_string();
+    // errors of the body mean the client went away or sent garbage; they are
+    // told apart from write errors by their kind
+    let stream = data_stream(body, Arc::clone(&trailers))
+        .inspect_ok(move |data| _ = counter.fetch_add(data.len() as u64, Ordering::Relaxed))
+        .map_err(|err| io::Error::new(io::ErrorKind::ConnectionAborted, err));
+    let stream = throttle(with_progress(stream, progress), throttles);
+    let mut reader = StreamReader::new(stream).take(max_bytes.map_or(u64::MAX, |max| max + 1));
+    // the hash is computed while writing, so the file isn't read again
+    let enabled = hash.is_some() || checksum.is_some() || announced;
+    let mut writer = HashingWriter::new(file, enabled);
+    let bytes_written = match tokio::io::copy(&mut reader, &mut writer).await {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::ConnectionAborted => {
+            // dropping the writer removes the partial file right away
+            drop(writer);
+            let bytes = received.load(Ordering::Relaxed);
+            tracing::warn!(path, bytes, "upload aborted by the client: {err}");
+            return Err(Error::new(StatusCode::BAD_REQUEST, "upload incomplete"));
+        }
+        Err(err) => return Err(err.into()),
+    };
+    if let Some(quota) = quota.filter(|quota| bytes_written > quota.remaining()) {
+        return Err(quota.exceeded(None));
+    }
+    let (mut file, digest) = writer.finish();
+    let digest = digest.map(|digest| to_hex(digest.as_ref()));
+    if let (Some(expected), Some(digest)) = (hash, &digest) {
+        if digest != expected {
+            return Err(Error::new(
+                StatusCode::BAD_REQUEST,
+                "file content doesn't match the hash in its name",
+            ));
+        }
+    }
+    let trailer = trailers
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take()
+        .and_then(|mut trailers: HeaderMap| trailers.remove(CHECKSUM_HEADER));
+    if announced && trailer.is_none() && checksum.is_none() {
+        return Err(Error::new(
+            StatusCode::BAD_REQUEST,
+            "announced checksum trailer is missing",
+        ));
+    }
+    if let (Some(expected), Some(digest)) = (trailer.or(checksum), &digest) {
+        if !expected.as_bytes().eq_ignore_ascii_case(digest.as_bytes()) {
+            return Err(Error::new(
+                StatusCode::BAD_REQUEST,
+                "file content doesn't match its checksum",
+            ));
+        }
+    }
+    tracing::debug!(bytes = bytes_written, "file written");
+    file.finalize().await?;
+    Ok(bytes_written)
+}
+
+// data_stream returns the data frames of body and keeps its trailers in
+// trailers
+fn data_stream(
+    mut body: Body,
+    trailers: Arc<Mutex<Option<HeaderMap>>>,
+) -> impl Stream<Item = std::result::Result<Bytes, axum::Error>> {
+    futures_util::stream::poll_fn(move |cx| loop {
+        let frame = match ready!(Pin::new(&mut body).poll_frame(cx)) {
+            Some(Ok(frame)) => frame,
+            Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+            None => return Poll::Ready(None),
+        };
+        match frame.into_data() {
+            Ok(data) => return Poll::Ready(Some(Ok(data))),
+            Err(frame) => {
+                if let Ok(map) = frame.into_trailers() {
+                    *trailers.lock().unwrap_or_else(PoisonError::into_inner) = Some(map);
+                }
+            }
+        }
+    })
+}
+
+// check_upload checks whether the user may upload the file and returns the
+// quotas applying to the upload; uploads which are known to exceed a quota
+// are rejected before reading them
+fn check_upload(
+    state: &State,
+    auth: &AuthFromRequest,
+    path: &str,
+    tpe: &str,
+    name: &str,
+    len: Option<u64>,
+) -> Result<Vec<Quota>> {
+    check_name(tpe, name)?;
+    let repo = path;
+    let path = Path::new(path);
+    check_auth_and_acl(state, auth, path, tpe, AccessType::Append)?;
+    if write_once(state, auth, repo, tpe) && state.storage.filename(path, tpe, name).exists() {
+        return Err(Error::new(
+            StatusCode::FORBIDDEN,
+            "key files can't be overwritten",
+        ));
+    }
+    let quotas = quotas(state, &auth.user, repo)?;
+    if let Some(len) = len {
+        if let Some(quota) = quotas.iter().find(|quota| len > quota.remaining()) {
+            return Err(quota.exceeded(Some(len)));
+        }
+    }
+    Ok(quotas)
+}
+
+async fn get_save_file(
+    state: &State,
+    path: &str,
+    tpe: &str,
+    name: &str,
+) -> Result<impl AsyncWrite + Unpin + Finalizer> {
+    tracing::debug!(path, tpe, name, "get_save_file");
+
+    let repo = path;
+    let path = Path::new(path);
+    match state.storage.create_file(path, tpe, name).await {
+        // like rest-server, create the type directory of a partially
+        // initialized repository on demand
+        Err(err)
+            if err.kind() == io::ErrorKind::NotFound
+                && tpe != CONFIG_TYPE
+                && state.storage_config().create_missing_dirs =>
+        {
+            let config_file = state.storage.filename(path, CONFIG_TYPE, CONFIG_NAME);
+            if !tokio::fs::try_exists(config_file).await? {
+                return Err(err.into());
+            }
+            let file = state.storage.filename(path, tpe, name);
+            if let Some(dir) = file.parent() {
+                tracing::info!(repo, dir = %dir.display(), "creating missing directory");
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            Ok(state.storage.create_file(path, tpe, name).await?)
+        }
+        res => Ok(res?),
+    }
+}
+
+fn delete_file(state: &State, auth: &AuthFromRequest, path: &str, tpe: &str, name: &str) -> Result {
+    check_name(tpe, name)?;
+    let repo = path;
+    let path = Path::new(path);
+    check_auth_and_acl(state, auth, path, tpe, AccessType::Modify)?;
+    if write_once(state, auth, repo, tpe) {
+        return Err(Error::new(
+            StatusCode::FORBIDDEN,
+            "key files can't be deleted",
+        ));
+    }
+    let metadata = std::fs::metadata(state.storage.filename(path, tpe, name))?;
+    if let Some(days) = state
+        .repo_config(repo)
+        .retention_days
+        .filter(|_| tpe != "locks")
+    {
+        let modified = metadata.modified()?;
+        if modified.elapsed().unwrap_or_default() < Duration::from_secs(days * 24 * 60 * 60) {
+            return Err(Error::new(
+                StatusCode::FORBIDDEN,
+                format!("file is retained for {days} days after it was written"),
+            ));
+        }
+    }
+    if let Some(immutability) = Immutability::read(state.storage.as_ref(), path) {
+        if immutability.protects(tpe, metadata.modified()?) {
+            return Err(Error::new(
+                StatusCode::FORBIDDEN,
+                format!(
+                    "file is immutable for {} days after it was written",
+                    immutability.days
+                ),
+            ));
+        }
+    }
+    // a client re-initializing the repository deletes the config file first
+    if tpe == CONFIG_TYPE {
+        let config_file = state.storage.filename(path, tpe, name);
+        if let Some(dir) = config_file.parent() {
+            versions::save(dir, state.storage_config().config_versions)?;
+        }
+    }
+    state.storage.remove_file(path, tpe, name)?;
+    state
+        .usage
+        .add(repo, -i64::try_from(metadata.len()).unwrap_or(i64::MAX));
+    state
+        .activity
+        .record(state.storage.as_ref(), repo, activity::Kind::Write);
+    notify(state, auth, "delete", repo, tpe, name);
+    Ok(StatusCode::OK.into_response())
+}
+
+// write_once returns whether existing files of type tpe may neither be
+// overwritten nor deleted by the user, which applies to key files if
+// write_once_keys is set; admins are exempt
+fn write_once(state: &State, auth: &AuthFromRequest, repo: &str, tpe: &str) -> bool {
+    tpe == "keys"
+        && state
+            .repo_config(repo)
+            .write_once_keys
+            .unwrap_or_else(|| state.storage_config().write_once_keys)
+        && !state.is_admin(&auth.user)
+}
+
+// delete_repository removes a whole repository, which only admins may do
+fn delete_repository(state: &State, auth: &AuthFromRequest, path: &str, d: Delete) -> Result {
+    tracing::debug!(path, force = d.force, "delete_repository");
+
+    if !state.is_admin(&auth.user) {
+        return Err(Error::new(StatusCode::FORBIDDEN, "admin access required"));
+    }
+    let repo = Path::new(path);
+    if !state
+        .storage
+        .filename(repo, CONFIG_TYPE, CONFIG_NAME)
+        .exists()
+    {
+        return Err(Error::new(StatusCode::NOT_FOUND, "repository not found"));
+    }
+    check_writable(state, path, &state.repo_config(path))?;
+    if Immutability::read(state.storage.as_ref(), repo).is_some() {
+        return Err(Error::new(StatusCode::FORBIDDEN, "repository is immutable"));
+    }
+    let prefix = format!("{path}/");
+    if let Some(nested) = state
+        .usage
+        .repos(state.storage.as_ref())
+        .into_iter()
+        .find(|other| other.starts_with(&prefix))
+    {
+        return Err(Error::new(
+            StatusCode::CONFLICT,
+            format!("repository contains repository {nested}"),
+        ));
+    }
+    // a lock means a client may still be using the repository
+    let locks = state.storage.read_dir(repo, "locks").count();
+    if locks > 0 && !d.force {
+        return Err(Error::new(
+            StatusCode::CONFLICT,
+            format!("repository has {locks} lock(s), use ?force=true to delete it anyway"),
+        ));
+    }
+    // large repositories need a second request with the token given by the first
+    let size = state.usage.get(state.storage.as_ref(), path)?;
+    if state
+        .storage_config()
+        .confirm_delete_size
+        .is_some_and(|max| size > max)
+    {
+        match d.token {
+            Some(token) if state.deletions.confirm(path, &token) => {}
+            Some(_) => {
+                return Err(Error::new(
+                    StatusCode::FORBIDDEN,
+                    "invalid or expired confirmation token",
+                ))
+            }
+            None => {
+                let confirmation = DeleteConfirmation {
+                    repo: path.to_string(),
+                    size,
+                    snapshots: state.storage.read_dir(repo, "snapshots").count(),
+                    token: state.deletions.issue(path),
+                    expires_in: TOKEN_VALIDITY.as_secs(),
+                };
+                return Ok((StatusCode::PRECONDITION_REQUIRED, Json(confirmation)).into_response());
+            }
+        }
+    }
+    state.storage.remove_repo(repo)?;
+    state.usage.remove_repo(path);
+    tracing::info!(user = auth.user, repo = path, "deleted repository");
+    notify(state, auth, "delete_repository", path, "", "");
+    state.mailer.send(
+        "delete_repository",
+        path,
+        &format!("repository {path} deleted"),
+        &format!("The repository {path} was deleted by user {:?}.", auth.user),
+    );
+    Ok(StatusCode::OK.into_response())
+}
+
+// request_path returns the path of a request, which is empty for the root route
+fn request_path(path: Option<extract::Path<String>>) -> String {
+    path.map(|extract::Path(path)| path).unwrap_or_default()
+}
+
+// path_parts decomposes the request path and resolves repository aliases
+fn path_parts(state: &State, path: Option<extract::Path<String>>) -> Result<PathParts> {
+    let mut parts = decompose_path(&request_path(path))?;
+    let aliases = state.aliases.read().unwrap_or_else(PoisonError::into_inner);
+    parts.repo = resolve_alias(&aliases, &parts.repo);
+    Ok(parts)
+}
+
+// resolve_alias replaces the longest alias of [aliases] which repo equals or
+// lies below by its target
+fn resolve_alias(aliases: &BTreeMap<String, String>, repo: &str) -> String {
+    let alias = aliases
+        .iter()
+        .filter_map(|(alias, target)| {
+            let rest = repo.strip_prefix(alias.as_str())?;
+            (rest.is_empty() || rest.starts_with('/')).then_some((alias.len(), target, rest))
+        })
+        .max_by_key(|(len, _, _)| *len);
+    match alias {
+        Some((_, target, rest)) => format!("{target}{rest}"),
+        None => repo.to_string(),
+    }
+}
+
+async fn head_path(
+    extract::State(state): extract::State<State>,
+    auth: AuthFromRequest,
+    path: Option<extract::Path<String>>,
+    headers: HeaderMap,
+) -> Result {
+    let parts = path_parts(&state, path)?;
+    if let Some(upstream) = upstream::remote(&state, &parts).await {
+        return upstream
+            .forward(&state, &auth, Method::HEAD, &parts, &headers)
+            .await;
+    }
+    match parts {
+        PathParts {
+            repo,
+            tpe: Some(tpe),
+            name: Some(name),
+        } => blocking(move || length(&state, &auth, &repo, &tpe, &name)).await,
+        _ => Err(Error::new(StatusCode::METHOD_NOT_ALLOWED, "not allowed")),
+    }
+}
+
+async fn get_path(
+    extract::State(state): extract::State<State>,
+    auth: AuthFromRequest,
+    path: Option<extract::Path<String>>,
+    headers: HeaderMap,
+) -> Result {
+    let parts = path_parts(&state, path)?;
+    if let Some(upstream) = upstream::remote(&state, &parts).await {
+        return upstream
+            .forward(&state, &auth, Method::GET, &parts, &headers)
+            .await;
+    }
+    match parts {
+        PathParts {
+            repo,
+            tpe: Some(tpe),
+            name: Some(name),
+        } => get_file(&state, &auth, &repo, &tpe, &name, &headers).await,
+        PathParts {
+            repo,
+            tpe: Some(tpe),
+            name: None,
+        } => blocking(move || list_files(&state, &auth, &repo, &tpe, &headers)).await,
+        // the REST protocol doesn't use GET on repositories, so this can't
+        // hide a repository named usage
+        PathParts {
+            repo, tpe: None, ..
+        } if repo == "usage" || repo.ends_with("/usage") => {
+            let repo = repo.strip_suffix("usage").unwrap_or_default();
+            let repo = repo.trim_end_matches('/').to_string();
+            blocking(move || get_usage(&state, &auth, &repo)).await
+        }
+        _ => Err(Error::new(StatusCode::METHOD_NOT_ALLOWED, "not allowed")),
+    }
+}
+
+async fn post_path(
+    extract::State(state): extract::State<State>,
+    auth: AuthFromRequest,
+    path: Option<extract::Path<String>>,
+    extract::Query(c): extract::Query<Create>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result {
+    match path_parts(&state, path)? {
+        PathParts {
+            repo, tpe: None, ..
+        } => {
+            let _lock = state.write_lock(&repo).await?;
+            blocking(move || create_repository(&state, &auth, &repo, c)).await
+        }
+        PathParts {
+            repo,
+            tpe: Some(tpe),
+            name: Some(name),
+        } => {
+            let mut quotas = {
+                let (state, auth) = (state.clone(), auth.clone());
+                let (repo, tpe, name) = (repo.clone(), tpe.clone(), name.clone());
+                let len = content_length(&headers);
+                blocking(move || check_upload(&state, &auth, &repo, &tpe, &name, len)).await
+            }
+            .inspect_err(|err| report_quota(&state, &repo, err))?;
+            // a retried upload of a file another request is writing gets its result
+            let upload = match IMMUTABLE_TYPES.contains(&tpe.as_str()) {
+                true => match state.uploads.start(&format!("{repo}/{tpe}/{name}")).await {
+                    Upload::First(upload) => Some(upload),
+                    Upload::Done => return Ok(StatusCode::OK.into_response()),
+                },
+                false => None,
+            };
+            let _lock = state.write_lock(&format!("{repo}/{tpe}/{name}")).await?;
+            let file = get_save_file(&state, &repo, &tpe, &name).await?;
+            let tightest = quotas.iter().min_by_key(|quota| quota.remaining());
+            let throttles = throttles(&state, &auth.user, &repo, Direction::Upload);
+            let hash = (tpe != CONFIG_TYPE && state.storage_config().verify_uploads)
+                .then_some(name.as_str());
+            let path = format!("{repo}/{tpe}/{name}");
+            let len = content_length(&headers);
+            let progress = state.progress(Direction::Upload, path, len);
+            let bytes = save_body(progress, body, file, tightest, throttles, hash, &headers)
+                .await
+                .inspect_err(|err| report_quota(&state, &repo, err))?;
+            if let Some(upload) = upload {
+                upload.succeeded();
+            }
+            state
+                .usage
+                .add(&repo, i64::try_from(bytes).unwrap_or(i64::MAX));
+            record_activity(&state, &repo, activity::Kind::Write);
+            // notify counts the snapshots after the first one is uploaded
+            blocking(move || {
+                notify(&state, &auth, "upload", &repo, &tpe, &name);
+                Ok(())
+            })
+            .await?;
+
+            let mut res = StatusCode::OK.into_response();
+            for quota in &mut quotas {
+                quota.used += bytes;
+                quota.add_headers(&mut res);
+            }
+            Ok(res)
+        }
+        _ => Err(Error::new(StatusCode::METHOD_NOT_ALLOWED, "not allowed")),
+    }
+}
+
+async fn delete_path(
+    extract::State(state): extract::State<State>,
+    auth: AuthFromRequest,
+    path: Option<extract::Path<String>>,
+    extract::Query(d): extract::Query<Delete>,
+) -> Result {
+    match path_parts(&state, path)? {
+        PathParts {
+            repo,
+            tpe: Some(tpe),
+            name: Some(name),
+        } => {
+            let _lock = state.write_lock(&format!("{repo}/{tpe}/{name}")).await?;
+            blocking(move || delete_file(&state, &auth, &repo, &tpe, &name)).await
+        }
+        PathParts {
+            repo, tpe: None, ..
+        } if !repo.is_empty() => {
+            let _lock = state.write_lock(&repo).await?;
+            blocking(move || delete_repository(&state, &auth, &repo, d)).await
+        }
+        _ => Err(Error::new(StatusCode::METHOD_NOT_ALLOWED, "not allowed")),
+    }
+}
+
+// acme_challenge answers HTTP-01 challenges of the ACME CA; no authentication is required
+async fn acme_challenge(
+    extract::State(state): extract::State<State>,
+    extract::Path(token): extract::Path<String>,
+) -> Result {
+    match state.challenges.get(&token) {
+        Some(key_authorization) => Ok(key_authorization.into_response()),
+        None => Err(Error::new(StatusCode::NOT_FOUND, "unknown challenge")),
+    }
+}
+
+// redirect_router answers all requests with 301 to HTTPS on port, but ACME
+// challenges, which the CA sends over plain HTTP
+fn redirect_router(state: State, port: u16) -> Router {
+    Router::new()
+        .route(
+            "/.well-known/acme-challenge/:token",
+            axum::routing::get(acme_challenge).with_state(state.clone()),
+        )
+        .fallback(move |req: Request| async move { redirect_to_https(&req, port) })
+        .layer(middleware::from_fn_with_state(state, add_security_headers))
+}
+
+fn redirect_to_https(req: &Request, port: u16) -> Response {
+    let Some(host) = request_host(req) else {
+        return Error::new(StatusCode::BAD_REQUEST, "no host given").into_response();
+    };
+    let host = match host.contains(':') {
+        true => format!("[{host}]"),
+        false => host,
+    };
+    let port = match port {
+        443 => String::new(),
+        port => format!(":{port}"),
+    };
+    let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+    (
+        StatusCode::MOVED_PERMANENTLY,
+        [(LOCATION, format!("https://{host}{port}{path}"))],
+    )
+        .into_response()
+}
+
+// router returns the axum router serving the REST API for the given state
+// router serves the API below server.base_path; ACME challenges are always
+// answered at the root, as the CA asks for them there
+pub fn router(state: State) -> Router {
+    let api = Router::new()
+        .merge(admin::router())
+        .merge(events::router())
+        .merge(info::router())
+        .merge(metrics::router())
+        .merge(profile::router())
+        .merge(runtime::router())
+        .merge(status::router())
+        .route(
+            "/",
+            post(post_path)
+                .get(get_path)
+                .head(head_path)
+                .delete(delete_path),
+        )
+        .route(
+            "/*path",
+            post(post_path)
+                .get(get_path)
+                .head(head_path)
+                .delete(delete_path),
+        )
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            limit_concurrency,
+        ))
+        .layer(middleware::from_fn_with_state(state.clone(), rate_limit_ip))
+        .layer(middleware::from_fn_with_state(state.clone(), geoip_filter))
+        .layer(middleware::from_fn_with_state(state.clone(), standby))
+        .layer(middleware::from_fn_with_state(state.clone(), maintenance))
+        .with_state(state.clone());
+    // the paths are rewritten before the API routes are matched
+    let api = Router::new()
+        .fallback_service(api)
+        .layer(middleware::from_fn_with_state(state.clone(), vhost));
+    let app = match state.config().server.base_path() {
+        "" => api,
+        base_path => Router::new()
+            .fallback_service(api)
+            .layer(middleware::from_fn_with_state(
+                base_path.to_string(),
+                strip_base_path,
+            )),
+    };
+    app.route(
+        "/.well-known/acme-challenge/:token",
+        axum::routing::get(acme_challenge).with_state(state.clone()),
+    )
+    .layer(middleware::from_fn_with_state(state.clone(), replica))
+    .layer(middleware::from_fn_with_state(state.clone(), client_ip))
+    .layer(middleware::from_fn_with_state(state, add_security_headers))
+}
+
+// security_headers returns the headers of [headers] to add to all responses
+fn security_headers(config: &Config) -> HeaderMap {
+    let headers = &config.headers;
+    let mut map = HeaderMap::new();
+    if headers.hsts_max_age > 0 && config.uses_tls() {
+        let mut hsts = format!("max-age={}", headers.hsts_max_age);
+        if headers.hsts_include_subdomains {
+            hsts.push_str("; includeSubDomains");
+        }
+        if let Ok(hsts) = HeaderValue::from_str(&hsts) {
+            map.insert(STRICT_TRANSPORT_SECURITY, hsts);
+        }
+    }
+    if headers.content_type_options {
+        map.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    }
+    if let Ok(policy) = HeaderValue::from_str(&headers.referrer_policy) {
+        if !policy.is_empty() {
+            map.insert(REFERRER_POLICY, policy);
+        }
+    }
+    map
+}
+
+// add_security_headers adds the security headers to responses which don't
+// set them already
+async fn add_security_headers(
+    extract::State(state): extract::State<State>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let mut res = next.run(req).await;
+    let headers = state
+        .security_headers
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    for (name, value) in &headers {
+        if !res.headers().contains_key(name) {
+            res.headers_mut().insert(name, value.clone());
+        }
+    }
+    res
+}
+
+// strip_base_path removes server.base_path from the request path before the
+// API routes are matched; requests outside of it get 404
+async fn strip_base_path(
+    extract::State(base_path): extract::State<String>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let path = match req.uri().path().strip_prefix(&base_path) {
+        Some("") => "/".to_string(),
+        Some(path) if path.starts_with('/') => path.to_string(),
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+    if let Err(err) = set_path(&mut req, &path) {
+        return err.into_response();
+    }
+    next.run(req).await
+}
+
+// vhost moves the repository paths of requests below the directory of their
+// host name given in [vhosts]. Admin and info endpoints are served for all
+// hosts, repository requests for hosts not listed get 421.
+async fn vhost(
+    extract::State(state): extract::State<State>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let path = req.uri().path();
+    if path.starts_with("/admin/") || path == "/api/info" {
+        return next.run(req).await;
+    }
+    let dir = {
+        let vhosts = state.vhosts.read().unwrap_or_else(PoisonError::into_inner);
+        if vhosts.is_empty() {
+            None
+        } else {
+            match request_host(&req).and_then(|host| vhosts.get(&host)) {
+                Some(dir) => Some(dir.clone()),
+                None => {
+                    return Error::new(StatusCode::MISDIRECTED_REQUEST, "unknown host")
+                        .into_response()
+                }
+            }
+        }
+    };
+    if let Some(dir) = dir {
+        let path = format!("/{dir}{path}");
+        if let Err(err) = set_path(&mut req, &path) {
+            return err.into_response();
+        }
+    }
+    next.run(req).await
+}
 
-    match tls {
-        false => app.listen(addr).await?,
+// request_host returns the host name the request is for, in lowercase and
+// without port
+fn request_host(req: &Request) -> Option<String> {
+    let host = match req.uri().host() {
+        Some(host) => host,
+        None => req.headers().get(HOST)?.to_str().ok()?,
+    };
+    let host = match host.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next()?,
+        None => host.split(':').next()?,
+    };
+    Some(host.trim_end_matches('.').to_lowercase())
+}
+
+// set_path replaces the path of the request URI, keeping the query
+fn set_path(req: &mut Request, path: &str) -> Result<()> {
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = path_and_query.parse().ok();
+    match Uri::from_parts(parts) {
+        Ok(uri) => {
+            *req.uri_mut() = uri;
+            Ok(())
+        }
+        Err(_) => Err(Error::new(StatusCode::BAD_REQUEST, "invalid path")),
+    }
+}
+
+// ListenAddr is an address to listen on. Addresses may be prefixed with
+// http:// or https:// to choose plaintext or TLS independent of --tls.
+#[derive(Debug, PartialEq)]
+pub struct ListenAddr {
+    pub addr: String,
+    pub tls: bool,
+}
+
+impl ListenAddr {
+    pub fn parse(addr: &str, tls: bool) -> Self {
+        match (addr.strip_prefix("http://"), addr.strip_prefix("https://")) {
+            (Some(addr), _) => Self {
+                addr: addr.to_string(),
+                tls: false,
+            },
+            (_, Some(addr)) => Self {
+                addr: addr.to_string(),
+                tls: true,
+            },
+            _ => Self {
+                addr: addr.to_string(),
+                tls,
+            },
+        }
+    }
+}
+
+// main serves the REST API. If the process was socket activated by systemd,
+// the passed sockets are used instead of the listen addresses.
+// On SIGINT or SIGTERM, no new connections are accepted and running requests
+// get server.shutdown_timeout seconds to finish before they are aborted.
+// On SIGHUP, the configuration is reloaded using load_config.
+pub async fn main(
+    state: State,
+    config: &Config,
+    load_config: impl Fn() -> anyhow::Result<Config> + Send + Sync + 'static,
+) -> anyhow::Result<()> {
+    // rustls is built with more than one crypto provider, so choose one explicitly
+    _ = rustls::crypto::ring::default_provider().install_default();
+    state.configure(config);
+    if config.vault.htpasswd_secret.is_some() {
+        let (auth, acl) = vault::load_access(config).await?;
+        state.set_access(auth, acl);
+    }
+    let app = router(state.clone());
+    let tls = config.tls.enable;
+
+    let activated = systemd::listeners().context("cannot use sockets passed by systemd")?;
+    let mut listeners = Vec::new();
+    if activated.is_empty() {
+        for addr in config
+            .server
+            .listen
+            .iter()
+            .map(|a| ListenAddr::parse(a, tls))
+        {
+            let listener = TcpListener::bind(&addr.addr)
+                .await
+                .with_context(|| format!("cannot listen on {}", addr.addr))?;
+            listeners.push((listener.into_std()?, addr.tls));
+        }
+    } else {
+        for socket in activated {
+            // sockets may choose TLS by FileDescriptorName=http or https
+            let tls = match socket.name.as_str() {
+                "http" => false,
+                "https" => true,
+                _ => tls,
+            };
+            listeners.push((socket.listener, tls));
+        }
+    }
+
+    let redirect = match &config.tls.redirect_listen {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("cannot listen on {addr}"))?;
+            let https = listeners.iter().find(|(_, tls)| *tls);
+            let port = match (config.tls.redirect_port, https) {
+                (Some(port), _) => port,
+                (None, Some((https, _))) => https.local_addr()?.port(),
+                (None, None) => bail!("[tls] redirect_listen is set, but no listener uses TLS"),
+            };
+            Some((listener.into_std()?, port))
+        }
+        None => None,
+    };
+
+    let tls_config = match listeners.iter().any(|(_, tls)| *tls) {
+        false => None,
+        true if config.acme.enable => {
+            let cache_dir = config.acme_cache_dir();
+            let tls_config = acme::tls_config(&config.acme, &config.tls, &cache_dir)?;
+            _ = tokio::spawn(acme::run(
+                config.acme.clone(),
+                config.tls.clone(),
+                cache_dir,
+                tls_config.clone(),
+                state.challenges.clone(),
+            ));
+            Some(tls_config)
+        }
+        true if config.vault.tls_secret.is_some() => {
+            let vault = Vault::new(&config.vault).context("[vault] address not given")?;
+            let tls_config = vault
+                .tls_config(&config.tls)
+                .await
+                .context("cannot load TLS certificate from Vault")?;
+            Some(tls_config.context("[vault] tls_secret not given")?)
+        }
         true => {
-            app.listen(
-                TlsListener::build()
-                    .addrs(addr)
-                    .cert(cert.expect("--cert not given"))
-                    .key(key.expect("--key not given")),
-            )
-            .await?
+            let cert = config
+                .tls
+                .cert
+                .clone()
+                .ok_or_else(|| anyhow!("--cert not given"))?;
+            let key = config
+                .tls
+                .key
+                .clone()
+                .ok_or_else(|| anyhow!("--key not given"))?;
+            let tls_config = RustlsConfig::from_config(tls::load(&config.tls, &cert, &key)?);
+            match config.tls.reload_interval {
+                0 => tls::check_expiry(&cert, config.tls.expiry_warning_days),
+                _ => {
+                    _ = tokio::spawn(tls::watch(
+                        tls_config.clone(),
+                        config.tls.clone(),
+                        cert,
+                        key,
+                    ))
+                }
+            }
+            Some(tls_config)
+        }
+    };
+
+    // everything needing root privileges is done, so drop them before serving
+    let ids = config.privileges()?;
+    privileges::drop(&ids).context("cannot switch user or group")?;
+    if ids != privileges::Ids::default() {
+        tracing::info!(uid = ?ids.uid, gid = ?ids.gid, "dropped privileges");
+    }
+
+    // advertise a TLS listener if there is one
+    let advertised = listeners.iter().find(|(_, tls)| *tls).or(listeners.first());
+    if let Some((listener, tls)) = advertised {
+        let fingerprint = match (&config.tls.cert, *tls && !config.acme.enable) {
+            (Some(cert), true) => tls::cert_fingerprint(cert)
+                .inspect_err(|err| tracing::warn!("cannot read certificate fingerprint: {err:#}"))
+                .ok(),
+            _ => None,
+        };
+        let service = discovery::Service {
+            addr: listener.local_addr()?,
+            tls: *tls,
+            fingerprint,
+            base_path: config.server.base_path().to_string(),
+        };
+        tokio::spawn(discovery::run(config.discovery.clone(), service));
+    }
+
+    let handle = Handle::new();
+    let timeout = Duration::from_secs(config.server.shutdown_timeout);
+    tokio::spawn(shutdown_on_signal(handle.clone(), timeout));
+    tokio::spawn(state.verifier.clone().schedule());
+    tokio::spawn(janitor::run(state.clone()));
+    tokio::spawn(mail::schedule(state.clone()));
+    tokio::spawn(mqtt::run(state.clone(), config.mqtt.clone()));
+    if config.ha.lease_file.is_some() {
+        state.set_leader(false);
+        tokio::spawn(ha::run(state.clone(), config.ha.clone()));
+    }
+    if vault::is_used(&config.vault) {
+        tokio::spawn(vault::run(state.clone(), tls_config.clone()));
+    }
+    if config.server.runtime_metrics {
+        tokio::spawn(state.watchdog.clone().run());
+    }
+    let redirect = redirect.map(|(listener, port)| {
+        serve(
+            redirect_router(state.clone(), port),
+            listener,
+            None,
+            handle.clone(),
+            &config.server,
+        )
+    });
+    let (reload_tx, reload_rx) = mpsc::channel(1);
+    _ = state.reloads.set(reload_tx);
+    tokio::spawn(reload_on_sighup(
+        state,
+        config.clone(),
+        tls_config.clone(),
+        load_config,
+        reload_rx,
+    ));
+
+    let servers = listeners.into_iter().map(|(listener, tls)| {
+        serve(
+            app.clone(),
+            listener,
+            tls.then(|| tls_config.clone()).flatten(),
+            handle.clone(),
+            &config.server,
+        )
+    });
+    let servers = futures_util::future::try_join_all(servers.chain(redirect));
+    if let Err(err) = systemd::notify("READY=1") {
+        tracing::warn!("cannot notify systemd: {err}");
+    }
+    servers.await?;
+    tracing::info!("server stopped");
+    Ok(())
+}
+
+// serve serves app on a single listener, using TLS if tls_config is given
+async fn serve(
+    app: Router,
+    listener: std::net::TcpListener,
+    tls_config: Option<RustlsConfig>,
+    handle: Handle,
+    server_config: &ServerConfig,
+) -> anyhow::Result<()> {
+    let addr = listener.local_addr()?;
+    let acceptor = TcpAcceptor::new(server_config);
+    match tls_config {
+        Some(config) => {
+            tracing::info!("listening on {} (TLS)", addr);
+            let mut server = axum_server::from_tcp(listener)
+                .acceptor(RustlsAcceptor::new(config).acceptor(acceptor));
+            configure_http(server.http_builder(), server_config)?;
+            server
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await?;
+        }
+        None => {
+            tracing::info!("listening on {}", addr);
+            let mut server = axum_server::from_tcp(listener).acceptor(acceptor);
+            configure_http(server.http_builder(), server_config)?;
+            server
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await?;
         }
     };
     Ok(())
 }
+
+// reload_on_sighup reloads the configuration on each SIGHUP and on each
+// request sent by State::reload. If loading fails, the old configuration is kept.
+#[cfg(unix)]
+async fn reload_on_sighup(
+    state: State,
+    mut config: Config,
+    tls_config: Option<RustlsConfig>,
+    load_config: impl Fn() -> anyhow::Result<Config>,
+    mut requests: mpsc::Receiver<ReloadRequest>,
+) {
+    let mut sighup = match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+        Ok(sighup) => Some(sighup),
+        Err(err) => {
+            tracing::warn!("cannot listen for SIGHUP: {err}");
+            None
+        }
+    };
+    let mut access = vault::load_access(&config).await.ok();
+    loop {
+        let reply = tokio::select! {
+            Some(()) = async { sighup.as_mut()?.recv().await } => {
+                tracing::info!("SIGHUP received, reloading configuration");
+                None
+            }
+            Some(reply) = requests.recv() => {
+                tracing::info!("reloading configuration on request");
+                Some(reply)
+            }
+            else => break,
+        };
+        let res = match reload(&state, &config, access.as_ref(), &tls_config, &load_config).await {
+            Ok((new_config, new_access)) => {
+                config = new_config;
+                access = Some(new_access);
+                Ok(())
+            }
+            Err(err) => {
+                tracing::error!("reload failed, keeping old configuration: {err:#}");
+                Err(err)
+            }
+        };
+        if let Some(reply) = reply {
+            _ = reply.send(res);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn reload_on_sighup(
+    _state: State,
+    _config: Config,
+    _tls_config: Option<RustlsConfig>,
+    _load_config: impl Fn() -> anyhow::Result<Config>,
+    _requests: mpsc::Receiver<ReloadRequest>,
+) {
+}
+
+// reload loads the new configuration and applies it. Everything is loaded
+// before anything is applied, so a broken file leaves the server unchanged.
+#[cfg(unix)]
+async fn reload(
+    state: &State,
+    old: &Config,
+    old_access: Option<&(Auth, Acl)>,
+    tls_config: &Option<RustlsConfig>,
+    load_config: &impl Fn() -> anyhow::Result<Config>,
+) -> anyhow::Result<(Config, (Auth, Acl))> {
+    let new = load_config()?;
+    for warning in new.check()? {
+        tracing::warn!("configuration: {warning}");
+    }
+    let (auth, acl) = vault::load_access(&new).await?;
+    tracing_subscriber::EnvFilter::try_new(&new.log.filter).context("invalid log filter")?;
+
+    // certificates obtained by ACME are reloaded by the ACME task
+    if let Some(tls_config) = tls_config.as_ref().filter(|_| !new.acme.enable) {
+        let from_vault = vault::reload_tls(&new, tls_config)
+            .await
+            .context("cannot load TLS certificate from Vault")?;
+        match (&new.tls.cert, &new.tls.key) {
+            _ if from_vault => {}
+            (Some(cert), Some(key)) => tls_config.reload_from_config(
+                tls::load(&new.tls, cert, key).context("cannot load TLS certificate")?,
+            ),
+            _ => anyhow::bail!("TLS is used, but cert or key is not given"),
+        }
+    }
+    logging::set_filter(&new.log.filter)?;
+    state.set_access(auth.clone(), acl.clone());
+    state.configure(&new);
+
+    let mut changes = old.diff(&new);
+    if let Some((old_auth, old_acl)) = old_access {
+        changes.extend(old_auth.diff(&auth));
+        changes.extend(old_acl.diff(&acl));
+    }
+    for change in &changes {
+        tracing::info!("reload: {change}");
+    }
+    if old.server.listen != new.server.listen
+        || old.server.user != new.server.user
+        || old.server.group != new.server.group
+        || old.server.worker_threads != new.server.worker_threads
+        || old.server.max_blocking_threads != new.server.max_blocking_threads
+        || old.server.thread_stack_size != new.server.thread_stack_size
+        || old.server.runtime_metrics != new.server.runtime_metrics
+        || old.server.base_path != new.server.base_path
+        || connection::changed(&old.server, &new.server)
+        || old.mqtt != new.mqtt
+        || old.ha != new.ha
+        || old.discovery != new.discovery
+        || old.storage.path != new.storage.path
+        || old.acme.enable != new.acme.enable
+        || old.acme.domains != new.acme.domains
+        || old.tls.redirect_listen != new.tls.redirect_listen
+        || old.tls.redirect_port != new.tls.redirect_port
+    {
+        tracing::warn!(
+            "changes of server.listen, server.user, server.group, the server thread settings, server.runtime_metrics, server.base_path, the server connection settings, storage.path, tls.redirect_listen, acme, mqtt, ha and discovery need a restart to take effect"
+        );
+    }
+    tracing::info!(changes = changes.len(), "configuration reloaded");
+    Ok((new, (auth, acl)))
+}
+
+// shutdown_on_signal waits for SIGINT or SIGTERM and then gracefully shuts
+// down all servers using handle. Uploads which are aborted after the timeout
+// remove their partially written files when dropped.
+async fn shutdown_on_signal(handle: Handle, timeout: Duration) {
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(err) => {
+                tracing::warn!("cannot listen for SIGTERM: {err}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        res = signal::ctrl_c() => {
+            if let Err(err) = res {
+                tracing::warn!("cannot listen for SIGINT: {err}");
+                return;
+            }
+        }
+        () = terminate => {}
+    }
+    let _ = systemd::notify("STOPPING=1");
+    tracing::info!(
+        connections = handle.connection_count(),
+        "shutting down, waiting up to {}s for running requests",
+        timeout.as_secs()
+    );
+    handle.graceful_shutdown(Some(timeout));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::WriteOrDeleteFile;
+    use crate::listing::FileEntry;
+    use crate::storage::{DiskSpace, LocalStorage};
+    use std::path::PathBuf;
+    use std::time::Instant;
+
+    // SLOW is how long the removal of a repository, reading its template
+    // and listing its snapshots take on SlowStorage
+    const SLOW: Duration = Duration::from_secs(1);
+
+    // SlowStorage simulates a slow disk
+    struct SlowStorage(LocalStorage);
+
+    #[async_trait::async_trait]
+    impl Storage for SlowStorage {
+        fn create_repo(&self, path: &Path, types: &[&str]) -> io::Result<()> {
+            self.0.create_repo(path, types)
+        }
+        fn read_dir(&self, path: &Path, tpe: &str) -> Box<dyn Iterator<Item = walkdir::DirEntry>> {
+            if tpe == "snapshots" {
+                std::thread::sleep(SLOW);
+            }
+            self.0.read_dir(path, tpe)
+        }
+        fn list(&self, path: &Path, tpe: &str) -> io::Result<Arc<Vec<FileEntry>>> {
+            self.0.list(path, tpe)
+        }
+        fn filename(&self, path: &Path, tpe: &str, name: &str) -> PathBuf {
+            self.0.filename(path, tpe, name)
+        }
+        async fn open_file(
+            &self,
+            path: &Path,
+            tpe: &str,
+            name: &str,
+        ) -> io::Result<tokio::fs::File> {
+            self.0.open_file(path, tpe, name).await
+        }
+        async fn create_file(
+            &self,
+            path: &Path,
+            tpe: &str,
+            name: &str,
+        ) -> io::Result<WriteOrDeleteFile> {
+            self.0.create_file(path, tpe, name).await
+        }
+        fn remove_file(&self, path: &Path, tpe: &str, name: &str) -> io::Result<()> {
+            self.0.remove_file(path, tpe, name)
+        }
+        fn remove_repo(&self, path: &Path) -> io::Result<()> {
+            std::thread::sleep(SLOW);
+            self.0.remove_repo(path)
+        }
+        fn size(&self, path: &Path) -> io::Result<u64> {
+            self.0.size(path)
+        }
+        fn repos(&self) -> Vec<String> {
+            self.0.repos()
+        }
+        fn disk_space(&self) -> io::Result<DiskSpace> {
+            self.0.disk_space()
+        }
+        fn marker(&self, path: &Path, name: &str) -> Option<String> {
+            if name == TEMPLATE_MARKER {
+                std::thread::sleep(SLOW);
+            }
+            self.0.marker(path, name)
+        }
+        fn set_marker(&self, path: &Path, name: &str, content: Option<&str>) -> io::Result<()> {
+            self.0.set_marker(path, name, content)
+        }
+    }
+
+    // the test runtime has a single thread, which must keep serving other
+    // tasks while a repository is removed
+    #[tokio::test]
+    async fn slow_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().join("repo");
+        std::fs::create_dir_all(repo.join("keys")).unwrap();
+        std::fs::write(repo.join("config"), "config").unwrap();
+        let state = State::new(
+            Auth::from_file(true, &PathBuf::new()).unwrap(),
+            Acl::default().with_admins(vec!["admin".to_string()]),
+            SlowStorage(LocalStorage::try_new(dir.path()).unwrap()),
+        );
+
+        // the template of the repository is read to find its concurrency
+        // limit, and the snapshots are counted after an upload
+        let mut config = test_config(dir.path());
+        config.templates = BTreeMap::from([("standard".to_string(), RepoConfig::default())]);
+        config.storage.verify_uploads = false;
+        state.configure(&config);
+        std::fs::create_dir_all(repo.join("snapshots")).unwrap();
+        let (addr, _server) = spawn(router(state.clone())).await;
+        let upload = reqwest::Client::new()
+            .post(format!("http://{addr}/repo/snapshots/{}", "a".repeat(64)))
+            .basic_auth("repo", Some(""))
+            .body("snapshot")
+            .send();
+        let upload = tokio::spawn(upload);
+        while !upload.is_finished() {
+            let start = Instant::now();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            assert!(start.elapsed() < SLOW, "the runtime was blocked");
+        }
+        assert_eq!(upload.await.unwrap().unwrap().status(), StatusCode::OK);
+
+        let delete = tokio::spawn(delete_path(
+            extract::State(state),
+            AuthFromRequest {
+                user: "admin".to_string(),
+            },
+            Some(extract::Path("repo".to_string())),
+            extract::Query(Delete::default()),
+        ));
+        let start = Instant::now();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(start.elapsed() < SLOW, "the runtime was blocked");
+        assert_eq!(delete.await.unwrap().unwrap().status(), StatusCode::OK);
+        assert!(!repo.exists());
+    }
+
+    // a configuration with problems found by validate isn't applied
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reload_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.storage.path = dir.path().to_path_buf();
+        config.auth.disable = true;
+        config.limits.requests_per_second = Some(5.0);
+        let state = State::from_config(&config).unwrap();
+
+        let mut new = config.clone();
+        new.limits.requests_per_second = Some(0.0);
+        let Err(err) = reload(&state, &config, None, &None, &|| Ok(new.clone())).await else {
+            panic!("an invalid configuration was applied");
+        };
+        assert!(err.to_string().contains("requests_per_second"), "{err:#}");
+        assert_eq!(state.limits().requests_per_second, Some(5.0));
+    }
+
+    #[tokio::test]
+    async fn upload_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        // SHA-256 of "hello"
+        let hash = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let file = dir.path().join(hash);
+        let save = |content: &'static str, hash| {
+            let file = file.clone();
+            async move {
+                let writer = WriteOrDeleteFile::new(file).await.unwrap();
+                let headers = HeaderMap::new();
+                let progress = Progress::new(
+                    &LogConfig::default(),
+                    Direction::Upload,
+                    "file".to_string(),
+                    None,
+                );
+                save_body(
+                    progress,
+                    Body::from(content),
+                    writer,
+                    None,
+                    Vec::new(),
+                    hash,
+                    &headers,
+                )
+                .await
+            }
+        };
+        assert_eq!(save("hello", Some(hash)).await.unwrap(), 5);
+        std::fs::remove_file(&file).unwrap();
+        let err = save("bit rot", Some(hash)).await.unwrap_err();
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+        // the rejected file is removed in the background
+        while file.exists() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(save("bit rot", None).await.unwrap(), 7);
+        std::fs::remove_file(&file).unwrap();
+
+        // a client going away mid-upload
+        let body = Body::from_stream(futures_util::stream::iter([
+            Ok(Bytes::from("hel")),
+            Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+        ]));
+        let writer = WriteOrDeleteFile::new(file.clone()).await.unwrap();
+        let headers = HeaderMap::new();
+        let progress = Progress::new(
+            &LogConfig::default(),
+            Direction::Upload,
+            "file".to_string(),
+            None,
+        );
+        let err = save_body(progress, body, writer, None, Vec::new(), None, &headers).await;
+        assert_eq!(err.unwrap_err().status(), StatusCode::BAD_REQUEST);
+        while file.exists() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn snapshot_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshots = dir.path().join("repo/snapshots");
+        std::fs::create_dir_all(&snapshots).unwrap();
+        let state = State::new(
+            Auth::from_file(true, &PathBuf::new()).unwrap(),
+            Acl::default(),
+            LocalStorage::try_new(dir.path()).unwrap(),
+        );
+        let auth = AuthFromRequest {
+            user: "alice".to_string(),
+        };
+        let events = |state: &State| {
+            let events: Vec<_> = state.events().recent().iter().map(|e| e.event).collect();
+            events
+        };
+
+        std::fs::write(snapshots.join("01"), "").unwrap();
+        notify(&state, &auth, "upload", "repo", "snapshots", "01");
+        // newest first
+        assert_eq!(events(&state), ["first_snapshot", "upload"]);
+        std::fs::write(snapshots.join("02"), "").unwrap();
+        notify(&state, &auth, "upload", "repo", "snapshots", "02");
+        notify(&state, &auth, "upload", "repo", "index", "03");
+        assert_eq!(events(&state).len(), 4);
+        notify(&state, &auth, "delete", "repo", "snapshots", "01");
+        let recent = state.events().recent();
+        let deleted = &recent[0];
+        assert_eq!(
+            (deleted.event, deleted.name.as_str()),
+            ("snapshot_deleted", "01")
+        );
+    }
+
+    // create_protocol checks the responses restic expects from
+    // POST /<repo>/?create=true, see
+    // https://restic.readthedocs.io/en/stable/100_references.html#rest-backend
+    #[tokio::test]
+    async fn create_protocol() {
+        let dir = tempfile::tempdir().unwrap();
+        let (addr, server) = test_server(dir.path(), |_| {}).await;
+        let url = format!("http://{addr}/repo");
+        let client = reqwest::Client::new();
+
+        // 200 with an empty body, also if the repository exists
+        for _ in 0..2 {
+            let res = client.post(format!("{url}/?create=true")).send().await;
+            let res = res.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.bytes().await.unwrap(), "");
+        }
+        for tpe in TYPES {
+            assert!(dir.path().join("repo").join(tpe).is_dir());
+        }
+        // POST without create=true doesn't create anything
+        let res = client
+            .post(format!("http://{addr}/other/"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(!dir.path().join("other").exists());
+
+        // restic saves the config after creating the repository
+        let saved = client.post(format!("{url}/config")).body("config").send();
+        assert_eq!(saved.await.unwrap().status(), StatusCode::OK);
+        let res = client.post(format!("{url}/?create=true")).send().await;
+        assert_eq!(res.unwrap().status(), StatusCode::OK);
+        let nested = client.post(format!("{url}/sub/?create=true")).send();
+        assert_eq!(nested.await.unwrap().status(), StatusCode::CONFLICT);
+        server.abort();
+    }
+
+    // file_server serves crate::router for a config file with private
+    // repositories, the given ACL file and the users "admin" and "bob" with
+    // password "secret", of which "admin" is an admin
+    async fn file_server(
+        dir: &Path,
+        acl_file: &str,
+    ) -> (SocketAddr, tokio::task::JoinHandle<io::Result<()>>) {
+        let data = dir.join("data");
+        let htpasswd = dir.join(".htpasswd");
+        let acl = dir.join("acl.toml");
+        let config_file = dir.join("rustic_server.toml");
+        std::fs::create_dir(&data).unwrap();
+        // {SHA} of "secret"
+        let sha = "{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=";
+        std::fs::write(&htpasswd, format!("admin:{sha}\nbob:{sha}\n")).unwrap();
+        std::fs::write(&acl, acl_file).unwrap();
+        std::fs::write(
+            &config_file,
+            format!(
+                "[storage]\npath = {data:?}\n[auth]\nhtpasswd = {htpasswd:?}\n[acl]\npath = {acl:?}\nprivate_repo = true\nadmins = [\"admin\"]\n"
+            ),
+        )
+        .unwrap();
+        spawn(crate::router(&Config::from_file(&config_file).unwrap()).unwrap()).await
+    }
+
+    // changes of tenants apply at once without the reload task of main
+    #[tokio::test]
+    async fn embedded_tenants() {
+        let dir = tempfile::tempdir().unwrap();
+        let (addr, server) = file_server(dir.path(), "").await;
+        let client = reqwest::Client::new();
+
+        let created = client
+            .post(format!("http://{addr}/admin/tenants"))
+            .basic_auth("admin", Some("secret"))
+            .json(&serde_json::json!({"user": "alice", "password": "pw"}))
+            .send();
+        assert_eq!(created.await.unwrap().status(), StatusCode::CREATED);
+        let repo = |user: &str| {
+            client
+                .post(format!("http://{addr}/alice/?create=true"))
+                .basic_auth(user, Some("pw"))
+                .send()
+        };
+        assert_eq!(repo("alice").await.unwrap().status(), StatusCode::OK);
+
+        let deleted = client
+            .delete(format!("http://{addr}/admin/tenants/alice"))
+            .basic_auth("admin", Some("secret"))
+            .send();
+        assert_eq!(deleted.await.unwrap().status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            repo("alice").await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+        server.abort();
+    }
+
+    // the ACLs of a renamed repository apply at once without the reload task
+    // of main
+    #[tokio::test]
+    async fn embedded_rename() {
+        let dir = tempfile::tempdir().unwrap();
+        let (addr, server) = file_server(dir.path(), "[old]\nbob = \"Read\"\n").await;
+        let old = dir.path().join("data/old");
+        std::fs::create_dir_all(old.join("keys")).unwrap();
+        std::fs::write(old.join("config"), "config").unwrap();
+        let client = reqwest::Client::new();
+
+        let renamed = client
+            .post(format!("http://{addr}/admin/repos/old/rename"))
+            .basic_auth("admin", Some("secret"))
+            .body("new")
+            .send();
+        assert_eq!(renamed.await.unwrap().status(), StatusCode::NO_CONTENT);
+        let config = client
+            .get(format!("http://{addr}/new/config"))
+            .basic_auth("bob", Some("secret"))
+            .send();
+        assert_eq!(config.await.unwrap().status(), StatusCode::OK);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let (addr, server) = test_server(dir.path(), |_| {}).await;
+        // SHA-256 of "hello"
+        let hash = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+        let client = reqwest::Client::new();
+        let created = client
+            .post(format!("http://{addr}/repo/?create=true"))
+            .send();
+        assert_eq!(created.await.unwrap().status(), StatusCode::OK);
+        let file = dir.path().join("repo/data/2c").join(hash);
+        let upload = |checksum: String| {
+            client
+                .post(format!("http://{addr}/repo/data/{hash}"))
+                .header(CHECKSUM_HEADER, checksum)
+                .body("hello")
+                .send()
+        };
+        let res = upload(hash.to_uppercase()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        std::fs::remove_file(&file).unwrap();
+        let bad = upload("0".repeat(64)).await.unwrap();
+        assert_eq!(bad.status(), StatusCode::BAD_REQUEST);
+
+        // reqwest can't send trailers
+        let trailer = |checksum: &str| {
+            let request = format!(
+                "POST /repo/data/{hash} HTTP/1.1\r\nHost: localhost\r\n\
+                 Transfer-Encoding: chunked\r\nTrailer: {CHECKSUM_HEADER}\r\n\
+                 Connection: close\r\n\r\n5\r\nhello\r\n0\r\n\
+                 {CHECKSUM_HEADER}: {checksum}\r\n\r\n"
+            );
+            async move {
+                let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+                tokio::io::AsyncWriteExt::write_all(&mut stream, request.as_bytes())
+                    .await
+                    .unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).await.unwrap();
+                response
+            }
+        };
+        assert!(trailer(hash).await.starts_with("HTTP/1.1 200"));
+        std::fs::remove_file(&file).unwrap();
+        let bad = trailer(&"0".repeat(64)).await;
+        assert!(bad.starts_with("HTTP/1.1 400"), "{bad}");
+        server.abort();
+    }
+
+    // hold_upload starts an upload to path whose body is never completed, so
+    // the request keeps its concurrency permits
+    async fn hold_upload(addr: SocketAddr, host: &str, path: &str) -> tokio::net::TcpStream {
+        let request = format!(
+            "POST {path}/data/{} HTTP/1.1\r\nHost: {host}\r\nContent-Length: 5\r\n\r\nhe",
+            "a".repeat(64)
+        );
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        tokio::io::AsyncWriteExt::write_all(&mut stream, request.as_bytes())
+            .await
+            .unwrap();
+        stream
+    }
+
+    // limited returns whether requests for the config file at url get 503
+    // within a second
+    async fn limited(url: &str, host: &str) -> bool {
+        let client = reqwest::Client::new();
+        for _ in 0..100 {
+            let res = client
+                .get(format!("{url}/config"))
+                .header(HOST, host)
+                .send();
+            if res.await.unwrap().status() == StatusCode::SERVICE_UNAVAILABLE {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    // the limit of a repository applies to the repository the handlers use,
+    // below the base path, in the directory of the host and URL-decoded
+    #[tokio::test]
+    async fn repo_concurrency() {
+        let dir = tempfile::tempdir().unwrap();
+        let (addr, server) = test_server(dir.path(), |config| {
+            config.server.base_path = Some("/backup".to_string());
+            config.vhosts =
+                BTreeMap::from([("backup.example.com".to_string(), "hosts".to_string())]);
+            let limit = RepoConfig {
+                max_requests: Some(1),
+                ..RepoConfig::default()
+            };
+            config.repos = BTreeMap::from([("hosts/my repo".to_string(), limit)]);
+        })
+        .await;
+        let (host, url) = (
+            "backup.example.com",
+            format!("http://{addr}/backup/my%20repo"),
+        );
+        let client = reqwest::Client::new();
+        let res = client
+            .post(format!("{url}?create=true"))
+            .header(HOST, host)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(dir.path().join("hosts/my repo/data").is_dir());
+
+        assert!(!limited(&url, host).await);
+        let held = hold_upload(addr, host, "/backup/my%20repo").await;
+        assert!(limited(&url, host).await);
+        drop(held);
+        server.abort();
+    }
+
+    // a repository reached by an alias shares the limit of its target
+    #[tokio::test]
+    async fn alias_concurrency() {
+        let dir = tempfile::tempdir().unwrap();
+        let (addr, server) = test_server(dir.path(), |config| {
+            config.aliases = BTreeMap::from([("old".to_string(), "new".to_string())]);
+            let limit = RepoConfig {
+                max_requests: Some(1),
+                ..RepoConfig::default()
+            };
+            config.repos = BTreeMap::from([("new".to_string(), limit)]);
+        })
+        .await;
+        let res = reqwest::Client::new()
+            .post(format!("http://{addr}/new?create=true"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let (host, url) = ("localhost", format!("http://{addr}/new"));
+        assert!(!limited(&url, host).await);
+        let held = hold_upload(addr, host, "/old").await;
+        assert!(limited(&url, host).await);
+        drop(held);
+        server.abort();
+    }
+
+    #[test]
+    fn io_errors() {
+        let status = |kind| Error::from(io::Error::from(kind)).status();
+        assert_eq!(status(io::ErrorKind::NotFound), StatusCode::NOT_FOUND);
+        assert_eq!(
+            status(io::ErrorKind::StorageFull),
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+        assert_eq!(
+            status(io::ErrorKind::ReadOnlyFilesystem),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            status(io::ErrorKind::PermissionDenied),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[test]
+    fn append_only_marker() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("repo/keys")).unwrap();
+        let state = State::new(
+            Auth::from_file(true, &PathBuf::new()).unwrap(),
+            Acl::default(),
+            LocalStorage::try_new(dir.path()).unwrap(),
+        );
+        let repo = Path::new("repo");
+        let check = |access| check_repo_config(&state, "repo", "data", access);
+        state
+            .storage
+            .set_marker(repo, APPEND_ONLY_MARKER, Some("compromised"))
+            .unwrap();
+        let err = check(AccessType::Modify).unwrap_err();
+        assert_eq!(err.status(), StatusCode::FORBIDDEN);
+        assert!(err.to_string().contains("append-only: compromised"));
+        assert!(check(AccessType::Append).is_ok());
+        state
+            .storage
+            .set_marker(repo, APPEND_ONLY_MARKER, None)
+            .unwrap();
+        assert!(check(AccessType::Modify).is_ok());
+    }
+
+    #[tokio::test]
+    async fn auth_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = State::new(
+            Auth::from_htpasswd(false, "alice:{SHA}GpHWL3ymc5liWkNopqtdSjuqYHM=\n"),
+            Acl::default(),
+            LocalStorage::try_new(dir.path()).unwrap(),
+        );
+        let (addr, server) = spawn(router(state.clone())).await;
+        let client = reqwest::Client::new();
+        let get = |user: &str, password: &str| {
+            client
+                .get(format!("http://{addr}/alice/config"))
+                .basic_auth(user, Some(password))
+                .send()
+        };
+        let wrong = get("alice", "secret").await.unwrap();
+        assert_eq!(wrong.status(), StatusCode::UNAUTHORIZED);
+        _ = get("mallory", "secret").await.unwrap();
+        _ = get("alice", "pw").await.unwrap();
+        _ = client.get(format!("http://{addr}/")).send().await.unwrap();
+        let metrics = state.metrics().render();
+        for reason in ["wrong_password", "unknown_user", "missing_credentials"] {
+            let line = format!("rustic_server_auth_failures_total{{reason=\"{reason}\"}} 1\n");
+            assert!(metrics.contains(&line), "{metrics}");
+        }
+        server.abort();
+    }
+
+    #[test]
+    fn read_only_marker() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("repo/keys")).unwrap();
+        let state = State::new(
+            Auth::from_file(true, &PathBuf::new()).unwrap(),
+            Acl::default(),
+            LocalStorage::try_new(dir.path()).unwrap(),
+        );
+        let check = |access| check_repo_config(&state, "repo", "data", access);
+        std::fs::write(dir.path().join("repo").join(READ_ONLY_MARKER), "").unwrap();
+        let err = check(AccessType::Append).unwrap_err();
+        assert_eq!(err.status(), StatusCode::FORBIDDEN);
+        assert_eq!(err.to_string(), "repository is read-only");
+        std::fs::write(
+            dir.path().join("repo").join(READ_ONLY_MARKER),
+            "migration\n",
+        )
+        .unwrap();
+        let err = check(AccessType::Modify).unwrap_err();
+        assert!(err.to_string().contains("read-only: migration"));
+        assert!(check(AccessType::Read).is_ok());
+        std::fs::remove_file(dir.path().join("repo").join(READ_ONLY_MARKER)).unwrap();
+        assert!(check(AccessType::Append).is_ok());
+    }
+
+    #[test]
+    fn templates() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path());
+        config.acl.admins = vec!["admin".to_string()];
+        let standard = RepoConfig {
+            quota: Some(1000),
+            ..RepoConfig::default()
+        };
+        config
+            .templates
+            .insert("standard".to_string(), standard.clone());
+        config
+            .templates
+            .insert("open".to_string(), RepoConfig::default());
+        config.users.insert(
+            "alice".to_string(),
+            UserConfig {
+                template: Some("standard".to_string()),
+                ..UserConfig::default()
+            },
+        );
+        let state = State::from_config(&config).unwrap();
+        let create = |user: &str, repo: &str, template: Option<&str>| {
+            let auth = AuthFromRequest {
+                user: user.to_string(),
+            };
+            let c = Create {
+                create: true,
+                template: template.map(str::to_string),
+            };
+            create_repository(&state, &auth, repo, c).map(|res| res.status())
+        };
+
+        assert_eq!(create("alice", "a", None).unwrap(), StatusCode::OK);
+        assert_eq!(state.repo_config("a"), standard);
+        let err = create("alice", "b", Some("open")).unwrap_err();
+        assert_eq!(err.status(), StatusCode::FORBIDDEN);
+        let err = create("admin", "b", Some("missing")).unwrap_err();
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            create("admin", "b", Some("standard")).unwrap(),
+            StatusCode::OK
+        );
+        assert_eq!(create("admin", "c", None).unwrap(), StatusCode::OK);
+        assert_eq!(state.repo_config("c"), RepoConfig::default());
+
+        // [repos] sections take precedence
+        state.set_repo_configs(BTreeMap::from([("b".to_string(), RepoConfig::default())]));
+        assert_eq!(state.repo_config("b"), RepoConfig::default());
+        // changed templates apply to existing repositories
+        state.set_templates(BTreeMap::new());
+        assert_eq!(state.repo_config("a"), RepoConfig::default());
+    }
+
+    #[test]
+    fn caching() {
+        let mut config = StorageConfig::default();
+        assert!(cache_headers(&config, "locks", "0123").is_none());
+        assert!(cache_headers(&config, CONFIG_TYPE, CONFIG_NAME).is_none());
+        let cache = cache_headers(&config, "data", "0123").unwrap();
+        assert_eq!(cache[ETAG], "\"0123\"");
+        assert_eq!(cache[CACHE_CONTROL], "private, max-age=31536000, immutable");
+        config.cache_public = true;
+        let cache = cache_headers(&config, "index", "0123").unwrap();
+        assert!(cache[CACHE_CONTROL].to_str().unwrap().starts_with("public"));
+        config.cache_max_age = 0;
+        assert!(cache_headers(&config, "data", "0123").is_none());
+
+        let request =
+            |tags: &'static str| HeaderMap::from_iter([(IF_NONE_MATCH, tags.parse().unwrap())]);
+        assert!(!not_modified(&HeaderMap::new(), &cache[ETAG]));
+        assert!(not_modified(&request("\"abc\", W/\"0123\""), &cache[ETAG]));
+        assert!(not_modified(&request("*"), &cache[ETAG]));
+        assert!(!not_modified(&request("\"abc\""), &cache[ETAG]));
+    }
+
+    #[tokio::test]
+    async fn ranges() {
+        let dir = tempfile::tempdir().unwrap();
+        let (addr, server) = test_server(dir.path(), |_| {}).await;
+        let client = reqwest::Client::new();
+        let created = client
+            .post(format!("http://{addr}/repo/?create=true"))
+            .send();
+        assert_eq!(created.await.unwrap().status(), StatusCode::OK);
+        let uploaded = client
+            .post(format!("http://{addr}/repo/config"))
+            .body("0123456789");
+        assert_eq!(uploaded.send().await.unwrap().status(), StatusCode::OK);
+        let get = |range: &str| {
+            client
+                .get(format!("http://{addr}/repo/config"))
+                .header(RANGE, range)
+                .send()
+        };
+
+        let single = get("bytes=2-4").await.unwrap();
+        assert_eq!(single.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(single.headers()[ACCEPT_RANGES], "bytes");
+        assert_eq!(single.headers()[CONTENT_RANGE], "bytes 2-4/10");
+        assert_eq!(single.text().await.unwrap(), "234");
+
+        let multi = get("bytes=0-1,8-").await.unwrap();
+        assert_eq!(multi.status(), StatusCode::PARTIAL_CONTENT);
+        let content_type = multi.headers()[CONTENT_TYPE].to_str().unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap()
+            .to_string();
+        let len: usize = multi.headers()[CONTENT_LENGTH]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let body = multi.text().await.unwrap();
+        assert_eq!(body.len(), len);
+        assert_eq!(
+            body,
+            format!(
+                "\r\n--{boundary}\r\ncontent-type: application/octet-stream\r\n\
+                 content-range: bytes 0-1/10\r\n\r\n01\
+                 \r\n--{boundary}\r\ncontent-type: application/octet-stream\r\n\
+                 content-range: bytes 8-9/10\r\n\r\n89\
+                 \r\n--{boundary}--\r\n"
+            )
+        );
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn unsatisfiable_ranges() {
+        let dir = tempfile::tempdir().unwrap();
+        let (addr, server) = test_server(dir.path(), |_| {}).await;
+        let client = reqwest::Client::new();
+        let created = client
+            .post(format!("http://{addr}/repo/?create=true"))
+            .send();
+        assert_eq!(created.await.unwrap().status(), StatusCode::OK);
+        let uploaded = client
+            .post(format!("http://{addr}/repo/config"))
+            .body("0123456789");
+        assert_eq!(uploaded.send().await.unwrap().status(), StatusCode::OK);
+
+        let ranges = [
+            "bytes=20-30".to_string(),
+            "bytes=5-2".to_string(),
+            "lines=1-2".to_string(),
+            format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(",")),
+        ];
+        for range in ranges {
+            let res = client
+                .get(format!("http://{addr}/repo/config"))
+                .header(RANGE, &range)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE, "{range}");
+            assert_eq!(res.headers()[CONTENT_RANGE], "bytes */10", "{range}");
+        }
+        server.abort();
+    }
+
+    // the library router can be nested below a prefix of another application
+    #[tokio::test]
+    async fn embedded() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        let app = Router::new().nest("/backup", crate::router(&config).unwrap());
+        let (addr, server) = spawn(app).await;
+        let url = format!("http://{addr}/backup");
+
+        let client = reqwest::Client::new();
+        let created = client.post(format!("{url}/repo/?create=true")).send();
+        assert_eq!(created.await.unwrap().status(), StatusCode::OK);
+        assert!(dir.path().join("repo/data/00").is_dir());
+        let uploaded = client.post(format!("{url}/repo/config")).body("config");
+        assert_eq!(uploaded.send().await.unwrap().status(), StatusCode::OK);
+        let config = client.get(format!("{url}/repo/config")).send();
+        assert_eq!(config.await.unwrap().text().await.unwrap(), "config");
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn base_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let (addr, server) = test_server(dir.path(), |config| {
+            config.server.base_path = Some("/backup/".to_string());
+        })
+        .await;
+
+        let client = reqwest::Client::new();
+        let created = client.post(format!("http://{addr}/backup/?create=true"));
+        assert_eq!(created.send().await.unwrap().status(), StatusCode::OK);
+        assert!(dir.path().join("data/00").is_dir());
+        let uploaded = client.post(format!("http://{addr}/backup/config"));
+        let uploaded = uploaded.body("config").send().await.unwrap();
+        assert_eq!(uploaded.status(), StatusCode::OK);
+        assert!(dir.path().join("config").is_file());
+        let outside = client.get(format!("http://{addr}/config")).send();
+        assert_eq!(outside.await.unwrap().status(), StatusCode::NOT_FOUND);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn vhosts() {
+        let dir = tempfile::tempdir().unwrap();
+        let (addr, server) = test_server(dir.path(), |config| {
+            config.vhosts = BTreeMap::from([("a.example".to_string(), "a".to_string())]);
+        })
+        .await;
+
+        let client = reqwest::Client::new();
+        let created = client.post(format!("http://{addr}/repo/?create=true"));
+        let created = created.header(HOST, "A.example:8000").send().await.unwrap();
+        assert_eq!(created.status(), StatusCode::OK);
+        assert!(dir.path().join("a/repo/data/00").is_dir());
+        let other = client.get(format!("http://{addr}/repo/config")).send();
+        assert_eq!(
+            other.await.unwrap().status(),
+            StatusCode::MISDIRECTED_REQUEST
+        );
+        server.abort();
+    }
+
+    #[test]
+    fn aliases() {
+        let aliases = BTreeMap::from([
+            ("old".to_string(), "new".to_string()),
+            ("old/b".to_string(), "b".to_string()),
+        ]);
+        assert_eq!(resolve_alias(&aliases, "old"), "new");
+        assert_eq!(resolve_alias(&aliases, "old/a"), "new/a");
+        assert_eq!(resolve_alias(&aliases, "old/b/c"), "b/c");
+        assert_eq!(resolve_alias(&aliases, "older"), "older");
+        assert_eq!(resolve_alias(&aliases, ""), "");
+    }
+
+    #[tokio::test]
+    async fn upstream() {
+        let remote = tempfile::tempdir().unwrap();
+        // SHA-256 of "hello"
+        let hash = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        std::fs::create_dir_all(remote.path().join("repo/keys")).unwrap();
+        std::fs::write(remote.path().join("repo/keys").join(hash), "hello").unwrap();
+        let (upstream, upstream_server) = test_server(remote.path(), |_| {}).await;
+
+        let local = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let (addr, server) = test_server(local.path(), |config| {
+            config.upstream.url = Some(format!("http://{upstream}/"));
+            config.upstream.cache_dir = Some(cache.path().to_path_buf());
+        })
+        .await;
+
+        let client = reqwest::Client::new();
+        let list = client.get(format!("http://{addr}/repo/keys/")).send();
+        assert_eq!(
+            list.await.unwrap().text().await.unwrap(),
+            format!("[\"{hash}\"]")
+        );
+        let key = client.get(format!("http://{addr}/repo/keys/{hash}")).send();
+        assert_eq!(key.await.unwrap().text().await.unwrap(), "hello");
+        // the cached file is served without the upstream server
+        upstream_server.abort();
+        std::fs::remove_dir_all(remote.path().join("repo")).unwrap();
+        assert!(cache.path().join("repo/keys").join(hash).is_file());
+        let key = client.get(format!("http://{addr}/repo/keys/{hash}")).send();
+        assert_eq!(key.await.unwrap().text().await.unwrap(), "hello");
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn replica() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("repo/keys")).unwrap();
+        let (addr, server) = test_server(dir.path(), |config| {
+            config.replica.primary = Some("https://primary.example/".to_string());
+        })
+        .await;
+
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let list = client.get(format!("http://{addr}/repo/keys/")).send();
+        assert_eq!(list.await.unwrap().status(), StatusCode::OK);
+        let upload = client.post(format!("http://{addr}/repo/locks/{}?x=1", "0".repeat(64)));
+        let upload = upload.body("lock").send().await.unwrap();
+        assert_eq!(upload.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(
+            upload.headers()[LOCATION],
+            format!("https://primary.example/repo/locks/{}?x=1", "0".repeat(64))
+        );
+        server.abort();
+    }
+
+    #[test]
+    fn security() {
+        let mut config = Config::default();
+        let headers = security_headers(&config);
+        assert_eq!(headers[X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(headers[REFERRER_POLICY], "no-referrer");
+        // HSTS is only sent with TLS
+        assert!(!headers.contains_key(STRICT_TRANSPORT_SECURITY));
+
+        config.server.listen = vec!["https://[::]:443".to_string()];
+        config.headers.hsts_include_subdomains = true;
+        config.headers.referrer_policy = String::new();
+        let headers = security_headers(&config);
+        assert_eq!(
+            headers[STRICT_TRANSPORT_SECURITY],
+            "max-age=31536000; includeSubDomains"
+        );
+        assert!(!headers.contains_key(REFERRER_POLICY));
+    }
+
+    #[tokio::test]
+    async fn https_redirect() {
+        let state = State::new(
+            Auth::from_file(true, &PathBuf::new()).unwrap(),
+            Acl::default(),
+            LocalStorage::try_new(Path::new("/nonexistent")).unwrap(),
+        );
+        let (addr, server) = spawn(redirect_router(state, 8443)).await;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let res = client
+            .post(format!("http://{addr}/repo/?create=true"))
+            .header(HOST, "Backup.example:80")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            res.headers()[LOCATION],
+            "https://backup.example:8443/repo/?create=true"
+        );
+        let challenge = client.get(format!("http://{addr}/.well-known/acme-challenge/abc"));
+        assert_eq!(
+            challenge.send().await.unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+        server.abort();
+    }
+
+    // test_config returns a config storing below dir without authentication
+    fn test_config(dir: &Path) -> Config {
+        let mut config = Config::default();
+        config.storage.path = dir.to_path_buf();
+        config.auth.disable = true;
+        config
+    }
+
+    // test_server serves the router of test_config changed by configure
+    async fn test_server(
+        dir: &Path,
+        configure: impl FnOnce(&mut Config),
+    ) -> (SocketAddr, tokio::task::JoinHandle<io::Result<()>>) {
+        let mut config = test_config(dir);
+        configure(&mut config);
+        spawn(crate::router(&config).unwrap()).await
+    }
+
+    // spawn serves app on a free port and returns its address
+    async fn spawn(app: Router) -> (SocketAddr, tokio::task::JoinHandle<io::Result<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+        });
+        (addr, server)
+    }
+
+    fn parts(repo: &str, tpe: Option<&str>, name: Option<&str>) -> PathParts {
+        PathParts {
+            repo: repo.to_string(),
+            tpe: tpe.map(str::to_string),
+            name: name.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn decompose() {
+        let name = "a".repeat(64);
+        assert_eq!(decompose_path("").unwrap(), parts("", None, None));
+        assert_eq!(decompose_path("/").unwrap(), parts("", None, None));
+        assert_eq!(decompose_path("repo").unwrap(), parts("repo", None, None));
+        assert_eq!(decompose_path("repo/").unwrap(), parts("repo", None, None));
+        assert_eq!(decompose_path("a/b/").unwrap(), parts("a/b", None, None));
+        assert_eq!(
+            decompose_path("config").unwrap(),
+            parts("", Some("config"), Some(""))
+        );
+        assert_eq!(
+            decompose_path("a/b/config").unwrap(),
+            parts("a/b", Some("config"), Some(""))
+        );
+        assert_eq!(
+            decompose_path("data/").unwrap(),
+            parts("", Some("data"), None)
+        );
+        assert_eq!(
+            decompose_path("repo/keys/").unwrap(),
+            parts("repo", Some("keys"), None)
+        );
+        assert_eq!(
+            decompose_path(&format!("repo/data/{name}")).unwrap(),
+            parts("repo", Some("data"), Some(&name))
+        );
+
+        assert!(decompose_path("../data/").is_err());
+        assert!(decompose_path("a/../b/").is_err());
+        assert!(decompose_path("a//b/").is_err());
+        assert!(decompose_path("data/b/").is_err());
+    }
+
+    #[test]
+    fn listen_addr() {
+        let addr = |addr: &str, tls| ListenAddr {
+            addr: addr.to_string(),
+            tls,
+        };
+        assert_eq!(
+            ListenAddr::parse("[::]:8000", false),
+            addr("[::]:8000", false)
+        );
+        assert_eq!(
+            ListenAddr::parse("[::]:8000", true),
+            addr("[::]:8000", true)
+        );
+        assert_eq!(
+            ListenAddr::parse("http://127.0.0.1:8000", true),
+            addr("127.0.0.1:8000", false)
+        );
+        assert_eq!(
+            ListenAddr::parse("https://0.0.0.0:8443", false),
+            addr("0.0.0.0:8443", true)
+        );
+    }
+}
@@ -0,0 +1,67 @@
+// mod webhook
+//
+// sends JSON notifications about repository events to the webhook URL
+// configured for the repository, and pings its healthcheck URL after backups
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use serde::Serialize;
+
+// TIMEOUT is the maximum time a webhook request may take
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+// Event describes a change of a repository
+#[derive(Clone, Debug, Serialize)]
+pub struct Event {
+    pub event: &'static str,
+    pub repo: String,
+    #[serde(rename = "type")]
+    pub tpe: String,
+    pub name: String,
+    pub user: String,
+}
+
+// send posts event to url in the background; failures are only logged
+pub fn send(url: &str, event: Event) {
+    let client = CLIENT.get_or_init(reqwest::Client::new).clone();
+    let url = url.to_string();
+    _ = tokio::spawn(async move {
+        let res = client
+            .post(&url)
+            .json(&event)
+            .timeout(TIMEOUT)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status);
+        if let Err(err) = res {
+            tracing::warn!(
+                url,
+                event = event.event,
+                repo = event.repo,
+                "webhook failed: {err}"
+            );
+        }
+    });
+}
+
+// ping sends a GET request to the healthcheck url of repo in the background,
+// as dead man's switches like healthchecks.io expect; failures are only logged
+pub fn ping(url: &str, repo: &str) {
+    let client = CLIENT.get_or_init(reqwest::Client::new).clone();
+    let url = url.to_string();
+    let repo = repo.to_string();
+    _ = tokio::spawn(async move {
+        let res = client
+            .get(&url)
+            .timeout(TIMEOUT)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status);
+        if let Err(err) = res {
+            tracing::warn!(url, repo, "healthcheck ping failed: {err}");
+        }
+    });
+}
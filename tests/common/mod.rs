@@ -0,0 +1,44 @@
+// helpers shared by the integration tests
+
+use std::net::SocketAddr;
+
+use rustic_server::config::Config;
+use tempfile::TempDir;
+
+// Server is a server on a random port storing into a temporary directory
+pub struct Server {
+    pub url: String,
+    pub dir: TempDir,
+    task: tokio::task::JoinHandle<std::io::Result<()>>,
+}
+
+impl Server {
+    pub async fn start(configure: impl FnOnce(&mut Config)) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.storage.path = dir.path().to_path_buf();
+        config.auth.disable = true;
+        configure(&mut config);
+        let app = rustic_server::router(&config).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+        });
+        Self {
+            url: format!("http://{addr}"),
+            dir,
+            task,
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
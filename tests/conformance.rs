@@ -0,0 +1,192 @@
+// conformance tests of the REST backend protocol as documented by restic in
+// https://restic.readthedocs.io/en/stable/100_references.html#rest-backend
+//
+// run with `cargo test --features conformance`
+
+mod common;
+
+use reqwest::header::{
+    ACCEPT, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, RANGE, WWW_AUTHENTICATE,
+};
+use reqwest::{Client, StatusCode};
+use ring::digest::{digest, SHA256};
+use serde_json::{json, Value};
+
+use common::Server;
+
+const API_V1: &str = "application/vnd.x.restic.rest.v1";
+const API_V2: &str = "application/vnd.x.restic.rest.v2";
+const TYPES: [&str; 5] = ["data", "keys", "locks", "snapshots", "index"];
+
+// name returns the name of a file with content, its SHA-256 in hex
+fn name(content: &str) -> String {
+    digest(&SHA256, content.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+async fn create(client: &Client, repo: &str) {
+    let res = client.post(format!("{repo}/?create=true")).send().await;
+    assert_eq!(res.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn create_repository() {
+    let server = Server::start(|_| {}).await;
+    let client = Client::new();
+    let repo = format!("{}/repo", server.url);
+
+    // 200 with an empty body, also if the repository exists
+    for _ in 0..2 {
+        let res = client
+            .post(format!("{repo}/?create=true"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.bytes().await.unwrap(), "");
+    }
+    for tpe in TYPES {
+        assert!(server.dir.path().join("repo").join(tpe).is_dir(), "{tpe}");
+    }
+    let res = client.post(format!("{}/other/", server.url)).send();
+    assert_eq!(res.await.unwrap().status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn config() {
+    let server = Server::start(|_| {}).await;
+    let client = Client::new();
+    let repo = format!("{}/repo", server.url);
+    create(&client, &repo).await;
+    let url = format!("{repo}/config");
+
+    let head = client.head(&url).send().await.unwrap();
+    assert_eq!(head.status(), StatusCode::NOT_FOUND);
+    let get = client.get(&url).send().await.unwrap();
+    assert_eq!(get.status(), StatusCode::NOT_FOUND);
+
+    let saved = client.post(&url).body("encrypted config").send().await;
+    assert_eq!(saved.unwrap().status(), StatusCode::OK);
+    let head = client.head(&url).send().await.unwrap();
+    assert_eq!(head.status(), StatusCode::OK);
+    assert_eq!(head.headers()[CONTENT_LENGTH], "16");
+    let get = client.get(&url).send().await.unwrap();
+    assert_eq!(get.status(), StatusCode::OK);
+    assert_eq!(get.bytes().await.unwrap(), "encrypted config");
+}
+
+#[tokio::test]
+async fn files() {
+    let server = Server::start(|_| {}).await;
+    let client = Client::new();
+    let repo = format!("{}/repo", server.url);
+    create(&client, &repo).await;
+
+    for tpe in TYPES {
+        let content = format!("content of a {tpe} file");
+        let url = format!("{repo}/{tpe}/{}", name(&content));
+
+        let head = client.head(&url).send().await.unwrap();
+        assert_eq!(head.status(), StatusCode::NOT_FOUND, "{tpe}");
+        let saved = client.post(&url).body(content.clone()).send().await;
+        assert_eq!(saved.unwrap().status(), StatusCode::OK, "{tpe}");
+
+        let head = client.head(&url).send().await.unwrap();
+        assert_eq!(head.status(), StatusCode::OK, "{tpe}");
+        let len = content.len().to_string();
+        assert_eq!(head.headers()[CONTENT_LENGTH], len.as_str(), "{tpe}");
+        let get = client.get(&url).send().await.unwrap();
+        assert_eq!(get.status(), StatusCode::OK, "{tpe}");
+        assert_eq!(get.headers()[ACCEPT_RANGES], "bytes");
+        assert_eq!(get.bytes().await.unwrap(), content.as_str(), "{tpe}");
+
+        // restic reads parts of pack files with ranges
+        let range = client
+            .get(&url)
+            .header(RANGE, "bytes=8-9")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(range.status(), StatusCode::PARTIAL_CONTENT, "{tpe}");
+        let expected = format!("bytes 8-9/{}", content.len());
+        assert_eq!(range.headers()[CONTENT_RANGE], expected.as_str());
+        assert_eq!(range.bytes().await.unwrap(), &content[8..10]);
+
+        let deleted = client.delete(&url).send().await.unwrap();
+        assert_eq!(deleted.status(), StatusCode::OK, "{tpe}");
+        let head = client.head(&url).send().await.unwrap();
+        assert_eq!(head.status(), StatusCode::NOT_FOUND, "{tpe}");
+        let get = client.get(&url).send().await.unwrap();
+        assert_eq!(get.status(), StatusCode::NOT_FOUND, "{tpe}");
+    }
+}
+
+#[tokio::test]
+async fn listings() {
+    let server = Server::start(|_| {}).await;
+    let client = Client::new();
+    let repo = format!("{}/repo", server.url);
+    create(&client, &repo).await;
+
+    for tpe in TYPES {
+        let url = format!("{repo}/{tpe}/");
+        // API version 1 returns the names, version 2 names and sizes
+        let list = client.get(&url).send().await.unwrap();
+        assert_eq!(list.status(), StatusCode::OK);
+        assert_eq!(list.headers()[CONTENT_TYPE], API_V1);
+        assert_eq!(list.json::<Value>().await.unwrap(), json!([]), "{tpe}");
+
+        let content = format!("listed {tpe} file");
+        let name = name(&content);
+        let saved = client.post(format!("{url}{name}")).body(content.clone());
+        assert_eq!(saved.send().await.unwrap().status(), StatusCode::OK);
+
+        let list = client.get(&url).header(ACCEPT, API_V1).send().await;
+        let list = list.unwrap();
+        assert_eq!(list.headers()[CONTENT_TYPE], API_V1);
+        assert_eq!(list.json::<Value>().await.unwrap(), json!([name]), "{tpe}");
+        let list = client.get(&url).header(ACCEPT, API_V2).send().await;
+        let list = list.unwrap();
+        assert_eq!(list.status(), StatusCode::OK);
+        assert_eq!(list.headers()[CONTENT_TYPE], API_V2);
+        assert_eq!(
+            list.json::<Value>().await.unwrap(),
+            json!([{"name": name, "size": content.len()}]),
+            "{tpe}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn authentication() {
+    let htpasswd = format!("alice:{}\n", bcrypt::hash("secret", 4).unwrap());
+    let server = Server::start(|config| {
+        config.auth.disable = false;
+        std::fs::write(config.storage.path.join(".htpasswd"), htpasswd).unwrap();
+    })
+    .await;
+    let client = Client::new();
+    let repo = format!("{}/alice", server.url);
+
+    let res = client.post(format!("{repo}/?create=true")).send().await;
+    let res = res.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    assert!(res.headers().contains_key(WWW_AUTHENTICATE));
+    let res = client
+        .post(format!("{repo}/?create=true"))
+        .basic_auth("alice", Some("wrong"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let res = client
+        .post(format!("{repo}/?create=true"))
+        .basic_auth("alice", Some("secret"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+}
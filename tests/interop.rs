@@ -0,0 +1,213 @@
+// interop tests running the restic and rustic binaries against the server:
+// a repository is initialized, backed up to, checked and restored from, and
+// the restored files are compared with the originals. Tests of a client
+// which isn't installed are skipped.
+//
+// run with `cargo test --features interop`; the binaries are taken from
+// $RESTIC_BIN and $RUSTIC_BIN or looked up in $PATH
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use common::Server;
+use walkdir::WalkDir;
+
+const PASSWORD: &str = "interop";
+
+// binary returns the path of the client name, if it's installed
+fn binary(name: &str, var: &str) -> Option<PathBuf> {
+    if let Some(path) = env::var_os(var) {
+        return Some(PathBuf::from(path));
+    }
+    let found = env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file());
+    if found.is_none() {
+        eprintln!("{name} not found, skipping; set {var} to run this test");
+    }
+    found
+}
+
+// start starts a server with authentication; the returned URL contains the
+// credentials of the user owning the repository
+async fn start() -> (Server, String) {
+    let htpasswd = format!("alice:{}\n", bcrypt::hash("secret", 4).unwrap());
+    let server = Server::start(|config| {
+        config.auth.disable = false;
+        fs::write(config.storage.path.join(".htpasswd"), htpasswd).unwrap();
+    })
+    .await;
+    let url = server.url.replace("http://", "rest:http://alice:secret@");
+    (server, format!("{url}/alice"))
+}
+
+// source writes the files to back up to dir/source
+fn source(dir: &Path) -> PathBuf {
+    let source = dir.join("source");
+    fs::create_dir_all(source.join("nested/deeper")).unwrap();
+    fs::write(source.join("small.txt"), "hello from the interop test\n").unwrap();
+    fs::write(source.join("nested/deeper/empty"), "").unwrap();
+    // large enough to be split into several chunks, not compressible
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let large: Vec<u8> = (0..6 << 20)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    fs::write(source.join("nested/large.bin"), large).unwrap();
+    source
+}
+
+// run runs cmd on a blocking thread and fails the test if it fails
+async fn run(mut cmd: Command) {
+    let name = format!("{cmd:?}");
+    let output = tokio::task::spawn_blocking(move || cmd.output())
+        .await
+        .unwrap()
+        .unwrap_or_else(|err| panic!("cannot run {name}: {err}"));
+    assert!(
+        output.status.success(),
+        "{name} failed: {}\n{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+// assert_restored checks that the directory named like source below target
+// contains the same files as source
+fn assert_restored(source: &Path, target: &Path) {
+    let restored = WalkDir::new(target)
+        .into_iter()
+        .filter_map(Result::ok)
+        .find(|e| e.file_type().is_dir() && e.file_name() == source.file_name().unwrap())
+        .unwrap_or_else(|| panic!("nothing restored to {}", target.display()))
+        .into_path();
+    let files = |dir: &Path| -> Vec<(PathBuf, Vec<u8>)> {
+        let mut files: Vec<_> = WalkDir::new(dir)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|e| e.file_type().is_file())
+            .map(|e| {
+                let path = e.path().strip_prefix(dir).unwrap().to_path_buf();
+                (path, fs::read(e.path()).unwrap())
+            })
+            .collect();
+        files.sort();
+        files
+    };
+    let (expected, restored) = (files(source), files(&restored));
+    let names = |files: &[(PathBuf, Vec<u8>)]| -> Vec<PathBuf> {
+        files.iter().map(|(path, _)| path.clone()).collect()
+    };
+    assert_eq!(names(&restored), names(&expected));
+    assert!(restored == expected, "restored files differ");
+}
+
+fn restic(bin: &Path, repo: &str, args: &[&str]) -> Command {
+    let mut cmd = Command::new(bin);
+    cmd.args(["--repo", repo, "--no-cache"])
+        .args(args)
+        .env("RESTIC_PASSWORD", PASSWORD);
+    cmd
+}
+
+fn rustic(bin: &Path, repo: &str, args: &[&str]) -> Command {
+    let mut cmd = Command::new(bin);
+    cmd.args(["--repository", repo, "--password", PASSWORD])
+        .args(["--no-cache", "--no-progress"])
+        .args(args);
+    cmd
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn restic_roundtrip() {
+    let Some(bin) = binary("restic", "RESTIC_BIN") else {
+        return;
+    };
+    let (server, repo) = start().await;
+    let dir = tempfile::tempdir().unwrap();
+    let source = source(dir.path());
+    let target = dir.path().join("target");
+    let source_arg = source.to_str().unwrap();
+
+    run(restic(&bin, &repo, &["init"])).await;
+    run(restic(&bin, &repo, &["backup", source_arg])).await;
+    // a second backup only adds a snapshot
+    run(restic(&bin, &repo, &["backup", source_arg])).await;
+    run(restic(&bin, &repo, &["check", "--read-data"])).await;
+    let target_arg = target.to_str().unwrap();
+    run(restic(
+        &bin,
+        &repo,
+        &["restore", "latest", "--target", target_arg],
+    ))
+    .await;
+    assert_restored(&source, &target);
+
+    run(restic(
+        &bin,
+        &repo,
+        &["forget", "--keep-last", "1", "--prune"],
+    ))
+    .await;
+    run(restic(&bin, &repo, &["check"])).await;
+    let snapshots = server.dir.path().join("alice/snapshots");
+    assert_eq!(fs::read_dir(snapshots).unwrap().count(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rustic_roundtrip() {
+    let Some(bin) = binary("rustic", "RUSTIC_BIN") else {
+        return;
+    };
+    let (_server, repo) = start().await;
+    let dir = tempfile::tempdir().unwrap();
+    let source = source(dir.path());
+    let target = dir.path().join("target");
+
+    run(rustic(&bin, &repo, &["init"])).await;
+    run(rustic(&bin, &repo, &["backup", source.to_str().unwrap()])).await;
+    run(rustic(&bin, &repo, &["check", "--read-data"])).await;
+    let target_arg = target.to_str().unwrap();
+    run(rustic(&bin, &repo, &["restore", "latest", target_arg])).await;
+    assert_restored(&source, &target);
+}
+
+// a repository written by restic is readable by rustic
+#[tokio::test(flavor = "multi_thread")]
+async fn restic_to_rustic() {
+    let (Some(restic_bin), Some(rustic_bin)) = (
+        binary("restic", "RESTIC_BIN"),
+        binary("rustic", "RUSTIC_BIN"),
+    ) else {
+        return;
+    };
+    let (_server, repo) = start().await;
+    let dir = tempfile::tempdir().unwrap();
+    let source = source(dir.path());
+    let target = dir.path().join("target");
+
+    run(restic(&restic_bin, &repo, &["init"])).await;
+    run(restic(
+        &restic_bin,
+        &repo,
+        &["backup", source.to_str().unwrap()],
+    ))
+    .await;
+    run(rustic(&rustic_bin, &repo, &["check", "--read-data"])).await;
+    let target_arg = target.to_str().unwrap();
+    run(rustic(
+        &rustic_bin,
+        &repo,
+        &["restore", "latest", target_arg],
+    ))
+    .await;
+    assert_restored(&source, &target);
+}